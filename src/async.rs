@@ -0,0 +1,165 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! Asynchronous interface to the database for use within tokio services. The
+//! operations are carried out on the blocking thread pool of the runtime, as
+//! they may wait on other users of the database or on writing to the log,
+//! leaving the executor free to run other tasks.
+
+use crate::error::Result;
+use crate::store::{Database, SharedDatabase};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+///
+/// Handle to a database whose operations are `async`. As with
+/// `SharedDatabase`, each handle has its own transactions, and cloning a
+/// handle yields another with no open transactions. Tasks that share one
+/// handle also share its transactions.
+///
+pub struct AsyncDatabase<K = String, V = String> {
+    handle: Arc<Mutex<SharedDatabase<K, V>>>,
+}
+
+impl<K, V> Clone for AsyncDatabase<K, V> {
+    fn clone(&self) -> Self {
+        let handle = self.handle.lock().unwrap().clone();
+        Self {
+            handle: Arc::new(Mutex::new(handle)),
+        }
+    }
+}
+
+impl<K, V> From<SharedDatabase<K, V>> for AsyncDatabase<K, V> {
+    fn from(handle: SharedDatabase<K, V>) -> Self {
+        Self {
+            handle: Arc::new(Mutex::new(handle)),
+        }
+    }
+}
+
+impl<K, V> AsyncDatabase<K, V>
+where
+    K: Ord + Clone + Send + Sync + 'static,
+    V: Hash + Eq + Clone + Send + Sync + 'static,
+{
+    /// Share the database, which must not have any open transactions.
+    pub fn new(database: Database<K, V>) -> Self {
+        SharedDatabase::new(database).into()
+    }
+
+    /// Run the operation on the blocking thread pool.
+    async fn run<T, F>(&self, operation: F) -> T
+    where
+        F: FnOnce(&mut SharedDatabase<K, V>) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let handle = self.handle.clone();
+        let task = tokio::task::spawn_blocking(move || operation(&mut handle.lock().unwrap()));
+        match task.await {
+            Ok(result) => result,
+            Err(err) => match err.try_into_panic() {
+                Ok(panic) => std::panic::resume_unwind(panic),
+                Err(err) => panic!("database operation failed: {}", err),
+            },
+        }
+    }
+
+    /// Retrieve the value for the given key, if any.
+    pub async fn get(&self, name: impl Into<K>) -> Option<V> {
+        let name = name.into();
+        self.run(move |db| db.get(&name)).await
+    }
+
+    /// Save the value using the given key.
+    pub async fn set(&self, name: impl Into<K>, value: impl Into<V>) {
+        let (name, value) = (name.into(), value.into());
+        self.run(move |db| db.set(name, value)).await
+    }
+
+    /// Removes the value with the given key.
+    pub async fn delete(&self, name: impl Into<K>) {
+        let name = name.into();
+        self.run(move |db| db.delete(&name)).await
+    }
+
+    /// Returns the number of occurrences of the given value.
+    pub async fn count(&self, value: impl Into<V>) -> u32 {
+        let value = value.into();
+        self.run(move |db| db.count(&value)).await
+    }
+
+    /// Add the values to the end of the list held by the key, returning the
+    /// new length of the list.
+    pub async fn rpush<I>(&self, name: impl Into<K>, values: I) -> Result<usize>
+    where
+        I: IntoIterator,
+        I::Item: Into<V>,
+    {
+        let name = name.into();
+        let values: Vec<V> = values.into_iter().map(Into::into).collect();
+        self.run(move |db| db.rpush(name, values)).await
+    }
+
+    /// Remove and return the first value of the list held by the key, waiting
+    /// for one to be added for at most the given time. Other tasks continue
+    /// to run in the meantime, though the handle is busy until it returns, so
+    /// each consumer should have its own.
+    pub async fn blpop(&self, name: impl Into<K>, timeout: Duration) -> Result<Option<V>> {
+        let name = name.into();
+        self.run(move |db| db.blpop(&name, timeout)).await
+    }
+
+    /// Start a new transaction on this handle.
+    pub async fn begin(&self) {
+        self.run(|db| db.begin()).await
+    }
+
+    /// Commit _all_ transactions open on this handle.
+    pub async fn commit(&self) -> Result<()> {
+        self.run(|db| db.commit()).await
+    }
+
+    /// Roll back the innermost transaction on this handle, failing with
+    /// `Error::NoTransaction` if there is no open transaction.
+    pub async fn rollback(&self) -> Result<()> {
+        self.run(|db| db.rollback()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_async_database() {
+        let first: AsyncDatabase = AsyncDatabase::new(Database::new());
+        let second = first.clone();
+        first.set("a", "10").await;
+        assert_eq!(second.get("a").await, Some("10".into()));
+        second.begin().await;
+        second.set("b", "10").await;
+        assert_eq!(second.count("10").await, 2);
+        assert_eq!(first.count("10").await, 1);
+        assert!(second.commit().await.is_ok());
+        assert_eq!(first.get("b").await, Some("10".into()));
+        first.begin().await;
+        first.delete("a").await;
+        assert!(first.rollback().await.is_ok());
+        assert!(first.rollback().await.is_err());
+        assert_eq!(first.count("10").await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_async_queue() {
+        let producer: AsyncDatabase = AsyncDatabase::new(Database::new());
+        let consumer = producer.clone();
+        let waiting = tokio::spawn(async move { consumer.blpop("q", Duration::MAX).await });
+        assert_eq!(producer.rpush("q", ["job"]).await.unwrap(), 1);
+        assert_eq!(waiting.await.unwrap().unwrap(), Some("job".into()));
+        let timeout = Duration::from_millis(10);
+        assert_eq!(producer.blpop("q", timeout).await.unwrap(), None);
+    }
+}
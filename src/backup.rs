@@ -0,0 +1,177 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! Backups made of a full snapshot followed by a chain of increments, each
+//! holding only the keys that changed since the backup before it, as made by
+//! `Database::backup()` and `Database::backup_incremental()`.
+//!
+//! Every backup writes a manifest, which lists the files to be applied to
+//! restore it, the snapshot first and then the increments in order, along
+//! with a fingerprint of the data of every key as it was backed up, against
+//! which the next increment finds the keys that changed. An increment is
+//! written in the same form as the write-ahead log, replacing the data of
+//! each key that changed and removing those that no longer exist.
+//!
+//! A manifest starts with the magic bytes `SDBM` and a version byte, followed
+//! by the number of files as a little-endian `u32` and the path of each in
+//! the same form as a key in the log, then the number of keys as a `u32` and
+//! each key with its fingerprint as a `u64`. The manifest of an encrypted
+//! database is sealed as a whole, as described in `crypt`, and its
+//! increments are encrypted as its log is.
+
+use crate::crypt::{self, Secret};
+use crate::persist;
+use std::fs;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 4] = b"SDBM";
+const VERSION: u8 = 1;
+
+///
+/// The files that make up a backup and the fingerprints of the keys it holds.
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+    /// The snapshot followed by the increments, in the order to be applied.
+    pub files: Vec<PathBuf>,
+    // each key, as bytes, and the fingerprint of its data, in key order
+    pub(crate) fingerprints: Vec<(Vec<u8>, u64)>,
+}
+
+impl Manifest {
+    /// Write the manifest to the file at the given path, replacing it only
+    /// once it has been written in its entirety. Given a secret, the manifest
+    /// is encrypted.
+    pub fn save(&self, path: &Path, secret: Option<&Secret>) -> io::Result<()> {
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        let mut writer = BufWriter::new(fs::File::create(&temp)?);
+        match secret {
+            Some(secret) => {
+                let mut contents = Vec::new();
+                self.write_to(&mut contents)?;
+                writer.write_all(&crypt::seal_file(secret, &contents)?)?;
+            }
+            None => self.write_to(&mut writer)?,
+        }
+        let file = writer.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        fs::rename(&temp, path)
+    }
+
+    /// Read the manifest in the file at the given path, which the secret
+    /// decrypts if it is encrypted.
+    pub fn load(path: &Path, secret: Option<&Secret>) -> io::Result<Self> {
+        Self::read_from(crypt::open_file(path, secret)?)
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        persist::write_count(writer, self.files.len())?;
+        for file in self.files.iter() {
+            let file = file
+                .to_str()
+                .ok_or_else(|| persist::invalid("backup paths must be valid UTF-8"))?;
+            persist::write_bytes(writer, file.as_bytes())?;
+        }
+        persist::write_count(writer, self.fingerprints.len())?;
+        for (name, fingerprint) in self.fingerprints.iter() {
+            persist::write_bytes(writer, name)?;
+            writer.write_all(&fingerprint.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn read_from<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut header = [0u8; 5];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != MAGIC || header[4] != VERSION {
+            return Err(persist::invalid("not a backup manifest"));
+        }
+        let mut files = Vec::new();
+        for _ in 0..read_count(&mut reader)? {
+            let file = String::from_utf8(read_bytes(&mut reader)?)
+                .map_err(|_| persist::invalid("backup paths must be valid UTF-8"))?;
+            files.push(PathBuf::from(file));
+        }
+        let mut fingerprints = Vec::new();
+        for _ in 0..read_count(&mut reader)? {
+            let name = read_bytes(&mut reader)?;
+            let mut fingerprint = [0u8; 8];
+            reader.read_exact(&mut fingerprint)?;
+            fingerprints.push((name, u64::from_le_bytes(fingerprint)));
+        }
+        Ok(Self {
+            files,
+            fingerprints,
+        })
+    }
+}
+
+///
+/// FNV-1a hash of the data of a key, with the length of each piece mixed in
+/// so that different pieces cannot run together to the same bytes.
+///
+pub(crate) struct Fingerprint(u64);
+
+impl Fingerprint {
+    pub fn new(kind: u8) -> Self {
+        let mut fingerprint = Self(0xcbf29ce484222325);
+        fingerprint.add(&[kind]);
+        fingerprint
+    }
+
+    pub fn add(&mut self, bytes: &[u8]) {
+        let length = (bytes.len() as u32).to_le_bytes();
+        for byte in length.iter().chain(bytes.iter()) {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    pub fn finish(self) -> u64 {
+        self.0
+    }
+}
+
+fn read_count<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut count = [0u8; 4];
+    reader.read_exact(&mut count)?;
+    Ok(u32::from_le_bytes(count))
+}
+
+/// Read a length-prefixed byte string, failing if the input ends first.
+fn read_bytes<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    persist::read_bytes(reader)?.ok_or_else(|| persist::invalid("manifest is incomplete"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest() {
+        let manifest = Manifest {
+            files: vec![PathBuf::from("/backups/0.snapshot"), PathBuf::from("1.log")],
+            fingerprints: vec![(b"a".to_vec(), 10), (b"b".to_vec(), 20)],
+        };
+        let path = std::env::temp_dir().join(format!("simpledb-manifest-{}", std::process::id()));
+        manifest.save(&path, None).unwrap();
+        assert_eq!(Manifest::load(&path, None).unwrap(), manifest);
+        let bytes = fs::read(&path).unwrap();
+        assert!(Manifest::read_from(&bytes[..bytes.len() - 1]).is_err());
+        let err = Manifest::read_from(&b"SDBC\x01"[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+        let mut one = Fingerprint::new(b'v');
+        one.add(b"ab");
+        let mut other = Fingerprint::new(b'v');
+        other.add(b"a");
+        other.add(b"b");
+        assert_ne!(one.finish(), other.finish());
+    }
+}
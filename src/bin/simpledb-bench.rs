@@ -0,0 +1,519 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! Workload generator for soak testing the database. Drives an in-process
+//! instance, or one served to Redis clients elsewhere, with a configurable mix
+//! of reads, writes, and transactions for as long as requested, periodically
+//! reporting throughput, latency percentiles, and memory growth.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use simpledb::store::Database;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "usage: simpledb-bench [options]
+
+options:
+  --duration <secs>      total run time in seconds (default 10)
+  --interval <secs>      seconds between progress reports (default 5)
+  --keys <n>             number of distinct keys (default 10000)
+  --values <n>           number of distinct values (default 100)
+  --value-size <bytes>   length of each value (default 8)
+  --distribution <name>  key distribution: uniform or zipf (default uniform)
+  --reads <ratio>        fraction of operations that are reads (default 0.8)
+  --tx-size <n>          operations per transaction, 0 for none (default 0)
+  --tx-depth <n>         nesting depth of each transaction (default 1)
+  --rollback <ratio>     fraction of transactions rolled back (default 0.1)
+  --seed <n>             random seed for a repeatable workload
+  --connect <host:port>  drive the instance served there rather than one in
+                         this process; transactions cannot be nested";
+
+/// Parse the value given for a command-line option.
+fn parse<T: FromStr>(arg: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {}", arg, value))
+}
+
+/// Settings for the generated workload.
+struct Config {
+    duration: Duration,
+    interval: Duration,
+    keys: usize,
+    values: usize,
+    value_size: usize,
+    zipf: bool,
+    reads: f64,
+    tx_size: usize,
+    tx_depth: usize,
+    rollback: f64,
+    seed: Option<u64>,
+    connect: Option<String>,
+}
+
+impl Config {
+    /// Construct a configuration from the command-line arguments.
+    fn from_args() -> Result<Self, String> {
+        let mut config = Self {
+            duration: Duration::from_secs(10),
+            interval: Duration::from_secs(5),
+            keys: 10_000,
+            values: 100,
+            value_size: 8,
+            zipf: false,
+            reads: 0.8,
+            tx_size: 0,
+            tx_depth: 1,
+            rollback: 0.1,
+            seed: None,
+            connect: None,
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            let value = args
+                .next()
+                .ok_or_else(|| format!("missing value for {}", arg))?;
+            match arg.as_str() {
+                "--duration" => {
+                    config.duration = Duration::from_secs(parse(&arg, &value)?);
+                }
+                "--interval" => {
+                    config.interval = Duration::from_secs(parse(&arg, &value)?);
+                }
+                "--keys" => config.keys = parse(&arg, &value)?,
+                "--values" => config.values = parse(&arg, &value)?,
+                "--value-size" => config.value_size = parse(&arg, &value)?,
+                "--distribution" => match value.as_str() {
+                    "uniform" => config.zipf = false,
+                    "zipf" => config.zipf = true,
+                    _ => return Err(format!("unknown distribution: {}", value)),
+                },
+                "--reads" => config.reads = parse(&arg, &value)?,
+                "--tx-size" => config.tx_size = parse(&arg, &value)?,
+                "--tx-depth" => config.tx_depth = parse(&arg, &value)?,
+                "--rollback" => config.rollback = parse(&arg, &value)?,
+                "--seed" => config.seed = Some(parse(&arg, &value)?),
+                "--connect" => config.connect = Some(value),
+                _ => return Err(format!("unknown option: {}\n\n{}", arg, USAGE)),
+            }
+        }
+        if config.keys == 0 || config.values == 0 {
+            return Err("--keys and --values must be greater than zero".into());
+        }
+        if config.interval.is_zero() {
+            return Err("--interval must be greater than zero".into());
+        }
+        if config.tx_size > 0 && config.tx_depth == 0 {
+            return Err("--tx-depth must be greater than zero with --tx-size".into());
+        }
+        if config.tx_size > 0 && config.tx_depth > 1 && config.connect.is_some() {
+            return Err("--tx-depth must be 1 with --connect, MULTI does not nest".into());
+        }
+        Ok(config)
+    }
+}
+
+///
+/// Chooses key indices according to either a uniform or Zipfian distribution.
+///
+struct KeyChooser {
+    // cumulative distribution for zipf, empty for uniform
+    cdf: Vec<f64>,
+    keys: usize,
+}
+
+impl KeyChooser {
+    /// Construct a chooser over `keys` indices.
+    fn new(keys: usize, zipf: bool) -> Self {
+        let mut cdf = Vec::new();
+        if zipf {
+            cdf.reserve(keys);
+            let mut sum = 0.0;
+            for rank in 1..=keys {
+                sum += 1.0 / rank as f64;
+                cdf.push(sum);
+            }
+            for p in cdf.iter_mut() {
+                *p /= sum;
+            }
+        }
+        Self { cdf, keys }
+    }
+
+    /// Select the index of the next key.
+    fn choose<R: Rng>(&self, rng: &mut R) -> usize {
+        if self.cdf.is_empty() {
+            rng.gen_range(0..self.keys)
+        } else {
+            let p: f64 = rng.gen();
+            self.cdf.partition_point(|c| *c < p).min(self.keys - 1)
+        }
+    }
+}
+
+///
+/// Operation latencies and counters for a reporting interval. Latencies are
+/// kept in logarithmic buckets so that memory use stays flat no matter how
+/// long the run lasts, at the cost of percentiles being accurate to within a
+/// few percent.
+///
+struct Stats {
+    buckets: Vec<u64>,
+    ops: u64,
+    max: Duration,
+    commits: u64,
+    rollbacks: u64,
+}
+
+// number of sub-buckets per power of two
+const SUB_BUCKETS: u32 = 16;
+
+impl Stats {
+    /// Construct an empty set of statistics.
+    fn new() -> Self {
+        Self {
+            buckets: vec![0; (64 * SUB_BUCKETS) as usize],
+            ops: 0,
+            max: Duration::ZERO,
+            commits: 0,
+            rollbacks: 0,
+        }
+    }
+
+    /// Map a latency in nanoseconds to its bucket.
+    fn bucket_of(nanos: u64) -> usize {
+        if nanos < SUB_BUCKETS as u64 {
+            return nanos as usize;
+        }
+        let exp = 63 - nanos.leading_zeros();
+        let mantissa = (nanos >> (exp - 4)) & (SUB_BUCKETS as u64 - 1);
+        ((exp - 3) * SUB_BUCKETS) as usize + mantissa as usize
+    }
+
+    /// Return the smallest latency in nanoseconds that falls in the bucket.
+    fn lower_bound(bucket: usize) -> u64 {
+        let bucket = bucket as u64;
+        let sub = SUB_BUCKETS as u64;
+        if bucket < sub {
+            return bucket;
+        }
+        let exp = bucket / sub + 3;
+        let mantissa = bucket % sub;
+        (1 << exp) | (mantissa << (exp - 4))
+    }
+
+    /// Record the latency of a single operation.
+    fn record(&mut self, elapsed: Duration) {
+        let nanos = elapsed.as_nanos().min(u64::MAX as u128) as u64;
+        self.buckets[Self::bucket_of(nanos)] += 1;
+        self.ops += 1;
+        self.max = self.max.max(elapsed);
+    }
+
+    /// Return the approximate latency at the given percentile (0 to 100).
+    fn percentile(&self, pct: f64) -> Duration {
+        let target = ((pct / 100.0) * self.ops as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return Duration::from_nanos(Self::lower_bound(bucket)).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    /// Print a summary line for the given time span, with the growth in memory
+    /// since the baseline, if there is one.
+    fn report(&self, label: &str, span: Duration, baseline_rss: Option<u64>) {
+        let throughput = self.ops as f64 / span.as_secs_f64().max(f64::EPSILON);
+        let memory = match (resident_memory(), baseline_rss) {
+            (Some(now), Some(base)) => format!(
+                "rss {} KiB ({:+} KiB)",
+                now / 1024,
+                (now as i64 - base as i64) / 1024
+            ),
+            _ => "rss n/a".into(),
+        };
+        println!(
+            "{}: {} ops, {:.0} ops/s, p50 {:?}, p90 {:?}, p99 {:?}, max {:?}, {} commits, {} rollbacks, {}",
+            label,
+            self.ops,
+            throughput,
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+            self.max,
+            self.commits,
+            self.rollbacks,
+            memory
+        );
+    }
+
+    /// Fold the counters from an interval into these totals and reset the
+    /// interval.
+    fn absorb(&mut self, other: &mut Stats) {
+        for (total, count) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *total += count;
+        }
+        self.ops += other.ops;
+        self.max = self.max.max(other.max);
+        self.commits += other.commits;
+        self.rollbacks += other.rollbacks;
+        *other = Stats::new();
+    }
+}
+
+///
+/// A connection to a database served to Redis clients, speaking just enough
+/// of the protocol to issue commands and read their replies.
+///
+struct Remote {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Remote {
+    /// Connect to the server at the given address.
+    fn connect(address: &str) -> io::Result<Self> {
+        let writer = TcpStream::connect(address)?;
+        writer.set_nodelay(true)?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(Self { reader, writer })
+    }
+
+    /// Send a command and wait for its reply, returning the bulk string, if
+    /// that is what the reply was.
+    fn call(&mut self, args: &[&str]) -> io::Result<Option<String>> {
+        let mut request = format!("*{}\r\n", args.len());
+        for arg in args {
+            request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.writer.write_all(request.as_bytes())?;
+        self.reply()
+    }
+
+    /// Read one reply, including any elements of an array.
+    fn reply(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let line = line.trim_end();
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, line.to_owned());
+        let (kind, rest) = line.split_at_checked(1).ok_or_else(invalid)?;
+        match kind {
+            "+" | ":" => Ok(None),
+            "-" => Err(io::Error::other(rest.to_owned())),
+            "$" => {
+                let Ok(len) = rest.parse::<usize>() else {
+                    // the null bulk string
+                    return Ok(None);
+                };
+                let mut data = vec![0; len + 2];
+                self.reader.read_exact(&mut data)?;
+                data.truncate(len);
+                Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+            }
+            "*" => {
+                let count = rest.parse::<i64>().map_err(|_| invalid())?;
+                for _ in 0..count {
+                    self.reply()?;
+                }
+                Ok(None)
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+///
+/// The database under load, either in this process or served elsewhere.
+///
+enum Target {
+    Local(Box<Database>),
+    Remote(Remote),
+}
+
+impl Target {
+    /// Read a value and count how many keys share it.
+    fn read(&mut self, key: &str) -> io::Result<()> {
+        match self {
+            Target::Local(database) => {
+                if let Some(value) = database.get_ref(key) {
+                    database.count(value);
+                }
+            }
+            Target::Remote(remote) => {
+                // within a transaction the reply is only that it is queued
+                if let Some(value) = remote.call(&["GET", key])? {
+                    remote.call(&["NUMEQUALTO", &value])?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> io::Result<()> {
+        match self {
+            Target::Local(database) => database.set(key, value),
+            Target::Remote(remote) => {
+                remote.call(&["SET", key, value])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> io::Result<()> {
+        match self {
+            Target::Local(database) => {
+                database.delete(key);
+            }
+            Target::Remote(remote) => {
+                remote.call(&["DEL", key])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Begin a transaction nested to the given depth.
+    fn begin(&mut self, depth: usize) -> io::Result<()> {
+        match self {
+            Target::Local(database) => {
+                for _ in 0..depth {
+                    database.begin();
+                }
+            }
+            Target::Remote(remote) => {
+                remote.call(&["MULTI"])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Commit every level of the transaction.
+    fn commit(&mut self) -> io::Result<()> {
+        match self {
+            Target::Local(database) => {
+                database.commit().unwrap();
+            }
+            Target::Remote(remote) => {
+                remote.call(&["EXEC"])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Roll back a transaction nested to the given depth.
+    fn rollback(&mut self, depth: usize) -> io::Result<()> {
+        match self {
+            Target::Local(database) => {
+                for _ in 0..depth {
+                    database.rollback().unwrap();
+                }
+            }
+            Target::Remote(remote) => {
+                remote.call(&["DISCARD"])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Return the resident set size of this process in bytes, if known.
+fn resident_memory() -> Option<u64> {
+    // only Linux exposes this cheaply without additional dependencies
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * 4096)
+}
+
+fn run(config: Config) -> io::Result<()> {
+    let mut rng = match config.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let chooser = KeyChooser::new(config.keys, config.zipf);
+    let keys: Vec<String> = (0..config.keys).map(|i| format!("key{}", i)).collect();
+    let values: Vec<String> = (0..config.values)
+        .map(|i| format!("{:0>width$}", i, width = config.value_size))
+        .collect();
+    let mut target = match config.connect {
+        Some(ref address) => Target::Remote(Remote::connect(address)?),
+        None => Target::Local(Box::new(Database::new())),
+    };
+    // the memory of a server elsewhere is beyond reach
+    let baseline_rss = resident_memory().filter(|_| config.connect.is_none());
+    let mut interval = Stats::new();
+    let mut totals = Stats::new();
+    let mut in_tx = 0;
+    let start = Instant::now();
+    let mut last_report = start;
+    while start.elapsed() < config.duration {
+        if config.tx_size > 0 && in_tx == 0 {
+            target.begin(config.tx_depth)?;
+        }
+        let key = &keys[chooser.choose(&mut rng)];
+        let began = Instant::now();
+        if rng.gen_bool(config.reads.clamp(0.0, 1.0)) {
+            target.read(key)?;
+        } else if rng.gen_bool(0.9) {
+            let value = &values[rng.gen_range(0..values.len())];
+            target.set(key, value)?;
+        } else {
+            target.delete(key)?;
+        }
+        interval.record(began.elapsed());
+        if config.tx_size > 0 {
+            in_tx += 1;
+            if in_tx >= config.tx_size {
+                let began = Instant::now();
+                if rng.gen_bool(config.rollback.clamp(0.0, 1.0)) {
+                    target.rollback(config.tx_depth)?;
+                    interval.rollbacks += 1;
+                } else {
+                    target.commit()?;
+                    interval.commits += 1;
+                }
+                interval.record(began.elapsed());
+                in_tx = 0;
+            }
+        }
+        if last_report.elapsed() >= config.interval {
+            let now = Instant::now();
+            interval.report(
+                &format!("{:>6}s", start.elapsed().as_secs()),
+                now - last_report,
+                baseline_rss,
+            );
+            totals.absorb(&mut interval);
+            last_report = now;
+        }
+    }
+    totals.absorb(&mut interval);
+    totals.report("total", start.elapsed(), baseline_rss);
+    Ok(())
+}
+
+fn main() {
+    match Config::from_args() {
+        Ok(config) => {
+            if let Err(err) = run(config) {
+                eprintln!("error: {}", err);
+                std::process::exit(1);
+            }
+        }
+        Err(msg) => {
+            eprintln!("{}", msg);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! Bit-level operations on values, so that flags for many items can
+//! be kept compactly in one value, such as a bit per user for those active on
+//! a given day. As in Redis, bit 0 is the most significant bit of the first
+//! byte, and a value grows with zero bits as needed when a bit past its end
+//! is set.
+//!
+//! The bits are the bytes of the value as it is, so that the bits of text
+//! are those of its characters. Where the value type cannot hold the bytes,
+//! as text cannot hold those that are not UTF-8, they are stored as the
+//! marker `BITS:` followed by the bytes in hexadecimal, so that bitmaps suit
+//! the REPL and the servers as well as databases of bytes.
+
+use crate::error::{Error, Result};
+use crate::persist::Persistent;
+use crate::store::{Database, Kind};
+use std::borrow::{Borrow, Cow};
+use std::hash::Hash;
+
+// marks a value that holds the bytes of a bitmap in hexadecimal
+const MAGIC: &[u8] = b"BITS:";
+
+/// Read the bytes of a bitmap from those of a value.
+fn decode(bytes: &[u8]) -> Cow<'_, [u8]> {
+    let decoded = bytes.strip_prefix(MAGIC).and_then(|hex| {
+        if hex.len() % 2 != 0 {
+            return None;
+        }
+        hex.chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+            .collect::<Option<Vec<u8>>>()
+    });
+    decoded.map_or(Cow::Borrowed(bytes), Cow::Owned)
+}
+
+/// Returns the value that holds the bytes of a bitmap.
+fn encode<V: Persistent>(bytes: Vec<u8>) -> V {
+    if !bytes.starts_with(MAGIC) {
+        if let Some(value) = V::from_bytes(bytes.clone()) {
+            return value;
+        }
+    }
+    let mut text = MAGIC.to_vec();
+    for byte in bytes {
+        text.extend(format!("{:02x}", byte).bytes());
+    }
+    V::from_bytes(text).expect("values can hold text")
+}
+
+impl<K, V> Database<K, V>
+where
+    K: Ord + Clone,
+    V: Hash + Eq + Clone + Persistent,
+{
+    /// Set or clear the bit at the offset in the value of the key, treating
+    /// a key with no value as all zero bits, and return the bit that was
+    /// there. The change is made like any other, within any open transaction,
+    /// and any time-to-live of the key is kept. Fails with `Error::WrongType`
+    /// if the key holds something other than a value.
+    pub fn setbit<Q>(&mut self, name: &Q, offset: u32, bit: bool) -> Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        if self.kind(name).is_some_and(|kind| kind != Kind::Value) {
+            return Err(Error::WrongType);
+        }
+        let mut bytes = self.bytes(name)?.into_owned();
+        let (index, mask) = ((offset / 8) as usize, 0x80 >> (offset % 8));
+        if bytes.len() <= index {
+            bytes.resize(index + 1, 0);
+        }
+        let old = bytes[index] & mask != 0;
+        if bit {
+            bytes[index] |= mask;
+        } else {
+            bytes[index] &= !mask;
+        }
+        self.replace_keeping_ttl(name, encode::<V>(bytes));
+        Ok(old)
+    }
+
+    /// Returns the bit at the offset in the value of the key, which is zero
+    /// past the end of the value or if the key has no value.
+    pub fn getbit<Q>(&self, name: &Q, offset: u32) -> Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        let bytes = self.bytes(name)?;
+        let byte = bytes.get((offset / 8) as usize).copied().unwrap_or(0);
+        Ok(byte & (0x80 >> (offset % 8)) != 0)
+    }
+
+    /// Returns the number of bits that are set in the bytes of the value of
+    /// the key from `start` to `stop` inclusive, where negative positions
+    /// count back from the end, so that 0 and -1 cover the whole value.
+    pub fn bitcount<Q>(&self, name: &Q, start: i64, stop: i64) -> Result<usize>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        let bytes = self.bytes(name)?;
+        let len = bytes.len() as i64;
+        let start = if start < 0 {
+            (len + start).max(0)
+        } else {
+            start
+        };
+        let stop = if stop < 0 {
+            len + stop
+        } else {
+            stop.min(len - 1)
+        };
+        if start > stop {
+            return Ok(0);
+        }
+        let bytes = &bytes[start as usize..=stop as usize];
+        Ok(bytes.iter().map(|b| b.count_ones() as usize).sum())
+    }
+
+    /// Returns the bytes of the value of the key, empty if it has none.
+    fn bytes<Q>(&self, name: &Q) -> Result<Cow<'_, [u8]>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        match self.get_ref(name) {
+            Some(value) => Ok(decode(value.to_bytes())),
+            None if self.kind(name).is_some() => Err(Error::WrongType),
+            None => Ok(Cow::Borrowed(&[])),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bits() {
+        let mut db: Database<String, Vec<u8>> = Database::default();
+        assert!(!db.setbit("b", 7, true).unwrap());
+        assert!(db.setbit("b", 7, true).unwrap());
+        assert_eq!(db.get("b"), Some(vec![0x01]));
+        db.setbit("b", 0, true).unwrap();
+        db.setbit("b", 17, true).unwrap();
+        assert_eq!(db.get("b"), Some(vec![0x81, 0x00, 0x40]));
+        assert!(db.getbit("b", 0).unwrap());
+        assert!(!db.getbit("b", 1).unwrap());
+        assert!(!db.getbit("b", 1000).unwrap());
+        assert!(!db.getbit("none", 0).unwrap());
+        assert_eq!(db.bitcount("b", 0, -1).unwrap(), 3);
+        assert_eq!(db.bitcount("b", -1, -1).unwrap(), 1);
+        assert_eq!(db.bitcount("b", 1, 1).unwrap(), 0);
+        assert_eq!(db.bitcount("b", 2, 0).unwrap(), 0);
+        assert_eq!(db.bitcount("none", 0, -1).unwrap(), 0);
+        // bits change within transactions like anything else
+        db.begin();
+        assert!(db.setbit("b", 0, false).unwrap());
+        assert_eq!(db.bitcount("b", 0, -1).unwrap(), 2);
+        db.rollback().unwrap();
+        assert_eq!(db.bitcount("b", 0, -1).unwrap(), 3);
+        assert_eq!(db.count(&vec![0x81, 0x00, 0x40]), 1);
+        db.rpush("l", [vec![1]]).unwrap();
+        assert!(matches!(db.setbit("l", 0, true), Err(Error::WrongType)));
+        assert!(matches!(db.getbit("l", 0), Err(Error::WrongType)));
+    }
+
+    #[test]
+    fn test_bits_text() {
+        let mut db: Database<String, String> = Database::default();
+        // the bits of text are those of its characters
+        db.set("t".to_owned(), "a".to_owned());
+        assert!(db.getbit("t", 1).unwrap());
+        assert_eq!(db.bitcount("t", 0, -1).unwrap(), 3);
+        assert!(!db.setbit("t", 6, true).unwrap());
+        assert_eq!(db.get("t"), Some("c".to_owned()));
+        // bytes that are not UTF-8 are kept in hexadecimal
+        db.setbit("t", 0, true).unwrap();
+        assert_eq!(db.get("t"), Some("BITS:e3".to_owned()));
+        assert!(db.getbit("t", 0).unwrap());
+        assert_eq!(db.bitcount("t", 0, -1).unwrap(), 5);
+        db.setbit("t", 0, false).unwrap();
+        assert_eq!(db.get("t"), Some("c".to_owned()));
+    }
+}
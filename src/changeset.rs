@@ -0,0 +1,147 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! Changes made to the values of a database, captured so that they can be
+//! shipped to and applied on another database, such as a mirror.
+//!
+//! Written out, a changeset starts with the magic bytes `SDBC` and a version
+//! byte, followed by the number of changes as a little-endian `u32`, and for
+//! each change the key in the same form as in the log, then the old value and
+//! the new value, each as a byte that is 1 if there is a value followed by
+//! the value, or 0 if there is none.
+
+use crate::persist::{self, Persistent};
+use std::io::{self, BufWriter, Read, Write};
+
+const MAGIC: &[u8; 4] = b"SDBC";
+const VERSION: u8 = 1;
+
+///
+/// A change to the value of a key, from the old value to the new, where
+/// `None` means the key had or has no value.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Change<K, V> {
+    pub name: K,
+    pub old: Option<V>,
+    pub new: Option<V>,
+}
+
+///
+/// The keys whose data differs between two states of a database, such as two
+/// snapshots, each list in key order, as returned by `Database::diff()`.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyDiff<K> {
+    /// Keys that hold data only in the newer state.
+    pub added: Vec<K>,
+    /// Keys that hold data only in the older state.
+    pub removed: Vec<K>,
+    /// Keys that hold data in both states, but different data, a different
+    /// kind of data, or a different time-to-live.
+    pub changed: Vec<K>,
+}
+
+///
+/// The changes made to the values of a database by its open transactions, in
+/// key order, as returned by `Database::changeset()`.
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Changeset<K, V> {
+    pub changes: Vec<Change<K, V>>,
+}
+
+impl<K, V> Changeset<K, V>
+where
+    K: Persistent,
+    V: Persistent,
+{
+    /// Write the changeset in the form described above.
+    pub fn write_to<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = BufWriter::new(writer);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        persist::write_count(&mut writer, self.changes.len())?;
+        for change in self.changes.iter() {
+            persist::write_bytes(&mut writer, change.name.to_bytes())?;
+            for value in [&change.old, &change.new] {
+                match value {
+                    Some(value) => {
+                        writer.write_all(&[1])?;
+                        persist::write_bytes(&mut writer, value.to_bytes())?;
+                    }
+                    None => writer.write_all(&[0])?,
+                }
+            }
+        }
+        writer.flush()
+    }
+
+    /// Read a changeset written by `write_to()`, failing if it is incomplete
+    /// or not a changeset at all.
+    pub fn read_from<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut header = [0u8; 5];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != MAGIC || header[4] != VERSION {
+            return Err(persist::invalid("not a changeset"));
+        }
+        let mut count = [0u8; 4];
+        reader.read_exact(&mut count)?;
+        let mut changes = Vec::new();
+        for _ in 0..u32::from_le_bytes(count) {
+            let name = persist::decode(read_bytes(&mut reader)?)?;
+            let old = read_value(&mut reader)?;
+            let new = read_value(&mut reader)?;
+            changes.push(Change { name, old, new });
+        }
+        Ok(Self { changes })
+    }
+}
+
+/// Read a length-prefixed byte string, failing if the input ends first.
+fn read_bytes<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    persist::read_bytes(reader)?.ok_or_else(|| persist::invalid("changeset is incomplete"))
+}
+
+/// Read a value that may be absent.
+fn read_value<R: Read, V: Persistent>(reader: &mut R) -> io::Result<Option<V>> {
+    let mut present = [0u8; 1];
+    reader.read_exact(&mut present)?;
+    match present[0] {
+        0 => Ok(None),
+        1 => Ok(Some(persist::decode(read_bytes(reader)?)?)),
+        _ => Err(persist::invalid("invalid value in changeset")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let changeset: Changeset<String, String> = Changeset {
+            changes: vec![
+                Change {
+                    name: "a".into(),
+                    old: None,
+                    new: Some("10".into()),
+                },
+                Change {
+                    name: "b".into(),
+                    old: Some("20".into()),
+                    new: None,
+                },
+            ],
+        };
+        let mut buffer: Vec<u8> = Vec::new();
+        changeset.write_to(&mut buffer).unwrap();
+        let read: Changeset<String, String> = Changeset::read_from(buffer.as_slice()).unwrap();
+        assert_eq!(read, changeset);
+        let err = Changeset::<String, String>::read_from(&buffer[..buffer.len() - 1]);
+        assert!(err.is_err());
+        let err = Changeset::<String, String>::read_from(&b"SDBS\x03"[..]);
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
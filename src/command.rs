@@ -0,0 +1,1316 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! The commands of the Simple Database Challenge, along with those for key
+//! expiry and listing, parsed from lines of text and carried out against a
+//! database, so that any frontend can offer the same command set as the REPL.
+//! A `Pipeline` of interceptors may stand around the execution of commands,
+//! to validate, rewrite, reject, or audit them.
+
+use crate::changeset::Change;
+use crate::parser;
+use crate::persist::Persistent;
+use crate::store::Database;
+use crate::stream::StreamId;
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::Hash;
+use std::ops::Bound;
+use std::time::Duration;
+
+///
+/// A command operating on the database.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// End the session.
+    End,
+    /// Set the key to the value.
+    Set(String, String),
+    /// Set the key to the value if it has none.
+    SetNx(String, String),
+    /// Set the key to the new value if it has the expected value: key,
+    /// expected, new.
+    Cas(String, String, String),
+    /// Copy the value of one key to another: source, destination, and
+    /// whether to replace any value the destination has.
+    Copy(String, String, bool),
+    /// Show the value of the key.
+    Get(String),
+    /// Show how many of the keys have a value.
+    Exists(Vec<String>),
+    /// Show the values of each of the keys.
+    MGet(Vec<String>),
+    /// Set each key to its value, all at once.
+    MSet(Vec<(String, String)>),
+    /// Remove the key.
+    Unset(String),
+    /// Remove the key, showing the value it had.
+    GetDel(String),
+    /// Show the value of the key after making it expire after the given
+    /// time, or never given `None`.
+    GetEx(String, Option<Duration>),
+    /// Show the number of keys set to the value.
+    NumEqualTo(String),
+    /// Add the suffix to the value of the key.
+    Append(String, String),
+    /// Add the amount to the integer value of the key.
+    IncrBy(String, i64),
+    /// Add the values to the front (true) or back of the list of the key.
+    Push(String, Vec<String>, bool),
+    /// Remove a value from the front (true) or back of the list of the key.
+    Pop(String, bool),
+    /// Show the values of the list of the key between the positions.
+    LRange(String, i64, i64),
+    /// Show the length of the list of the key.
+    LLen(String),
+    /// Set fields of the hash of the key to their values.
+    HSet(String, Vec<(String, String)>),
+    /// Show the value of a field of the hash of the key.
+    HGet(String, String),
+    /// Remove fields from the hash of the key.
+    HDel(String, Vec<String>),
+    /// Show every field of the hash of the key, each followed by its value.
+    HGetAll(String),
+    /// Show the number of fields in the hash of the key.
+    HLen(String),
+    /// Give members of the sorted set of the key their scores.
+    ZAdd(String, Vec<(f64, String)>),
+    /// Show the score of a member of the sorted set of the key.
+    ZScore(String, String),
+    /// Remove members from the sorted set of the key.
+    ZRem(String, Vec<String>),
+    /// Show the members of the sorted set of the key between the ranks, and
+    /// whether to show their scores.
+    ZRange(String, i64, i64, bool),
+    /// Show the members of the sorted set of the key with scores within the
+    /// bounds, and whether to show their scores.
+    ZRangeByScore(String, Bound<f64>, Bound<f64>, bool),
+    /// Add an entry of fields and values to the stream of the key.
+    XAdd(String, Vec<(String, String)>),
+    /// Show the entries of the stream of the key between the identifiers.
+    XRange(String, StreamId, StreamId),
+    /// Show the number of entries in the stream of the key.
+    XLen(String),
+    /// Add elements to the HyperLogLog of the key.
+    PfAdd(String, Vec<String>),
+    /// Show the estimated number of distinct elements added to any of the
+    /// HyperLogLogs of the keys.
+    PfCount(Vec<String>),
+    /// Merge the HyperLogLogs of the sources into that of the destination.
+    PfMerge(String, Vec<String>),
+    /// Set or clear the bit at the offset in the value of the key.
+    SetBit(String, u32, bool),
+    /// Show the bit at the offset in the value of the key.
+    GetBit(String, u32),
+    /// Show the number of bits set in the bytes of the value of the key
+    /// between the positions.
+    BitCount(String, i64, i64),
+    /// Make the key expire after the given time.
+    Expire(String, Duration),
+    /// Remove the time-to-live of the key.
+    Persist(String),
+    /// Show the seconds until the key expires.
+    Ttl(String),
+    /// Show the kind of data held by the key.
+    Type(String),
+    /// Show the type that the value of the key represents.
+    ValueType(String),
+    /// Show the part of the JSON document of the key found along the path.
+    JsonGet(String, String),
+    /// Replace the part of the JSON document of the key found along the path.
+    JsonSet(String, String, serde_json::Value),
+    /// Show the number of keys that have a value.
+    DbSize,
+    /// Remove every key.
+    FlushAll,
+    /// Show a key chosen at random.
+    RandomKey,
+    /// List the keys that match the glob pattern, after skipping the given
+    /// number of them, and at most the limit, if any.
+    Keys(String, usize, Option<usize>),
+    /// List a page of keys: cursor, number of keys to visit, glob pattern.
+    Scan(String, usize, String),
+    /// Start a transaction, which is rolled back if still open after the
+    /// timeout, if any.
+    Begin(Option<Duration>),
+    /// Roll back the current transaction.
+    Rollback,
+    /// Commit all open transactions.
+    Commit,
+    /// Commit the given number of innermost transactions.
+    CommitN(usize),
+    /// Show the changes made by the innermost transaction.
+    Pending,
+}
+
+///
+/// Result of carrying out a command.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Response {
+    /// The command succeeded with nothing to report.
+    Done,
+    /// The value of a key, if it has one.
+    Value(Option<String>),
+    /// The values of several keys, in the order requested.
+    Values(Vec<Option<String>>),
+    /// Values in the order of the list that holds them.
+    List(Vec<String>),
+    /// A count or flag, such as the number of keys with a value.
+    Integer(i64),
+    /// Keys listed in key order.
+    Keys(Vec<String>),
+    /// The cursor from which to continue a scan, and the keys found.
+    Page(String, Vec<String>),
+    /// Entries of a stream, each an identifier with its fields and values.
+    Entries(Vec<(String, Vec<(String, String)>)>),
+    /// Changes to the values of keys, in key order.
+    Changes(Vec<Change<String, String>>),
+    /// The command failed for the given reason, such as `NO TRANSACTION`.
+    Error(String),
+    /// The session should end.
+    End,
+}
+
+///
+/// Reason a line is not a valid command.
+///
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The line could not be split into words.
+    Syntax(parser::Error),
+    /// There is no command by the given name.
+    Unknown(String),
+    /// The arguments are missing or invalid, as described.
+    Arguments(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Syntax(err) => write!(f, "{}", err),
+            Error::Unknown(name) => write!(f, "unknown command: {}", name),
+            Error::Arguments(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Parse a line of input into a command, returning `None` if it is blank.
+pub fn parse(line: &str) -> Result<Option<Command>, Error> {
+    let words = parser::tokenize(line).map_err(Error::Syntax)?;
+    parse_words(&words)
+}
+
+/// Parse a line already split into words, returning `None` if there are none.
+pub fn parse_words<S: AsRef<str>>(words: &[S]) -> Result<Option<Command>, Error> {
+    let mut iter = words.iter().map(|word| word.as_ref());
+    let cmd = match iter.next() {
+        Some(cmd) => cmd,
+        None => return Ok(None),
+    };
+    let mut arg = |what: &str| {
+        iter.next()
+            .map(str::to_owned)
+            .ok_or_else(|| Error::Arguments(format!("missing {} for {}", what, cmd)))
+    };
+    let command = match cmd {
+        "END" => Command::End,
+        "SET" => Command::Set(arg("name")?, arg("value")?),
+        "SETNX" => Command::SetNx(arg("name")?, arg("value")?),
+        "CAS" => Command::Cas(arg("name")?, arg("expected value")?, arg("new value")?),
+        "COPY" => {
+            let source = arg("source")?;
+            let destination = arg("destination")?;
+            let replace = match arg("option") {
+                Ok(option) if option == "REPLACE" => true,
+                Ok(option) => {
+                    let msg = format!("unexpected argument for COPY: {}", option);
+                    return Err(Error::Arguments(msg));
+                }
+                Err(_) => false,
+            };
+            Command::Copy(source, destination, replace)
+        }
+        "GET" => Command::Get(arg("name")?),
+        "EXISTS" | "MGET" => {
+            let names: Vec<String> = iter.map(str::to_owned).collect();
+            if names.is_empty() {
+                return Err(Error::Arguments(format!("missing name for {}", cmd)));
+            }
+            match cmd {
+                "EXISTS" => Command::Exists(names),
+                _ => Command::MGet(names),
+            }
+        }
+        "MSET" => {
+            let mut pairs = vec![(arg("name")?, arg("value")?)];
+            while let Ok(name) = arg("name") {
+                pairs.push((name, arg("value")?));
+            }
+            Command::MSet(pairs)
+        }
+        "UNSET" => Command::Unset(arg("name")?),
+        "GETDEL" => Command::GetDel(arg("name")?),
+        "GETEX" => {
+            let name = arg("name")?;
+            match arg("option") {
+                Ok(option) if option == "EX" => {
+                    let seconds = arg("seconds")?;
+                    let seconds = seconds.parse().map_err(|_| {
+                        Error::Arguments(format!("invalid seconds for GETEX: {}", seconds))
+                    })?;
+                    Command::GetEx(name, Some(Duration::from_secs(seconds)))
+                }
+                Ok(option) if option == "PERSIST" => Command::GetEx(name, None),
+                Ok(option) => {
+                    let msg = format!("unexpected argument for GETEX: {}", option);
+                    return Err(Error::Arguments(msg));
+                }
+                Err(_) => Command::Get(name),
+            }
+        }
+        "NUMEQUALTO" => Command::NumEqualTo(arg("value")?),
+        "APPEND" => Command::Append(arg("name")?, arg("value")?),
+        "INCR" => Command::IncrBy(arg("name")?, 1),
+        "DECR" => Command::IncrBy(arg("name")?, -1),
+        "INCRBY" => {
+            let name = arg("name")?;
+            let amount = arg("amount")?;
+            let amount = amount
+                .parse()
+                .map_err(|_| Error::Arguments(format!("invalid amount for INCRBY: {}", amount)))?;
+            Command::IncrBy(name, amount)
+        }
+        "EXPIRE" => {
+            let name = arg("name")?;
+            let seconds = arg("seconds")?;
+            let seconds = seconds.parse().map_err(|_| {
+                Error::Arguments(format!("invalid seconds for EXPIRE: {}", seconds))
+            })?;
+            Command::Expire(name, Duration::from_secs(seconds))
+        }
+        "LPUSH" | "RPUSH" => {
+            let name = arg("name")?;
+            let values = vec![arg("value")?];
+            let values = values.into_iter().chain(iter.map(str::to_owned)).collect();
+            Command::Push(name, values, cmd == "LPUSH")
+        }
+        "LPOP" => Command::Pop(arg("name")?, true),
+        "RPOP" => Command::Pop(arg("name")?, false),
+        "LRANGE" => {
+            let name = arg("name")?;
+            let mut position = || {
+                let value = arg("position")?;
+                value.parse().map_err(|_| {
+                    Error::Arguments(format!("invalid position for LRANGE: {}", value))
+                })
+            };
+            Command::LRange(name, position()?, position()?)
+        }
+        "LLEN" => Command::LLen(arg("name")?),
+        "HSET" => {
+            let name = arg("name")?;
+            let mut fields = vec![(arg("field")?, arg("value")?)];
+            while let Ok(field) = arg("field") {
+                fields.push((field, arg("value")?));
+            }
+            Command::HSet(name, fields)
+        }
+        "HGET" => Command::HGet(arg("name")?, arg("field")?),
+        "HDEL" => {
+            let name = arg("name")?;
+            let fields = vec![arg("field")?];
+            Command::HDel(
+                name,
+                fields.into_iter().chain(iter.map(str::to_owned)).collect(),
+            )
+        }
+        "HGETALL" => Command::HGetAll(arg("name")?),
+        "HLEN" => Command::HLen(arg("name")?),
+        "ZADD" => {
+            let name = arg("name")?;
+            let score = |value: String| match score_bound(&value) {
+                Some(Bound::Included(score)) => Ok(score),
+                _ => Err(Error::Arguments(format!(
+                    "invalid score for ZADD: {}",
+                    value
+                ))),
+            };
+            let mut members = vec![(score(arg("score")?)?, arg("member")?)];
+            while let Ok(value) = arg("score") {
+                members.push((score(value)?, arg("member")?));
+            }
+            Command::ZAdd(name, members)
+        }
+        "ZSCORE" => Command::ZScore(arg("name")?, arg("member")?),
+        "ZREM" => {
+            let name = arg("name")?;
+            let members = vec![arg("member")?];
+            Command::ZRem(
+                name,
+                members.into_iter().chain(iter.map(str::to_owned)).collect(),
+            )
+        }
+        "ZRANGE" | "ZRANGEBYSCORE" => {
+            let name = arg("name")?;
+            let (start, stop) = (arg("start")?, arg("stop")?);
+            let with_scores = match arg("option") {
+                Ok(option) if option == "WITHSCORES" => true,
+                Ok(option) => {
+                    let msg = format!("unexpected argument for {}: {}", cmd, option);
+                    return Err(Error::Arguments(msg));
+                }
+                Err(_) => false,
+            };
+            if cmd == "ZRANGE" {
+                let rank = |value: &str| {
+                    value.parse().map_err(|_| {
+                        Error::Arguments(format!("invalid rank for ZRANGE: {}", value))
+                    })
+                };
+                Command::ZRange(name, rank(&start)?, rank(&stop)?, with_scores)
+            } else {
+                let bound = |value: &str| {
+                    score_bound(value).ok_or_else(|| {
+                        Error::Arguments(format!("invalid score for ZRANGEBYSCORE: {}", value))
+                    })
+                };
+                Command::ZRangeByScore(name, bound(&start)?, bound(&stop)?, with_scores)
+            }
+        }
+        "XADD" => {
+            let name = arg("name")?;
+            // identifiers are always chosen by the database
+            let id = arg("ID")?;
+            if id != "*" {
+                let msg = format!("only * is supported as the ID for XADD: {}", id);
+                return Err(Error::Arguments(msg));
+            }
+            let mut fields = vec![(arg("field")?, arg("value")?)];
+            while let Ok(field) = arg("field") {
+                fields.push((field, arg("value")?));
+            }
+            Command::XAdd(name, fields)
+        }
+        "XRANGE" => {
+            let name = arg("name")?;
+            let mut id = |end| {
+                let value = arg(if end { "end" } else { "start" })?;
+                StreamId::parse_bound(&value, end)
+                    .ok_or_else(|| Error::Arguments(format!("invalid ID for XRANGE: {}", value)))
+            };
+            Command::XRange(name, id(false)?, id(true)?)
+        }
+        "XLEN" => Command::XLen(arg("name")?),
+        "PFADD" => Command::PfAdd(arg("name")?, iter.map(str::to_owned).collect()),
+        "PFCOUNT" => {
+            let names = vec![arg("name")?];
+            Command::PfCount(names.into_iter().chain(iter.map(str::to_owned)).collect())
+        }
+        "PFMERGE" => Command::PfMerge(arg("destination")?, iter.map(str::to_owned).collect()),
+        "SETBIT" | "GETBIT" => {
+            let name = arg("name")?;
+            let offset = arg("offset")?;
+            let offset = offset
+                .parse()
+                .map_err(|_| Error::Arguments(format!("invalid offset for {}: {}", cmd, offset)))?;
+            if cmd == "GETBIT" {
+                Command::GetBit(name, offset)
+            } else {
+                let bit = match arg("bit")?.as_str() {
+                    "0" => false,
+                    "1" => true,
+                    bit => {
+                        let msg = format!("invalid bit for SETBIT: {}", bit);
+                        return Err(Error::Arguments(msg));
+                    }
+                };
+                Command::SetBit(name, offset, bit)
+            }
+        }
+        "BITCOUNT" => {
+            let name = arg("name")?;
+            let position = |value: &str| {
+                value.parse().map_err(|_| {
+                    Error::Arguments(format!("invalid position for BITCOUNT: {}", value))
+                })
+            };
+            match iter.collect::<Vec<&str>>()[..] {
+                [] => Command::BitCount(name, 0, -1),
+                [start, stop] => Command::BitCount(name, position(start)?, position(stop)?),
+                [_] => return Err(Error::Arguments("missing stop for BITCOUNT".into())),
+                _ => return Err(Error::Arguments("too many positions for BITCOUNT".into())),
+            }
+        }
+        "PERSIST" => Command::Persist(arg("name")?),
+        "TTL" => Command::Ttl(arg("name")?),
+        "TYPE" => Command::Type(arg("name")?),
+        "VALUETYPE" => Command::ValueType(arg("name")?),
+        "JSON.GET" => Command::JsonGet(arg("name")?, arg("path").unwrap_or_else(|_| "$".into())),
+        "JSON.SET" => {
+            let (name, path, json) = (arg("name")?, arg("path")?, arg("JSON")?);
+            let value = serde_json::from_str(&json)
+                .map_err(|err| Error::Arguments(format!("invalid JSON for JSON.SET: {}", err)))?;
+            Command::JsonSet(name, path, value)
+        }
+        "DBSIZE" => Command::DbSize,
+        "FLUSHALL" => Command::FlushAll,
+        "RANDOMKEY" => Command::RandomKey,
+        "KEYS" => {
+            let pattern = arg("pattern").unwrap_or_else(|_| "*".into());
+            match arg("option") {
+                Ok(option) if option == "LIMIT" => {
+                    let mut number = |what: &str| {
+                        let value = arg(what)?;
+                        value.parse().map_err(|_| {
+                            Error::Arguments(format!("invalid {} for KEYS: {}", what, value))
+                        })
+                    };
+                    let offset = number("offset")?;
+                    Command::Keys(pattern, offset, Some(number("count")?))
+                }
+                Ok(option) => {
+                    let msg = format!("unexpected argument for KEYS: {}", option);
+                    return Err(Error::Arguments(msg));
+                }
+                Err(_) => Command::Keys(pattern, 0, None),
+            }
+        }
+        "SCAN" => {
+            let cursor = arg("cursor")?;
+            let (mut count, mut pattern) = (10, "*".to_owned());
+            while let Ok(option) = arg("option") {
+                match option.as_str() {
+                    "MATCH" => pattern = arg("pattern")?,
+                    "COUNT" => {
+                        count = arg("count")?
+                            .parse()
+                            .map_err(|_| Error::Arguments("invalid count for SCAN".into()))?;
+                    }
+                    _ => {
+                        let msg = format!("unexpected argument for SCAN: {}", option);
+                        return Err(Error::Arguments(msg));
+                    }
+                }
+            }
+            Command::Scan(cursor, count, pattern)
+        }
+        "BEGIN" => match iter.next() {
+            Some(seconds) => {
+                let seconds = seconds.parse().map_err(|_| {
+                    Error::Arguments(format!("invalid seconds for BEGIN: {}", seconds))
+                })?;
+                Command::Begin(Some(Duration::from_secs(seconds)))
+            }
+            None => Command::Begin(None),
+        },
+        "ROLLBACK" => Command::Rollback,
+        "COMMIT" => match iter.next() {
+            Some(count) => match count.parse() {
+                Ok(count) if count > 0 => Command::CommitN(count),
+                _ => {
+                    let msg = format!("invalid count for COMMIT: {}", count);
+                    return Err(Error::Arguments(msg));
+                }
+            },
+            None => Command::Commit,
+        },
+        "PENDING" => Command::Pending,
+        _ => return Err(Error::Unknown(cmd.to_owned())),
+    };
+    Ok(Some(command))
+}
+
+/// Parse a score that bounds a range, which is exclusive when it starts with
+/// `(`, as in `(1.5`, and may be `-inf` or `+inf`. Returns `None` if it is
+/// not a number.
+pub fn score_bound(text: &str) -> Option<Bound<f64>> {
+    let (bound, score): (fn(f64) -> Bound<f64>, &str) = match text.strip_prefix('(') {
+        Some(score) => (Bound::Excluded, score),
+        None => (Bound::Included, text),
+    };
+    score.parse::<f64>().ok().filter(|s| !s.is_nan()).map(bound)
+}
+
+/// Carry out the command against the database.
+pub fn execute<V>(database: &mut Database<String, V>, command: Command) -> Response
+where
+    V: Hash + Eq + Clone + From<String> + AsRef<str> + Borrow<str> + Persistent,
+{
+    if let Err(err) = database.check_deadline() {
+        return Response::Error(err.to_string());
+    }
+    match command {
+        Command::End => Response::End,
+        Command::Set(name, value) => {
+            database.set(name, value);
+            Response::Done
+        }
+        Command::SetNx(name, value) => Response::Integer(database.set_nx(name, value) as i64),
+        Command::Cas(name, expected, value) => {
+            Response::Integer(database.compare_and_swap(&name, expected.as_str(), value) as i64)
+        }
+        Command::Copy(source, destination, replace) => {
+            Response::Integer(database.copy(&source, destination, replace) as i64)
+        }
+        Command::Get(name) => {
+            Response::Value(database.get_ref(&name).map(|v| v.as_ref().to_owned()))
+        }
+        Command::Exists(names) => {
+            let found = names.iter().filter(|name| database.exists(*name)).count();
+            Response::Integer(found as i64)
+        }
+        Command::MGet(names) => {
+            let names: Vec<&str> = names.iter().map(String::as_str).collect();
+            let values = database.mget(&names);
+            Response::Values(
+                values
+                    .into_iter()
+                    .map(|v| v.map(|v| v.as_ref().to_owned()))
+                    .collect(),
+            )
+        }
+        Command::MSet(pairs) => {
+            database.mset(pairs);
+            Response::Done
+        }
+        Command::GetDel(name) => {
+            Response::Value(database.get_del(&name).map(|v| v.as_ref().to_owned()))
+        }
+        Command::GetEx(name, ttl) => match database.get_and_touch_ttl(&name, ttl) {
+            Ok(value) => Response::Value(value.map(|v| v.as_ref().to_owned())),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Command::Unset(name) => {
+            database.delete(&name);
+            Response::Done
+        }
+        Command::NumEqualTo(value) => Response::Integer(database.count(value.as_str()) as i64),
+        Command::Append(name, suffix) => Response::Integer(database.append(&name, &suffix) as i64),
+        Command::IncrBy(name, amount) => match database.incr_by(&name, amount) {
+            Ok(value) => Response::Integer(value),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Command::Push(name, values, front) => {
+            let result = match front {
+                true => database.lpush(name, values),
+                false => database.rpush(name, values),
+            };
+            match result {
+                Ok(len) => Response::Integer(len as i64),
+                Err(err) => Response::Error(err.to_string()),
+            }
+        }
+        Command::Pop(name, front) => {
+            let result = match front {
+                true => database.lpop(&name),
+                false => database.rpop(&name),
+            };
+            match result {
+                Ok(value) => Response::Value(value.map(|v| v.as_ref().to_owned())),
+                Err(err) => Response::Error(err.to_string()),
+            }
+        }
+        Command::LRange(name, start, stop) => match database.lrange(&name, start, stop) {
+            Ok(values) => Response::List(values.iter().map(|v| v.as_ref().to_owned()).collect()),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Command::LLen(name) => match database.llen(&name) {
+            Ok(len) => Response::Integer(len as i64),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Command::HSet(name, fields) => match database.hset(name, fields) {
+            Ok(added) => Response::Integer(added as i64),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Command::HGet(name, field) => match database.hget(&name, &field) {
+            Ok(value) => Response::Value(value.map(|v| v.as_ref().to_owned())),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Command::HDel(name, fields) => {
+            let fields: Vec<&String> = fields.iter().collect();
+            match database.hdel(&name, &fields) {
+                Ok(removed) => Response::Integer(removed as i64),
+                Err(err) => Response::Error(err.to_string()),
+            }
+        }
+        Command::HGetAll(name) => match database.hgetall(&name) {
+            Ok(fields) => Response::List(
+                fields
+                    .into_iter()
+                    .flat_map(|(field, value)| [field, value.as_ref().to_owned()])
+                    .collect(),
+            ),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Command::HLen(name) => match database.hlen(&name) {
+            Ok(len) => Response::Integer(len as i64),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Command::ZAdd(name, members) => match database.zadd(name, members) {
+            Ok(added) => Response::Integer(added as i64),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Command::ZScore(name, member) => match database.zscore(&name, &member) {
+            Ok(score) => Response::Value(score.map(|score| score.to_string())),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Command::ZRem(name, members) => {
+            let members: Vec<&String> = members.iter().collect();
+            match database.zrem(&name, &members) {
+                Ok(removed) => Response::Integer(removed as i64),
+                Err(err) => Response::Error(err.to_string()),
+            }
+        }
+        Command::ZRange(name, start, stop, with_scores) => {
+            ranked(database.zrange(&name, start, stop), with_scores)
+        }
+        Command::ZRangeByScore(name, min, max, with_scores) => {
+            ranked(database.zrange_by_score(&name, min, max), with_scores)
+        }
+        Command::XAdd(name, fields) => match database.xadd(name, fields) {
+            Ok(id) => Response::Value(Some(id.to_string())),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Command::XRange(name, start, end) => match database.xrange(&name, start, end) {
+            Ok(entries) => Response::Entries(
+                entries
+                    .into_iter()
+                    .map(|(id, fields)| {
+                        let fields = fields
+                            .into_iter()
+                            .map(|(field, value)| (field, value.as_ref().to_owned()))
+                            .collect();
+                        (id.to_string(), fields)
+                    })
+                    .collect(),
+            ),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Command::XLen(name) => match database.xlen(&name) {
+            Ok(len) => Response::Integer(len as i64),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Command::PfAdd(name, elements) => match database.pfadd(&name, elements) {
+            Ok(changed) => Response::Integer(changed as i64),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Command::PfCount(names) => {
+            let names: Vec<&String> = names.iter().collect();
+            match database.pfcount(&names) {
+                Ok(count) => Response::Integer(count as i64),
+                Err(err) => Response::Error(err.to_string()),
+            }
+        }
+        Command::PfMerge(destination, sources) => {
+            let sources: Vec<&String> = sources.iter().collect();
+            finish(database.pfmerge(&destination, &sources))
+        }
+        Command::SetBit(name, offset, bit) => match database.setbit(&name, offset, bit) {
+            Ok(old) => Response::Integer(old as i64),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Command::GetBit(name, offset) => match database.getbit(&name, offset) {
+            Ok(bit) => Response::Integer(bit as i64),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Command::BitCount(name, start, stop) => match database.bitcount(&name, start, stop) {
+            Ok(count) => Response::Integer(count as i64),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Command::Expire(name, ttl) => match database.expire(&name, ttl) {
+            Ok(set) => Response::Integer(set as i64),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Command::Persist(name) => Response::Integer(database.persist(&name) as i64),
+        // seconds remaining rounded up, -1 for no expiry, -2 for no value
+        Command::Ttl(name) => match database.ttl(&name) {
+            Ok(Some(ttl)) => {
+                let seconds = ttl.as_secs() + u64::from(ttl.subsec_nanos() > 0);
+                Response::Integer(seconds as i64)
+            }
+            Ok(None) => Response::Integer(-1),
+            Err(_) => Response::Integer(-2),
+        },
+        Command::Type(name) => {
+            let kind = database.kind(&name).map_or("none", |kind| kind.name());
+            Response::Value(Some(kind.to_owned()))
+        }
+        Command::ValueType(name) => match database.inferred_type(&name) {
+            Ok(kind) => Response::Value(Some(kind.unwrap_or("none").to_owned())),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Command::JsonGet(name, path) => match database.json_get(&name, &path) {
+            Ok(value) => Response::Value(value.map(|value| value.to_string())),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Command::JsonSet(name, path, value) => match database.json_set(name, &path, value) {
+            Ok(true) => Response::Done,
+            Ok(false) => Response::Value(None),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Command::DbSize => Response::Integer(database.len() as i64),
+        Command::FlushAll => {
+            database.clear();
+            Response::Done
+        }
+        Command::RandomKey => Response::Value(database.random_key().cloned()),
+        Command::Keys(pattern, offset, limit) => {
+            let keys = database.keys(&pattern).into_iter().skip(offset);
+            let keys = keys.take(limit.unwrap_or(usize::MAX));
+            Response::Keys(keys.cloned().collect())
+        }
+        Command::Scan(cursor, count, pattern) => match database.scan(&cursor, count, &pattern) {
+            Some((keys, next)) => Response::Page(next, keys.into_iter().cloned().collect()),
+            None => Response::Error(format!("invalid cursor for SCAN: {}", cursor)),
+        },
+        Command::Begin(None) => {
+            database.begin();
+            Response::Done
+        }
+        Command::Begin(Some(timeout)) => {
+            database.begin_with(timeout);
+            Response::Done
+        }
+        Command::Rollback => finish(database.rollback()),
+        Command::Commit => finish(database.commit()),
+        Command::CommitN(count) => finish(database.commit_n(count)),
+        Command::Pending => Response::Changes(
+            database
+                .pending()
+                .into_iter()
+                .map(|change| Change {
+                    name: change.name,
+                    old: change.old.map(|v| v.as_ref().to_owned()),
+                    new: change.new.map(|v| v.as_ref().to_owned()),
+                })
+                .collect(),
+        ),
+    }
+}
+
+///
+/// Stands between a frontend and the execution of its commands, observing,
+/// rewriting, or rejecting them, such as for custom validation or auditing.
+/// Both methods have default implementations, so that implementors need only
+/// override those of interest.
+///
+pub trait Interceptor: Send {
+    /// Called before the command is carried out, which it may rewrite in
+    /// place. Returns the response with which to answer instead, such as an
+    /// error that rejects it, or `None` to let it go on.
+    fn before(&mut self, _command: &mut Command) -> Option<Response> {
+        None
+    }
+
+    /// Called with the command that was carried out, or rejected, and the
+    /// response to it.
+    fn after(&mut self, _command: &Command, _response: &Response) {}
+}
+
+///
+/// A chain of interceptors around `execute()`. Each is given the command as
+/// rewritten by those added before it, and once it has been answered, each
+/// that saw it is told of the response, the last added first.
+///
+#[derive(Default)]
+pub struct Pipeline {
+    interceptors: Vec<Box<dyn Interceptor>>,
+}
+
+impl Pipeline {
+    /// Construct a pipeline with no interceptors, which only executes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an interceptor to the end of the chain, nearest the database.
+    pub fn add(&mut self, interceptor: Box<dyn Interceptor>) {
+        self.interceptors.push(interceptor);
+    }
+
+    /// Returns the number of interceptors in the chain.
+    pub fn len(&self) -> usize {
+        self.interceptors.len()
+    }
+
+    /// Returns true if the chain has no interceptors.
+    pub fn is_empty(&self) -> bool {
+        self.interceptors.is_empty()
+    }
+
+    /// Pass the command through the interceptors and carry out whatever
+    /// comes out of them, unless one answers it first. Without interceptors
+    /// this is the same as `execute()`.
+    pub fn execute<V>(&mut self, database: &mut Database<String, V>, command: Command) -> Response
+    where
+        V: Hash + Eq + Clone + From<String> + AsRef<str> + Borrow<str> + Persistent,
+    {
+        if self.interceptors.is_empty() {
+            return execute(database, command);
+        }
+        let mut command = command;
+        let mut seen = 0;
+        let mut answer = None;
+        for interceptor in self.interceptors.iter_mut() {
+            seen += 1;
+            answer = interceptor.before(&mut command);
+            if answer.is_some() {
+                break;
+            }
+        }
+        // a copy is kept only to be shown to the interceptors afterwards
+        let response = match answer {
+            Some(response) => response,
+            None => execute(database, command.clone()),
+        };
+        for interceptor in self.interceptors[..seen].iter_mut().rev() {
+            interceptor.after(&command, &response);
+        }
+        response
+    }
+}
+
+/// List the members of a sorted set, each followed by its score if wanted.
+fn ranked(result: crate::Result<Vec<(String, f64)>>, with_scores: bool) -> Response {
+    match result {
+        Ok(members) => Response::List(
+            members
+                .into_iter()
+                .flat_map(|(member, score)| {
+                    let score = with_scores.then(|| score.to_string());
+                    std::iter::once(member).chain(score)
+                })
+                .collect(),
+        ),
+        Err(err) => Response::Error(err.to_string()),
+    }
+}
+
+/// Report the outcome of an operation that returns nothing.
+fn finish<T>(result: crate::Result<T>) -> Response {
+    match result {
+        Ok(_) => Response::Done,
+        Err(err) => Response::Error(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("  "), Ok(None));
+        assert_eq!(
+            parse("SET name 'two words'"),
+            Ok(Some(Command::Set("name".into(), "two words".into())))
+        );
+        assert_eq!(parse("KEYS"), Ok(Some(Command::Keys("*".into(), 0, None))));
+        assert_eq!(
+            parse("KEYS a* LIMIT 20 10"),
+            Ok(Some(Command::Keys("a*".into(), 20, Some(10))))
+        );
+        assert_eq!(
+            parse("KEYS * LIMIT 5").unwrap_err().to_string(),
+            "missing count for KEYS"
+        );
+        assert_eq!(
+            parse("KEYS * LIMIT x 5").unwrap_err().to_string(),
+            "invalid offset for KEYS: x"
+        );
+        assert_eq!(
+            parse("KEYS * 10").unwrap_err().to_string(),
+            "unexpected argument for KEYS: 10"
+        );
+        assert_eq!(
+            parse("MSET a 1 b 2"),
+            Ok(Some(Command::MSet(vec![
+                ("a".into(), "1".into()),
+                ("b".into(), "2".into())
+            ])))
+        );
+        assert_eq!(
+            parse("MSET a 1 b").unwrap_err().to_string(),
+            "missing value for MSET"
+        );
+        assert_eq!(
+            parse("CAS a 1").unwrap_err().to_string(),
+            "missing new value for CAS"
+        );
+        assert_eq!(
+            parse("COPY a b NOW").unwrap_err().to_string(),
+            "unexpected argument for COPY: NOW"
+        );
+        assert_eq!(
+            parse("LRANGE l 0 end").unwrap_err().to_string(),
+            "invalid position for LRANGE: end"
+        );
+        assert_eq!(
+            parse("RPUSH l").unwrap_err().to_string(),
+            "missing value for RPUSH"
+        );
+        assert_eq!(
+            parse("HSET h f").unwrap_err().to_string(),
+            "missing value for HSET"
+        );
+        assert_eq!(
+            parse("HDEL h a b"),
+            Ok(Some(Command::HDel(
+                "h".into(),
+                vec!["a".into(), "b".into()]
+            )))
+        );
+        assert_eq!(
+            parse("ZRANGEBYSCORE z (1 +inf WITHSCORES"),
+            Ok(Some(Command::ZRangeByScore(
+                "z".into(),
+                Bound::Excluded(1.0),
+                Bound::Included(f64::INFINITY),
+                true
+            )))
+        );
+        assert_eq!(
+            parse("ZADD z nan a").unwrap_err().to_string(),
+            "invalid score for ZADD: nan"
+        );
+        assert_eq!(
+            parse("ZADD z (1 a").unwrap_err().to_string(),
+            "invalid score for ZADD: (1"
+        );
+        assert_eq!(
+            parse("SETBIT b 7 1"),
+            Ok(Some(Command::SetBit("b".into(), 7, true)))
+        );
+        assert_eq!(
+            parse("SETBIT b 7 yes").unwrap_err().to_string(),
+            "invalid bit for SETBIT: yes"
+        );
+        assert_eq!(
+            parse("GETBIT b -1").unwrap_err().to_string(),
+            "invalid offset for GETBIT: -1"
+        );
+        assert_eq!(
+            parse("BITCOUNT b 1"),
+            Err(Error::Arguments("missing stop for BITCOUNT".into()))
+        );
+        assert_eq!(
+            parse("XRANGE s - 5"),
+            Ok(Some(Command::XRange(
+                "s".into(),
+                StreamId::MIN,
+                StreamId {
+                    millis: 5,
+                    seq: u64::MAX
+                }
+            )))
+        );
+        assert_eq!(
+            parse("XADD s 1-1 a b").unwrap_err().to_string(),
+            "only * is supported as the ID for XADD: 1-1"
+        );
+        assert_eq!(
+            parse("XRANGE s x +").unwrap_err().to_string(),
+            "invalid ID for XRANGE: x"
+        );
+        assert_eq!(
+            parse("JSON.SET j $ {").unwrap_err().to_string(),
+            "invalid JSON for JSON.SET: EOF while parsing an object at line 1 column 1"
+        );
+        assert_eq!(
+            parse("MGET").unwrap_err().to_string(),
+            "missing name for MGET"
+        );
+        assert_eq!(
+            parse("SCAN 0 COUNT 5 MATCH a*"),
+            Ok(Some(Command::Scan("0".into(), 5, "a*".into())))
+        );
+        assert_eq!(
+            parse("EXPIRE a 60"),
+            Ok(Some(Command::Expire("a".into(), Duration::from_secs(60))))
+        );
+        assert_eq!(
+            parse("SET name").unwrap_err().to_string(),
+            "missing value for SET"
+        );
+        assert_eq!(
+            parse("EXPIRE a soon").unwrap_err().to_string(),
+            "invalid seconds for EXPIRE: soon"
+        );
+        assert_eq!(
+            parse("BEGIN soon").unwrap_err().to_string(),
+            "invalid seconds for BEGIN: soon"
+        );
+        assert_eq!(parse("COMMIT 2"), Ok(Some(Command::CommitN(2))));
+        assert_eq!(
+            parse("COMMIT 0").unwrap_err().to_string(),
+            "invalid count for COMMIT: 0"
+        );
+        assert_eq!(parse("FLY"), Err(Error::Unknown("FLY".into())));
+        assert_eq!(
+            parse("GET \"a"),
+            Err(Error::Syntax(parser::Error::UnterminatedQuote('"')))
+        );
+    }
+
+    #[test]
+    fn test_pipeline() {
+        use std::sync::{Arc, Mutex};
+
+        // rejects changes to keys starting with an underscore
+        struct Guard;
+        impl Interceptor for Guard {
+            fn before(&mut self, command: &mut Command) -> Option<Response> {
+                match command {
+                    Command::Set(name, _) if name.starts_with('_') => {
+                        Some(Response::Error(format!("{} is reserved", name)))
+                    }
+                    _ => None,
+                }
+            }
+        }
+        // keeps values in upper case
+        struct Upper;
+        impl Interceptor for Upper {
+            fn before(&mut self, command: &mut Command) -> Option<Response> {
+                if let Command::Set(_, value) = command {
+                    *value = value.to_uppercase();
+                }
+                None
+            }
+        }
+        // records what was answered, and where it stands in the chain
+        struct Audit(&'static str, Arc<Mutex<Vec<String>>>);
+        impl Interceptor for Audit {
+            fn after(&mut self, command: &Command, response: &Response) {
+                let entry = format!("{} {:?} {:?}", self.0, command, response);
+                self.1.lock().unwrap().push(entry);
+            }
+        }
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut pipeline = Pipeline::new();
+        assert!(pipeline.is_empty());
+        pipeline.add(Box::new(Audit("outer", log.clone())));
+        pipeline.add(Box::new(Guard));
+        pipeline.add(Box::new(Upper));
+        pipeline.add(Box::new(Audit("inner", log.clone())));
+        assert_eq!(pipeline.len(), 4);
+        let mut db = Database::new();
+        let mut run = |line: &str| pipeline.execute(&mut db, parse(line).unwrap().unwrap());
+        assert_eq!(run("SET a shout"), Response::Done);
+        assert_eq!(run("GET a"), Response::Value(Some("SHOUT".into())));
+        assert_eq!(run("SET _a x"), Response::Error("_a is reserved".into()));
+        assert_eq!(run("GET _a"), Response::Value(None));
+        let log = log.lock().unwrap();
+        assert_eq!(log.len(), 7);
+        assert_eq!(log[0], r#"inner Set("a", "SHOUT") Done"#);
+        assert_eq!(log[1], r#"outer Set("a", "SHOUT") Done"#);
+        // those past the one that rejects a command never see it
+        assert_eq!(log[4], r#"outer Set("_a", "x") Error("_a is reserved")"#);
+    }
+
+    #[test]
+    fn test_execute_deadline() {
+        // each command checks the deadline before it is run, so none lands
+        // in an expired transaction
+        let mut db: Database = Database::new();
+        db.begin_with(Duration::ZERO);
+        db.set("a", "10");
+        let mut run = |line: &str| execute(&mut db, parse(line).unwrap().unwrap());
+        assert_eq!(
+            run("SET b 20"),
+            Response::Error("transaction expired".into())
+        );
+        assert_eq!(run("GET a"), Response::Value(None));
+        assert_eq!(run("GET b"), Response::Value(None));
+    }
+
+    #[test]
+    fn test_execute() {
+        let mut db = Database::new();
+        let mut run = |line: &str| execute(&mut db, parse(line).unwrap().unwrap());
+        assert_eq!(run("SET a 10"), Response::Done);
+        assert_eq!(run("GET a"), Response::Value(Some("10".into())));
+        assert_eq!(run("GET b"), Response::Value(None));
+        assert_eq!(run("SETNX a 20"), Response::Integer(0));
+        assert_eq!(run("CAS a 20 30"), Response::Integer(0));
+        assert_eq!(run("CAS a 10 10"), Response::Integer(1));
+        assert_eq!(run("COPY a c"), Response::Integer(1));
+        assert_eq!(run("COPY b c REPLACE"), Response::Integer(0));
+        assert_eq!(run("EXISTS a b c c"), Response::Integer(3));
+        assert_eq!(run("GETDEL c"), Response::Value(Some("10".into())));
+        assert_eq!(run("GETDEL c"), Response::Value(None));
+        assert_eq!(run("MSET c 20 d 30"), Response::Done);
+        assert_eq!(
+            run("MGET a b c"),
+            Response::Values(vec![Some("10".into()), None, Some("20".into())])
+        );
+        assert_eq!(run("UNSET c"), Response::Done);
+        assert_eq!(run("UNSET d"), Response::Done);
+        assert_eq!(run("NUMEQUALTO 10"), Response::Integer(1));
+        assert_eq!(run("INCRBY n 5"), Response::Integer(5));
+        assert_eq!(run("DECR n"), Response::Integer(4));
+        assert_eq!(run("APPEND n five"), Response::Integer(5));
+        assert_eq!(
+            run("INCR n"),
+            Response::Error("value is not an integer or out of range".into())
+        );
+        assert_eq!(run("UNSET n"), Response::Done);
+        assert_eq!(run("RPUSH l x 'y z'"), Response::Integer(2));
+        assert_eq!(run("LPUSH l w"), Response::Integer(3));
+        assert_eq!(
+            run("LRANGE l 0 -1"),
+            Response::List(vec!["w".into(), "x".into(), "y z".into()])
+        );
+        assert_eq!(run("RPOP l"), Response::Value(Some("y z".into())));
+        assert_eq!(run("LLEN l"), Response::Integer(2));
+        assert_eq!(
+            run("LLEN a"),
+            Response::Error("key holds the wrong kind of value".into())
+        );
+        assert_eq!(run("UNSET l"), Response::Done);
+        assert_eq!(run("HSET h b 2 a 1"), Response::Integer(2));
+        assert_eq!(run("HGET h a"), Response::Value(Some("1".into())));
+        assert_eq!(
+            run("HGETALL h"),
+            Response::List(vec!["a".into(), "1".into(), "b".into(), "2".into()])
+        );
+        assert_eq!(run("HDEL h a c"), Response::Integer(1));
+        assert_eq!(run("HLEN h"), Response::Integer(1));
+        assert_eq!(
+            run("HGET a f"),
+            Response::Error("key holds the wrong kind of value".into())
+        );
+        assert_eq!(run("UNSET h"), Response::Done);
+        assert_eq!(run("ZADD z 2 b 1.5 a 3 c"), Response::Integer(3));
+        assert_eq!(run("ZSCORE z a"), Response::Value(Some("1.5".into())));
+        assert_eq!(run("ZREM z c d"), Response::Integer(1));
+        assert_eq!(
+            run("ZRANGE z 0 -1 WITHSCORES"),
+            Response::List(vec!["a".into(), "1.5".into(), "b".into(), "2".into()])
+        );
+        assert_eq!(
+            run("ZRANGEBYSCORE z 2 +inf"),
+            Response::List(vec!["b".into()])
+        );
+        assert_eq!(run("TYPE z"), Response::Value(Some("zset".into())));
+        assert_eq!(run("TYPE a"), Response::Value(Some("string".into())));
+        assert_eq!(run("UNSET z"), Response::Done);
+        assert_eq!(run("TYPE z"), Response::Value(Some("none".into())));
+        assert_eq!(run(r#"JSON.SET j $ '{"n": [1, 2]}'"#), Response::Done);
+        assert_eq!(run("JSON.SET j $.m.x 3"), Response::Value(None));
+        assert_eq!(run("JSON.SET j $.n[0] 5"), Response::Done);
+        assert_eq!(run("JSON.GET j $.n"), Response::Value(Some("[5,2]".into())));
+        assert_eq!(
+            run("JSON.GET j"),
+            Response::Value(Some(r#"{"n":[5,2]}"#.into()))
+        );
+        assert_eq!(
+            run("JSON.GET j n"),
+            Response::Error("invalid path: n".into())
+        );
+        assert_eq!(run("UNSET j"), Response::Done);
+        let Response::Value(Some(id)) = run("XADD s * kind login") else {
+            panic!("expected an ID");
+        };
+        assert_eq!(run("XLEN s"), Response::Integer(1));
+        assert_eq!(
+            run("XRANGE s - +"),
+            Response::Entries(vec![(id, vec![("kind".into(), "login".into())])])
+        );
+        assert_eq!(run("TYPE s"), Response::Value(Some("stream".into())));
+        assert_eq!(
+            run("XLEN a"),
+            Response::Error("key holds the wrong kind of value".into())
+        );
+        assert_eq!(run("UNSET s"), Response::Done);
+        assert_eq!(run("PFADD h x y"), Response::Integer(1));
+        assert_eq!(run("PFADD h x"), Response::Integer(0));
+        assert_eq!(run("PFADD g z"), Response::Integer(1));
+        assert_eq!(run("PFMERGE h g"), Response::Done);
+        assert_eq!(run("PFCOUNT h"), Response::Integer(3));
+        assert_eq!(
+            run("PFCOUNT a"),
+            Response::Error("key holds the wrong kind of value".into())
+        );
+        assert_eq!(run("UNSET h"), Response::Done);
+        assert_eq!(run("UNSET g"), Response::Done);
+        assert_eq!(run("SETBIT f 0 1"), Response::Integer(0));
+        assert_eq!(run("SETBIT f 9 1"), Response::Integer(0));
+        assert_eq!(run("SETBIT f 9 1"), Response::Integer(1));
+        assert_eq!(run("GETBIT f 0"), Response::Integer(1));
+        assert_eq!(run("GETBIT f 1"), Response::Integer(0));
+        assert_eq!(run("BITCOUNT f"), Response::Integer(2));
+        assert_eq!(run("BITCOUNT f 1 1"), Response::Integer(1));
+        assert_eq!(run("RPUSH m x"), Response::Integer(1));
+        assert_eq!(run("VALUETYPE f"), Response::Value(Some("string".into())));
+        assert_eq!(run("VALUETYPE n"), Response::Value(Some("none".into())));
+        assert_eq!(
+            run("VALUETYPE m"),
+            Response::Error("key holds the wrong kind of value".into())
+        );
+        assert_eq!(
+            run("GETBIT m 0"),
+            Response::Error("key holds the wrong kind of value".into())
+        );
+        assert_eq!(run("UNSET m"), Response::Done);
+        assert_eq!(run("UNSET f"), Response::Done);
+        assert_eq!(run("TTL a"), Response::Integer(-1));
+        assert_eq!(
+            run("EXPIRE a 18446744073709551615"),
+            Response::Error("invalid expire time".into())
+        );
+        assert_eq!(run("TTL a"), Response::Integer(-1));
+        assert_eq!(run("EXPIRE a 60"), Response::Integer(1));
+        assert_eq!(run("TTL a"), Response::Integer(60));
+        assert_eq!(run("PERSIST a"), Response::Integer(1));
+        assert_eq!(run("GETEX a EX 30"), Response::Value(Some("10".into())));
+        assert_eq!(run("TTL a"), Response::Integer(30));
+        assert_eq!(run("GETEX a"), Response::Value(Some("10".into())));
+        assert_eq!(run("TTL a"), Response::Integer(30));
+        assert_eq!(run("GETEX a PERSIST"), Response::Value(Some("10".into())));
+        assert_eq!(run("TTL a"), Response::Integer(-1));
+        assert_eq!(run("GETEX none EX 30"), Response::Value(None));
+        assert_eq!(run("TTL b"), Response::Integer(-2));
+        assert_eq!(run("BEGIN"), Response::Done);
+        assert_eq!(run("SET b 10"), Response::Done);
+        assert_eq!(
+            run("PENDING"),
+            Response::Changes(vec![Change {
+                name: "b".into(),
+                old: None,
+                new: Some("10".into()),
+            }])
+        );
+        assert_eq!(run("KEYS"), Response::Keys(vec!["a".into(), "b".into()]));
+        assert_eq!(run("KEYS * LIMIT 1 5"), Response::Keys(vec!["b".into()]));
+        assert_eq!(run("KEYS * LIMIT 0 1"), Response::Keys(vec!["a".into()]));
+        assert_eq!(run("KEYS * LIMIT 2 1"), Response::Keys(vec![]));
+        assert_eq!(run("DBSIZE"), Response::Integer(2));
+        assert!(matches!(run("RANDOMKEY"), Response::Value(Some(_))));
+        assert_eq!(
+            run("SCAN 0 COUNT 1"),
+            Response::Page("62".into(), vec!["a".into()])
+        );
+        assert_eq!(run("FLUSHALL"), Response::Done);
+        assert_eq!(run("DBSIZE"), Response::Integer(0));
+        assert_eq!(run("ROLLBACK"), Response::Done);
+        assert_eq!(run("ROLLBACK"), Response::Error("NO TRANSACTION".into()));
+        assert_eq!(run("COMMIT"), Response::Error("NO TRANSACTION".into()));
+        assert_eq!(run("UNSET a"), Response::Done);
+        assert_eq!(run("NUMEQUALTO 10"), Response::Integer(0));
+        // the command after the timeout fails, the transaction being gone
+        assert_eq!(run("BEGIN 0"), Response::Done);
+        assert_eq!(
+            run("SET a 10"),
+            Response::Error("transaction expired".into())
+        );
+        assert_eq!(run("ROLLBACK"), Response::Error("NO TRANSACTION".into()));
+        assert_eq!(run("END"), Response::End);
+    }
+}
@@ -0,0 +1,257 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! Encryption of the files to which a database is persisted, so that none of
+//! its data is written to the disk in plaintext: the write-ahead log, the
+//! snapshots, and the manifests and increments of backups.
+//!
+//! Each file is encrypted with its own key, derived with Argon2id from the
+//! secret, a passphrase or the contents of a key file, and a random salt.
+//! An encrypted file starts with the magic bytes `SDBE`, a version byte, the
+//! sixteen bytes of the salt, and a sealed box holding nothing, which tells
+//! the wrong secret apart from a damaged file. Each box is a random 24-byte
+//! nonce followed by the contents encrypted with XChaCha20-Poly1305, whose
+//! tag tells of any change to them. A snapshot or a manifest follows the
+//! header with a single box holding the whole of it, while the log seals
+//! each batch as a record of its own, so that it can still be appended to.
+
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+const MAGIC: &[u8; 4] = b"SDBE";
+const VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+
+/// Length of the header with which every encrypted file starts.
+pub(crate) const HEADER_LEN: usize = MAGIC.len() + 1 + SALT_LEN + NONCE_LEN + TAG_LEN;
+
+///
+/// The passphrase or key from which the keys that encrypt the files of a
+/// database are derived. It is never written anywhere, nor shown by `Debug`.
+///
+#[derive(Clone)]
+pub struct Secret(Arc<[u8]>);
+
+impl Secret {
+    /// Use the given passphrase.
+    pub fn passphrase(passphrase: &str) -> Self {
+        Self(passphrase.as_bytes().into())
+    }
+
+    /// Use the contents of the key file at the given path, which may hold
+    /// random bytes or a passphrase, without the line break at its end.
+    /// Fails if the file is empty.
+    pub fn read_key_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut bytes = fs::read(path.as_ref())?;
+        if bytes.ends_with(b"\n") {
+            bytes.pop();
+            if bytes.ends_with(b"\r") {
+                bytes.pop();
+            }
+        }
+        if bytes.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} holds no key", path.as_ref().display()),
+            ));
+        }
+        Ok(Self(bytes.into()))
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+///
+/// Encrypts and decrypts the contents of one file with the key derived from
+/// the secret and the salt of that file.
+///
+#[derive(Clone)]
+pub(crate) struct Cipher {
+    salt: [u8; SALT_LEN],
+    // the empty box of the header
+    check: Vec<u8>,
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    /// Derive the key for a new file, with a salt of its own.
+    pub fn new(secret: &Secret) -> io::Result<Self> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let mut cipher = Self::with_salt(secret, salt)?;
+        cipher.check = cipher.seal(&[])?;
+        Ok(cipher)
+    }
+
+    /// Derive the key for the file with the given salt.
+    fn with_salt(secret: &Secret, salt: [u8; SALT_LEN]) -> io::Result<Self> {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(&secret.0, &salt, &mut key)
+            .map_err(|err| io::Error::other(err.to_string()))?;
+        Ok(Self {
+            salt,
+            check: Vec::new(),
+            aead: XChaCha20Poly1305::new(Key::from_slice(&key)),
+        })
+    }
+
+    /// Returns the header with which the file starts.
+    pub fn header(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.push(VERSION);
+        header.extend_from_slice(&self.salt);
+        header.extend_from_slice(&self.check);
+        header
+    }
+
+    /// Encrypt the bytes, returning the nonce followed by the ciphertext.
+    pub fn seal(&self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = self
+            .aead
+            .encrypt(XNonce::from_slice(&nonce), plaintext)
+            .map_err(|_| io::Error::other("encryption failed"))?;
+        let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypt what `seal()` returned, or `None` if it was not sealed with
+    /// this key or has been changed since.
+    pub fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.aead
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .ok()
+    }
+}
+
+/// Read the header of an encrypted file, if the input starts with one, and
+/// return the cipher for the rest of it. Fails if the file is encrypted but
+/// there is no secret with which to read it, or the secret is wrong.
+pub(crate) fn read_header<R: BufRead>(
+    reader: &mut R,
+    secret: Option<&Secret>,
+    path: &Path,
+) -> io::Result<Option<Cipher>> {
+    if !reader.fill_buf()?.starts_with(MAGIC) {
+        return Ok(None);
+    }
+    let mut header = [0u8; HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    if header[4] != VERSION {
+        return Err(invalid(path, "is encrypted in an unsupported version"));
+    }
+    let Some(secret) = secret else {
+        return Err(invalid(path, "is encrypted, a passphrase or key is needed"));
+    };
+    let mut salt = [0u8; SALT_LEN];
+    salt.copy_from_slice(&header[5..5 + SALT_LEN]);
+    let mut cipher = Cipher::with_salt(secret, salt)?;
+    let check = &header[5 + SALT_LEN..];
+    if cipher.open(check).is_none() {
+        return Err(invalid(path, "could not be decrypted, the key is wrong"));
+    }
+    cipher.check = check.to_vec();
+    Ok(Some(cipher))
+}
+
+/// Encrypt the whole of the contents of a file, with a key of its own.
+pub(crate) fn seal_file(secret: &Secret, contents: &[u8]) -> io::Result<Vec<u8>> {
+    let cipher = Cipher::new(secret)?;
+    let mut sealed = cipher.header();
+    sealed.extend(cipher.seal(contents)?);
+    Ok(sealed)
+}
+
+/// Open the file at the given path for reading, decrypting it first if it
+/// was written by `seal_file()`. A file that is not encrypted is read as it
+/// is, whether or not there is a secret.
+pub(crate) fn open_file(path: &Path, secret: Option<&Secret>) -> io::Result<Box<dyn Read>> {
+    let mut reader = io::BufReader::new(fs::File::open(path)?);
+    match read_header(&mut reader, secret, path)? {
+        Some(cipher) => {
+            let mut sealed = Vec::new();
+            reader.read_to_end(&mut sealed)?;
+            let contents = cipher
+                .open(&sealed)
+                .ok_or_else(|| invalid(path, "is damaged"))?;
+            Ok(Box::new(io::Cursor::new(contents)))
+        }
+        None => Ok(Box::new(reader)),
+    }
+}
+
+fn invalid(path: &Path, msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} {}", path.display(), msg),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_file() {
+        let secret = Secret::passphrase("correct horse");
+        let path = std::env::temp_dir().join(format!("simpledb-sealed-{}", std::process::id()));
+        let sealed = seal_file(&secret, b"plaintext").unwrap();
+        assert_eq!(sealed.len(), HEADER_LEN + NONCE_LEN + 9 + 16);
+        assert!(!sealed.windows(9).any(|w| w == b"plaintext"));
+        fs::write(&path, &sealed).unwrap();
+        let mut contents = Vec::new();
+        open_file(&path, Some(&secret))
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents, b"plaintext");
+        assert!(open_file(&path, None).is_err());
+        let wrong = Secret::passphrase("battery staple");
+        assert!(open_file(&path, Some(&wrong)).is_err());
+        // any change to the contents is caught
+        let mut damaged = sealed.clone();
+        *damaged.last_mut().unwrap() ^= 1;
+        fs::write(&path, &damaged).unwrap();
+        assert!(open_file(&path, Some(&secret)).is_err());
+        // files that are not encrypted are read as they are
+        fs::write(&path, b"SDBS").unwrap();
+        let mut contents = Vec::new();
+        open_file(&path, Some(&secret))
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents, b"SDBS");
+        // the key file may end with a line break
+        fs::write(&path, b"correct horse\r\n").unwrap();
+        let read = Secret::read_key_file(&path).unwrap();
+        assert_eq!(&*read.0, b"correct horse");
+        assert_eq!(format!("{:?}", read), "Secret(..)");
+        fs::write(&path, b"\n").unwrap();
+        assert!(Secret::read_key_file(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,239 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! Several independent databases held together, so that a single process can
+//! keep separate keyspaces, such as test data apart from real data. The
+//! databases are numbered from zero and may also be given names. Each has its
+//! own keys, value counts, and transactions. The others encrypt what they
+//! save as the first does. Only the first is persisted, so while it is, the
+//! others may not be selected, nor may keys be moved out of it.
+
+use crate::store::Database;
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::ops::{Index, IndexMut};
+
+///
+/// Collection of databases addressed by number or by name.
+///
+pub struct Databases<K = String, V = String> {
+    databases: Vec<Database<K, V>>,
+    // name of each database, if it has one
+    names: Vec<Option<String>>,
+    // whether the first database is persisted, the others being kept only
+    // in memory
+    persisted: bool,
+}
+
+impl<K, V> Databases<K, V>
+where
+    K: Ord + Clone,
+    V: Hash + Eq + Clone,
+{
+    /// Construct the given number of empty, unnamed databases. There is
+    /// always at least one.
+    pub fn new(count: usize) -> Self {
+        Self::with_first(Database::default(), count)
+    }
+
+    /// Construct the given number of databases, the first of which is the
+    /// one given, such as one opened from a write-ahead log, in which case
+    /// it is persisted.
+    pub fn with_first(first: Database<K, V>, count: usize) -> Self {
+        let persisted = first.lsn().is_some();
+        let secret = first.encryption().cloned();
+        let mut databases = vec![first];
+        databases.resize_with(count.max(1), || {
+            let mut database = Database::default();
+            database.set_encryption(secret.clone());
+            database
+        });
+        let names = vec![None; databases.len()];
+        Self {
+            databases,
+            names,
+            persisted,
+        }
+    }
+
+    /// Set whether the first database is persisted, such as to snapshots
+    /// saved on a schedule, which a write-ahead log already makes it.
+    pub fn set_persisted(&mut self, persisted: bool) {
+        self.persisted = persisted;
+    }
+
+    /// Returns true if the first database is persisted, so that only it may
+    /// be used, as whatever the others held would not be.
+    pub fn is_persisted(&self) -> bool {
+        self.persisted
+    }
+
+    /// Add an empty database with the given name, returning its number. If
+    /// there is already one by that name, its number is returned instead.
+    pub fn add(&mut self, name: &str) -> usize {
+        if let Some(index) = self.position(name) {
+            return index;
+        }
+        let mut database = Database::default();
+        database.set_encryption(self.databases[0].encryption().cloned());
+        self.databases.push(database);
+        self.names.push(Some(name.to_owned()));
+        self.databases.len() - 1
+    }
+
+    /// Returns the number of the database with the given name.
+    fn position(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|n| n.as_deref() == Some(name))
+    }
+
+    /// Returns the number of the database given either by its number or by
+    /// its name, or `None` if there is no such database.
+    pub fn find(&self, selector: &str) -> Option<usize> {
+        match selector.parse::<usize>() {
+            Ok(index) if index < self.databases.len() => Some(index),
+            Ok(_) => None,
+            Err(_) => self.position(selector),
+        }
+    }
+
+    /// Returns the name of the database with the given number, if it has one.
+    pub fn name(&self, index: usize) -> Option<&str> {
+        self.names.get(index).and_then(Option::as_deref)
+    }
+
+    /// Returns the number of databases.
+    pub fn len(&self) -> usize {
+        self.databases.len()
+    }
+
+    /// Always false, as there is at least one database.
+    pub fn is_empty(&self) -> bool {
+        self.databases.is_empty()
+    }
+
+    /// Move the key and its value, along with any time-to-live, from one
+    /// database to another, returning whether it was moved. Nothing is moved
+    /// if the key has no value in the source or already has one in the
+    /// destination, nor out of the first database while it is persisted, as
+    /// the key would be lost.
+    pub fn move_key<Q>(&mut self, source: usize, destination: usize, name: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        if source == destination || source >= self.len() || destination >= self.len() {
+            return false;
+        }
+        if self.persisted && source == 0 {
+            return false;
+        }
+        if self.databases[destination].exists(name) {
+            return false;
+        }
+        let Ok(ttl) = self.databases[source].ttl(name) else {
+            return false;
+        };
+        let Some(value) = self.databases[source].get_del(name) else {
+            return false;
+        };
+        let target = &mut self.databases[destination];
+        target.set(name.to_owned(), value);
+        if let Some(ttl) = ttl {
+            // the time came from a deadline, so it can be represented
+            let _ = target.expire(name, ttl);
+        }
+        true
+    }
+
+    /// Returns the database with the given number, if there is one.
+    pub fn get(&self, index: usize) -> Option<&Database<K, V>> {
+        self.databases.get(index)
+    }
+
+    /// Returns the database with the given number, if there is one.
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut Database<K, V>> {
+        self.databases.get_mut(index)
+    }
+}
+
+impl<K, V> Index<usize> for Databases<K, V> {
+    type Output = Database<K, V>;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.databases[index]
+    }
+}
+
+impl<K, V> IndexMut<usize> for Databases<K, V> {
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        &mut self.databases[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_databases() {
+        let mut dbs: Databases = Databases::new(2);
+        assert_eq!(dbs.len(), 2);
+        assert_eq!(dbs.find("1"), Some(1));
+        assert_eq!(dbs.find("2"), None);
+        assert_eq!(dbs.find("test"), None);
+        assert_eq!(dbs.add("test"), 2);
+        assert_eq!(dbs.add("test"), 2);
+        assert_eq!(dbs.find("test"), Some(2));
+        assert_eq!(dbs.name(2), Some("test"));
+        assert_eq!(dbs.name(0), None);
+        // each database has its own keys and transactions
+        dbs[0].set("a", "10");
+        dbs[2].begin();
+        dbs[2].set("a", "20");
+        assert_eq!(dbs[0].get("a"), Some("10".into()));
+        assert_eq!(dbs[0].transaction_depth(), 0);
+        dbs[2].rollback().unwrap();
+        assert_eq!(dbs[2].get("a"), None);
+        assert_eq!(dbs[1].count("10"), 0);
+    }
+
+    #[test]
+    fn test_move_key() {
+        let mut dbs: Databases = Databases::new(3);
+        dbs[0].set("a", "10");
+        dbs[0].set("b", "10");
+        dbs[1].set("b", "20");
+        assert!(dbs.move_key(0, 2, "a"));
+        assert_eq!(dbs[0].get("a"), None);
+        assert_eq!(dbs[2].get("a"), Some("10".into()));
+        assert_eq!(dbs[0].count("10"), 1);
+        assert_eq!(dbs[2].count("10"), 1);
+        assert!(!dbs.move_key(0, 2, "a"));
+        assert!(!dbs.move_key(0, 1, "b"));
+        assert_eq!(dbs[0].get("b"), Some("10".into()));
+        assert!(!dbs.move_key(0, 0, "b"));
+        assert!(!dbs.move_key(0, 3, "b"));
+        dbs[0]
+            .expire("b", std::time::Duration::from_secs(3600))
+            .unwrap();
+        assert!(dbs.move_key(0, 2, "b"));
+        assert!(dbs[2].ttl("b").unwrap().is_some());
+        // nothing is moved out of a persisted database
+        dbs.set_persisted(true);
+        dbs[0].set("c", "30");
+        assert!(!dbs.move_key(0, 1, "c"));
+        assert_eq!(dbs[0].get("c"), Some("30".into()));
+        assert!(dbs.move_key(2, 0, "b"));
+    }
+
+    #[test]
+    fn test_persisted() {
+        let path = std::env::temp_dir().join(format!("simpledb-dbs-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let dbs: Databases = Databases::with_first(Database::open(&path).unwrap(), 2);
+        assert!(dbs.is_persisted());
+        assert!(!Databases::<String, String>::new(2).is_persisted());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,201 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! Reading and writing `KEY=VALUE` files in the style of `.env` files and Java
+//! properties, for staging configuration data in the database.
+//!
+//! Blank lines and lines starting with `#` or `!` are ignored, as is a leading
+//! `export`. Either `=` or `:` separates the key from the value. Values may be
+//! enclosed in double quotes, in which `\n`, `\t`, `\"` and `\\` are escapes, or
+//! in single quotes, which are taken literally. An unquoted value ends at a `#`
+//! that follows whitespace, and surrounding whitespace is removed.
+
+use anyhow::{anyhow, Result};
+use std::io::{self, BufRead, Write};
+
+/// Read all of the key/value pairs from the input, in the order given.
+pub fn read<R: BufRead>(input: R) -> Result<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        if let Some(pair) =
+            parse_line(&line).map_err(|msg| anyhow!("line {}: {}", index + 1, msg))?
+        {
+            pairs.push(pair);
+        }
+    }
+    Ok(pairs)
+}
+
+/// Parse a single line, returning `None` if it is blank or a comment.
+fn parse_line(line: &str) -> Result<Option<(String, String)>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') || line.starts_with('!') {
+        return Ok(None);
+    }
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let split = line
+        .find(['=', ':'])
+        .ok_or_else(|| "expected KEY=VALUE".to_owned())?;
+    let key = line[..split].trim();
+    if key.is_empty() {
+        return Err("missing key".into());
+    }
+    let value = parse_value(line[split + 1..].trim())?;
+    Ok(Some((key.to_owned(), value)))
+}
+
+/// Parse the part of the line following the separator.
+fn parse_value(raw: &str) -> Result<String, String> {
+    let mut chars = raw.chars();
+    match chars.next() {
+        Some('"') => {
+            let mut value = String::new();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => return check_trailing(chars.as_str()).map(|_| value),
+                    '\\' => match chars.next() {
+                        Some('n') => value.push('\n'),
+                        Some('t') => value.push('\t'),
+                        Some('r') => value.push('\r'),
+                        Some(other) => value.push(other),
+                        None => break,
+                    },
+                    _ => value.push(c),
+                }
+            }
+            Err("unterminated double quote".into())
+        }
+        Some('\'') => {
+            let rest = chars.as_str();
+            let end = rest.find('\'').ok_or("unterminated single quote")?;
+            check_trailing(&rest[end + 1..])?;
+            Ok(rest[..end].to_owned())
+        }
+        _ => {
+            let mut end = raw.len();
+            let mut previous = ' ';
+            for (index, c) in raw.char_indices() {
+                if c == '#' && previous.is_whitespace() {
+                    end = index;
+                    break;
+                }
+                previous = c;
+            }
+            Ok(raw[..end].trim_end().to_owned())
+        }
+    }
+}
+
+/// Ensure nothing but a comment follows a quoted value.
+fn check_trailing(rest: &str) -> Result<(), String> {
+    let rest = rest.trim_start();
+    if rest.is_empty() || rest.starts_with('#') {
+        Ok(())
+    } else {
+        Err("unexpected text after closing quote".into())
+    }
+}
+
+/// Write the key/value pairs to the output, one per line, quoting the values
+/// as needed for them to be read back unchanged.
+pub fn write<'a, W, I>(mut output: W, pairs: I) -> io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = (&'a str, &'a str)>,
+{
+    for (key, value) in pairs {
+        writeln!(output, "{}={}", key, quote(value))?;
+    }
+    Ok(())
+}
+
+/// Quote the value if it would not otherwise be read back as it is.
+fn quote(value: &str) -> String {
+    let plain = !value.is_empty()
+        && !value.starts_with(['"', '\''])
+        && value.trim() == value
+        && !value.contains(['#', '\\', '\n', '\r', '\t']);
+    if plain {
+        return value.to_owned();
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read() {
+        let input = "# comment\n\
+            ! another comment\n\
+            \n\
+            export PATH=/usr/bin\n\
+            name = value # trailing comment\n\
+            url: http://example.com/#anchor\n\
+            empty=\n\
+            double=\"two words\\n\\\"quoted\\\"\"\n\
+            single='no $escapes\\n here' # comment\n";
+        let pairs = read(input.as_bytes()).unwrap();
+        let expected = vec![
+            ("PATH", "/usr/bin"),
+            ("name", "value"),
+            ("url", "http://example.com/#anchor"),
+            ("empty", ""),
+            ("double", "two words\n\"quoted\""),
+            ("single", "no $escapes\\n here"),
+        ];
+        let actual: Vec<(&str, &str)> = pairs
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_read_errors() {
+        let err = read("a=1\nnot a pair\n".as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "line 2: expected KEY=VALUE");
+        let err = read("a=\"open".as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "line 1: unterminated double quote");
+        assert!(read("a='x' y".as_bytes()).is_err());
+        assert!(read("=x".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let pairs = vec![
+            ("plain", "value"),
+            ("spaces", " padded "),
+            ("comment", "a #b"),
+            ("quotes", "\"quoted\" and 'single'"),
+            ("escapes", "tab\there\nnewline \\ backslash"),
+            ("empty", ""),
+        ];
+        let mut buffer: Vec<u8> = Vec::new();
+        write(&mut buffer, pairs.iter().copied()).unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        assert!(text.starts_with("plain=value\n"));
+        let read_back = read(text.as_bytes()).unwrap();
+        let actual: Vec<(&str, &str)> = read_back
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(actual, pairs);
+    }
+}
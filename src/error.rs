@@ -0,0 +1,126 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! The ways in which operations of the database can fail, so that callers can
+//! tell them apart rather than receiving a `bool` or a message.
+
+use crate::{command, parser};
+use std::fmt;
+use std::io;
+
+///
+/// Reason an operation of the database failed.
+///
+#[derive(Debug)]
+pub enum Error {
+    /// There is no open transaction to commit or roll back.
+    NoTransaction,
+    /// No savepoint of the given name is open in the current transaction.
+    NoSavepoint(String),
+    /// No open transaction has the given name.
+    NoNamedTransaction(String),
+    /// A transaction was open past its deadline and has been rolled back.
+    TransactionExpired,
+    /// A key no longer holds the value that a change expects it to.
+    Conflict,
+    /// The key has no value.
+    KeyNotFound,
+    /// The value is not an integer, or the result would overflow.
+    NotAnInteger,
+    /// The value is not a number, such as a score that is NaN.
+    NotAFloat,
+    /// The time-to-live is too long for the time at which it ends to be
+    /// represented.
+    InvalidExpireTime,
+    /// The key holds a different kind of value than the operation expects,
+    /// such as a list rather than a string.
+    WrongType,
+    /// The input could not be parsed, for the reason given.
+    Parse(String),
+    /// Reading or writing a file failed.
+    Io(io::Error),
+}
+
+/// Result of an operation of the database.
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NoTransaction => write!(f, "NO TRANSACTION"),
+            Error::NoSavepoint(name) => write!(f, "no such savepoint: {}", name),
+            Error::NoNamedTransaction(name) => write!(f, "no transaction named: {}", name),
+            Error::TransactionExpired => write!(f, "transaction expired"),
+            Error::Conflict => write!(f, "key no longer holds the expected value"),
+            Error::KeyNotFound => write!(f, "key not found"),
+            Error::NotAnInteger => write!(f, "value is not an integer or out of range"),
+            Error::NotAFloat => write!(f, "value is not a valid float"),
+            Error::InvalidExpireTime => write!(f, "invalid expire time"),
+            Error::WrongType => write!(f, "key holds the wrong kind of value"),
+            Error::Parse(msg) => write!(f, "{}", msg),
+            Error::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        if err.is_io() {
+            Error::Io(err.into())
+        } else {
+            Error::Parse(err.to_string())
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::Io(io::Error::other(err))
+    }
+}
+
+impl From<parser::Error> for Error {
+    fn from(err: parser::Error) -> Self {
+        Error::Parse(err.to_string())
+    }
+}
+
+impl From<command::Error> for Error {
+    fn from(err: command::Error) -> Self {
+        Error::Parse(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversions() {
+        let err: Error = io::Error::new(io::ErrorKind::NotFound, "gone").into();
+        assert!(matches!(err, Error::Io(_)));
+        assert!(std::error::Error::source(&err).is_some());
+        let err: Error = serde_json::from_str::<String>("[1,").unwrap_err().into();
+        assert!(matches!(err, Error::Parse(_)));
+        let err: Error = parser::Error::TrailingBackslash.into();
+        assert_eq!(err.to_string(), "trailing backslash");
+        assert_eq!(Error::NoTransaction.to_string(), "NO TRANSACTION");
+    }
+}
@@ -0,0 +1,160 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! Approximate per-key access counts, for finding hot keys without any
+//! external instrumentation.
+
+use crate::observer::Observer;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// largest counter value, beyond which the estimate would overflow
+const MAX_COUNTER: u8 = 63;
+
+///
+/// Observer that counts reads and writes of each key using Morris counters,
+/// which need only a single byte per key regardless of how often it is used.
+/// Each access increments the counter `c` with probability `2^-c`, making the
+/// estimated number of accesses `2^c - 1`.
+///
+/// Clones share the same counters, so one clone can be attached to the
+/// database while another is kept for reading the frequencies.
+///
+#[derive(Clone)]
+pub struct FrequencyTracker<K = String> {
+    inner: Arc<Mutex<Counters<K>>>,
+}
+
+struct Counters<K> {
+    counts: HashMap<K, u8>,
+    rng: StdRng,
+}
+
+impl<K> FrequencyTracker<K>
+where
+    K: Hash + Eq + Clone,
+{
+    /// Construct a tracker with no recorded accesses.
+    pub fn new() -> Self {
+        Self::from_rng(StdRng::from_entropy())
+    }
+
+    /// Construct a tracker whose estimates are repeatable for a given seed.
+    pub fn with_seed(seed: u64) -> Self {
+        Self::from_rng(StdRng::seed_from_u64(seed))
+    }
+
+    fn from_rng(rng: StdRng) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Counters {
+                counts: HashMap::new(),
+                rng,
+            })),
+        }
+    }
+
+    /// Record one access of the key.
+    pub fn touch(&self, name: &K) {
+        let mut inner = self.inner.lock().unwrap();
+        let Counters { counts, rng } = &mut *inner;
+        let counter = counts.entry(name.clone()).or_insert(0);
+        if *counter < MAX_COUNTER && rng.gen_bool(0.5f64.powi(*counter as i32)) {
+            *counter += 1;
+        }
+    }
+
+    /// Forget the accesses of the key, such as when it has been removed.
+    pub fn forget<Q>(&self, name: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.inner.lock().unwrap().counts.remove(name);
+    }
+
+    /// Returns the estimated number of accesses of the key.
+    pub fn frequency<Q>(&self, name: &Q) -> u64
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let inner = self.inner.lock().unwrap();
+        let counter = inner.counts.get(name).copied().unwrap_or(0);
+        (1u64 << counter) - 1
+    }
+}
+
+impl<K> Default for FrequencyTracker<K>
+where
+    K: Hash + Eq + Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K> Observer<K> for FrequencyTracker<K>
+where
+    K: Hash + Eq + Clone + Send,
+{
+    fn on_get(&self, name: &K, found: bool, _elapsed: Duration) {
+        if found {
+            self.touch(name);
+        }
+    }
+
+    fn on_set(&self, name: &K, _elapsed: Duration) {
+        self.touch(name);
+    }
+
+    fn on_delete(&self, name: &K, _elapsed: Duration) {
+        self.forget(name);
+    }
+
+    fn on_evict(&self, name: &K) {
+        self.forget(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Database;
+
+    #[test]
+    fn test_estimates() {
+        let tracker: FrequencyTracker = FrequencyTracker::with_seed(42);
+        assert_eq!(tracker.frequency("foo"), 0);
+        // the first access is always counted
+        tracker.touch(&"foo".into());
+        assert_eq!(tracker.frequency("foo"), 1);
+        for _ in 0..9_999 {
+            tracker.touch(&"foo".into());
+        }
+        let estimate = tracker.frequency("foo");
+        assert!((2_000..=50_000).contains(&estimate), "{}", estimate);
+        tracker.forget("foo");
+        assert_eq!(tracker.frequency("foo"), 0);
+    }
+
+    #[test]
+    fn test_observer() {
+        let tracker: FrequencyTracker = FrequencyTracker::with_seed(7);
+        let mut db = Database::new();
+        db.add_observer(Box::new(tracker.clone()));
+        db.set("a", "1");
+        db.get_ref("a");
+        db.get_ref("b");
+        assert!(tracker.frequency("a") >= 1);
+        // reading a missing key is not an access of that key
+        assert_eq!(tracker.frequency("b"), 0);
+        db.delete("a");
+        assert_eq!(tracker.frequency("a"), 0);
+    }
+}
@@ -0,0 +1,132 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! Glob-style pattern matching of keys and values, following the conventions
+//! of Redis: `*` matches any sequence of characters, `?` matches any single
+//! character, `[abc]` and `[a-z]` match one character from a set or range,
+//! `[^abc]` matches one character not in the set, and `\` escapes the next
+//! character.
+
+/// Returns true if the text matches the pattern in its entirety.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    matches_from(&pattern, &text)
+}
+
+fn matches_from(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // position of the last star and the text it has consumed up to, so that
+    // a mismatch can backtrack by having the star consume one more character
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match_class(&pattern[p..], text[t]),
+            Some('\\') if p + 1 < pattern.len() => (pattern[p + 1] == text[t]).then_some(2),
+            Some(c) => (*c == text[t]).then_some(1),
+            None => None,
+        };
+        match step {
+            Some(width) => {
+                p += width;
+                t += 1;
+            }
+            None => match backtrack {
+                Some((star, consumed)) => {
+                    p = star + 1;
+                    t = consumed + 1;
+                    backtrack = Some((star, consumed + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Match a character against the class at the start of the pattern, returning
+/// the width of the class if it matches. An unterminated class is treated as
+/// a literal `[`.
+fn match_class(pattern: &[char], c: char) -> Option<usize> {
+    let mut i = 1;
+    let negated = pattern.get(i) == Some(&'^');
+    if negated {
+        i += 1;
+    }
+    let mut found = false;
+    loop {
+        match pattern.get(i) {
+            None => return (c == '[').then_some(1),
+            Some(']') => break,
+            Some('\\') if i + 1 < pattern.len() => {
+                found |= pattern[i + 1] == c;
+                i += 2;
+            }
+            Some(low) if pattern.get(i + 1) == Some(&'-') && i + 2 < pattern.len() => {
+                let high = pattern[i + 2];
+                if high == ']' {
+                    // a trailing dash is taken literally
+                    found |= *low == c || c == '-';
+                    i += 2;
+                    continue;
+                }
+                found |= (*low.min(&high)..=*low.max(&high)).contains(&c);
+                i += 3;
+            }
+            Some(other) => {
+                found |= *other == c;
+                i += 1;
+            }
+        }
+    }
+    (found != negated).then_some(i + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcards() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("user:*", "user:42"));
+        assert!(!matches("user:*", "session:42"));
+        assert!(matches("*:42", "user:42"));
+        assert!(matches("u*r*2", "user:42"));
+        assert!(!matches("u*r*3", "user:42"));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("exact", "exact"));
+        assert!(!matches("exact", "exactly"));
+        assert!(matches("a*b*c", "aXbYbZc"));
+    }
+
+    #[test]
+    fn test_classes() {
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("key[0-9]", "key7"));
+        assert!(!matches("key[0-9]", "keyx"));
+        assert!(matches("[a-]", "-"));
+        assert!(matches("a[", "a["));
+    }
+
+    #[test]
+    fn test_escapes() {
+        assert!(matches("what\\?", "what?"));
+        assert!(!matches("what\\?", "whatx"));
+        assert!(matches("\\*star", "*star"));
+        assert!(!matches("\\*star", "a star"));
+        assert!(matches("[\\]]", "]"));
+    }
+}
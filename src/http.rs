@@ -0,0 +1,396 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! Network server offering the database as an HTTP API with JSON bodies.
+//!
+//! | Request                | Effect                                     |
+//! |------------------------|--------------------------------------------|
+//! | `GET /keys/{name}`     | value of the key, or 404 if it has none    |
+//! | `PUT /keys/{name}`     | set the key to the `value` in the body     |
+//! | `DELETE /keys/{name}`  | remove the key, or 404 if it has no value  |
+//! | `GET /counts/{value}`  | number of keys set to the value            |
+//! | `POST /tx/begin`       | open a transaction                         |
+//! | `POST /tx/commit`      | commit all open transactions               |
+//! | `POST /tx/rollback`    | roll back the innermost transaction        |
+//!
+//! Path segments may be percent-encoded. As every client shares the one
+//! database, transactions are shared by them as well. Each connection carries
+//! a single request, after which it is closed.
+
+use crate::store::Database;
+use serde_json::{json, Value};
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// limits on requests, to keep a misbehaving client from exhausting memory
+const MAX_LINE_LENGTH: u64 = 64 * 1024;
+const MAX_HEADERS: usize = 100;
+const MAX_BODY_LENGTH: usize = 64 * 1024 * 1024;
+const BODY_TOO_LARGE: &str = "body too large";
+
+///
+/// A request, reduced to the parts that the API uses.
+///
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+///
+/// A response with a JSON body.
+///
+#[derive(Debug, PartialEq)]
+struct Response {
+    status: u16,
+    body: Value,
+}
+
+impl Response {
+    fn ok(body: Value) -> Self {
+        Self { status: 200, body }
+    }
+
+    fn error(status: u16, msg: &str) -> Self {
+        Self {
+            status,
+            body: json!({ "error": msg }),
+        }
+    }
+
+    fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let body = self.body.to_string();
+        write!(
+            out,
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status,
+            reason(self.status),
+            body.len(),
+            body
+        )
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        _ => "Error",
+    }
+}
+
+/// Accept connections on the listener until it fails, serving each one on a
+/// separate thread.
+pub fn serve<V>(listener: TcpListener, database: Arc<Mutex<Database<String, V>>>) -> io::Result<()>
+where
+    V: Hash + Eq + Clone + From<String> + AsRef<str> + Borrow<str> + Send + Sync + 'static,
+{
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let database = database.clone();
+        thread::spawn(move || {
+            // errors such as the client going away only affect this connection
+            let _ = serve_connection(stream, &database);
+        });
+    }
+    Ok(())
+}
+
+fn serve_connection<V>(stream: TcpStream, database: &Mutex<Database<String, V>>) -> io::Result<()>
+where
+    V: Hash + Eq + Clone + From<String> + AsRef<str> + Borrow<str>,
+{
+    let reader = BufReader::new(stream.try_clone()?);
+    let writer = BufWriter::new(stream);
+    handle(reader, writer, database)
+}
+
+/// Read one request from the client and write the response.
+fn handle<R, W, V>(
+    mut reader: R,
+    mut writer: W,
+    database: &Mutex<Database<String, V>>,
+) -> io::Result<()>
+where
+    R: BufRead,
+    W: Write,
+    V: Hash + Eq + Clone + From<String> + AsRef<str> + Borrow<str>,
+{
+    let response = match read_request(&mut reader) {
+        Ok(Some(request)) => {
+            let mut database = database.lock().unwrap();
+            execute(&mut database, &request)
+        }
+        Ok(None) => return Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+            let status = if err.to_string() == BODY_TOO_LARGE {
+                413
+            } else {
+                400
+            };
+            Response::error(status, &err.to_string())
+        }
+        Err(err) => return Err(err),
+    };
+    response.write_to(&mut writer)?;
+    writer.flush()
+}
+
+/// Carry out a single request.
+fn execute<V>(database: &mut Database<String, V>, request: &Request) -> Response
+where
+    V: Hash + Eq + Clone + From<String> + AsRef<str> + Borrow<str>,
+{
+    let path = request.path.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    let (resource, name) = match segments.as_slice() {
+        [resource, name] => (*resource, *name),
+        _ => return Response::error(404, "not found"),
+    };
+    let method = request.method.as_str();
+    if resource == "tx" {
+        if method != "POST" {
+            return Response::error(405, "method not allowed");
+        }
+        let result = match name {
+            "begin" => {
+                database.begin();
+                Ok(())
+            }
+            "commit" => database.commit().map(|_| ()),
+            "rollback" => database.rollback().map(|_| ()),
+            _ => return Response::error(404, "not found"),
+        };
+        return match result {
+            Ok(()) => Response::ok(json!({ "depth": database.transaction_depth() })),
+            Err(_) => Response::error(409, "no transaction"),
+        };
+    }
+    let name = match percent_decode(name) {
+        Some(name) => name,
+        None => return Response::error(400, "invalid percent-encoding in path"),
+    };
+    match (resource, method) {
+        ("keys", "GET") => match database.get_ref(name.as_str()) {
+            Some(value) => Response::ok(json!({ "name": name, "value": value.as_ref() })),
+            None => Response::error(404, "not found"),
+        },
+        ("keys", "PUT") => {
+            let body: Value = match serde_json::from_slice(&request.body) {
+                Ok(body) => body,
+                Err(err) => return Response::error(400, &format!("invalid JSON: {}", err)),
+            };
+            let Some(value) = body.get("value").and_then(Value::as_str) else {
+                return Response::error(400, "body must have a string \"value\"");
+            };
+            let response = Response::ok(json!({ "name": name, "value": value }));
+            database.set(name, value.to_owned());
+            response
+        }
+        ("keys", "DELETE") => {
+            if database.get_ref(name.as_str()).is_none() {
+                return Response::error(404, "not found");
+            }
+            database.delete(name.as_str());
+            Response::ok(json!({ "name": name, "deleted": true }))
+        }
+        ("counts", "GET") => {
+            let count = database.count(name.as_str());
+            Response::ok(json!({ "value": name, "count": count }))
+        }
+        ("keys", _) | ("counts", _) => Response::error(405, "method not allowed"),
+        _ => Response::error(404, "not found"),
+    }
+}
+
+/// Read the next request, returning `None` if the client sent nothing.
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Option<Request>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(invalid("malformed request line"));
+    };
+    let mut content_length = 0;
+    let mut headers = 0;
+    loop {
+        let header = read_line(reader)?.ok_or_else(|| invalid("unexpected end of headers"))?;
+        if header.is_empty() {
+            break;
+        }
+        headers += 1;
+        if headers > MAX_HEADERS {
+            return Err(invalid("too many headers"));
+        }
+        if let Some((field, value)) = header.split_once(':') {
+            if field.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| invalid("invalid Content-Length"))?;
+            }
+        }
+    }
+    if content_length > MAX_BODY_LENGTH {
+        return Err(invalid(BODY_TOO_LARGE));
+    }
+    let mut body = vec![0; content_length];
+    reader
+        .read_exact(&mut body)
+        .map_err(|_| invalid("unexpected end of body"))?;
+    Ok(Some(Request {
+        method: method.to_ascii_uppercase(),
+        path: path.to_owned(),
+        body,
+    }))
+}
+
+/// Read a line without its terminator.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    if reader.take(MAX_LINE_LENGTH).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        return Err(invalid("line too long or incomplete"));
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| invalid("request is not valid UTF-8"))
+}
+
+/// Decode the `%XX` escapes in a path segment.
+fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::CompactString;
+
+    fn request(database: &Mutex<Database<String, CompactString>>, input: &str) -> (u16, Value) {
+        let mut output: Vec<u8> = Vec::new();
+        handle(input.as_bytes(), &mut output, database).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let status = output[9..12].parse().unwrap();
+        let (_, body) = output.split_once("\r\n\r\n").unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn test_keys() {
+        let database = Mutex::new(Database::default());
+        let put = "PUT /keys/a%20b HTTP/1.1\r\nContent-Length: 14\r\n\r\n{\"value\":\"10\"}";
+        assert_eq!(
+            request(&database, put),
+            (200, json!({ "name": "a b", "value": "10" }))
+        );
+        assert_eq!(
+            request(&database, "GET /keys/a%20b HTTP/1.1\r\n\r\n"),
+            (200, json!({ "name": "a b", "value": "10" }))
+        );
+        assert_eq!(
+            request(&database, "GET /counts/10 HTTP/1.1\r\n\r\n"),
+            (200, json!({ "value": "10", "count": 1 }))
+        );
+        assert_eq!(
+            request(&database, "DELETE /keys/a%20b HTTP/1.1\r\n\r\n"),
+            (200, json!({ "name": "a b", "deleted": true }))
+        );
+        let (status, _) = request(&database, "GET /keys/a%20b HTTP/1.1\r\n\r\n");
+        assert_eq!(status, 404);
+        let (status, _) = request(&database, "DELETE /keys/a%20b HTTP/1.1\r\n\r\n");
+        assert_eq!(status, 404);
+    }
+
+    #[test]
+    fn test_transactions() {
+        let database = Mutex::new(Database::default());
+        assert_eq!(
+            request(&database, "POST /tx/begin HTTP/1.1\r\n\r\n"),
+            (200, json!({ "depth": 1 }))
+        );
+        let put = "PUT /keys/a HTTP/1.1\r\nContent-Length: 13\r\n\r\n{\"value\":\"1\"}";
+        request(&database, put);
+        assert_eq!(
+            request(&database, "POST /tx/rollback HTTP/1.1\r\n\r\n"),
+            (200, json!({ "depth": 0 }))
+        );
+        assert_eq!(database.lock().unwrap().get("a"), None);
+        assert_eq!(
+            request(&database, "POST /tx/commit HTTP/1.1\r\n\r\n"),
+            (409, json!({ "error": "no transaction" }))
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let database = Mutex::new(Database::default());
+        let (status, _) = request(&database, "GET /nothing/here HTTP/1.1\r\n\r\n");
+        assert_eq!(status, 404);
+        let (status, _) = request(&database, "POST /keys/a HTTP/1.1\r\n\r\n");
+        assert_eq!(status, 405);
+        let (status, _) = request(&database, "GET /tx/begin HTTP/1.1\r\n\r\n");
+        assert_eq!(status, 405);
+        let put = "PUT /keys/a HTTP/1.1\r\nContent-Length: 7\r\n\r\n{\"v\":1}";
+        let (status, _) = request(&database, put);
+        assert_eq!(status, 400);
+        let (status, _) = request(&database, "GET /keys/%zz HTTP/1.1\r\n\r\n");
+        assert_eq!(status, 400);
+        let short = "PUT /keys/a HTTP/1.1\r\nContent-Length: 99\r\n\r\n{}";
+        assert_eq!(
+            request(&database, short),
+            (400, json!({ "error": "unexpected end of body" }))
+        );
+    }
+
+    #[test]
+    fn test_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let database: Arc<Mutex<Database>> = Arc::new(Mutex::new(Database::new()));
+        let shared = database.clone();
+        thread::spawn(move || serve(listener, shared));
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"PUT /keys/x HTTP/1.1\r\nContent-Length: 13\r\n\r\n{\"value\":\"1\"}")
+            .unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        assert!(reply.starts_with("HTTP/1.1 200 OK\r\n"), "{}", reply);
+        assert_eq!(database.lock().unwrap().get("x"), Some("1".into()));
+    }
+}
@@ -0,0 +1,270 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! HyperLogLog estimates the number of distinct elements added to it, such as
+//! unique visitors, in a fixed amount of space rather than by keeping every
+//! element. The estimate is typically within one percent of the true count.
+//!
+//! The registers are stored in an ordinary value as text: the marker `HYLL`
+//! followed by one character per register, `0` plus its value. Being a value,
+//! it works within transactions and is written to the log and to snapshots,
+//! and as text it suits the REPL and the servers as well as databases of
+//! bytes.
+
+use crate::error::{Error, Result};
+use crate::persist::Persistent;
+use crate::store::Database;
+use std::borrow::Borrow;
+use std::hash::Hash;
+
+// marks a value that holds the registers
+const MAGIC: &str = "HYLL";
+// bits of the hash that choose a register
+const PRECISION: u32 = 14;
+const REGISTERS: usize = 1 << PRECISION;
+
+///
+/// The registers of a HyperLogLog, each holding the longest run of leading
+/// zero bits, plus one, seen in the hashes of the elements assigned to it.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    fn new() -> Self {
+        Self {
+            registers: vec![0; REGISTERS],
+        }
+    }
+
+    /// Read the registers from a value, returning `None` if it does not hold
+    /// them.
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let registers = bytes.strip_prefix(MAGIC.as_bytes())?;
+        if registers.len() != REGISTERS {
+            return None;
+        }
+        let registers: Option<Vec<u8>> = registers
+            .iter()
+            .map(|b| {
+                b.checked_sub(b'0')
+                    .filter(|r| *r <= 64 - PRECISION as u8 + 1)
+            })
+            .collect();
+        registers.map(|registers| Self { registers })
+    }
+
+    /// Returns the registers as the text to be stored in a value.
+    fn encode(&self) -> String {
+        let mut text = String::with_capacity(MAGIC.len() + REGISTERS);
+        text.push_str(MAGIC);
+        text.extend(self.registers.iter().map(|r| (b'0' + r) as char));
+        text
+    }
+
+    /// Add the element, returning true if any register changed.
+    fn add(&mut self, element: &[u8]) -> bool {
+        let hash = hash(element);
+        let index = (hash >> (64 - PRECISION)) as usize;
+        // the guard bit limits the run to the bits that remain
+        let rest = (hash << PRECISION) | (1 << (PRECISION - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Combine with the other, so as to count the elements added to either.
+    fn merge(&mut self, other: &HyperLogLog) {
+        for (mine, theirs) in self.registers.iter_mut().zip(other.registers.iter()) {
+            *mine = (*mine).max(*theirs);
+        }
+    }
+
+    /// Returns the estimated number of distinct elements added.
+    fn count(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            // linear counting is more accurate for small counts
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+}
+
+/// Hash the element with FNV-1a and a final mix, which unlike the hasher of
+/// the standard library is certain to stay the same in every build, as the
+/// registers are persisted.
+fn hash(element: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in element.iter() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58476d1ce4e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+impl<K, V> Database<K, V>
+where
+    K: Ord + Clone,
+    V: Hash + Eq + Clone + Persistent + From<String>,
+{
+    /// Add the elements to the HyperLogLog held by the value of the key,
+    /// creating it if the key has no value, and return true if the estimated
+    /// count may have changed. Any time-to-live of the key is kept. Fails
+    /// with `Error::WrongType` if the key holds anything else.
+    pub fn pfadd<Q, E>(&mut self, name: &Q, elements: impl IntoIterator<Item = E>) -> Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+        E: AsRef<[u8]>,
+    {
+        let (mut hll, mut changed) = match self.hyperloglog(name)? {
+            Some(hll) => (hll, false),
+            None => (HyperLogLog::new(), true),
+        };
+        for element in elements {
+            changed |= hll.add(element.as_ref());
+        }
+        if changed {
+            self.replace_keeping_ttl(name, hll.encode());
+        }
+        Ok(changed)
+    }
+
+    /// Returns the estimated number of distinct elements added to any of the
+    /// HyperLogLogs held by the keys, those without a value counting as
+    /// empty.
+    pub fn pfcount<Q>(&self, names: &[&Q]) -> Result<u64>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        Ok(self.union(names)?.map_or(0, |hll| hll.count()))
+    }
+
+    /// Merge the HyperLogLogs held by the sources into the one held by the
+    /// destination, creating it if the key has no value, so that it counts
+    /// the elements added to any of them.
+    pub fn pfmerge<Q>(&mut self, destination: &Q, sources: &[&Q]) -> Result<()>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        let mut names = vec![destination];
+        names.extend_from_slice(sources);
+        let merged = self.union(&names)?.unwrap_or_else(HyperLogLog::new);
+        self.replace_keeping_ttl(destination, merged.encode());
+        Ok(())
+    }
+
+    /// Returns the union of the HyperLogLogs held by the keys, or `None` if
+    /// none of them has a value.
+    fn union<Q>(&self, names: &[&Q]) -> Result<Option<HyperLogLog>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        let mut union: Option<HyperLogLog> = None;
+        for name in names.iter() {
+            if let Some(hll) = self.hyperloglog(*name)? {
+                match union.as_mut() {
+                    Some(union) => union.merge(&hll),
+                    None => union = Some(hll),
+                }
+            }
+        }
+        Ok(union)
+    }
+
+    /// Returns the HyperLogLog held by the value of the key, if it has one.
+    fn hyperloglog<Q>(&self, name: &Q) -> Result<Option<HyperLogLog>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        match self.get_ref(name) {
+            Some(value) => HyperLogLog::decode(value.to_bytes())
+                .map(Some)
+                .ok_or(Error::WrongType),
+            None if self.kind(name).is_some() => Err(Error::WrongType),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hyperloglog() {
+        let mut hll = HyperLogLog::new();
+        assert_eq!(hll.count(), 0);
+        for n in 0..10_000 {
+            hll.add(n.to_string().as_bytes());
+        }
+        assert!(!hll.add(b"42"));
+        let count = hll.count() as f64;
+        assert!((count - 10_000.0).abs() < 200.0, "estimated {}", count);
+        let decoded = HyperLogLog::decode(hll.encode().as_bytes()).unwrap();
+        assert_eq!(decoded, hll);
+        assert!(HyperLogLog::decode(b"HYLL").is_none());
+        assert!(HyperLogLog::decode(b"text").is_none());
+        let mut other = HyperLogLog::new();
+        for n in 5_000..20_000 {
+            other.add(n.to_string().as_bytes());
+        }
+        hll.merge(&other);
+        let count = hll.count() as f64;
+        assert!((count - 20_000.0).abs() < 400.0, "estimated {}", count);
+    }
+
+    #[test]
+    fn test_database() {
+        let mut db = Database::new();
+        assert!(db.pfadd("a", ["x", "y", "z"]).unwrap());
+        assert!(!db.pfadd("a", ["x"]).unwrap());
+        assert!(db.pfadd("b", ["z", "w"]).unwrap());
+        assert_eq!(db.pfcount(&["a"]).unwrap(), 3);
+        assert_eq!(db.pfcount(&["a", "b", "none"]).unwrap(), 4);
+        assert_eq!(db.pfcount(&["none"]).unwrap(), 0);
+        db.begin();
+        db.pfmerge("c", &["a", "b"]).unwrap();
+        assert_eq!(db.pfcount(&["c"]).unwrap(), 4);
+        db.rollback().unwrap();
+        assert!(!db.exists("c"));
+        db.set("s", "text");
+        assert!(matches!(db.pfadd("s", ["x"]), Err(Error::WrongType)));
+        assert!(matches!(db.pfcount(&["a", "s"]), Err(Error::WrongType)));
+        // being a value, the registers are written to the log
+        let path = std::env::temp_dir().join(format!("simpledb-pf-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut db: Database = Database::open(&path).unwrap();
+            db.pfadd("a", ["x", "y"]).unwrap();
+        }
+        let db: Database = Database::open(&path).unwrap();
+        assert_eq!(db.pfcount(&["a"]).unwrap(), 2);
+        std::fs::remove_file(&path).unwrap();
+        // the registers work as bytes as well as text
+        let mut bytes: Database<String, Vec<u8>> = Database::default();
+        bytes.pfadd("a", [b"x"]).unwrap();
+        assert_eq!(bytes.pfcount(&["a"]).unwrap(), 1);
+    }
+}
@@ -0,0 +1,374 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! Bookkeeping kept alongside the values in every snapshot of the store: the
+//! number of occurrences of each value, the time at which keys expire, and
+//! optionally the keys grouped by the length of their values.
+
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::ops::RangeInclusive;
+use std::time::SystemTime;
+
+///
+/// A multiset of values, tracking how many times each value occurs. Counts are
+/// signed so that adjustments can be applied in any order. Like the store, the
+/// index is a persistent map that is cheap to copy.
+///
+#[derive(Clone)]
+pub struct ValueIndex<V> {
+    counts: im::HashMap<V, i64>,
+}
+
+impl<V> ValueIndex<V>
+where
+    V: Hash + Eq + Clone,
+{
+    /// Construct an empty index.
+    pub fn new() -> Self {
+        Self {
+            counts: im::HashMap::new(),
+        }
+    }
+
+    /// Record one more occurrence of the value.
+    pub fn increment(&mut self, value: &V) {
+        self.adjust(value, 1);
+    }
+
+    /// Record one fewer occurrence of the value.
+    pub fn decrement(&mut self, value: &V) {
+        self.adjust(value, -1);
+    }
+
+    /// Change the count for the value by the given amount, only copying the
+    /// value if it was not already in the index. Values whose count becomes
+    /// zero are removed so that the index holds only values that occur.
+    pub fn adjust(&mut self, value: &V, delta: i64) {
+        if let Some(c) = self.counts.get_mut(value) {
+            *c += delta;
+            if *c == 0 {
+                self.counts.remove(value);
+            }
+        } else if delta != 0 {
+            self.counts.insert(value.clone(), delta);
+        }
+    }
+
+    /// Change the count for the value by the given amount, taking ownership of
+    /// the value.
+    pub fn adjust_owned(&mut self, value: V, delta: i64) {
+        if let Some(c) = self.counts.get_mut(&value) {
+            *c += delta;
+            if *c == 0 {
+                self.counts.remove(&value);
+            }
+        } else if delta != 0 {
+            self.counts.insert(value, delta);
+        }
+    }
+
+    /// Returns the recorded number of occurrences of the value.
+    pub fn count<Q>(&self, value: &Q) -> i64
+    where
+        V: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        *self.counts.get(value).unwrap_or(&0)
+    }
+
+    /// Visit each value that occurs along with its count.
+    pub fn iter(&self) -> impl Iterator<Item = (&V, i64)> {
+        self.counts
+            .iter()
+            .filter(|(_, c)| **c > 0)
+            .map(|(v, c)| (v, *c))
+    }
+
+    /// Returns the number of distinct values that occur, not counting any
+    /// whose count is negative while adjustments are still being applied.
+    pub fn distinct(&self) -> usize {
+        self.iter().count()
+    }
+}
+
+impl<V> Default for ValueIndex<V>
+where
+    V: Hash + Eq + Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> IntoIterator for ValueIndex<V>
+where
+    V: Hash + Eq + Clone,
+{
+    type Item = (V, i64);
+    type IntoIter = im::hashmap::ConsumingIter<(V, i64)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.counts.into_iter()
+    }
+}
+
+///
+/// Keys grouped by the byte length of their values, for finding keys whose
+/// values fall within a range of sizes without visiting every entry. The
+/// length of a value is given by a function, as not every value type has one.
+///
+#[derive(Clone)]
+pub struct LengthIndex<K, V> {
+    keys: im::OrdMap<usize, im::OrdSet<K>>,
+    length_of: fn(&V) -> usize,
+}
+
+impl<K, V> LengthIndex<K, V>
+where
+    K: Ord + Clone,
+{
+    /// Construct an empty index using the given measure of value length.
+    pub fn new(length_of: fn(&V) -> usize) -> Self {
+        Self {
+            keys: im::OrdMap::new(),
+            length_of,
+        }
+    }
+
+    /// Record that the key now has the given value.
+    pub fn insert(&mut self, name: K, value: &V) {
+        let length = (self.length_of)(value);
+        self.keys.entry(length).or_default().insert(name);
+    }
+
+    /// Record that the key no longer has the given value.
+    pub fn remove<Q>(&mut self, name: &Q, value: &V)
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let length = (self.length_of)(value);
+        if let Some(keys) = self.keys.get_mut(&length) {
+            keys.remove(name);
+            if keys.is_empty() {
+                self.keys.remove(&length);
+            }
+        }
+    }
+
+    /// Visit the keys whose values have a length within the range, ordered by
+    /// length and then by key.
+    pub fn range(&self, lengths: RangeInclusive<usize>) -> impl Iterator<Item = &K> {
+        self.keys.range(lengths).flat_map(|(_, keys)| keys.iter())
+    }
+}
+
+///
+/// The time at which each key that has a time-to-live expires, also ordered by
+/// time so that finding the keys that are due does not require visiting all
+/// of them. The times are of the system clock rather than monotonic, so that
+/// they keep their meaning once written to the disk.
+///
+#[derive(Clone)]
+pub struct ExpiryIndex<K> {
+    deadlines: im::OrdMap<K, SystemTime>,
+    due: im::OrdSet<(SystemTime, K)>,
+}
+
+impl<K> ExpiryIndex<K>
+where
+    K: Ord + Clone,
+{
+    /// Construct an index in which no keys expire.
+    pub fn new() -> Self {
+        Self {
+            deadlines: im::OrdMap::new(),
+            due: im::OrdSet::new(),
+        }
+    }
+
+    /// Returns true if no keys are set to expire.
+    pub fn is_empty(&self) -> bool {
+        self.deadlines.is_empty()
+    }
+
+    /// Returns the time at which the key expires, if ever.
+    pub fn get<Q>(&self, name: &Q) -> Option<SystemTime>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.deadlines.get(name).copied()
+    }
+
+    /// Set the time at which the key expires, replacing any earlier setting.
+    pub fn insert(&mut self, name: K, deadline: SystemTime) {
+        if let Some(previous) = self.deadlines.insert(name.clone(), deadline) {
+            self.due.remove(&(previous, name.clone()));
+        }
+        self.due.insert((deadline, name));
+    }
+
+    /// Make the key never expire, returning the time at which it would have.
+    pub fn remove<Q>(&mut self, name: &Q) -> Option<SystemTime>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.remove_with_key(name).map(|(_, deadline)| deadline)
+    }
+
+    /// Make the key never expire, returning the key as it was stored and the
+    /// time at which it would have.
+    pub fn remove_with_key<Q>(&mut self, name: &Q) -> Option<(K, SystemTime)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let (name, deadline) = self.deadlines.remove_with_key(name)?;
+        self.due.remove(&(deadline, name.clone()));
+        Some((name, deadline))
+    }
+
+    /// Visit the keys that have expired as of the given time, earliest first.
+    pub fn expired(&self, now: SystemTime) -> impl Iterator<Item = &K> {
+        self.due
+            .iter()
+            .take_while(move |(deadline, _)| *deadline <= now)
+            .map(|(_, name)| name)
+    }
+
+    /// Visit every key that expires and its time, in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, SystemTime)> {
+        self.deadlines
+            .iter()
+            .map(|(name, deadline)| (name, *deadline))
+    }
+
+    /// Visit the keys whose times differ from those of the older index, in
+    /// key order, with the time each now has, or `None` for a key that no
+    /// longer expires.
+    pub fn changes_since<'a>(
+        &'a self,
+        older: &'a Self,
+    ) -> impl Iterator<Item = (&'a K, Option<SystemTime>)> + 'a {
+        use im::ordmap::DiffItem;
+        older
+            .deadlines
+            .diff(&self.deadlines)
+            .map(|item| match item {
+                DiffItem::Add(name, deadline) => (name, Some(*deadline)),
+                DiffItem::Update { new, .. } => (new.0, Some(*new.1)),
+                DiffItem::Remove(name, _) => (name, None),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_increment_decrement() {
+        let mut index: ValueIndex<String> = ValueIndex::new();
+        assert_eq!(index.count("foo"), 0);
+        index.increment(&"foo".into());
+        index.increment(&"foo".into());
+        index.increment(&"bar".into());
+        assert_eq!(index.count("foo"), 2);
+        assert_eq!(index.count("bar"), 1);
+        index.decrement(&"foo".into());
+        assert_eq!(index.count("foo"), 1);
+        // counts are allowed to go negative
+        index.decrement(&"baz".into());
+        assert_eq!(index.count("baz"), -1);
+    }
+
+    #[test]
+    fn test_adjust() {
+        let mut index: ValueIndex<u32> = ValueIndex::default();
+        index.adjust(&7, 5);
+        index.adjust_owned(7, 3);
+        index.adjust_owned(8, 1);
+        assert_eq!(index.count(&7), 8);
+        assert_eq!(index.count(&8), 1);
+        let mut pairs: Vec<(u32, i64)> = index.into_iter().collect();
+        pairs.sort();
+        assert_eq!(pairs, vec![(7, 8), (8, 1)]);
+    }
+
+    #[test]
+    fn test_zero_counts_removed() {
+        let mut index: ValueIndex<String> = ValueIndex::new();
+        index.increment(&"foo".into());
+        index.increment(&"bar".into());
+        index.decrement(&"bar".into());
+        index.adjust_owned("baz".into(), -1);
+        index.adjust_owned("baz".into(), 1);
+        index.adjust(&"qux".into(), 0);
+        assert_eq!(index.count("foo"), 1);
+        assert_eq!(index.distinct(), 1);
+        let pairs: Vec<(&String, i64)> = index.iter().collect();
+        assert_eq!(pairs, vec![(&"foo".to_owned(), 1)]);
+        let pairs: Vec<(String, i64)> = index.into_iter().collect();
+        assert_eq!(pairs, vec![("foo".into(), 1)]);
+    }
+
+    #[test]
+    fn test_length_index() {
+        let mut index: LengthIndex<String, String> = LengthIndex::new(String::len);
+        index.insert("b".into(), &"xx".into());
+        index.insert("a".into(), &"yy".into());
+        index.insert("c".into(), &"zzzz".into());
+        index.insert("d".into(), &"".into());
+        let keys: Vec<&String> = index.range(1..=4).collect();
+        assert_eq!(keys, vec!["a", "b", "c"]);
+        index.remove("a", &"yy".into());
+        index.remove("c", &"zzzz".into());
+        let keys: Vec<&String> = index.range(0..=usize::MAX).collect();
+        assert_eq!(keys, vec!["d", "b"]);
+        assert_eq!(index.range(3..=10).count(), 0);
+    }
+
+    #[test]
+    fn test_expiry_index() {
+        let now = SystemTime::now();
+        let later = now + std::time::Duration::from_secs(60);
+        let mut index: ExpiryIndex<String> = ExpiryIndex::new();
+        assert!(index.is_empty());
+        index.insert("b".into(), now);
+        index.insert("a".into(), later);
+        index.insert("c".into(), now);
+        let expired: Vec<&String> = index.expired(now).collect();
+        assert_eq!(expired, vec!["b", "c"]);
+        // moving the deadline replaces the old one
+        index.insert("c".into(), later);
+        assert_eq!(index.get("c"), Some(later));
+        let expired: Vec<&String> = index.expired(now).collect();
+        assert_eq!(expired, vec!["b"]);
+        assert_eq!(index.remove("b"), Some(now));
+        assert_eq!(index.remove("b"), None);
+        assert_eq!(index.expired(later).count(), 2);
+        let older = index.clone();
+        index.insert("a".into(), now);
+        index.insert("d".into(), later);
+        index.remove("c");
+        let changes: Vec<(&String, Option<SystemTime>)> = index.changes_since(&older).collect();
+        assert_eq!(
+            changes,
+            vec![
+                (&"a".into(), Some(now)),
+                (&"c".into(), None),
+                (&"d".into(), Some(later))
+            ]
+        );
+        let deadlines: Vec<(&String, SystemTime)> = index.iter().collect();
+        assert_eq!(deadlines, vec![(&"a".into(), now), (&"d".into(), later)]);
+        index.remove("d");
+        index.remove("a");
+        index.remove("c");
+        assert!(index.is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! A small subset of JSONPath for reaching into JSON documents: a path starts
+//! with `$` for the whole document, followed by any number of `.name` to
+//! select a member of an object and `[index]` to select an element of an
+//! array, where a negative index counts back from the end. For example,
+//! `$.users[0].name`.
+
+use crate::error::{Error, Result};
+use serde_json::Value;
+
+///
+/// One step along a path.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Segment {
+    /// A member of an object.
+    Member(String),
+    /// An element of an array.
+    Index(i64),
+}
+
+/// Split the path into the steps that lead from the root of a document.
+pub fn parse(path: &str) -> Result<Vec<Segment>> {
+    let invalid = || Error::Parse(format!("invalid path: {}", path));
+    let mut rest = path.strip_prefix('$').ok_or_else(invalid)?;
+    let mut segments = Vec::new();
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('.') {
+            let end = after.find(['.', '[']).unwrap_or(after.len());
+            if end == 0 {
+                return Err(invalid());
+            }
+            segments.push(Segment::Member(after[..end].to_owned()));
+            rest = &after[end..];
+        } else if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']').ok_or_else(invalid)?;
+            let index = after[..end].trim().parse().map_err(|_| invalid())?;
+            segments.push(Segment::Index(index));
+            rest = &after[end + 1..];
+        } else {
+            return Err(invalid());
+        }
+    }
+    Ok(segments)
+}
+
+/// Returns the position in an array of the given length for the index.
+fn position(index: i64, len: usize) -> Option<usize> {
+    let position = if index < 0 { len as i64 + index } else { index };
+    (0..len as i64)
+        .contains(&position)
+        .then_some(position as usize)
+}
+
+/// Returns the part of the document found along the path, if any.
+pub fn get<'a>(document: &'a Value, path: &[Segment]) -> Option<&'a Value> {
+    path.iter()
+        .try_fold(document, |node, segment| match segment {
+            Segment::Member(name) => node.as_object()?.get(name),
+            Segment::Index(index) => {
+                let array = node.as_array()?;
+                array.get(position(*index, array.len())?)
+            }
+        })
+}
+
+/// Replace the part of the document found along the path with the value,
+/// returning false if the path leads nowhere. The last step may name a new
+/// member of an object, but all of the others must already exist.
+pub fn set(document: &mut Value, path: &[Segment], value: Value) -> bool {
+    let Some((last, parents)) = path.split_last() else {
+        *document = value;
+        return true;
+    };
+    let mut node = document;
+    for segment in parents.iter() {
+        let next = match segment {
+            Segment::Member(name) => node.as_object_mut().and_then(|o| o.get_mut(name)),
+            Segment::Index(index) => node.as_array_mut().and_then(|array| {
+                let position = position(*index, array.len())?;
+                array.get_mut(position)
+            }),
+        };
+        match next {
+            Some(next) => node = next,
+            None => return false,
+        }
+    }
+    match last {
+        Segment::Member(name) => match node.as_object_mut() {
+            Some(object) => {
+                object.insert(name.clone(), value);
+                true
+            }
+            None => false,
+        },
+        Segment::Index(index) => match node.as_array_mut() {
+            Some(array) => match position(*index, array.len()) {
+                Some(position) => {
+                    array[position] = value;
+                    true
+                }
+                None => false,
+            },
+            None => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse() {
+        assert!(parse("$").unwrap().is_empty());
+        assert_eq!(
+            parse("$.users[-1].name").unwrap(),
+            vec![
+                Segment::Member("users".into()),
+                Segment::Index(-1),
+                Segment::Member("name".into())
+            ]
+        );
+        assert!(parse("users").is_err());
+        assert!(parse("$.").is_err());
+        assert!(parse("$[x]").is_err());
+        assert_eq!(parse("$[1").unwrap_err().to_string(), "invalid path: $[1");
+    }
+
+    #[test]
+    fn test_get_set() {
+        let mut doc = json!({"users": [{"name": "ann"}, {"name": "bob"}]});
+        let path = parse("$.users[-1].name").unwrap();
+        assert_eq!(get(&doc, &path), Some(&json!("bob")));
+        assert_eq!(get(&doc, &parse("$.users[2]").unwrap()), None);
+        assert_eq!(get(&doc, &parse("$.users.name").unwrap()), None);
+        assert!(set(&mut doc, &path, json!("cy")));
+        assert!(set(&mut doc, &parse("$.count").unwrap(), json!(2)));
+        assert!(!set(&mut doc, &parse("$.users[5]").unwrap(), json!(null)));
+        assert!(!set(&mut doc, &parse("$.missing.name").unwrap(), json!(1)));
+        assert_eq!(
+            doc,
+            json!({"users": [{"name": "ann"}, {"name": "cy"}], "count": 2})
+        );
+        assert!(set(&mut doc, &[], json!([])));
+        assert_eq!(doc, json!([]));
+    }
+}
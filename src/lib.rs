@@ -1,4 +1,32 @@
 //
 // Copyright (c) 2022 Nathan Fiedler
 //
+#[cfg(feature = "async")]
+pub mod r#async;
+pub mod backup;
+mod bitmap;
+pub mod changeset;
+pub mod command;
+pub mod crypt;
+pub mod databases;
+pub mod envfile;
+pub mod error;
+pub mod frequency;
+pub mod glob;
+pub mod http;
+mod hyperloglog;
+mod index;
+mod jsonpath;
+pub mod observer;
+pub mod parser;
+pub mod persist;
+pub mod schedule;
+pub mod server;
+mod sortedset;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
 pub mod store;
+pub mod stream;
+pub mod value;
+
+pub use error::{Error, Result};
@@ -1,75 +1,2235 @@
 //
 // Copyright (c) 2022 Nathan Fiedler
 //
-use simpledb::store::Database;
-use std::io::{self, Write};
-
-fn eval_and_print(database: &mut Database, line: &str) {
-    // The names and values do _not_ contain spaces, which makes the process of
-    // parsing the commands nothing more than splitting on whitespace.
-    let mut iter = line.split_whitespace();
-    if let Some(cmd) = iter.next() {
-        if cmd == "END" {
-            std::process::exit(0);
-        } else if cmd == "SET" {
-            if let Some(name) = iter.next() {
-                if let Some(value) = iter.next() {
-                    database.set(name, value);
-                } else {
-                    println!("missing value for SET");
+use clap::{CommandFactory, Parser};
+use rand::Rng;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use simpledb::command::{self, Command as Data, Pipeline, Response};
+use simpledb::crypt::Secret;
+use simpledb::databases::Databases;
+use simpledb::envfile;
+use simpledb::frequency::FrequencyTracker;
+use simpledb::glob;
+use simpledb::http;
+use simpledb::parser;
+use simpledb::persist::{Durability, RestorePoint};
+use simpledb::schedule::SnapshotSchedule;
+use simpledb::server;
+use simpledb::store::{CompactString, Database, ImportMode};
+use simpledb::Error;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+// number of databases that SELECT may choose from, as with Redis
+const DATABASES: usize = 16;
+
+// why files with the data in plaintext are not written
+const PLAINTEXT: &str = "would be written in plaintext, but the database is encrypted";
+
+///
+/// Simple in-memory database with nested transactions.
+///
+#[derive(Parser)]
+#[command(version, about)]
+struct Args {
+    /// File of commands to execute instead of reading them interactively.
+    script: Option<PathBuf>,
+
+    /// Write-ahead log in which committed changes are saved, and from which
+    /// they are restored when starting.
+    #[arg(long, value_name = "FILE")]
+    wal: Option<PathBuf>,
+
+    /// How often the write-ahead log is synced to the disk, so that changes
+    /// survive the machine failing, rather than only the process.
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = FsyncPolicy::No, requires = "wal")]
+    appendfsync: FsyncPolicy,
+
+    /// Snapshot file, as written by the SAVE command, from which to load the
+    /// database when starting.
+    #[arg(long, value_name = "FILE", conflicts_with = "wal")]
+    snapshot: Option<PathBuf>,
+
+    /// File holding the key, or a passphrase, from which the keys that
+    /// encrypt the write-ahead log, snapshots, and backups are derived. A
+    /// passphrase may instead be given in the `SIMPLEDB_PASSPHRASE`
+    /// environment variable.
+    #[arg(long, value_name = "FILE")]
+    key_file: Option<PathBuf>,
+
+    /// Validate the commands and report what they would change, without
+    /// modifying the database.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// During a dry run, also report GET and UNSET of keys that do not exist.
+    #[arg(long)]
+    check_keys: bool,
+
+    /// Keep approximate counts of how often each key is accessed, as reported
+    /// by the FREQ command.
+    #[arg(long)]
+    track_frequency: bool,
+
+    /// Index keys by the length of their values, to speed up KEYSBYLEN.
+    #[arg(long)]
+    length_index: bool,
+
+    /// Template for the interactive prompt, in which `{depth}` is replaced by
+    /// the number of open transactions and `{pending}` by the number of keys
+    /// changed within them.
+    #[arg(long, value_name = "TEMPLATE")]
+    prompt: Option<String>,
+
+    /// File in which to save the command history, defaults to
+    /// `~/.simpledb_history`.
+    #[arg(long, value_name = "FILE")]
+    history_file: Option<PathBuf>,
+
+    /// What to do when a command in the script fails.
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = ErrorPolicy::Continue)]
+    on_error: ErrorPolicy,
+
+    /// Maximum number of commands to keep in the history, zero disables it.
+    #[arg(long, value_name = "COUNT", default_value_t = 1000)]
+    history_size: usize,
+
+    /// Number of lines of the output of a command to show at a time when
+    /// used from a terminal, before asking whether to show more, zero to
+    /// never ask.
+    #[arg(long, value_name = "LINES", default_value_t = 40)]
+    page_size: usize,
+
+    #[command(subcommand)]
+    command: Option<Subcommand>,
+}
+
+///
+/// How a script responds to a command that fails.
+///
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum ErrorPolicy {
+    /// Stop executing the script.
+    Stop,
+    /// Skip the failed command and carry on.
+    Continue,
+    /// Roll back the enclosing transaction and skip the rest of it.
+    Rollback,
+}
+
+///
+/// How often the write-ahead log is synced to the disk, named as in Redis.
+///
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum FsyncPolicy {
+    /// Sync on every commit.
+    Always,
+    /// Sync once a second in the background.
+    Everysec,
+    /// Leave the syncing to the operating system.
+    No,
+}
+
+impl From<FsyncPolicy> for Durability {
+    fn from(policy: FsyncPolicy) -> Self {
+        match policy {
+            FsyncPolicy::Always => Durability::Sync,
+            FsyncPolicy::Everysec => Durability::EverySecond,
+            FsyncPolicy::No => Durability::Flush,
+        }
+    }
+}
+
+#[derive(clap::Subcommand)]
+enum Subcommand {
+    /// Print a shell completion script to standard output.
+    Completions {
+        /// Shell for which to generate the completions.
+        shell: clap_complete::Shell,
+    },
+    /// Serve the database over the network instead of interactively.
+    Serve {
+        /// Speak the Redis protocol, so that `redis-cli` and other Redis
+        /// clients can connect.
+        #[arg(long)]
+        resp: bool,
+
+        /// Offer an HTTP API with JSON request and response bodies.
+        #[arg(long, conflicts_with = "resp")]
+        http: bool,
+
+        /// Address on which to accept connections, defaults to port 6379 for
+        /// the Redis protocol and 8080 for HTTP on the loopback interface.
+        #[arg(long, value_name = "ADDRESS")]
+        listen: Option<String>,
+
+        /// Snapshot file to which the committed state is saved in the
+        /// background while serving.
+        #[arg(long, value_name = "FILE")]
+        save: Option<PathBuf>,
+
+        /// How often to consider saving a snapshot.
+        #[arg(long, value_name = "SECONDS", default_value_t = 300, requires = "save")]
+        save_interval: u64,
+
+        /// Number of keys that must have changed since the last snapshot for
+        /// another to be saved.
+        #[arg(long, value_name = "COUNT", default_value_t = 1, requires = "save")]
+        save_changes: usize,
+    },
+    /// Write the committed values of the database, as loaded with `--wal` or
+    /// `--snapshot`, to a table of a SQLite file.
+    #[cfg(feature = "sqlite")]
+    ExportSqlite {
+        /// SQLite file to write, created if it does not exist.
+        out: PathBuf,
+
+        /// Table to hold the values, which is emptied first if it exists.
+        #[arg(long, value_name = "NAME", default_value = "kv")]
+        table: String,
+    },
+    /// Compare two snapshot files, listing the keys added, removed, and
+    /// changed from the first to the second.
+    DiffSnapshots {
+        /// Snapshot of the earlier state.
+        before: PathBuf,
+
+        /// Snapshot of the later state.
+        after: PathBuf,
+
+        /// Format in which to print the differences.
+        #[arg(long, value_enum, value_name = "FORMAT", default_value_t = DiffFormat::Text)]
+        output: DiffFormat,
+    },
+}
+
+///
+/// How the differences between snapshots are printed.
+///
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum DiffFormat {
+    /// A line for each key, starting with `+` if it was added, `-` if it was
+    /// removed, or `~` if it was changed.
+    Text,
+    /// An object with the `added`, `removed`, and `changed` keys.
+    Json,
+}
+
+///
+/// Destination for the results of commands, which go to standard output and,
+/// while a session transcript is being recorded, to the transcript file.
+///
+#[derive(Default)]
+struct Output {
+    transcript: Option<(PathBuf, fs::File)>,
+    // present if long output is shown a page at a time
+    pager: Option<Pager>,
+}
+
+impl Output {
+    /// Prepare for the output of the next command.
+    fn start(&mut self) {
+        if let Some(pager) = self.pager.as_mut() {
+            pager.shown = 0;
+            pager.stopped = false;
+        }
+    }
+
+    /// Write a line of output, unless the pager was told to stop showing the
+    /// output of this command, in which case it is only recorded.
+    fn line(&mut self, args: fmt::Arguments) {
+        if self.pager.as_mut().is_none_or(Pager::admit) {
+            println!("{}", args);
+        }
+        self.record(args);
+    }
+
+    /// Write a line to the transcript only, if one is being recorded.
+    fn record(&mut self, args: fmt::Arguments) {
+        if let Some((path, file)) = self.transcript.as_mut() {
+            if let Err(err) = writeln!(file, "{}", args) {
+                eprintln!("error: could not write {}: {}", path.display(), err);
+                self.transcript = None;
+            }
+        }
+    }
+}
+
+///
+/// Pauses the output of a command after each page of lines, asking whether to
+/// show more, so that a large result does not flood the terminal.
+///
+struct Pager {
+    // lines shown between asking
+    size: usize,
+    // lines of the output of the current command shown since last asking
+    shown: usize,
+    // whether the rest of the output of the current command is dropped
+    stopped: bool,
+    // asks whether to show another page
+    more: Box<dyn FnMut() -> bool>,
+}
+
+impl Pager {
+    /// Ask at the terminal after every `size` lines, more being shown unless
+    /// the answer is `q`.
+    fn new(size: usize) -> Self {
+        Self {
+            size,
+            shown: 0,
+            stopped: false,
+            more: Box::new(|| {
+                ask("--More-- (Enter for more, q to stop) ")
+                    .is_some_and(|answer| !answer.trim().eq_ignore_ascii_case("q"))
+            }),
+        }
+    }
+
+    /// Returns true if the next line is to be shown, first asking whether to
+    /// go on if a page has been shown.
+    fn admit(&mut self) -> bool {
+        if self.stopped {
+            return false;
+        }
+        if self.shown == self.size {
+            if !(self.more)() {
+                self.stopped = true;
+                return false;
+            }
+            self.shown = 0;
+        }
+        self.shown += 1;
+        true
+    }
+}
+
+/// Print the question and return the line answering it from standard
+/// input, or `None` at the end of input.
+fn ask(question: &str) -> Option<String> {
+    print!("{}", question);
+    io::stdout().flush().ok()?;
+    let mut answer = String::new();
+    match io::stdin().read_line(&mut answer) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(answer),
+    }
+}
+
+/// Write a formatted line of output, like `println!`.
+macro_rules! emit {
+    ($out:expr, $($arg:tt)*) => {
+        $out.line(format_args!($($arg)*))
+    };
+}
+
+/// Produce the prompt for the next command, showing the transaction state.
+fn render_prompt(template: Option<&str>, database: &Database<String, CompactString>) -> String {
+    let depth = database.transaction_depth();
+    let template = match template {
+        Some(template) => template,
+        None if !database.in_transaction() => return "> ".into(),
+        None => "(tx:{depth} +{pending}) > ",
+    };
+    let mut prompt = template.replace("{depth}", &depth.to_string());
+    if prompt.contains("{pending}") {
+        let pending = database.pending_count();
+        prompt = prompt.replace("{pending}", &pending.to_string());
+    }
+    prompt
+}
+
+///
+/// A command entered by the user, borrowing its arguments from the input.
+///
+enum Command<'a> {
+    Data(Data),
+    History(Option<usize>),
+    Chaos(ChaosSetting),
+    DryRun(Option<bool>),
+    Transcript(Option<&'a str>),
+    Status,
+    Freq(&'a str),
+    Select(&'a str),
+    Move(&'a str, &'a str),
+    ImportEnv(&'a str),
+    ExportEnv(&'a str),
+    KeysByLen(usize, usize),
+    Distinct,
+    Counts(Option<&'a str>, Option<usize>),
+    Save(&'a str),
+    RewriteAof,
+    RestoreTo(RestorePoint),
+    ImportJson(&'a str, ImportMode),
+    ExportJson(&'a str),
+    // FLUSHALL, yet to be confirmed
+    FlushAll,
+}
+
+impl<'a> Command<'a> {
+    /// Parse the words of a line of input into a command, returning `None` if
+    /// there are none, or an error message if the command is not valid.
+    fn parse(words: &'a [String]) -> Result<Option<Self>, String> {
+        let mut iter = words.iter().map(String::as_str);
+        let cmd = match iter.next() {
+            Some(cmd) => cmd,
+            None => return Ok(None),
+        };
+        let command = match cmd {
+            "HISTORY" => match iter.next().map(str::parse::<usize>) {
+                Some(Ok(count)) => Command::History(Some(count)),
+                Some(Err(_)) => return Err("invalid count for HISTORY".into()),
+                None => Command::History(None),
+            },
+            "CHAOS" => Command::Chaos(ChaosSetting::parse(iter)?),
+            "DRYRUN" => match iter.next() {
+                Some("ON") => Command::DryRun(Some(true)),
+                Some("OFF") => Command::DryRun(Some(false)),
+                Some(_) => return Err("expected ON or OFF for DRYRUN".into()),
+                None => Command::DryRun(None),
+            },
+            "TRANSCRIPT" => Command::Transcript(iter.next()),
+            "STATUS" => Command::Status,
+            "SELECT" => Command::Select(iter.next().ok_or("missing database for SELECT")?),
+            "MOVE" => Command::Move(
+                iter.next().ok_or("missing name for MOVE")?,
+                iter.next().ok_or("missing database for MOVE")?,
+            ),
+            "FREQ" => Command::Freq(iter.next().ok_or("missing name for FREQ")?),
+            "DISTINCT" => match iter.next() {
+                Some("EXACT") | None => Command::Distinct,
+                Some(_) => return Err("expected EXACT for DISTINCT".into()),
+            },
+            "COUNTS" => {
+                let (mut pattern, mut limit) = (None, None);
+                while let Some(arg) = iter.next() {
+                    if arg == "LIMIT" {
+                        let count = iter.next().ok_or("missing limit for COUNTS")?;
+                        limit = Some(count.parse().map_err(|_| "invalid limit for COUNTS")?);
+                    } else if pattern.is_none() && limit.is_none() {
+                        pattern = Some(arg);
+                    } else {
+                        return Err(format!("unexpected argument for COUNTS: {}", arg));
+                    }
+                }
+                Command::Counts(pattern, limit)
+            }
+            "KEYSBYLEN" => {
+                let mut bound = || -> Result<usize, String> {
+                    let arg = iter.next().ok_or("missing length for KEYSBYLEN")?;
+                    arg.parse()
+                        .map_err(|_| format!("invalid length for KEYSBYLEN: {}", arg))
+                };
+                Command::KeysByLen(bound()?, bound()?)
+            }
+            "IMPORTJSON" => {
+                let path = iter.next().ok_or("missing file for IMPORTJSON")?;
+                let mode = match iter.next() {
+                    Some("REPLACE") => ImportMode::Replace,
+                    Some("MERGE") | None => ImportMode::Merge,
+                    Some(_) => return Err("expected MERGE or REPLACE for IMPORTJSON".into()),
+                };
+                Command::ImportJson(path, mode)
+            }
+            "EXPORTJSON" => Command::ExportJson(iter.next().ok_or("missing file for EXPORTJSON")?),
+            "SAVE" => Command::Save(iter.next().ok_or("missing file for SAVE")?),
+            "BGREWRITEAOF" => Command::RewriteAof,
+            "RESTORETO" => {
+                let invalid = |arg: &str| format!("invalid position for RESTORETO: {}", arg);
+                let point = match iter.next().ok_or("missing position for RESTORETO")? {
+                    "AGO" => {
+                        let arg = iter.next().ok_or("missing seconds for RESTORETO AGO")?;
+                        let seconds: u64 = arg.parse().map_err(|_| invalid(arg))?;
+                        let time = SystemTime::now()
+                            .checked_sub(Duration::from_secs(seconds))
+                            .ok_or_else(|| format!("invalid time for RESTORETO AGO: {}", arg))?;
+                        RestorePoint::Time(time)
+                    }
+                    arg => RestorePoint::Lsn(arg.parse().map_err(|_| invalid(arg))?),
+                };
+                Command::RestoreTo(point)
+            }
+            "IMPORTENV" => Command::ImportEnv(iter.next().ok_or("missing file for IMPORTENV")?),
+            "EXPORTENV" => Command::ExportEnv(iter.next().ok_or("missing file for EXPORTENV")?),
+            // removing everything is too easily done by mistake, so is confirmed
+            // unless forced
+            "FLUSHALL" => match iter.next() {
+                Some("FORCE") => Command::Data(Data::FlushAll),
+                Some(_) => return Err("expected FORCE for FLUSHALL".into()),
+                None => Command::FlushAll,
+            },
+            "FLUSHALL!" => Command::Data(Data::FlushAll),
+            // the commands that any frontend may offer
+            _ => {
+                let command = command::parse_words(words).map_err(|err| err.to_string())?;
+                return Ok(command.map(Command::Data));
+            }
+        };
+        Ok(Some(command))
+    }
+}
+
+///
+/// Change to the chaos settings requested by the CHAOS command.
+///
+enum ChaosSetting {
+    Latency(Duration, f64),
+    Error(f64),
+    Off,
+    Show,
+}
+
+impl ChaosSetting {
+    /// Parse the arguments of the CHAOS command.
+    fn parse<'a, I: Iterator<Item = &'a str>>(mut args: I) -> Result<Self, String> {
+        match args.next() {
+            Some("latency") => {
+                let delay = args
+                    .next()
+                    .and_then(parse_duration)
+                    .ok_or("missing or invalid delay for CHAOS latency")?;
+                let p = parse_probability(args.next()).ok_or("invalid probability for CHAOS")?;
+                Ok(ChaosSetting::Latency(delay, p))
+            }
+            Some("error") => {
+                let p = parse_probability(args.next()).ok_or("invalid probability for CHAOS")?;
+                Ok(ChaosSetting::Error(p))
+            }
+            Some("off") => Ok(ChaosSetting::Off),
+            Some(other) => Err(format!("unknown CHAOS mode: {}", other)),
+            None => Ok(ChaosSetting::Show),
+        }
+    }
+}
+
+///
+/// Settings for injecting random delays and failures into command execution,
+/// for testing how applications cope with a misbehaving store.
+///
+#[derive(Default)]
+struct Chaos {
+    // injected delay and the probability of applying it
+    latency: Option<(Duration, f64)>,
+    // probability that a command fails without being executed
+    error: Option<f64>,
+}
+
+impl Chaos {
+    /// Apply the configured chaos to the command about to be executed,
+    /// returning false if the command should fail instead.
+    fn strike(&self) -> bool {
+        let mut rng = rand::thread_rng();
+        if let Some((delay, p)) = self.latency {
+            if rng.gen_bool(p) {
+                std::thread::sleep(delay);
+            }
+        }
+        if let Some(p) = self.error {
+            if rng.gen_bool(p) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Carry out the CHAOS command.
+    fn configure(&mut self, setting: ChaosSetting, out: &mut Output) {
+        match setting {
+            ChaosSetting::Latency(delay, p) => self.latency = Some((delay, p)),
+            ChaosSetting::Error(p) => self.error = Some(p),
+            ChaosSetting::Off => *self = Default::default(),
+            ChaosSetting::Show => {
+                match self.latency {
+                    Some((delay, p)) => emit!(out, "latency {:?} p={}", delay, p),
+                    None => emit!(out, "latency off"),
                 }
+                match self.error {
+                    Some(p) => emit!(out, "error p={}", p),
+                    None => emit!(out, "error off"),
+                }
+            }
+        }
+    }
+}
+
+/// Parse a duration such as `50ms`, `2s`, or `100us`.
+fn parse_duration(arg: &str) -> Option<Duration> {
+    let split = arg.find(|c: char| !c.is_ascii_digit())?;
+    let amount: u64 = arg[..split].parse().ok()?;
+    match &arg[split..] {
+        "us" => Some(Duration::from_micros(amount)),
+        "ms" => Some(Duration::from_millis(amount)),
+        "s" => Some(Duration::from_secs(amount)),
+        _ => None,
+    }
+}
+
+/// Parse an optional `p=<probability>` argument, defaulting to always.
+fn parse_probability(arg: Option<&str>) -> Option<f64> {
+    match arg {
+        Some(arg) => {
+            let p: f64 = arg.strip_prefix("p=")?.parse().ok()?;
+            if (0.0..=1.0).contains(&p) {
+                Some(p)
             } else {
-                println!("missing name for SET");
+                None
+            }
+        }
+        None => Some(1.0),
+    }
+}
+
+///
+/// Tracks the effect that commands would have while in dry-run mode, so that
+/// later commands can be checked against the keys set or removed by earlier
+/// ones without modifying the database.
+///
+struct DryRun {
+    check_keys: bool,
+    // keys set (true) or removed (false), one map per level of transaction
+    layers: Vec<HashMap<String, bool>>,
+    // number of data commands checked
+    commands: usize,
+    // number of problems reported
+    problems: usize,
+}
+
+impl DryRun {
+    /// Start a dry run with nothing changed yet.
+    fn new(check_keys: bool) -> Self {
+        Self {
+            check_keys,
+            layers: vec![HashMap::new()],
+            commands: 0,
+            problems: 0,
+        }
+    }
+
+    /// Returns true if the key would have a value at this point.
+    fn exists(&self, database: &Database<String, CompactString>, name: &str) -> bool {
+        for layer in self.layers.iter().rev() {
+            if let Some(present) = layer.get(name) {
+                return *present;
+            }
+        }
+        database.exists(name)
+    }
+
+    /// Warn about a key that does not exist, if checking keys.
+    fn check(
+        &mut self,
+        database: &Database<String, CompactString>,
+        cmd: &str,
+        name: &str,
+        out: &mut Output,
+    ) {
+        if self.check_keys && !self.exists(database, name) {
+            emit!(out, "warning: {} of missing key {}", cmd, name);
+            self.problems += 1;
+        }
+    }
+
+    /// Report what the command would do, recording its effect.
+    fn simulate(
+        &mut self,
+        database: &Database<String, CompactString>,
+        command: &Command,
+        out: &mut Output,
+    ) {
+        let open = self.layers.len() - 1 + database.transaction_depth();
+        match command {
+            Command::Data(Data::Set(name, value)) => {
+                emit!(out, "would set {} to {}", name, value);
+                self.layers
+                    .last_mut()
+                    .unwrap()
+                    .insert(name.to_string(), true);
+            }
+            Command::Data(Data::Get(name)) => self.check(database, "GET", name, out),
+            Command::Data(Data::MGet(names)) => {
+                for name in names.iter() {
+                    self.check(database, "MGET", name, out);
+                }
+            }
+            Command::Data(Data::MSet(pairs)) => {
+                for (name, value) in pairs.iter() {
+                    emit!(out, "would set {} to {}", name, value);
+                    self.layers
+                        .last_mut()
+                        .unwrap()
+                        .insert(name.to_string(), true);
+                }
+            }
+            Command::Data(Data::Unset(name)) => {
+                self.check(database, "UNSET", name, out);
+                emit!(out, "would unset {}", name);
+                self.layers
+                    .last_mut()
+                    .unwrap()
+                    .insert(name.to_string(), false);
+            }
+            Command::Data(Data::SetNx(name, value)) if !self.exists(database, name) => {
+                emit!(out, "would set {} to {}", name, value);
+                self.layers
+                    .last_mut()
+                    .unwrap()
+                    .insert(name.to_string(), true);
+            }
+            Command::Data(Data::Cas(name, expected, value)) => {
+                self.check(database, "CAS", name, out);
+                emit!(out, "would set {} to {} if it is {}", name, value, expected);
+            }
+            Command::Data(Data::Copy(source, destination, replace)) => {
+                self.check(database, "COPY", source, out);
+                if *replace || !self.exists(database, destination) {
+                    emit!(out, "would copy {} to {}", source, destination);
+                    self.layers
+                        .last_mut()
+                        .unwrap()
+                        .insert(destination.to_string(), true);
+                }
+            }
+            Command::Data(Data::Append(name, suffix)) => {
+                emit!(out, "would append {} to {}", suffix, name);
+                self.layers
+                    .last_mut()
+                    .unwrap()
+                    .insert(name.to_string(), true);
+            }
+            Command::Data(Data::IncrBy(name, amount)) => {
+                emit!(out, "would add {} to {}", amount, name);
+                self.layers
+                    .last_mut()
+                    .unwrap()
+                    .insert(name.to_string(), true);
+            }
+            Command::Data(Data::GetEx(name, ttl)) => {
+                self.check(database, "GETEX", name, out);
+                match ttl {
+                    Some(ttl) => emit!(out, "would expire {} in {}s", name, ttl.as_secs()),
+                    None => emit!(out, "would persist {}", name),
+                }
+            }
+            Command::Data(Data::GetDel(name)) => {
+                self.check(database, "GETDEL", name, out);
+                emit!(out, "would unset {}", name);
+                self.layers
+                    .last_mut()
+                    .unwrap()
+                    .insert(name.to_string(), false);
+            }
+            Command::Data(Data::Push(name, values, _)) => {
+                emit!(out, "would push {} values onto {}", values.len(), name);
+                self.layers
+                    .last_mut()
+                    .unwrap()
+                    .insert(name.to_string(), true);
+            }
+            Command::Data(Data::Pop(name, _)) => {
+                self.check(database, "POP", name, out);
+                emit!(out, "would pop a value from {}", name);
+            }
+            Command::Data(Data::HSet(name, fields)) => {
+                emit!(out, "would set {} fields of {}", fields.len(), name);
+                self.layers
+                    .last_mut()
+                    .unwrap()
+                    .insert(name.to_string(), true);
+            }
+            Command::Data(Data::HGet(name, _)) => self.check(database, "HGET", name, out),
+            Command::Data(Data::JsonSet(name, path, _)) => {
+                emit!(out, "would set {} of {}", path, name);
+                self.layers
+                    .last_mut()
+                    .unwrap()
+                    .insert(name.to_string(), true);
+            }
+            Command::Data(Data::ZAdd(name, members)) => {
+                emit!(out, "would add {} members to {}", members.len(), name);
+                self.layers
+                    .last_mut()
+                    .unwrap()
+                    .insert(name.to_string(), true);
+            }
+            Command::Data(Data::ZRem(name, members)) => {
+                self.check(database, "ZREM", name, out);
+                emit!(out, "would remove {} members from {}", members.len(), name);
+            }
+            Command::Data(Data::XAdd(name, fields)) => {
+                emit!(
+                    out,
+                    "would add an entry of {} fields to {}",
+                    fields.len(),
+                    name
+                );
+                self.layers
+                    .last_mut()
+                    .unwrap()
+                    .insert(name.to_string(), true);
+            }
+            Command::Data(Data::PfAdd(name, elements)) => {
+                emit!(out, "would add {} elements to {}", elements.len(), name);
+                self.layers
+                    .last_mut()
+                    .unwrap()
+                    .insert(name.to_string(), true);
+            }
+            Command::Data(Data::SetBit(name, offset, bit)) => {
+                emit!(
+                    out,
+                    "would set bit {} of {} to {}",
+                    offset,
+                    name,
+                    *bit as u8
+                );
+                self.layers
+                    .last_mut()
+                    .unwrap()
+                    .insert(name.to_string(), true);
+            }
+            Command::Data(Data::PfMerge(destination, sources)) => {
+                for name in sources.iter() {
+                    self.check(database, "PFMERGE", name, out);
+                }
+                emit!(
+                    out,
+                    "would merge {} keys into {}",
+                    sources.len(),
+                    destination
+                );
+                self.layers
+                    .last_mut()
+                    .unwrap()
+                    .insert(destination.to_string(), true);
+            }
+            Command::Data(Data::HDel(name, fields)) => {
+                self.check(database, "HDEL", name, out);
+                emit!(out, "would remove {} fields from {}", fields.len(), name);
+            }
+            Command::Data(Data::Expire(name, ttl)) => {
+                self.check(database, "EXPIRE", name, out);
+                emit!(out, "would expire {} in {}s", name, ttl.as_secs());
+            }
+            Command::Data(Data::Persist(name)) => {
+                self.check(database, "PERSIST", name, out);
+                emit!(out, "would persist {}", name);
+            }
+            Command::Data(Data::FlushAll) | Command::FlushAll => {
+                emit!(out, "would remove all keys");
+                let mut names: Vec<String> = database.keys("*").into_iter().cloned().collect();
+                for layer in self.layers.iter() {
+                    names.extend(layer.keys().cloned());
+                }
+                let layer = self.layers.last_mut().unwrap();
+                for name in names.into_iter() {
+                    layer.insert(name, false);
+                }
+            }
+            Command::Data(Data::Begin(_)) => {
+                emit!(out, "would begin a transaction");
+                self.layers.push(HashMap::new());
+            }
+            Command::Data(Data::Rollback) if open == 0 => self.no_transaction(out),
+            Command::Data(Data::Rollback) => {
+                emit!(out, "would roll back a transaction");
+                if self.layers.len() > 1 {
+                    self.layers.pop();
+                }
+            }
+            Command::Data(Data::Commit) if open == 0 => self.no_transaction(out),
+            Command::Data(Data::Commit) => {
+                emit!(out, "would commit all transactions");
+                let mut merged = HashMap::new();
+                for layer in self.layers.drain(..) {
+                    merged.extend(layer);
+                }
+                self.layers.push(merged);
+            }
+            Command::Data(Data::CommitN(count)) if open < *count => self.no_transaction(out),
+            Command::Data(Data::CommitN(count)) => {
+                emit!(out, "would commit {} transactions", count);
+                for _ in 0..(*count).min(self.layers.len() - 1) {
+                    let layer = self.layers.pop().unwrap();
+                    self.layers.last_mut().unwrap().extend(layer);
+                }
+            }
+            Command::Select(selector) => emit!(out, "would select database {}", selector),
+            Command::Move(name, selector) => {
+                self.check(database, "MOVE", name, out);
+                emit!(out, "would move {} to database {}", name, selector);
+                self.layers
+                    .last_mut()
+                    .unwrap()
+                    .insert(name.to_string(), false);
+            }
+            Command::ImportJson(path, ImportMode::Merge) => {
+                emit!(out, "would import keys from {}", path);
+            }
+            Command::ExportEnv(path) | Command::ExportJson(path) => {
+                emit!(out, "would export the keys to {}", path);
+            }
+            Command::Save(path) => emit!(out, "would save a snapshot to {}", path),
+            Command::RewriteAof => emit!(out, "would rewrite the log"),
+            Command::RestoreTo(RestorePoint::Lsn(lsn)) => {
+                emit!(out, "would restore to log position {}", lsn);
+            }
+            Command::RestoreTo(RestorePoint::Time(time)) => {
+                let ago = time.elapsed().unwrap_or_default().as_secs();
+                emit!(out, "would restore to {}s ago", ago);
+            }
+            Command::ImportJson(path, ImportMode::Replace) => {
+                emit!(out, "would replace all keys with those from {}", path);
+            }
+            _ => (),
+        }
+    }
+
+    /// Report a transaction command that would fail.
+    fn no_transaction(&mut self, out: &mut Output) {
+        emit!(out, "NO TRANSACTION");
+        self.problems += 1;
+    }
+}
+
+///
+/// Commands entered in previous and current sessions, saved to a file so that
+/// they survive restarts of the program.
+///
+struct History {
+    path: Option<PathBuf>,
+    max_entries: usize,
+    entries: Vec<String>,
+}
+
+impl History {
+    /// Load the history from the given file, if any, keeping only the most
+    /// recent entries.
+    fn open(path: Option<PathBuf>, max_entries: usize) -> Self {
+        let mut entries: Vec<String> = Vec::new();
+        if let Some(path) = path.as_ref() {
+            if let Ok(contents) = fs::read_to_string(path) {
+                entries = contents.lines().map(String::from).collect();
+            }
+        }
+        let mut history = Self {
+            path,
+            max_entries,
+            entries,
+        };
+        if history.entries.len() > max_entries {
+            let excess = history.entries.len() - max_entries;
+            history.entries.drain(..excess);
+            if let Err(err) = history.rewrite() {
+                eprintln!("error: could not save history: {}", err);
+                history.path = None;
+            }
+        }
+        history
+    }
+
+    /// Replace the history file with the entries held in memory.
+    fn rewrite(&self) -> io::Result<()> {
+        if let Some(path) = self.path.as_ref() {
+            let mut file = fs::File::create(path)?;
+            for entry in self.entries.iter() {
+                writeln!(file, "{}", entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the command in memory and in the history file.
+    fn add(&mut self, command: &str) {
+        let command = command.trim();
+        if command.is_empty() || self.max_entries == 0 {
+            return;
+        }
+        self.entries.push(command.to_owned());
+        let result = if self.entries.len() > self.max_entries {
+            self.entries.remove(0);
+            self.rewrite()
+        } else if let Some(path) = self.path.as_ref() {
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", command))
+        } else {
+            Ok(())
+        };
+        if let Err(err) = result {
+            eprintln!("error: could not save history: {}", err);
+            self.path = None;
+        }
+    }
+
+    /// Print the most recent commands, or all of them if `count` is `None`.
+    fn print(&self, count: Option<usize>, out: &mut Output) {
+        let count = count.unwrap_or(self.entries.len()).min(self.entries.len());
+        let start = self.entries.len() - count;
+        for (index, entry) in self.entries.iter().enumerate().skip(start) {
+            emit!(out, "{:5}  {}", index + 1, entry);
+        }
+    }
+}
+
+/// Result of evaluating a single command.
+enum Outcome {
+    Done,
+    Failed,
+    End,
+}
+
+///
+/// State of the interactive session.
+///
+// asks the question, returning true if the answer is yes
+type Confirm = Box<dyn FnMut(&str) -> bool>;
+
+struct Repl {
+    databases: Databases<String, CompactString>,
+    // number of the database that commands operate on
+    selected: usize,
+    chaos: Chaos,
+    history: History,
+    output: Output,
+    // write-ahead log, if the database is persisted
+    wal: Option<PathBuf>,
+    // file and time of the most recent SAVE
+    last_snapshot: Option<(PathBuf, SystemTime)>,
+    // shared with the database, if counting accesses
+    frequency: Option<FrequencyTracker>,
+    // whether unknown keys are reported when a dry run is started
+    check_keys: bool,
+    // present while commands are only being checked
+    dry_run: Option<DryRun>,
+    // interceptors through which the commands of the database pass
+    pipeline: Pipeline,
+    // absent if there is no one to ask
+    confirm: Option<Confirm>,
+}
+
+impl Repl {
+    /// Start a session on the database, with the given history.
+    fn new(database: Database<String, CompactString>, history: History) -> Self {
+        Self {
+            databases: Databases::with_first(database, DATABASES),
+            selected: 0,
+            chaos: Chaos::default(),
+            history,
+            output: Output::default(),
+            wal: None,
+            last_snapshot: None,
+            frequency: None,
+            check_keys: false,
+            dry_run: None,
+            pipeline: Pipeline::new(),
+            confirm: None,
+        }
+    }
+
+    /// Parse and carry out a single command, reporting whether it failed or
+    /// ended the session.
+    fn eval_and_print(&mut self, line: &str) -> Outcome {
+        self.output.start();
+        self.output.record(format_args!("> {}", line));
+        let outcome = self.eval(line);
+        if let Some(err) = self.databases[0].take_log_error() {
+            emit!(
+                self.output,
+                "error: could not write log, changes are no longer saved: {}",
+                err
+            );
+            return Outcome::Failed;
+        }
+        outcome
+    }
+
+    fn eval(&mut self, line: &str) -> Outcome {
+        let out = &mut self.output;
+        let database = &mut self.databases[self.selected];
+        let words = parser::tokenize(line).map_err(|err| err.to_string());
+        let command = match words
+            .as_deref()
+            .map_err(String::clone)
+            .and_then(Command::parse)
+        {
+            Ok(Some(command)) => command,
+            Ok(None) => return Outcome::Done,
+            Err(msg) => {
+                emit!(out, "{}", msg);
+                if let Some(dry_run) = self.dry_run.as_mut() {
+                    dry_run.problems += 1;
+                }
+                return Outcome::Failed;
             }
-        } else if cmd == "GET" {
-            if let Some(name) = iter.next() {
-                if let Some(value) = database.get(name) {
-                    println!("{}", value);
+        };
+        let command = match command {
+            Command::FlushAll if self.dry_run.is_none() => match self
+                .confirm
+                .as_mut()
+                .map(|confirm| confirm("remove every key?"))
+            {
+                Some(true) => Command::Data(Data::FlushAll),
+                Some(false) => {
+                    emit!(out, "FLUSHALL cancelled");
+                    return Outcome::Done;
+                }
+                None => {
+                    emit!(
+                        out,
+                        "FLUSHALL removes every key, confirm with FLUSHALL FORCE"
+                    );
+                    return Outcome::Failed;
+                }
+            },
+            command => command,
+        };
+        match command {
+            Command::Data(Data::End) => return Outcome::End,
+            Command::Chaos(setting) => self.chaos.configure(setting, out),
+            Command::History(count) => self.history.print(count, out),
+            Command::Transcript(arg) => self.transcript(arg),
+            Command::Status => self.status(),
+            Command::Counts(pattern, limit) => {
+                let mut counts = database.value_counts();
+                if let Some(pattern) = pattern {
+                    counts.retain(|(value, _)| glob::matches(pattern, value));
+                }
+                // the database gives the most common first in no particular
+                // order, so sort equal counts by value for repeatable output
+                counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+                for (value, count) in counts.iter().take(limit.unwrap_or(usize::MAX)) {
+                    emit!(out, "{} {}", value, count);
+                }
+            }
+            Command::Distinct => emit!(out, "{}", database.distinct_value_count()),
+            // listing keys changes nothing, so is allowed in a dry run
+            Command::Data(data @ (Data::Keys(..) | Data::Scan(..))) => {
+                return print_response(out, self.pipeline.execute(database, data));
+            }
+            Command::KeysByLen(min, max) => {
+                for name in database.keys_by_len(min..=max) {
+                    emit!(out, "{}", name);
+                }
+            }
+            Command::Freq(name) => match self.frequency.as_ref() {
+                Some(tracker) => emit!(out, "{}", tracker.frequency(name)),
+                None => emit!(out, "frequency tracking is off"),
+            },
+            Command::DryRun(Some(true)) => {
+                if self.dry_run.is_none() {
+                    self.dry_run = Some(DryRun::new(self.check_keys));
+                }
+            }
+            Command::DryRun(Some(false)) => self.finish_dry_run(),
+            Command::DryRun(None) => match self.dry_run.as_ref() {
+                Some(_) => emit!(out, "dry run on"),
+                None => emit!(out, "dry run off"),
+            },
+            Command::ExportEnv(path) if database.encryption().is_some() => {
+                emit!(out, "error: {} {}", path, PLAINTEXT);
+                return Outcome::Failed;
+            }
+            Command::ExportJson(path) if database.encryption().is_some() => {
+                emit!(out, "error: {} {}", path, PLAINTEXT);
+                return Outcome::Failed;
+            }
+            Command::ImportEnv(path) => {
+                let pairs = match fs::File::open(path)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|file| envfile::read(io::BufReader::new(file)))
+                {
+                    Ok(pairs) => pairs,
+                    Err(err) => {
+                        emit!(out, "error: could not import {}: {}", path, err);
+                        if let Some(dry_run) = self.dry_run.as_mut() {
+                            dry_run.problems += 1;
+                        }
+                        return Outcome::Failed;
+                    }
+                };
+                if let Some(dry_run) = self.dry_run.as_mut() {
+                    for (name, value) in pairs.iter() {
+                        dry_run.commands += 1;
+                        dry_run.simulate(
+                            database,
+                            &Command::Data(Data::Set(name.clone(), value.clone())),
+                            out,
+                        );
+                    }
+                } else if !self.chaos.strike() {
+                    emit!(out, "error: injected failure");
+                    return Outcome::Failed;
                 } else {
-                    println!("NULL")
+                    let count = pairs.len();
+                    for (name, value) in pairs.into_iter() {
+                        database.set(name, value.as_str());
+                    }
+                    emit!(out, "imported {} keys", count);
                 }
-            } else {
-                println!("missing name for GET");
             }
-        } else if cmd == "UNSET" {
-            if let Some(name) = iter.next() {
-                database.delete(name);
-            } else {
-                println!("missing name for UNSET");
+            command if self.dry_run.is_some() => {
+                let dry_run = self.dry_run.as_mut().unwrap();
+                dry_run.commands += 1;
+                dry_run.simulate(database, &command, out);
             }
-        } else if cmd == "NUMEQUALTO" {
-            if let Some(value) = iter.next() {
-                let count = database.count(value);
-                println!("{}", count);
-            } else {
-                println!("missing value for NUMEQUALTO");
+            // confirmed or refused above when not a dry run
+            Command::FlushAll => unreachable!(),
+            Command::ExportEnv(path) => {
+                let result = fs::File::create(path).and_then(|file| {
+                    let mut writer = io::BufWriter::new(file);
+                    let pairs = database.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+                    envfile::write(&mut writer, pairs)?;
+                    writer.flush()
+                });
+                match result {
+                    Ok(()) => emit!(out, "exported {} keys", database.len()),
+                    Err(err) => {
+                        emit!(out, "error: could not write {}: {}", path, err);
+                        return Outcome::Failed;
+                    }
+                }
             }
-        } else if cmd == "BEGIN" {
-            database.begin();
-        } else if cmd == "ROLLBACK" {
-            if !database.rollback() {
-                println!("NO TRANSACTION");
+            Command::ExportJson(path) => {
+                let result = fs::File::create(path)
+                    .map_err(Error::from)
+                    .and_then(|file| database.export_json(file));
+                match result {
+                    Ok(()) => emit!(out, "exported {} keys", database.len()),
+                    Err(err) => {
+                        emit!(out, "error: could not write {}: {}", path, err);
+                        return Outcome::Failed;
+                    }
+                }
             }
-        } else if cmd == "COMMIT" {
-            if !database.commit() {
-                println!("NO TRANSACTION");
+            Command::Save(path) => match database.save_snapshot(path) {
+                Ok(()) => self.last_snapshot = Some((PathBuf::from(path), SystemTime::now())),
+                Err(err) => {
+                    emit!(out, "error: could not save {}: {}", path, err);
+                    return Outcome::Failed;
+                }
+            },
+            // only the first database is persisted
+            Command::RewriteAof => {
+                if let Err(err) = self.databases[0].rewrite_aof() {
+                    emit!(out, "error: could not rewrite the log: {}", err);
+                    return Outcome::Failed;
+                }
+            }
+            Command::RestoreTo(point) => {
+                if let Err(err) = self.databases[0].restore_to(point) {
+                    emit!(out, "error: could not restore: {}", err);
+                    return Outcome::Failed;
+                }
+            }
+            Command::Select(selector) => {
+                if self.databases.is_persisted() && self.databases.find(selector) != Some(0) {
+                    emit!(
+                        out,
+                        "only database 0 is persisted, another cannot be selected"
+                    );
+                    return Outcome::Failed;
+                }
+                self.selected = match self.databases.find(selector) {
+                    Some(index) => index,
+                    // a name not yet in use makes another database
+                    None if selector.parse::<usize>().is_err() => self.databases.add(selector),
+                    None => {
+                        emit!(out, "no such database: {}", selector);
+                        return Outcome::Failed;
+                    }
+                };
+            }
+            Command::Move(name, selector) => match self.databases.find(selector) {
+                _ if self.selected == 0 && self.databases.is_persisted() => {
+                    emit!(
+                        out,
+                        "only database 0 is persisted, keys cannot be moved out of it"
+                    );
+                    return Outcome::Failed;
+                }
+                Some(index) => {
+                    let moved = self.databases.move_key(self.selected, index, name);
+                    emit!(out, "{}", moved as i32);
+                }
+                None => {
+                    emit!(out, "no such database: {}", selector);
+                    return Outcome::Failed;
+                }
+            },
+            _ if !self.chaos.strike() => {
+                emit!(out, "error: injected failure");
+                return Outcome::Failed;
             }
+            Command::ImportJson(path, mode) => {
+                let result = fs::File::open(path)
+                    .map_err(Error::from)
+                    .and_then(|file| database.import_json(file, mode));
+                match result {
+                    Ok(count) => emit!(out, "imported {} keys", count),
+                    Err(err) => {
+                        emit!(out, "error: could not import {}: {}", path, err);
+                        return Outcome::Failed;
+                    }
+                }
+            }
+            Command::Data(data) => {
+                return print_response(out, self.pipeline.execute(database, data));
+            }
+        }
+        Outcome::Done
+    }
+
+    /// Carry out the TRANSCRIPT command, which starts recording the session to
+    /// the named file, stops recording with `OFF`, or shows the current file.
+    fn transcript(&mut self, arg: Option<&str>) {
+        match arg {
+            Some("OFF") => self.output.transcript = None,
+            Some(name) if self.databases[0].encryption().is_some() => {
+                emit!(self.output, "error: {} {}", name, PLAINTEXT);
+            }
+            Some(name) => {
+                let path = PathBuf::from(name);
+                let result = fs::OpenOptions::new().create(true).append(true).open(&path);
+                match result {
+                    Ok(file) => self.output.transcript = Some((path, file)),
+                    Err(err) => emit!(self.output, "error: could not open {}: {}", name, err),
+                }
+            }
+            None => {
+                let status = match self.output.transcript.as_ref() {
+                    Some((path, _)) => format!("transcript {}", path.display()),
+                    None => "transcript off".into(),
+                };
+                emit!(self.output, "{}", status);
+            }
+        }
+    }
+
+    /// Carry out the STATUS command, summarizing the state of the session.
+    fn status(&mut self) {
+        let database = &self.databases[self.selected];
+        let out = &mut self.output;
+        match self.databases.name(self.selected) {
+            Some(name) => emit!(out, "database: {} ({})", self.selected, name),
+            None => emit!(out, "database: {}", self.selected),
+        }
+        emit!(out, "transaction depth: {}", database.transaction_depth());
+        emit!(out, "pending changes: {}", database.pending_count());
+        emit!(out, "keys: {}", database.len());
+        // keys and values as seen by the current transaction, not counting
+        // the snapshots saved by each level or the index of value counts
+        let entry = std::mem::size_of::<(String, CompactString)>();
+        let bytes: usize = database
+            .iter()
+            .map(|(key, value)| {
+                let heap = if value.is_heap_allocated() {
+                    value.capacity()
+                } else {
+                    0
+                };
+                entry + key.capacity() + heap
+            })
+            .sum();
+        emit!(out, "memory: ~{} bytes (estimated)", bytes);
+        match self.wal.as_ref() {
+            Some(path) => {
+                let size = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+                emit!(out, "persistence: wal {} ({} bytes)", path.display(), size);
+                if let Some(lsn) = self.databases[0].lsn() {
+                    emit!(out, "log position: {}", lsn);
+                }
+            }
+            None => emit!(out, "persistence: none"),
+        }
+        match self.last_snapshot.as_ref() {
+            Some((path, time)) => {
+                let age = time.elapsed().unwrap_or_default().as_secs();
+                emit!(out, "last snapshot: {} ({}s ago)", path.display(), age);
+            }
+            None => emit!(out, "last snapshot: none"),
+        }
+        match out
+            .transcript
+            .as_ref()
+            .map(|(path, _)| path.display().to_string())
+        {
+            Some(path) => emit!(out, "transcript: {}", path),
+            None => emit!(out, "transcript: off"),
+        }
+        let dry_run = if self.dry_run.is_some() { "on" } else { "off" };
+        emit!(out, "dry run: {}", dry_run);
+        let chaos = if self.chaos.latency.is_some() || self.chaos.error.is_some() {
+            "on"
         } else {
-            println!("unknown command: {}", cmd);
+            "off"
+        };
+        emit!(out, "chaos: {}", chaos);
+    }
+
+    /// End the dry run, if any, and summarize what it found.
+    fn finish_dry_run(&mut self) {
+        if let Some(dry_run) = self.dry_run.take() {
+            emit!(
+                self.output,
+                "dry run: {} commands checked, {} problems found",
+                dry_run.commands,
+                dry_run.problems
+            );
+        }
+    }
+}
+
+/// Print the result of a command, reporting whether it failed or ended the
+/// session.
+fn print_response(out: &mut Output, response: Response) -> Outcome {
+    match response {
+        Response::Done => (),
+        Response::Value(Some(value)) => emit!(out, "{}", value),
+        Response::Value(None) => emit!(out, "NULL"),
+        Response::Values(values) => {
+            for value in values.iter() {
+                emit!(out, "{}", value.as_deref().unwrap_or("NULL"));
+            }
+        }
+        Response::Integer(n) => emit!(out, "{}", n),
+        Response::List(values) => {
+            for value in values.iter() {
+                emit!(out, "{}", value);
+            }
+        }
+        Response::Keys(keys) => {
+            for name in keys.iter() {
+                emit!(out, "{}", name);
+            }
+        }
+        Response::Page(cursor, keys) => {
+            emit!(out, "{}", cursor);
+            for name in keys.iter() {
+                emit!(out, "{}", name);
+            }
+        }
+        Response::Entries(entries) => {
+            for (id, fields) in entries.iter() {
+                emit!(out, "{}", id);
+                for (field, value) in fields.iter() {
+                    emit!(out, "  {} {}", field, value);
+                }
+            }
+        }
+        Response::Changes(changes) => {
+            for change in changes.iter() {
+                let old = change.old.as_deref().unwrap_or("NULL");
+                let new = change.new.as_deref().unwrap_or("NULL");
+                emit!(out, "{}: {} -> {}", change.name, old, new);
+            }
+        }
+        Response::Error(msg) => {
+            emit!(out, "{}", msg);
+            return Outcome::Failed;
+        }
+        Response::End => return Outcome::End,
+    }
+    Outcome::Done
+}
+
+/// Construct a line editor whose history, reached with the arrow keys, starts
+/// with the saved history. Returns `None` if the terminal is not supported.
+fn open_editor(history: &History) -> Option<DefaultEditor> {
+    let config = rustyline::Config::builder()
+        .max_history_size(history.max_entries.max(1))
+        .ok()?
+        .auto_add_history(false)
+        .build();
+    let mut editor = DefaultEditor::with_config(config).ok()?;
+    for entry in history.entries.iter() {
+        let _ = editor.add_history_entry(entry.as_str());
+    }
+    Some(editor)
+}
+
+/// Read a complete command using the line editor, joining lines that end with
+/// a backslash as `read_command()` does. Interrupting the input with Ctrl-C
+/// discards it, while Ctrl-D ends the session.
+fn read_edited(editor: &mut DefaultEditor, prompt: &str) -> io::Result<Option<String>> {
+    let mut command = String::new();
+    let mut prompt = prompt;
+    loop {
+        match editor.readline(prompt) {
+            Ok(line) => match line.strip_suffix('\\') {
+                Some(partial) => {
+                    command.push_str(partial);
+                    prompt = "... ";
+                }
+                None => {
+                    command.push_str(&line);
+                    return Ok(Some(command));
+                }
+            },
+            Err(ReadlineError::Interrupted) => return Ok(Some(String::new())),
+            Err(ReadlineError::Eof) if command.is_empty() => return Ok(None),
+            Err(ReadlineError::Eof) => return Ok(Some(command)),
+            Err(ReadlineError::Io(err)) => return Err(err),
+            Err(err) => return Err(io::Error::other(err)),
+        }
+    }
+}
+
+/// Read a complete command from standard input, joining lines that end with a
+/// backslash with the line that follows, much like a shell. Returns `None` at
+/// the end of input. The number of lines read is added to `lines`.
+fn read_command<R: BufRead>(
+    input: &mut R,
+    prompt: Option<&str>,
+    lines: &mut usize,
+) -> io::Result<Option<String>> {
+    let mut command = String::new();
+    let mut prompt = prompt;
+    loop {
+        if let Some(prompt) = prompt {
+            print!("{}", prompt);
+            io::stdout().flush()?;
+        }
+        let mut buffer = String::new();
+        if input.read_line(&mut buffer)? == 0 {
+            return Ok(if command.is_empty() {
+                None
+            } else {
+                Some(command)
+            });
+        }
+        *lines += 1;
+        let line = buffer.trim_end_matches(['\r', '\n']);
+        if let Some(partial) = line.strip_suffix('\\') {
+            command.push_str(partial);
+            prompt = prompt.map(|_| "... ");
+        } else {
+            command.push_str(line);
+            return Ok(Some(command));
+        }
+    }
+}
+
+/// Execute the commands in a script, applying the error policy to those that
+/// fail, and return the number of failures.
+fn run_script<R: BufRead>(repl: &mut Repl, script: &mut R, policy: ErrorPolicy) -> usize {
+    let mut failures: Vec<(usize, String)> = Vec::new();
+    let mut lines = 0;
+    // nesting depth of transactions begun within a rolled back transaction,
+    // whose commands are skipped until it ends
+    let mut skipping: Option<usize> = None;
+    loop {
+        let line_number = lines + 1;
+        let mut input = match read_command(script, None, &mut lines) {
+            Ok(Some(input)) => input,
+            Ok(None) => break,
+            Err(err) => {
+                emit!(repl.output, "error: {:?}", err);
+                failures.push((line_number, "(unreadable)".into()));
+                break;
+            }
+        };
+        if let Some(depth) = skipping {
+            let words = parser::tokenize(&input).unwrap_or_default();
+            let command = Command::parse(&words);
+            skipping = match command {
+                Ok(Some(Command::Data(Data::Begin(_)))) => Some(depth + 1),
+                Ok(Some(Command::Data(Data::Rollback))) if depth > 0 => Some(depth - 1),
+                Ok(Some(Command::Data(Data::CommitN(count)))) if depth >= count => {
+                    Some(depth - count)
+                }
+                Ok(Some(Command::Data(Data::Rollback)))
+                | Ok(Some(Command::Data(Data::Commit)))
+                | Ok(Some(Command::Data(Data::CommitN(_)))) => None,
+                _ => Some(depth),
+            };
+            // a commit also applies to any transactions enclosing the one that
+            // was rolled back, so it is executed if any remain
+            let open = repl.databases[repl.selected].transaction_depth();
+            input = match command {
+                Ok(Some(Command::Data(Data::Commit))) if open > 0 => input,
+                Ok(Some(Command::Data(Data::CommitN(count)))) if count > depth + 1 => {
+                    format!("COMMIT {}", count - depth - 1)
+                }
+                _ => continue,
+            };
+        }
+        match repl.eval_and_print(&input) {
+            Outcome::Done => (),
+            Outcome::End => break,
+            Outcome::Failed => {
+                failures.push((line_number, input.trim().to_owned()));
+                match policy {
+                    ErrorPolicy::Stop => break,
+                    ErrorPolicy::Continue => (),
+                    ErrorPolicy::Rollback => {
+                        if repl.databases[repl.selected].rollback().is_ok() {
+                            emit!(repl.output, "rolled back transaction");
+                            skipping = Some(0);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    if !failures.is_empty() {
+        emit!(repl.output, "failures: {}", failures.len());
+        for (line_number, command) in failures.iter() {
+            emit!(repl.output, "  line {}: {}", line_number, command);
+        }
+    }
+    failures.len()
+}
+
+/// Returns the secret with which the database is encrypted, read from the
+/// key file or the environment, if either was given, exiting if that fails.
+fn read_secret(args: &Args) -> Option<Secret> {
+    match args.key_file.as_ref() {
+        Some(path) => match Secret::read_key_file(path) {
+            Ok(secret) => Some(secret),
+            Err(err) => {
+                eprintln!("error: could not read {}: {}", path.display(), err);
+                std::process::exit(1);
+            }
+        },
+        None => std::env::var("SIMPLEDB_PASSPHRASE")
+            .ok()
+            .filter(|passphrase| !passphrase.is_empty())
+            .map(|passphrase| Secret::passphrase(&passphrase)),
+    }
+}
+
+/// Load the database from the write-ahead log or snapshot, if either was
+/// given, exiting if that fails. Given a key file or passphrase, the log and
+/// snapshot are encrypted.
+fn open_database(args: &Args) -> Database<String, CompactString> {
+    let secret = read_secret(args);
+    let mut database = match args.wal.as_ref() {
+        Some(path) => {
+            let recovered = match secret.clone() {
+                Some(secret) => Database::recover_encrypted(path, secret),
+                None => Database::recover(path),
+            };
+            match recovered {
+                Ok((mut database, report)) => {
+                    if report.discarded > 0 {
+                        eprintln!(
+                            "warning: discarded {} bytes at the end of {} that were not a complete record",
+                            report.discarded,
+                            path.display()
+                        );
+                    }
+                    database.set_durability(args.appendfsync.into());
+                    database
+                }
+                Err(err) => {
+                    eprintln!("error: could not open {}: {}", path.display(), err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => match args.snapshot.as_ref() {
+            Some(path) => load_snapshot(path, secret.clone()),
+            None => Database::default(),
+        },
+    };
+    database.set_encryption(secret);
+    database
+}
+
+/// Load the database from a snapshot, exiting if that fails.
+fn load_snapshot(path: &Path, secret: Option<Secret>) -> Database<String, CompactString> {
+    let loaded = match secret {
+        Some(secret) => Database::load_encrypted_snapshot(path, secret),
+        None => Database::load_snapshot(path),
+    };
+    loaded.unwrap_or_else(|err| {
+        eprintln!("error: could not load {}: {}", path.display(), err);
+        std::process::exit(1);
+    })
+}
+
+/// Print the keys that differ between the two snapshots.
+fn diff_snapshots(args: &Args, before: &Path, after: &Path, format: DiffFormat) {
+    let secret = read_secret(args);
+    let before = load_snapshot(before, secret.clone());
+    let after = load_snapshot(after, secret);
+    let diff = before.diff(&after);
+    match format {
+        DiffFormat::Text => {
+            for (mark, names) in [
+                ("+", &diff.added),
+                ("-", &diff.removed),
+                ("~", &diff.changed),
+            ] {
+                for name in names.iter() {
+                    println!("{} {}", mark, name);
+                }
+            }
+        }
+        DiffFormat::Json => {
+            let json = serde_json::json!({
+                "added": diff.added,
+                "removed": diff.removed,
+                "changed": diff.changed,
+            });
+            println!("{}", json);
         }
     }
 }
 
+/// Save snapshots of the database to the file as the schedule calls for, on
+/// a thread of its own so that clients are held up only while a snapshot is
+/// taken, not while it is written.
+fn save_on_schedule<T: Send + 'static>(
+    shared: Arc<Mutex<T>>,
+    database: fn(&T) -> &Database<String, CompactString>,
+    path: PathBuf,
+    mut schedule: SnapshotSchedule<String, CompactString>,
+) {
+    thread::spawn(move || loop {
+        thread::sleep(schedule.due().saturating_duration_since(Instant::now()));
+        let snapshot = schedule.poll(database(&shared.lock().unwrap()));
+        if let Some(snapshot) = snapshot {
+            match snapshot.save(&path) {
+                Ok(()) => schedule.saved(snapshot),
+                Err(err) => eprintln!("error: could not save {}: {}", path.display(), err),
+            }
+        }
+    });
+}
+
+/// Accept network connections until the listener fails.
+fn serve(
+    args: &Args,
+    resp: bool,
+    http: bool,
+    listen: Option<&str>,
+    save: Option<(&Path, Duration, usize)>,
+) {
+    let listen = match (resp, http) {
+        (true, _) => listen.unwrap_or("127.0.0.1:6379"),
+        (_, true) => listen.unwrap_or("127.0.0.1:8080"),
+        _ => {
+            eprintln!("error: a protocol must be chosen with --resp or --http");
+            std::process::exit(1);
+        }
+    };
+    let mut database = open_database(args);
+    if args.length_index {
+        database.enable_length_index();
+    }
+    let listener = match TcpListener::bind(listen) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("error: could not listen on {}: {}", listen, err);
+            std::process::exit(1);
+        }
+    };
+    if let Ok(address) = listener.local_addr() {
+        println!("listening on {}", address);
+    }
+    let save = save.map(|(path, interval, changes)| {
+        let schedule = SnapshotSchedule::new(&database, interval, changes);
+        (path.to_owned(), schedule)
+    });
+    let result = if http {
+        let shared = Arc::new(Mutex::new(database));
+        if let Some((path, schedule)) = save {
+            save_on_schedule(shared.clone(), |database| database, path, schedule);
+        }
+        http::serve(listener, shared)
+    } else {
+        let mut databases = Databases::with_first(database, DATABASES);
+        databases.set_persisted(databases.is_persisted() || save.is_some());
+        let shared = Arc::new(Mutex::new(databases));
+        if let Some((path, schedule)) = save {
+            save_on_schedule(shared.clone(), |databases| &databases[0], path, schedule);
+        }
+        server::serve(listener, shared)
+    };
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
+}
+
 fn main() {
-    let mut database = Database::new();
-    // the read-eval-print-loop
+    let args = Args::parse();
+    match args.command.as_ref() {
+        Some(Subcommand::Completions { shell }) => {
+            let mut command = Args::command();
+            let name = command.get_name().to_owned();
+            clap_complete::generate(*shell, &mut command, name, &mut io::stdout());
+            return;
+        }
+        Some(Subcommand::Serve {
+            resp,
+            http,
+            listen,
+            save,
+            save_interval,
+            save_changes,
+        }) => {
+            let save = save
+                .as_deref()
+                .map(|path| (path, Duration::from_secs(*save_interval), *save_changes));
+            serve(&args, *resp, *http, listen.as_deref(), save);
+            return;
+        }
+        #[cfg(feature = "sqlite")]
+        Some(Subcommand::ExportSqlite { out, table }) => {
+            let database = open_database(&args);
+            if database.encryption().is_some() {
+                eprintln!("error: {} {}", out.display(), PLAINTEXT);
+                std::process::exit(1);
+            }
+            match simpledb::sqlite::export(&database.freeze_snapshot(), out, table) {
+                Ok(count) => println!("exported {} keys", count),
+                Err(err) => {
+                    eprintln!("error: could not export to {}: {}", out.display(), err);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Subcommand::DiffSnapshots {
+            before,
+            after,
+            output,
+        }) => {
+            diff_snapshots(&args, before, after, *output);
+            return;
+        }
+        None => (),
+    }
+    // scripts are neither prompted for nor recorded in the history
+    let script = match args.script.as_ref() {
+        Some(path) => match fs::File::open(path) {
+            Ok(file) => Some(io::BufReader::new(file)),
+            Err(err) => {
+                eprintln!("error: could not open {}: {}", path.display(), err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let database = open_database(&args);
+    // nor is the history saved while the database is encrypted, as it would
+    // hold the values in plaintext
+    let history_file = match script {
+        Some(_) => None,
+        None if database.encryption().is_some() => None,
+        None => args.history_file.clone().or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".simpledb_history"))
+        }),
+    };
+    let history = History::open(history_file, args.history_size);
+    let mut repl = Repl::new(database, history);
+    repl.wal = args.wal.clone();
+    repl.check_keys = args.check_keys;
+    if args.length_index {
+        repl.databases[0].enable_length_index();
+    }
+    if args.track_frequency {
+        let tracker = FrequencyTracker::new();
+        repl.databases[0].add_observer(Box::new(tracker.clone()));
+        repl.frequency = Some(tracker);
+    }
+    if args.dry_run {
+        repl.dry_run = Some(DryRun::new(args.check_keys));
+    }
+    if let Some(mut script) = script {
+        let failures = run_script(&mut repl, &mut script, args.on_error);
+        repl.finish_dry_run();
+        if failures > 0 {
+            std::process::exit(1);
+        }
+        return;
+    }
+    // the read-eval-print-loop, with line editing and paged output when used
+    // from a terminal
+    let mut editor = if io::stdin().is_terminal() {
+        open_editor(&repl.history)
+    } else {
+        None
+    };
+    if args.page_size > 0 && io::stdin().is_terminal() && io::stdout().is_terminal() {
+        repl.output.pager = Some(Pager::new(args.page_size));
+    }
+    repl.confirm = Some(Box::new(|question| {
+        ask(&format!("{} [y/N] ", question)).is_some_and(|answer| {
+            let answer = answer.trim();
+            answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes")
+        })
+    }));
+    let mut lines = 0;
     loop {
-        print!("> ");
-        io::stdout().flush().unwrap();
-        let mut input = String::new();
-        match io::stdin().read_line(&mut input) {
-            Ok(_) => eval_and_print(&mut database, &input),
-            Err(err) => println!("error: {:?}", err),
+        let prompt = render_prompt(args.prompt.as_deref(), &repl.databases[repl.selected]);
+        let input = match editor.as_mut() {
+            Some(editor) => read_edited(editor, &prompt),
+            None => read_command(&mut io::stdin().lock(), Some(&prompt), &mut lines),
+        };
+        match input {
+            Ok(Some(input)) => {
+                repl.history.add(&input);
+                if let Some(editor) = editor.as_mut() {
+                    let _ = editor.add_history_entry(input.trim());
+                }
+                if let Outcome::End = repl.eval_and_print(&input) {
+                    break;
+                }
+            }
+            Ok(None) => break,
+            Err(err) => emit!(repl.output, "error: {:?}", err),
+        }
+    }
+    repl.finish_dry_run();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Start a session whose output is recorded to a transcript of its own,
+    /// so that it can be checked.
+    fn session(name: &str) -> (Repl, PathBuf) {
+        let path = std::env::temp_dir().join(format!("simpledb-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut repl = Repl::new(Database::default(), History::open(None, 0));
+        repl.transcript(Some(path.to_str().unwrap()));
+        (repl, path)
+    }
+
+    /// Returns the transcript of the session, removing the file.
+    fn transcript(repl: Repl, path: &Path) -> String {
+        drop(repl);
+        let contents = fs::read_to_string(path).unwrap();
+        fs::remove_file(path).unwrap();
+        contents
+    }
+
+    fn run(repl: &mut Repl, script: &str, policy: ErrorPolicy) -> usize {
+        run_script(repl, &mut script.as_bytes(), policy)
+    }
+
+    fn get(repl: &Repl, name: &str) -> Option<String> {
+        repl.databases[0].get(name).map(String::from)
+    }
+
+    #[test]
+    fn test_error_policy() {
+        let script = "SET a 1\nFLY\nSET b 2\n";
+        let (mut repl, path) = session("continue");
+        assert_eq!(run(&mut repl, script, ErrorPolicy::Continue), 1);
+        assert_eq!(get(&repl, "b"), Some("2".into()));
+        let output = transcript(repl, &path);
+        assert!(output.ends_with("failures: 1\n  line 2: FLY\n"));
+        let (mut repl, path) = session("stop");
+        assert_eq!(run(&mut repl, script, ErrorPolicy::Stop), 1);
+        assert_eq!(get(&repl, "a"), Some("1".into()));
+        assert_eq!(get(&repl, "b"), None);
+        transcript(repl, &path);
+        // lines joined by a backslash are reported by the first of them
+        let (mut repl, path) = session("lines");
+        let script = "SET a \\\n1\nFLY \\\naway\n";
+        assert_eq!(run(&mut repl, script, ErrorPolicy::Continue), 1);
+        assert_eq!(get(&repl, "a"), Some("1".into()));
+        assert!(transcript(repl, &path).ends_with("  line 3: FLY away\n"));
+    }
+
+    #[test]
+    fn test_rollback_policy() {
+        let (mut repl, path) = session("rollback");
+        // the rest of the failed transaction is skipped, including any begun
+        // within it, up to the commit or rollback that ends it
+        let script = "SET a 1\nBEGIN\nSET a 2\nFLY\nSET b 2\nBEGIN\nSET c 2\nCOMMIT\nSET d 2\n";
+        assert_eq!(run(&mut repl, script, ErrorPolicy::Rollback), 1);
+        assert_eq!(get(&repl, "a"), Some("1".into()));
+        assert_eq!(get(&repl, "b"), None);
+        assert_eq!(get(&repl, "c"), None);
+        assert_eq!(get(&repl, "d"), Some("2".into()));
+        assert_eq!(repl.databases[0].transaction_depth(), 0);
+        // only the innermost transaction is rolled back, and a commit still
+        // applies to those enclosing it
+        let script = "BEGIN\nSET e 1\nBEGIN\nSET f 1\nFLY\nSET g 1\nCOMMIT\nSET h 1\n";
+        assert_eq!(run(&mut repl, script, ErrorPolicy::Rollback), 1);
+        assert_eq!(repl.databases[0].transaction_depth(), 0);
+        assert_eq!(get(&repl, "e"), Some("1".into()));
+        assert_eq!(get(&repl, "f"), None);
+        assert_eq!(get(&repl, "g"), None);
+        assert_eq!(get(&repl, "h"), Some("1".into()));
+        // as does a count beyond the skipped transactions
+        let script = "BEGIN\nBEGIN\nSET i 1\nBEGIN\nFLY\nBEGIN\nSET j 1\nCOMMIT 3\n";
+        assert_eq!(run(&mut repl, script, ErrorPolicy::Rollback), 1);
+        assert_eq!(repl.databases[0].transaction_depth(), 1);
+        assert_eq!(run(&mut repl, "COMMIT", ErrorPolicy::Rollback), 0);
+        assert_eq!(get(&repl, "i"), Some("1".into()));
+        assert_eq!(get(&repl, "j"), None);
+        // outside of a transaction there is nothing to skip
+        assert_eq!(run(&mut repl, "FLY\nSET k 1\n", ErrorPolicy::Rollback), 1);
+        assert_eq!(get(&repl, "k"), Some("1".into()));
+        let output = transcript(repl, &path);
+        assert_eq!(output.matches("rolled back transaction").count(), 3);
+    }
+
+    #[test]
+    fn test_pipeline() {
+        // refuses keys starting with an underscore, keeps values in upper case
+        struct Guard;
+        impl command::Interceptor for Guard {
+            fn before(&mut self, command: &mut Data) -> Option<Response> {
+                match command {
+                    Data::Set(name, _) if name.starts_with('_') => {
+                        Some(Response::Error(format!("{} is reserved", name)))
+                    }
+                    Data::Set(_, value) => {
+                        *value = value.to_uppercase();
+                        None
+                    }
+                    _ => None,
+                }
+            }
+        }
+
+        let (mut repl, path) = session("pipeline");
+        repl.pipeline.add(Box::new(Guard));
+        assert!(matches!(repl.eval_and_print("SET a shout"), Outcome::Done));
+        assert!(matches!(repl.eval_and_print("SET _a x"), Outcome::Failed));
+        repl.eval_and_print("KEYS");
+        assert_eq!(get(&repl, "a"), Some("SHOUT".into()));
+        assert_eq!(get(&repl, "_a"), None);
+        assert_eq!(
+            transcript(repl, &path),
+            "> SET a shout\n> SET _a x\n_a is reserved\n> KEYS\na\n"
+        );
+    }
+
+    #[test]
+    fn test_pager() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let (mut repl, path) = session("pager");
+        // goes on when first asked, and stops when asked again
+        let asked = Rc::new(Cell::new(0));
+        let counter = asked.clone();
+        let mut pager = Pager::new(2);
+        pager.more = Box::new(move || {
+            counter.set(counter.get() + 1);
+            counter.get() < 2
+        });
+        repl.output.pager = Some(pager);
+        repl.eval_and_print("MSET a 1 b 2 c 3 d 4 e 5 f 6");
+        repl.eval_and_print("KEYS");
+        assert_eq!(asked.get(), 2);
+        assert!(repl.output.pager.as_ref().unwrap().stopped);
+        // each command starts afresh, and short output is not paged
+        repl.eval_and_print("KEYS * LIMIT 0 2");
+        assert_eq!(asked.get(), 2);
+        assert!(!repl.output.pager.as_ref().unwrap().stopped);
+        // all of the output is recorded, whether or not it was shown
+        let output = transcript(repl, &path);
+        assert!(output.contains("> KEYS\na\nb\nc\nd\ne\nf\n"));
+    }
+
+    #[test]
+    fn test_confirm() {
+        let (mut repl, path) = session("confirm");
+        repl.eval_and_print("MSET a 1 b 2");
+        // scripts are never asked, and must force it
+        assert!(matches!(repl.eval_and_print("FLUSHALL"), Outcome::Failed));
+        assert_eq!(repl.databases[0].len(), 2);
+        let answers = std::rc::Rc::new(std::cell::RefCell::new(vec![true, false]));
+        let given = answers.clone();
+        repl.confirm = Some(Box::new(move |question| {
+            assert_eq!(question, "remove every key?");
+            given.borrow_mut().pop().unwrap()
+        }));
+        assert!(matches!(repl.eval_and_print("FLUSHALL"), Outcome::Done));
+        assert_eq!(repl.databases[0].len(), 2);
+        assert!(matches!(repl.eval_and_print("FLUSHALL"), Outcome::Done));
+        assert_eq!(repl.databases[0].len(), 0);
+        // forcing it, or a dry run, asks nothing
+        repl.eval_and_print("SET a 1");
+        assert!(matches!(repl.eval_and_print("FLUSHALL!"), Outcome::Done));
+        assert_eq!(repl.databases[0].len(), 0);
+        repl.eval_and_print("SET a 1");
+        repl.eval_and_print("DRYRUN ON");
+        repl.eval_and_print("FLUSHALL");
+        repl.eval_and_print("DRYRUN OFF");
+        repl.eval_and_print("FLUSHALL FORCE");
+        assert_eq!(repl.databases[0].len(), 0);
+        assert!(answers.borrow().is_empty());
+        let output = transcript(repl, &path);
+        assert!(output
+            .contains("> FLUSHALL\nFLUSHALL removes every key, confirm with FLUSHALL FORCE\n"));
+        assert!(output.contains("> FLUSHALL\nFLUSHALL cancelled\n"));
+        assert!(output.contains("> FLUSHALL\nwould remove all keys\n"));
+    }
+
+    #[test]
+    fn test_transcript() {
+        let (mut repl, path) = session("transcript");
+        repl.eval_and_print("SET a 1");
+        repl.eval_and_print("GET a");
+        repl.eval_and_print("TRANSCRIPT OFF");
+        repl.eval_and_print("GET a");
+        assert_eq!(
+            transcript(repl, &path),
+            "> SET a 1\n> GET a\n1\n> TRANSCRIPT OFF\n"
+        );
+    }
+
+    #[test]
+    fn test_prompt() {
+        let mut database = Database::default();
+        assert_eq!(render_prompt(None, &database), "> ");
+        database.begin();
+        assert_eq!(render_prompt(None, &database), "(tx:1 +0) > ");
+        database.set("a", "1");
+        database.begin();
+        assert_eq!(render_prompt(None, &database), "(tx:2 +1) > ");
+        let template = Some("db[{depth}|{pending}]$ ");
+        assert_eq!(render_prompt(template, &database), "db[2|1]$ ");
+        database.commit().unwrap();
+        assert_eq!(render_prompt(Some("{depth}> "), &database), "0> ");
+    }
+
+    #[test]
+    fn test_history() {
+        let path = std::env::temp_dir().join(format!("simpledb-history-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut history = History::open(Some(path.clone()), 2);
+        history.add("SET a 1");
+        history.add("  ");
+        history.add("GET a ");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "SET a 1\nGET a\n");
+        // only the most recent are kept, in memory and in the file
+        history.add("GET b");
+        assert_eq!(history.entries, vec!["GET a", "GET b"]);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "GET a\nGET b\n");
+        let history = History::open(Some(path.clone()), 1);
+        assert_eq!(history.entries, vec!["GET b"]);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "GET b\n");
+        // a size of zero keeps nothing
+        let mut history = History::open(Some(path.clone()), 0);
+        history.add("GET c");
+        assert!(history.entries.is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let dir = std::env::temp_dir();
+        let file = |name: &str| dir.join(format!("simpledb-dry-{}-{}", name, std::process::id()));
+        let (wal, env, saved, exported) = (file("wal"), file("env"), file("save"), file("json"));
+        for path in [&wal, &saved, &exported] {
+            let _ = fs::remove_file(path);
+        }
+        fs::write(&env, "e=5\n").unwrap();
+        let mut database: Database<String, CompactString> = Database::open(&wal).unwrap();
+        for line in [
+            "SET a 1",
+            "SET n 1",
+            "RPUSH l x y",
+            "HSET h f v",
+            "ZADD z 1 m",
+            "PFADD p x",
+            "JSON.SET j $ '{\"a\":1}'",
+        ] {
+            command::execute(&mut database, command::parse(line).unwrap().unwrap());
+        }
+        let logged = fs::read(&wal).unwrap();
+        let mut repl = Repl::new(database, History::open(None, 0));
+        repl.dry_run = Some(DryRun::new(false));
+        let script = [
+            "SET b 2",
+            "SETNX c 3",
+            "CAS a 1 2",
+            "COPY a d",
+            "COPY a n REPLACE",
+            "MSET e 4 f 5",
+            "UNSET n",
+            "GETDEL a",
+            "GETEX a EX 60",
+            "GETEX a PERSIST",
+            "APPEND a x",
+            "INCR n",
+            "INCRBY n 5",
+            "DECR n",
+            "LPUSH l z",
+            "RPOP l",
+            "HSET h g w",
+            "HDEL h f",
+            "ZADD z 2 o",
+            "ZREM z m",
+            "XADD s * f v",
+            "PFADD p y",
+            "PFMERGE q p",
+            "SETBIT bits 3 1",
+            "JSON.SET j $.a 2",
+            "EXPIRE n 1",
+            "PERSIST a",
+            "BEGIN",
+            "SET t 1",
+            "COMMIT",
+            "BEGIN",
+            "ROLLBACK",
+            "BEGIN",
+            "BEGIN",
+            "COMMIT 2",
+            "FLUSHALL FORCE",
+            "MOVE a 1",
+            "SELECT 1",
+        ];
+        for line in script.iter() {
+            repl.eval_and_print(line);
+        }
+        let paths = [&env, &saved, &exported].map(|path| path.to_str().unwrap().to_owned());
+        for line in [
+            format!("IMPORTENV {}", paths[0]),
+            format!("IMPORTJSON {} REPLACE", paths[0]),
+            format!("SAVE {}", paths[1]),
+            format!("EXPORTJSON {}", paths[2]),
+            format!("EXPORTENV {}", paths[2]),
+            "BGREWRITEAOF".into(),
+            "RESTORETO 0".into(),
+            "RESTORETO AGO 60".into(),
+        ] {
+            repl.eval_and_print(&line);
+        }
+        let dry_run = repl.dry_run.as_ref().unwrap();
+        assert_eq!(dry_run.commands, script.len() + 8);
+        // every command was valid
+        assert_eq!(dry_run.problems, 0);
+        // neither the databases nor the log, nor any other file, was changed
+        assert_eq!(repl.selected, 0);
+        assert_eq!(repl.databases[0].transaction_depth(), 0);
+        assert!(repl.databases[1].is_empty());
+        assert_eq!(fs::read(&wal).unwrap(), logged);
+        let reopened: Database<String, CompactString> = Database::open(&wal).unwrap();
+        let diff = reopened.diff(&repl.databases[0]);
+        assert!(diff.added.is_empty() && diff.removed.is_empty() && diff.changed.is_empty());
+        assert_eq!(repl.databases[0].len(), 7);
+        assert!(!saved.exists());
+        assert!(!exported.exists());
+        // once it ends, commands are carried out again
+        repl.eval_and_print("DRYRUN OFF");
+        repl.eval_and_print("SET b 2");
+        assert_eq!(repl.databases[0].get("b"), Some("2".into()));
+        drop(repl);
+        drop(reopened);
+        for path in [&wal, &env] {
+            fs::remove_file(path).unwrap();
         }
     }
 }
@@ -0,0 +1,74 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! Hooks for embedding applications to observe database activity, such as to
+//! feed their own metrics systems, without this crate depending on any
+//! particular telemetry library.
+
+use std::time::Duration;
+
+///
+/// Receives notifications of database operations along with the time each
+/// took to complete. All methods have empty default implementations so that
+/// implementors need only override those of interest. The type parameter is
+/// the key type of the database being observed.
+///
+pub trait Observer<K = String>: Send {
+    /// Called after a key has been read; `found` indicates if it had a value.
+    fn on_get(&self, _name: &K, _found: bool, _elapsed: Duration) {}
+
+    /// Called after a value has been saved under the given key.
+    fn on_set(&self, _name: &K, _elapsed: Duration) {}
+
+    /// Called after a key has been removed by the client.
+    fn on_delete(&self, _name: &K, _elapsed: Duration) {}
+
+    /// Called after open transactions have been committed.
+    fn on_commit(&self, _elapsed: Duration) {}
+
+    /// Called after the current transaction has been rolled back.
+    fn on_rollback(&self, _elapsed: Duration) {}
+
+    /// Called after `on_commit()` with the keys whose data the commit
+    /// changed, in key order.
+    fn on_commit_keys(&self, _names: &[K]) {}
+
+    /// Called after `on_rollback()` with the keys whose changes were
+    /// discarded, in key order.
+    fn on_rollback_keys(&self, _names: &[K]) {}
+
+    /// Called when a key is removed by the database itself rather than by an
+    /// explicit delete from the client.
+    fn on_evict(&self, _name: &K) {}
+}
+
+///
+/// Observer that passes the keys changed by each commit to a function, as
+/// registered by `Database::on_commit()`.
+///
+pub(crate) struct CommitHook<F>(pub F);
+
+impl<K, F> Observer<K> for CommitHook<F>
+where
+    F: Fn(&[K]) + Send,
+{
+    fn on_commit_keys(&self, names: &[K]) {
+        (self.0)(names)
+    }
+}
+
+///
+/// Observer that passes the keys whose changes each rollback discarded to a
+/// function, as registered by `Database::on_rollback()`.
+///
+pub(crate) struct RollbackHook<F>(pub F);
+
+impl<K, F> Observer<K> for RollbackHook<F>
+where
+    F: Fn(&[K]) + Send,
+{
+    fn on_rollback_keys(&self, names: &[K]) {
+        (self.0)(names)
+    }
+}
@@ -0,0 +1,253 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! Splitting of command lines into words, in the manner of a shell, so that
+//! names and values may contain spaces. Words are separated by whitespace,
+//! except within quotes: double quotes allow the escapes `\"`, `\\`, `\n`,
+//! `\t` and `\r`, while single quotes are taken literally. Outside of quotes a
+//! backslash takes the next character literally. Quoted and unquoted parts
+//! that are not separated by whitespace form a single word, and `""` is an
+//! empty word.
+//!
+//! Binary data may be given as a literal at the start of a word: `x'...'`
+//! holds bytes in hexadecimal and `b64'...'` holds them in base64, either
+//! quote may be used, and whitespace within them is ignored. Words holding
+//! bytes that are not UTF-8 can only be had from `tokenize_bytes()`.
+
+use std::fmt;
+
+///
+/// Reason a line could not be split into words.
+///
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// A quote, given here, was left open at the end of the line.
+    UnterminatedQuote(char),
+    /// The line ended with a backslash that escapes nothing.
+    TrailingBackslash,
+    /// A binary literal, in the encoding given here, could not be decoded.
+    InvalidLiteral(&'static str),
+    /// A word holds bytes that are not UTF-8 text.
+    NotText,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnterminatedQuote('"') => write!(f, "unterminated double quote"),
+            Error::UnterminatedQuote(_) => write!(f, "unterminated single quote"),
+            Error::TrailingBackslash => write!(f, "trailing backslash"),
+            Error::InvalidLiteral(encoding) => write!(f, "invalid {} literal", encoding),
+            Error::NotText => write!(f, "binary literal is not UTF-8 text"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Split the line into words, removing the quotes and escapes. Fails with
+/// `Error::NotText` if a binary literal holds bytes that are not UTF-8.
+pub fn tokenize(line: &str) -> Result<Vec<String>, Error> {
+    tokenize_bytes(line)?
+        .into_iter()
+        .map(|word| String::from_utf8(word).map_err(|_| Error::NotText))
+        .collect()
+}
+
+/// Split the line into words of bytes, removing the quotes and escapes and
+/// decoding any binary literals.
+pub fn tokenize_bytes(line: &str) -> Result<Vec<Vec<u8>>, Error> {
+    let mut words = Vec::new();
+    // the current word, which exists once any part of it has been seen, even
+    // if that was only an empty pair of quotes
+    let mut word: Option<Vec<u8>> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if word.is_none() {
+            if let Some((bytes, rest)) = binary_literal(c, chars.as_str())? {
+                word = Some(bytes);
+                chars = rest.chars();
+                continue;
+            }
+        }
+        let push = |word: &mut Vec<u8>, c: char| {
+            word.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes())
+        };
+        match c {
+            c if c.is_whitespace() => {
+                if let Some(word) = word.take() {
+                    words.push(word);
+                }
+            }
+            '\\' => {
+                let escaped = chars.next().ok_or(Error::TrailingBackslash)?;
+                push(word.get_or_insert_with(Vec::new), escaped);
+            }
+            '\'' => {
+                let word = word.get_or_insert_with(Vec::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => push(word, c),
+                        None => return Err(Error::UnterminatedQuote('\'')),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(Vec::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => word.push(b'\n'),
+                            Some('t') => word.push(b'\t'),
+                            Some('r') => word.push(b'\r'),
+                            Some(other) => push(word, other),
+                            None => return Err(Error::UnterminatedQuote('"')),
+                        },
+                        Some(c) => push(word, c),
+                        None => return Err(Error::UnterminatedQuote('"')),
+                    }
+                }
+            }
+            _ => push(word.get_or_insert_with(Vec::new), c),
+        }
+    }
+    if let Some(word) = word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// If the character and the rest of the line start a binary literal, returns
+/// its bytes and the rest of the line after it.
+fn binary_literal(c: char, rest: &str) -> Result<Option<(Vec<u8>, &str)>, Error> {
+    let (encoding, rest) = match (c, rest.strip_prefix("64")) {
+        ('x', _) => ("hex", rest),
+        ('b', Some(rest)) => ("base64", rest),
+        _ => return Ok(None),
+    };
+    let quote = match rest.chars().next() {
+        Some(quote) if quote == '\'' || quote == '"' => quote,
+        _ => return Ok(None),
+    };
+    let (text, rest) = rest[1..]
+        .split_once(quote)
+        .ok_or(Error::UnterminatedQuote(quote))?;
+    let digits: Vec<u8> = text.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    let bytes = match encoding {
+        "hex" => decode_hex(&digits),
+        _ => decode_base64(&digits),
+    };
+    let bytes = bytes.ok_or(Error::InvalidLiteral(encoding))?;
+    Ok(Some((bytes, rest)))
+}
+
+fn decode_hex(digits: &[u8]) -> Option<Vec<u8>> {
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+fn decode_base64(digits: &[u8]) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(digits.len() * 3 / 4);
+    let (mut bits, mut count) = (0u32, 0);
+    // the padding is optional
+    let len = digits.iter().rposition(|b| *b != b'=').map_or(0, |i| i + 1);
+    for digit in &digits[..len] {
+        let value = match digit {
+            b'A'..=b'Z' => digit - b'A',
+            b'a'..=b'z' => digit - b'a' + 26,
+            b'0'..=b'9' => digit - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | u32::from(value);
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+    // a digit left over on its own holds too few bits for a byte
+    (count < 6).then_some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(tokenize("  SET  a   10 ").unwrap(), vec!["SET", "a", "10"]);
+        assert!(tokenize("   ").unwrap().is_empty());
+        assert_eq!(
+            tokenize(r#"SET greeting "hello, world""#).unwrap(),
+            vec!["SET", "greeting", "hello, world"]
+        );
+        assert_eq!(
+            tokenize(r#"SET 'my key' 'no \escapes'"#).unwrap(),
+            vec!["SET", "my key", "no \\escapes"]
+        );
+        assert_eq!(
+            tokenize(r#"SET a "tab\there \"quoted\" \\""#).unwrap(),
+            vec!["SET", "a", "tab\there \"quoted\" \\"]
+        );
+        assert_eq!(tokenize(r"one\ word \'").unwrap(), vec!["one word", "'"]);
+        assert_eq!(
+            tokenize(r#"pre"fix"'ed' "" x"#).unwrap(),
+            vec!["prefixed", "", "x"]
+        );
+    }
+
+    #[test]
+    fn test_binary_literals() {
+        assert_eq!(
+            tokenize_bytes("SET a x'00ff 10' b64\"3q2+7w==\" b64'AQ'").unwrap(),
+            vec![
+                b"SET".to_vec(),
+                b"a".to_vec(),
+                vec![0x00, 0xff, 0x10],
+                vec![0xde, 0xad, 0xbe, 0xef],
+                vec![0x01]
+            ]
+        );
+        assert_eq!(
+            tokenize("SET x'68690a' b64'aGk='").unwrap(),
+            vec!["SET", "hi\n", "hi"]
+        );
+        // only at the start of a word, and only when followed by a quote
+        assert_eq!(
+            tokenize("ax'61' x b64 'b'x").unwrap(),
+            vec!["ax61", "x", "b64", "bx"]
+        );
+        assert_eq!(tokenize("SET a x'ff'"), Err(Error::NotText));
+        assert_eq!(tokenize("x'abc'"), Err(Error::InvalidLiteral("hex")));
+        assert_eq!(tokenize("x'zz'"), Err(Error::InvalidLiteral("hex")));
+        assert_eq!(tokenize("b64'A'"), Err(Error::InvalidLiteral("base64")));
+        assert_eq!(tokenize("b64'a-b'"), Err(Error::InvalidLiteral("base64")));
+        assert_eq!(tokenize("x'00"), Err(Error::UnterminatedQuote('\'')));
+        assert_eq!(
+            Error::InvalidLiteral("hex").to_string(),
+            "invalid hex literal"
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(tokenize("SET a \"open"), Err(Error::UnterminatedQuote('"')));
+        assert_eq!(tokenize("SET a 'open"), Err(Error::UnterminatedQuote('\'')));
+        assert_eq!(tokenize("SET a b\\"), Err(Error::TrailingBackslash));
+        assert_eq!(
+            Error::UnterminatedQuote('"').to_string(),
+            "unterminated double quote"
+        );
+    }
+}
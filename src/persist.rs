@@ -0,0 +1,1815 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! Persistence of the database to a write-ahead log, to which every committed
+//! change is appended and which is replayed when the database is opened.
+//!
+//! Each record in the log is a one byte operation followed by the length of
+//! the key as a little-endian `u32` and the key itself, and for a set, the
+//! length of the value and the value. Changes to the fields of a hash are
+//! recorded the same way, with the field between the key and the value. An
+//! entry added to a stream is recorded as the key, the sixteen bytes of the
+//! entry identifier, the number of fields as a `u32`, and each field and
+//! value. Values pushed onto either end of a list are recorded as the key,
+//! the number of values as a `u32` and each value, and values popped from
+//! either end as the key alone. A member given a score in a sorted set is
+//! recorded as the key, the member, and the score as a little-endian `f64`,
+//! and one removed as the key and the member. A JSON document is recorded
+//! as the key and the whole of the document as JSON text, which replaces
+//! whatever document the key held. The time at which a key expires is
+//! recorded as the key and the time in milliseconds since the epoch as a
+//! `u64`, and its removal as the key alone. As setting or removing a key
+//! also removes its time-to-live, the time follows whatever the key was
+//! last given.
+//!
+//! Records are written within batch records: the operation byte, the length
+//! of the records within as a `u32` and the records themselves, then their
+//! FNV-1a checksum as a `u32`. The records of a batch start with one holding
+//! the time it was written, in milliseconds since the epoch as a `u64`, so
+//! that the database can be restored to how it was at a given point in the
+//! history of the log. The changes of a transaction are written together as
+//! a single batch, so that after a crash either all of them are replayed or
+//! none are, and any other change as a batch of its own, so that every record
+//! is checked. Logs written before batches existed hold records
+//! outside of them, which are still read.
+//!
+//! When the database is encrypted, the log starts with the header described
+//! in the `crypt` module, and each batch is instead sealed as a record of
+//! its own: the operation byte and the length of the sealed box as a `u32`,
+//! then the box holding the records of the batch, preceded by the time as in
+//! any other batch. The tag of the box takes the place of the checksum.
+//!
+//! Replaying the log stops at the first record that is cut short, has a
+//! checksum that does not match, or does not start with a known operation,
+//! as is left by a crash while a record was being written. The rest of the
+//! log is removed, so that new records follow the last one that was whole,
+//! and the number of bytes removed is reported in a `RecoveryReport`.
+//!
+//! As the log only grows, it can be rewritten to hold just a record for each
+//! value, list, member of a sorted set, document, field of a hash, entry of a
+//! stream, and time-to-live, all in a single batch that starts with a record
+//! of only the operation byte, marking it as the base of the log, before
+//! which the database cannot be restored. The new log is written to a
+//! temporary file beside the old, while the records still being appended to
+//! the old log are also kept, to be added to the new one before it takes the
+//! place of the old one by being renamed.
+//!
+//! For tests of recovery, the `fault-injection` feature offers a
+//! `FaultHook` through which writes to the log can be cut short, torn,
+//! damaged, or failed, and syncs failed, at chosen points.
+//!
+//! The committed state can also be saved to a snapshot, which starts with the
+//! magic bytes `SDBS` and a version byte, followed by the number of keys as a
+//! little-endian `u64` and each key and value in the same form as in the log,
+//! then the number of distinct values and each value with its count as an
+//! `i64`, then the number of hashes and each key with the number of its
+//! fields and each field and value, and finally the number of streams and
+//! each key with the number of its entries, and each identifier with the
+//! number of its fields and each field and value, then the number of lists
+//! and each key with the number of its values and each value, and then the
+//! number of sorted sets and each key with the number of its members and
+//! each member and its score, then the number of JSON documents and each
+//! key and document, and last the number of keys that expire and each key
+//! with its time. Older snapshots, version 1 without hashes, version 2
+//! without streams, and version 3 without lists, sorted sets, documents or
+//! times to live, can still be read. Loading a
+//! snapshot is much faster than replaying a long log. The snapshot of an
+//! encrypted database is sealed as a whole, as described in `crypt`.
+
+use crate::crypt::{self, Cipher, Secret};
+use crate::stream::{Entry, EntryRefs, StreamId};
+use compact_str::CompactString;
+use std::fs;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// operations recorded in the log
+const OP_SET: u8 = 1;
+const OP_UNSET: u8 = 2;
+const OP_HSET: u8 = 3;
+const OP_HDEL: u8 = 4;
+const OP_XADD: u8 = 5;
+const OP_BATCH: u8 = 6;
+const OP_TIME: u8 = 7;
+const OP_SEALED: u8 = 8;
+const OP_LPUSH: u8 = 9;
+const OP_RPUSH: u8 = 10;
+const OP_LPOP: u8 = 11;
+const OP_RPOP: u8 = 12;
+const OP_ZADD: u8 = 13;
+const OP_ZREM: u8 = 14;
+const OP_JSON: u8 = 15;
+const OP_EXPIRE: u8 = 16;
+const OP_PERSIST: u8 = 17;
+const OP_BASE: u8 = 18;
+
+// identifies a snapshot file and the version of its format
+const SNAPSHOT_MAGIC: &[u8; 4] = b"SDBS";
+const SNAPSHOT_VERSION: u8 = 4;
+
+///
+/// Keys and values that can be written to and read from the log as bytes.
+///
+pub trait Persistent: Sized {
+    /// Returns the bytes to be written for this key or value.
+    fn to_bytes(&self) -> &[u8];
+
+    /// Reconstruct a key or value from its bytes, returning `None` if they
+    /// are not valid for this type.
+    fn from_bytes(bytes: Vec<u8>) -> Option<Self>;
+}
+
+impl Persistent for String {
+    fn to_bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Self> {
+        String::from_utf8(bytes).ok()
+    }
+}
+
+impl Persistent for Vec<u8> {
+    fn to_bytes(&self) -> &[u8] {
+        self
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Self> {
+        Some(bytes)
+    }
+}
+
+impl Persistent for CompactString {
+    fn to_bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Self> {
+        CompactString::from_utf8(bytes).ok()
+    }
+}
+
+///
+/// How far the log is written before a change is considered made.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Changes are handed to the operating system, so that they survive the
+    /// process crashing but not necessarily the machine failing.
+    #[default]
+    Flush,
+    /// Changes are also synced to the disk, so that they survive the machine
+    /// failing, at the cost of waiting for the disk on every commit.
+    Sync,
+    /// Changes are synced to the disk once a second by a background thread,
+    /// so that the machine failing loses at most the last second of changes,
+    /// without commits waiting for the disk.
+    EverySecond,
+}
+
+///
+/// What was found in the log when it was opened.
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Number of changes replayed from the log.
+    pub replayed: usize,
+    /// Number of bytes discarded from the end of the log, as they did not
+    /// make up a complete record with a matching checksum, such as a record
+    /// that was being written when the process or the machine crashed.
+    pub discarded: u64,
+}
+
+///
+/// A point in the history of the log to which the database may be restored.
+/// Changes written before batches were timed always come before it, while a
+/// point before the log was last rewritten is no longer in it.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RestorePoint {
+    /// Just before the first change committed at or after the time.
+    Time(SystemTime),
+    /// After the given number of batches, each the changes of a transaction
+    /// or a single change, as counted by `Database::lsn()`.
+    Lsn(u64),
+}
+
+///
+/// A point in the writing of the log at which a fault may be injected.
+///
+#[cfg(any(test, feature = "fault-injection"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultPoint {
+    /// Bytes are about to be written at the offset from the start of the
+    /// file: the operation byte, length, contents, or checksum of a record.
+    Write { offset: u64, len: usize },
+    /// The file is about to be synced to the disk.
+    Sync,
+}
+
+///
+/// A fault to inject into the writing of the log. At a sync, any fault
+/// makes it fail.
+///
+#[cfg(any(test, feature = "fault-injection"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Write only the given number of the bytes, leaving the rest to be
+    /// written again, as a write may. Writing none is taken as `Fail`, since
+    /// a write that makes no progress would otherwise be retried forever.
+    Short(usize),
+    /// Write only the given number of the bytes and fail, as if the process
+    /// had crashed part way through a record.
+    Torn(usize),
+    /// Write the bytes with the one at the given index inverted, as if the
+    /// disk had damaged it.
+    Flip(usize),
+    /// Fail without writing anything.
+    Fail,
+}
+
+///
+/// Chooses the faults to inject into the writing of the log, for tests of
+/// recovery. Any closure taking a `FaultPoint` will do.
+///
+#[cfg(any(test, feature = "fault-injection"))]
+pub trait FaultHook: Send {
+    /// Returns the fault to inject at the point, if any.
+    fn inject(&mut self, point: FaultPoint) -> Option<Fault>;
+}
+
+#[cfg(any(test, feature = "fault-injection"))]
+impl<F> FaultHook for F
+where
+    F: FnMut(FaultPoint) -> Option<Fault> + Send,
+{
+    fn inject(&mut self, point: FaultPoint) -> Option<Fault> {
+        self(point)
+    }
+}
+
+/// Changes read back from the log, with what was recovered and discarded.
+pub(crate) type Replay<K, V> = (Vec<Mutation<K, V>>, RecoveryReport);
+
+/// The cipher of a log, if it is encrypted, with the changes read from it and
+/// the number of bytes they occupy.
+type LogContents<K, V> = (Option<Cipher>, Vec<Mutation<K, V>>, u64);
+
+///
+/// A change read back from the log.
+///
+pub(crate) enum Mutation<K, V> {
+    Set(K, V),
+    Unset(K),
+    /// key, field, value
+    HSet(K, K, V),
+    /// key, field
+    HDel(K, K),
+    /// key, entry identifier, fields and values
+    XAdd(K, StreamId, Vec<(K, V)>),
+    /// key, values pushed onto the front of the list one at a time
+    LPush(K, Vec<V>),
+    /// key, values pushed onto the end of the list
+    RPush(K, Vec<V>),
+    /// key of the list whose first value was popped
+    LPop(K),
+    /// key of the list whose last value was popped
+    RPop(K),
+    /// key, member, score
+    ZAdd(K, K, f64),
+    /// key, member
+    ZRem(K, K),
+    /// key, the whole of the document
+    Json(K, serde_json::Value),
+    /// key, the time at which it expires
+    Expire(K, SystemTime),
+    /// key that no longer expires
+    Persist(K),
+    /// milliseconds since the epoch at which the batch that follows was
+    /// written, marking where it starts
+    Time(u64),
+    /// the records that follow in the batch hold the whole of the state, as
+    /// written by a rewrite, with nothing before them
+    Base,
+}
+
+///
+/// Appends committed changes to the log file. The first failure to write is
+/// kept for the database to report, after which nothing more is written so
+/// that the log never has gaps in the middle.
+///
+pub(crate) struct WriteAheadLog<K, V> {
+    path: PathBuf,
+    writer: LogFile,
+    error: Option<io::Error>,
+    failed: bool,
+    // number of batches in the log, those written before batches were timed
+    // not counted
+    records: u64,
+    // number of batches when a rewrite of the log began
+    rewrite_start: u64,
+    durability: Durability,
+    // records of the batch being written, if any
+    batch: Option<Vec<u8>>,
+    // tells the thread syncing every second to stop, if there is one
+    syncer: Option<Arc<AtomicBool>>,
+    // seals each batch, if the log is encrypted
+    cipher: Option<Cipher>,
+    key_bytes: fn(&K) -> &[u8],
+    value_bytes: fn(&V) -> &[u8],
+}
+
+impl<K, V> WriteAheadLog<K, V>
+where
+    K: Persistent,
+    V: Persistent,
+{
+    /// Open the log at the given path, creating it if necessary, and return
+    /// the changes it already holds, in the order they were made, along with
+    /// what was recovered and discarded. Given a secret, the log is
+    /// encrypted, and one that already holds records that are not is refused.
+    pub fn open(path: &Path, secret: Option<&Secret>) -> io::Result<(Self, Replay<K, V>)> {
+        let (cipher, mutations, length) = match read_file(path, secret) {
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (None, Vec::new(), 0),
+            Err(err) => return Err(err),
+        };
+        let (cipher, empty) = match (cipher, secret) {
+            (Some(cipher), _) => (Some(cipher), false),
+            (None, Some(_)) if length > 0 => {
+                return Err(invalid(format!("{} is not encrypted", path.display())));
+            }
+            (None, Some(secret)) => (Some(Cipher::new(secret)?), true),
+            (None, None) => (None, false),
+        };
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        // drop any partial record so that new records follow the last one
+        // that was complete
+        let size = file.metadata()?.len();
+        if size > length {
+            file.set_len(length)?;
+        }
+        let times = mutations
+            .iter()
+            .filter(|mutation| matches!(mutation, Mutation::Time(_)))
+            .count();
+        let bases = mutations
+            .iter()
+            .filter(|mutation| matches!(mutation, Mutation::Base))
+            .count();
+        let report = RecoveryReport {
+            replayed: mutations.len() - times - bases,
+            discarded: size.saturating_sub(length),
+        };
+        let mut log = Self::new(path.to_owned(), file, cipher);
+        if empty {
+            log.write_header()?;
+        }
+        log.records = times as u64;
+        Ok((log, (mutations, report)))
+    }
+
+    /// Create a log at the given path, replacing any file already there, to
+    /// which changes are written by some other means than committing them,
+    /// such as an increment of a backup. Given a secret, the log is
+    /// encrypted.
+    pub fn create(path: &Path, secret: Option<&Secret>) -> io::Result<Self> {
+        let cipher = secret.map(Cipher::new).transpose()?;
+        let mut log = Self::new(path.to_owned(), fs::File::create(path)?, cipher);
+        log.write_header()?;
+        Ok(log)
+    }
+
+    fn new(path: PathBuf, file: fs::File, cipher: Option<Cipher>) -> Self {
+        Self {
+            path,
+            writer: LogFile {
+                #[cfg(any(test, feature = "fault-injection"))]
+                offset: file.metadata().map_or(0, |meta| meta.len()),
+                writer: BufWriter::new(file),
+                copy: None,
+                #[cfg(any(test, feature = "fault-injection"))]
+                faults: None,
+            },
+            error: None,
+            failed: false,
+            records: 0,
+            rewrite_start: 0,
+            durability: Durability::default(),
+            batch: None,
+            syncer: None,
+            cipher,
+            key_bytes: K::to_bytes,
+            value_bytes: V::to_bytes,
+        }
+    }
+
+    /// Write the header of an encrypted log to the file, which is empty.
+    fn write_header(&mut self) -> io::Result<()> {
+        if let Some(cipher) = self.cipher.as_ref() {
+            self.writer.write_all(&cipher.header())?;
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Start rewriting the log, returning a new log in a temporary file
+    /// beside this one to which the current state is to be written. Until
+    /// `finish_rewrite()` is called, whatever is written to this log is also
+    /// kept, to be added to the new log once the state has been written.
+    pub fn begin_rewrite(&mut self) -> io::Result<Self> {
+        if self.failed {
+            return Err(io::Error::other("writing to the log has failed"));
+        }
+        if self.writer.copy.is_some() {
+            return Err(io::Error::other("the log is already being rewritten"));
+        }
+        let mut temp = self.path.as_os_str().to_owned();
+        temp.push(".rewrite");
+        let temp = PathBuf::from(temp);
+        let file = fs::File::create(&temp)?;
+        // the records kept meanwhile are sealed with the key of this log, so
+        // the new log shares it
+        let mut rewritten = Self::new(temp, file, self.cipher.clone());
+        rewritten.write_header()?;
+        self.writer.copy = Some(Vec::new());
+        self.rewrite_start = self.records;
+        Ok(rewritten)
+    }
+
+    /// Add what was written to this log since `begin_rewrite()` to the new
+    /// log and replace this log with it, so that from then on records are
+    /// appended to the new log. If anything fails, the new log is removed
+    /// and this one is kept as it was.
+    pub fn finish_rewrite(&mut self, mut rewritten: Self) -> io::Result<()> {
+        let copy = self.writer.copy.take().unwrap_or_default();
+        let result = if self.failed {
+            Err(io::Error::other("writing to the log has failed"))
+        } else if rewritten.failed {
+            Err(rewritten
+                .take_error()
+                .unwrap_or_else(|| io::Error::other("writing the new log has failed")))
+        } else {
+            rewritten
+                .writer
+                .write_all(&copy)
+                .and_then(|_| rewritten.writer.flush())
+                .and_then(|_| rewritten.writer.sync())
+                .and_then(|_| fs::rename(&rewritten.path, &self.path))
+        };
+        if let Err(err) = result {
+            let _ = fs::remove_file(&rewritten.path);
+            return Err(err);
+        }
+        std::mem::swap(&mut self.writer, &mut rewritten.writer);
+        #[cfg(any(test, feature = "fault-injection"))]
+        {
+            self.writer.faults = rewritten.writer.faults.take();
+        }
+        self.records = rewritten.records + (self.records - self.rewrite_start);
+        // the thread syncing every second has the old file
+        self.set_durability(self.durability);
+        Ok(())
+    }
+}
+
+impl<K, V> WriteAheadLog<K, V> {
+    /// Set how far the log is written when it is flushed.
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+        self.stop_syncer();
+        if durability == Durability::EverySecond {
+            match self.writer.file().try_clone() {
+                Ok(file) => {
+                    let stop = Arc::new(AtomicBool::new(false));
+                    let stopped = stop.clone();
+                    thread::spawn(move || loop {
+                        thread::sleep(Duration::from_secs(1));
+                        if stopped.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        // no commit waits on this sync, so there is nothing
+                        // to which a failure could be reported
+                        let _ = file.sync_data();
+                    });
+                    self.syncer = Some(stop);
+                }
+                Err(err) => self.check(Err(err)),
+            }
+        }
+    }
+
+    /// Inject the faults chosen by the hook into the writing of the log from
+    /// now on.
+    #[cfg(any(test, feature = "fault-injection"))]
+    pub fn set_fault_hook(&mut self, hook: Box<dyn FaultHook>) {
+        self.writer.faults = Some(hook);
+    }
+
+    fn stop_syncer(&mut self) {
+        if let Some(stop) = self.syncer.take() {
+            stop.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Start collecting the records that follow into a batch, to be written
+    /// by `end_batch()` as a single record.
+    pub fn begin_batch(&mut self) {
+        self.batch = Some(Vec::new());
+    }
+
+    /// Write the records collected since `begin_batch()` as one record.
+    pub fn end_batch(&mut self) {
+        let Some(records) = self.batch.take() else {
+            return;
+        };
+        if self.failed || records.is_empty() {
+            return;
+        }
+        let result = write_batch(&mut self.writer, self.cipher.as_ref(), &records);
+        self.counted(result);
+    }
+
+    /// Returns the number of batches in the log, each the changes of a
+    /// transaction or a single change, which is where `RestorePoint::Lsn`
+    /// with that number would restore to.
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Returns the path of the log.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn counted(&mut self, result: io::Result<()>) {
+        if result.is_ok() {
+            self.records += 1;
+        }
+        self.check(result);
+    }
+
+    /// Write a record into the batch being collected or, if there is none,
+    /// to the file as a batch of its own, so that every record is covered by
+    /// a checksum.
+    fn record<F>(&mut self, write: F)
+    where
+        F: FnOnce(&mut Vec<u8>) -> io::Result<()>,
+    {
+        if self.failed {
+            return;
+        }
+        match self.batch.as_mut() {
+            Some(records) => {
+                let result = write(records);
+                self.check(result);
+            }
+            None => {
+                let mut records = Vec::new();
+                let cipher = self.cipher.as_ref();
+                let result = write(&mut records)
+                    .and_then(|_| write_batch(&mut self.writer, cipher, &records));
+                self.counted(result);
+            }
+        }
+    }
+
+    /// Record that the key was given the value.
+    pub fn set(&mut self, name: &K, value: &V) {
+        let (name, value) = ((self.key_bytes)(name), (self.value_bytes)(value));
+        self.record(|out| write_record(out, OP_SET, name, Some(value)));
+    }
+
+    /// Record that the key was removed.
+    pub fn unset(&mut self, name: &K) {
+        let name = (self.key_bytes)(name);
+        self.record(|out| write_record(out, OP_UNSET, name, None));
+    }
+
+    /// Record that the field of the hash held by the key was given the value.
+    pub fn hset(&mut self, name: &K, field: &K, value: &V) {
+        let (name, field) = ((self.key_bytes)(name), (self.key_bytes)(field));
+        let value = (self.value_bytes)(value);
+        self.record(|out| {
+            write_record(out, OP_HSET, name, Some(field))?;
+            write_bytes(out, value)
+        });
+    }
+
+    /// Record that the field was removed from the hash held by the key.
+    pub fn hdel(&mut self, name: &K, field: &K) {
+        let (name, field) = ((self.key_bytes)(name), (self.key_bytes)(field));
+        self.record(|out| write_record(out, OP_HDEL, name, Some(field)));
+    }
+
+    /// Record that the entry was added to the stream held by the key.
+    pub fn xadd(&mut self, name: &K, id: StreamId, fields: &[(K, V)]) {
+        let name = (self.key_bytes)(name);
+        let (key_bytes, value_bytes) = (self.key_bytes, self.value_bytes);
+        self.record(|out| {
+            write_record(out, OP_XADD, name, Some(&id.to_bytes()))?;
+            write_count(out, fields.len())?;
+            for (field, value) in fields {
+                write_bytes(out, key_bytes(field))?;
+                write_bytes(out, value_bytes(value))?;
+            }
+            Ok(())
+        });
+    }
+
+    /// Record that the values were pushed onto the front of the list held by
+    /// the key, one at a time, or onto the end of it.
+    pub fn push<'a, I>(&mut self, name: &K, values: I, front: bool)
+    where
+        I: ExactSizeIterator<Item = &'a V>,
+        V: 'a,
+    {
+        let name = (self.key_bytes)(name);
+        let value_bytes = self.value_bytes;
+        let op = if front { OP_LPUSH } else { OP_RPUSH };
+        self.record(|out| {
+            write_record(out, op, name, None)?;
+            write_count(out, values.len())?;
+            for value in values {
+                write_bytes(out, value_bytes(value))?;
+            }
+            Ok(())
+        });
+    }
+
+    /// Record that a value was popped from the front of the list held by the
+    /// key, or from the end of it.
+    pub fn pop(&mut self, name: &K, front: bool) {
+        let name = (self.key_bytes)(name);
+        let op = if front { OP_LPOP } else { OP_RPOP };
+        self.record(|out| write_record(out, op, name, None));
+    }
+
+    /// Record that the member of the sorted set held by the key was given
+    /// the score.
+    pub fn zadd(&mut self, name: &K, member: &K, score: f64) {
+        let (name, member) = ((self.key_bytes)(name), (self.key_bytes)(member));
+        self.record(|out| {
+            write_record(out, OP_ZADD, name, Some(member))?;
+            out.write_all(&score.to_le_bytes())
+        });
+    }
+
+    /// Record that the member was removed from the sorted set held by the
+    /// key.
+    pub fn zrem(&mut self, name: &K, member: &K) {
+        let (name, member) = ((self.key_bytes)(name), (self.key_bytes)(member));
+        self.record(|out| write_record(out, OP_ZREM, name, Some(member)));
+    }
+
+    /// Record that the key was given the JSON document.
+    pub fn json(&mut self, name: &K, document: &serde_json::Value) {
+        let name = (self.key_bytes)(name);
+        self.record(|out| {
+            let text = serde_json::to_vec(document).map_err(io::Error::other)?;
+            write_record(out, OP_JSON, name, Some(&text))
+        });
+    }
+
+    /// Record the time at which the key expires.
+    pub fn expire(&mut self, name: &K, deadline: SystemTime) {
+        let name = (self.key_bytes)(name);
+        self.record(|out| {
+            write_record(out, OP_EXPIRE, name, None)?;
+            out.write_all(&to_millis(deadline).to_le_bytes())
+        });
+    }
+
+    /// Record that the key no longer expires.
+    pub fn persist(&mut self, name: &K) {
+        let name = (self.key_bytes)(name);
+        self.record(|out| write_record(out, OP_PERSIST, name, None));
+    }
+
+    /// Record that the records which follow in the batch hold the whole of
+    /// the state, so that the log cannot be restored to any earlier point.
+    pub fn base(&mut self) {
+        self.record(|out| out.write_all(&[OP_BASE]));
+    }
+
+    /// Write out any buffered records.
+    pub fn flush(&mut self) {
+        if self.failed {
+            return;
+        }
+        let mut result = self.writer.flush();
+        if self.durability == Durability::Sync {
+            result = result.and_then(|_| self.writer.sync());
+        }
+        self.check(result);
+    }
+
+    /// Returns true if writing to the log has failed, after which nothing
+    /// more is written.
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Returns the first error encountered writing to the log, if any, and
+    /// not already returned.
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    fn check(&mut self, result: io::Result<()>) {
+        if let Err(err) = result {
+            if !self.failed {
+                self.error = Some(err);
+                self.failed = true;
+            }
+        }
+    }
+}
+
+///
+/// The file of the log, which keeps a copy of what is written to it while the
+/// log is being rewritten.
+///
+struct LogFile {
+    writer: BufWriter<fs::File>,
+    copy: Option<Vec<u8>>,
+    // where the next byte is written, for the fault hook
+    #[cfg(any(test, feature = "fault-injection"))]
+    offset: u64,
+    #[cfg(any(test, feature = "fault-injection"))]
+    faults: Option<Box<dyn FaultHook>>,
+}
+
+impl LogFile {
+    fn file(&self) -> &fs::File {
+        self.writer.get_ref()
+    }
+
+    /// Sync what has been flushed to the disk.
+    fn sync(&mut self) -> io::Result<()> {
+        #[cfg(any(test, feature = "fault-injection"))]
+        if let Some(hook) = self.faults.as_mut() {
+            if hook.inject(FaultPoint::Sync).is_some() {
+                return Err(io::Error::other("injected sync failure"));
+            }
+        }
+        self.writer.get_ref().sync_data()
+    }
+
+    fn put(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        if let Some(copy) = self.copy.as_mut() {
+            copy.extend_from_slice(&buf[..written]);
+        }
+        #[cfg(any(test, feature = "fault-injection"))]
+        {
+            self.offset += written as u64;
+        }
+        Ok(written)
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        #[cfg(any(test, feature = "fault-injection"))]
+        if let Some(hook) = self.faults.as_mut() {
+            let point = FaultPoint::Write {
+                offset: self.offset,
+                len: buf.len(),
+            };
+            match hook.inject(point) {
+                Some(Fault::Short(len)) if len > 0 || buf.is_empty() => {
+                    return self.put(&buf[..len.min(buf.len())]);
+                }
+                Some(Fault::Torn(len)) => {
+                    let mut torn = &buf[..len.min(buf.len())];
+                    while !torn.is_empty() {
+                        let written = self.put(torn)?;
+                        torn = &torn[written..];
+                    }
+                    return Err(io::Error::other("injected torn write"));
+                }
+                Some(Fault::Flip(index)) if index < buf.len() => {
+                    let mut damaged = buf.to_vec();
+                    damaged[index] ^= 0xff;
+                    let written = self.put(&damaged)?;
+                    return Ok(written);
+                }
+                Some(Fault::Flip(_)) | None => {}
+                Some(Fault::Short(_)) | Some(Fault::Fail) => {
+                    return Err(io::Error::other("injected write failure"));
+                }
+            }
+        }
+        self.put(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<K, V> Drop for WriteAheadLog<K, V> {
+    fn drop(&mut self) {
+        self.stop_syncer();
+    }
+}
+
+/// Write the records as a batch, preceded by the time and followed by their
+/// checksum, or sealed if there is a cipher.
+fn write_batch<W: Write + ?Sized>(
+    writer: &mut W,
+    cipher: Option<&Cipher>,
+    records: &[u8],
+) -> io::Result<()> {
+    let millis = to_millis(SystemTime::now());
+    let mut batch = Vec::with_capacity(9 + records.len());
+    batch.push(OP_TIME);
+    batch.extend_from_slice(&millis.to_le_bytes());
+    batch.extend_from_slice(records);
+    if let Some(cipher) = cipher {
+        return write_record(writer, OP_SEALED, &cipher.seal(&batch)?, None);
+    }
+    write_record(writer, OP_BATCH, &batch, None)?;
+    writer.write_all(&checksum(&batch).to_le_bytes())
+}
+
+/// Returns the time as milliseconds since the epoch, as it is written.
+pub(crate) fn to_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn write_record<W: Write + ?Sized>(
+    writer: &mut W,
+    op: u8,
+    name: &[u8],
+    value: Option<&[u8]>,
+) -> io::Result<()> {
+    writer.write_all(&[op])?;
+    write_bytes(writer, name)?;
+    if let Some(value) = value {
+        write_bytes(writer, value)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn write_bytes<W: Write + ?Sized>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
+    let length = u32::try_from(bytes.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "key or value too large"))?;
+    writer.write_all(&length.to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+pub(crate) fn write_count<W: Write + ?Sized>(writer: &mut W, count: usize) -> io::Result<()> {
+    let count = u32::try_from(count)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many fields"))?;
+    writer.write_all(&count.to_le_bytes())
+}
+
+/// Read the changes in a log written in its entirety, such as an increment of
+/// a backup, failing if any of it is missing or damaged.
+pub(crate) fn read_changes<K, V>(
+    path: &Path,
+    secret: Option<&Secret>,
+) -> io::Result<Vec<Mutation<K, V>>>
+where
+    K: Persistent,
+    V: Persistent,
+{
+    let size = fs::metadata(path)?.len();
+    let (_, mutations, length) = read_file(path, secret)?;
+    if length != size {
+        return Err(invalid(format!("{} is damaged", path.display())));
+    }
+    Ok(mutations)
+}
+
+/// Read the changes in the log up to the point in its history.
+pub(crate) fn read_until<K, V>(
+    path: &Path,
+    secret: Option<&Secret>,
+    point: RestorePoint,
+) -> io::Result<Vec<Mutation<K, V>>>
+where
+    K: Persistent,
+    V: Persistent,
+{
+    let (_, mut mutations, _) = read_file(path, secret)?;
+    let mut batches = 0;
+    let end = mutations.iter().position(|mutation| {
+        let Mutation::Time(millis) = mutation else {
+            return false;
+        };
+        batches += 1;
+        match point {
+            RestorePoint::Time(time) => UNIX_EPOCH + Duration::from_millis(*millis) >= time,
+            RestorePoint::Lsn(lsn) => batches > lsn,
+        }
+    });
+    let base = mutations
+        .iter()
+        .position(|mutation| matches!(mutation, Mutation::Base));
+    if let Some(end) = end {
+        if base.is_some_and(|base| end < base) {
+            return Err(io::Error::other(
+                "the log was rewritten after the point, and no longer holds it",
+            ));
+        }
+        mutations.truncate(end);
+    }
+    Ok(mutations)
+}
+
+/// Read the complete records from the log in the file, returning the cipher
+/// of the log if it is encrypted, the changes, and the number of bytes they
+/// occupy along with the header.
+fn read_file<K, V>(path: &Path, secret: Option<&Secret>) -> io::Result<LogContents<K, V>>
+where
+    K: Persistent,
+    V: Persistent,
+{
+    let mut reader = io::BufReader::new(fs::File::open(path)?);
+    let cipher = crypt::read_header(&mut reader, secret, path)?;
+    let (mutations, mut length) = read_log(reader, cipher.as_ref())?;
+    if cipher.is_some() {
+        length += crypt::HEADER_LEN as u64;
+    }
+    Ok((cipher, mutations, length))
+}
+
+/// Read all of the complete records from the log, returning them along with
+/// the number of bytes they occupy. Given a cipher, only sealed batches are
+/// read.
+fn read_log<R, K, V>(reader: R, cipher: Option<&Cipher>) -> io::Result<(Vec<Mutation<K, V>>, u64)>
+where
+    R: Read,
+    K: Persistent,
+    V: Persistent,
+{
+    let mut reader = CountingReader {
+        inner: reader,
+        count: 0,
+    };
+    let mut mutations = Vec::new();
+    let mut length = 0;
+    loop {
+        let mut op = [0u8; 1];
+        if reader.read(&mut op)? == 0 {
+            break;
+        }
+        if cipher.is_some() && op[0] != OP_SEALED {
+            break;
+        }
+        let mutation = match op[0] {
+            OP_SET => {
+                let (Some(name), Some(value)) =
+                    (read_bytes(&mut reader)?, read_bytes(&mut reader)?)
+                else {
+                    break;
+                };
+                Mutation::Set(decode(name)?, decode(value)?)
+            }
+            OP_UNSET => match read_bytes(&mut reader)? {
+                Some(name) => Mutation::Unset(decode(name)?),
+                None => break,
+            },
+            OP_HSET => {
+                let (Some(name), Some(field), Some(value)) = (
+                    read_bytes(&mut reader)?,
+                    read_bytes(&mut reader)?,
+                    read_bytes(&mut reader)?,
+                ) else {
+                    break;
+                };
+                Mutation::HSet(decode(name)?, decode(field)?, decode(value)?)
+            }
+            OP_HDEL => {
+                let (Some(name), Some(field)) =
+                    (read_bytes(&mut reader)?, read_bytes(&mut reader)?)
+                else {
+                    break;
+                };
+                Mutation::HDel(decode(name)?, decode(field)?)
+            }
+            OP_XADD => match read_entry(&mut reader)? {
+                Some((name, (id, fields))) => Mutation::XAdd(name, id, fields),
+                None => break,
+            },
+            OP_LPUSH | OP_RPUSH => {
+                let Some(name) = read_bytes(&mut reader)? else {
+                    break;
+                };
+                let Some(values) = read_values(&mut reader)? else {
+                    break;
+                };
+                match op[0] {
+                    OP_LPUSH => Mutation::LPush(decode(name)?, values),
+                    _ => Mutation::RPush(decode(name)?, values),
+                }
+            }
+            OP_ZADD => {
+                let (Some(name), Some(member)) =
+                    (read_bytes(&mut reader)?, read_bytes(&mut reader)?)
+                else {
+                    break;
+                };
+                let mut score = [0u8; 8];
+                if reader.read_exact(&mut score).is_err() {
+                    break;
+                }
+                let score = f64::from_le_bytes(score);
+                if score.is_nan() {
+                    return Err(invalid("sorted set member without a score"));
+                }
+                Mutation::ZAdd(decode(name)?, decode(member)?, score)
+            }
+            OP_ZREM => {
+                let (Some(name), Some(member)) =
+                    (read_bytes(&mut reader)?, read_bytes(&mut reader)?)
+                else {
+                    break;
+                };
+                Mutation::ZRem(decode(name)?, decode(member)?)
+            }
+            OP_JSON => {
+                let (Some(name), Some(text)) = (read_bytes(&mut reader)?, read_bytes(&mut reader)?)
+                else {
+                    break;
+                };
+                Mutation::Json(decode(name)?, decode_json(&text)?)
+            }
+            OP_EXPIRE => {
+                let Some(name) = read_bytes(&mut reader)? else {
+                    break;
+                };
+                let mut millis = [0u8; 8];
+                if reader.read_exact(&mut millis).is_err() {
+                    break;
+                }
+                let deadline = UNIX_EPOCH + Duration::from_millis(u64::from_le_bytes(millis));
+                Mutation::Expire(decode(name)?, deadline)
+            }
+            OP_PERSIST => match read_bytes(&mut reader)? {
+                Some(name) => Mutation::Persist(decode(name)?),
+                None => break,
+            },
+            OP_LPOP | OP_RPOP => match read_bytes(&mut reader)? {
+                Some(name) if op[0] == OP_LPOP => Mutation::LPop(decode(name)?),
+                Some(name) => Mutation::RPop(decode(name)?),
+                None => break,
+            },
+            OP_BASE => Mutation::Base,
+            OP_TIME => {
+                let mut millis = [0u8; 8];
+                if reader.read_exact(&mut millis).is_err() {
+                    break;
+                }
+                Mutation::Time(u64::from_le_bytes(millis))
+            }
+            OP_BATCH => {
+                let Some(records) = read_bytes(&mut reader)? else {
+                    break;
+                };
+                let mut expected = [0u8; 4];
+                if reader.read_exact(&mut expected).is_err()
+                    || u32::from_le_bytes(expected) != checksum(&records)
+                {
+                    break;
+                }
+                mutations.extend(read_batch(&records)?);
+                length = reader.count;
+                continue;
+            }
+            OP_SEALED => {
+                let Some(cipher) = cipher else {
+                    return Err(invalid("encrypted record in a log that is not encrypted"));
+                };
+                let Some(sealed) = read_bytes(&mut reader)? else {
+                    break;
+                };
+                // a box that does not open was torn or tampered with
+                let Some(records) = cipher.open(&sealed) else {
+                    break;
+                };
+                mutations.extend(read_batch(&records)?);
+                length = reader.count;
+                continue;
+            }
+            // the rest is garbage, such as where a torn write left zeros
+            _ => break,
+        };
+        mutations.push(mutation);
+        length = reader.count;
+    }
+    Ok((mutations, length))
+}
+
+/// Read the records within a batch, all of which must be complete.
+fn read_batch<K, V>(records: &[u8]) -> io::Result<Vec<Mutation<K, V>>>
+where
+    K: Persistent,
+    V: Persistent,
+{
+    let (batch, read) = read_log(records, None)?;
+    if read != records.len() as u64 {
+        return Err(invalid("incomplete record within a batch"));
+    }
+    Ok(batch)
+}
+
+/// Read the rest of a record of an entry added to a stream, returning `None`
+/// if the input ends before all of it could be read.
+fn read_entry<R, K, V>(reader: &mut R) -> io::Result<Option<(K, Entry<K, V>)>>
+where
+    R: Read,
+    K: Persistent,
+    V: Persistent,
+{
+    let (Some(name), Some(id)) = (read_bytes(reader)?, read_bytes(reader)?) else {
+        return Ok(None);
+    };
+    let id = StreamId::from_bytes(&id).ok_or_else(|| invalid("invalid stream entry identifier"))?;
+    let mut count = [0u8; 4];
+    if let Err(err) = reader.read_exact(&mut count) {
+        return end_of_input(err);
+    }
+    let mut fields = Vec::new();
+    for _ in 0..u32::from_le_bytes(count) {
+        let (Some(field), Some(value)) = (read_bytes(reader)?, read_bytes(reader)?) else {
+            return Ok(None);
+        };
+        fields.push((decode(field)?, decode(value)?));
+    }
+    Ok(Some((decode(name)?, (id, fields))))
+}
+
+/// Read the count and values of a push onto a list, returning `None` if the
+/// input ends before all of them could be read.
+fn read_values<R, V>(reader: &mut R) -> io::Result<Option<Vec<V>>>
+where
+    R: Read,
+    V: Persistent,
+{
+    let mut count = [0u8; 4];
+    if let Err(err) = reader.read_exact(&mut count) {
+        return end_of_input(err);
+    }
+    let mut values = Vec::new();
+    for _ in 0..u32::from_le_bytes(count) {
+        let Some(value) = read_bytes(reader)? else {
+            return Ok(None);
+        };
+        values.push(decode(value)?);
+    }
+    Ok(Some(values))
+}
+
+/// Returns the FNV-1a hash of the bytes, which is enough to tell a batch torn
+/// by a crash from one written completely.
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for byte in bytes.iter() {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+///
+/// Keeps track of the number of bytes read.
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Read a length-prefixed byte string, returning `None` if the input ends
+/// before all of it could be read.
+pub(crate) fn read_bytes<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut length = [0u8; 4];
+    if let Err(err) = reader.read_exact(&mut length) {
+        return end_of_input(err);
+    }
+    let length = u32::from_le_bytes(length) as usize;
+    let mut bytes = Vec::new();
+    reader.take(length as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < length {
+        return Ok(None);
+    }
+    Ok(Some(bytes))
+}
+
+fn end_of_input<T>(err: io::Error) -> io::Result<Option<T>> {
+    if err.kind() == io::ErrorKind::UnexpectedEof {
+        Ok(None)
+    } else {
+        Err(err)
+    }
+}
+
+pub(crate) fn decode<T: Persistent>(bytes: Vec<u8>) -> io::Result<T> {
+    T::from_bytes(bytes)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid key or value"))
+}
+
+fn decode_json(text: &[u8]) -> io::Result<serde_json::Value> {
+    serde_json::from_slice(text).map_err(|_| invalid("invalid JSON document"))
+}
+
+///
+/// Contents of a snapshot file.
+///
+#[derive(Debug)]
+pub(crate) struct SnapshotContents<K, V> {
+    /// Keys with their values.
+    pub entries: Vec<(K, V)>,
+    /// Count of each distinct value.
+    pub counts: Vec<(V, i64)>,
+    /// Keys with the fields and values of their hashes.
+    pub hashes: Vec<(K, Vec<(K, V)>)>,
+    /// Keys with the entries of their streams.
+    pub streams: Vec<(K, Vec<Entry<K, V>>)>,
+    /// Keys with the values of their lists.
+    pub lists: Vec<(K, Vec<V>)>,
+    /// Keys with the members and scores of their sorted sets.
+    pub sorted: Vec<(K, Vec<(K, f64)>)>,
+    /// Keys with their JSON documents.
+    pub documents: Vec<(K, serde_json::Value)>,
+    /// Keys with the times at which they expire.
+    pub expiry: Vec<(K, SystemTime)>,
+}
+
+/// Fields and values of a hash to be written to a snapshot.
+pub(crate) type HashEntries<'a, K, V> = Vec<(&'a K, &'a V)>;
+
+///
+/// What is written to a snapshot, borrowed from the state being saved, in
+/// the same order as `SnapshotContents`.
+///
+pub(crate) struct SnapshotRefs<'a, K, V> {
+    pub entries: Vec<(&'a K, &'a V)>,
+    pub counts: Vec<(&'a V, i64)>,
+    pub hashes: Vec<(&'a K, HashEntries<'a, K, V>)>,
+    pub streams: Vec<(&'a K, EntryRefs<'a, K, V>)>,
+    pub lists: Vec<(&'a K, Vec<&'a V>)>,
+    pub sorted: Vec<(&'a K, Vec<(&'a K, f64)>)>,
+    pub documents: Vec<(&'a K, &'a serde_json::Value)>,
+    pub expiry: Vec<(&'a K, SystemTime)>,
+}
+
+/// Write a snapshot to the file at the given path, replacing it only once the
+/// snapshot has been written completely. Given a secret, the snapshot is
+/// encrypted.
+pub(crate) fn save_snapshot<K, V>(
+    path: &Path,
+    secret: Option<&Secret>,
+    snapshot: &SnapshotRefs<K, V>,
+) -> io::Result<()>
+where
+    K: Persistent,
+    V: Persistent,
+{
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let mut writer = BufWriter::new(fs::File::create(&temp)?);
+    match secret {
+        Some(secret) => {
+            let mut contents = Vec::new();
+            write_snapshot(&mut contents, snapshot)?;
+            writer.write_all(&crypt::seal_file(secret, &contents)?)?;
+        }
+        None => write_snapshot(&mut writer, snapshot)?,
+    }
+    let file = writer.into_inner().map_err(|err| err.into_error())?;
+    file.sync_all()?;
+    fs::rename(&temp, path)
+}
+
+fn write_snapshot<W, K, V>(writer: &mut W, snapshot: &SnapshotRefs<K, V>) -> io::Result<()>
+where
+    W: Write,
+    K: Persistent,
+    V: Persistent,
+{
+    writer.write_all(SNAPSHOT_MAGIC)?;
+    writer.write_all(&[SNAPSHOT_VERSION])?;
+    writer.write_all(&(snapshot.entries.len() as u64).to_le_bytes())?;
+    for (name, value) in snapshot.entries.iter() {
+        write_bytes(writer, name.to_bytes())?;
+        write_bytes(writer, value.to_bytes())?;
+    }
+    writer.write_all(&(snapshot.counts.len() as u64).to_le_bytes())?;
+    for (value, count) in snapshot.counts.iter() {
+        write_bytes(writer, value.to_bytes())?;
+        writer.write_all(&count.to_le_bytes())?;
+    }
+    writer.write_all(&(snapshot.hashes.len() as u64).to_le_bytes())?;
+    for (name, fields) in snapshot.hashes.iter() {
+        write_bytes(writer, name.to_bytes())?;
+        writer.write_all(&(fields.len() as u64).to_le_bytes())?;
+        for (field, value) in fields {
+            write_bytes(writer, field.to_bytes())?;
+            write_bytes(writer, value.to_bytes())?;
+        }
+    }
+    writer.write_all(&(snapshot.streams.len() as u64).to_le_bytes())?;
+    for (name, stream) in snapshot.streams.iter() {
+        write_bytes(writer, name.to_bytes())?;
+        writer.write_all(&(stream.len() as u64).to_le_bytes())?;
+        for (id, fields) in stream.iter() {
+            writer.write_all(&id.to_bytes())?;
+            writer.write_all(&(fields.len() as u64).to_le_bytes())?;
+            for (field, value) in fields.iter() {
+                write_bytes(writer, field.to_bytes())?;
+                write_bytes(writer, value.to_bytes())?;
+            }
+        }
+    }
+    writer.write_all(&(snapshot.lists.len() as u64).to_le_bytes())?;
+    for (name, values) in snapshot.lists.iter() {
+        write_bytes(writer, name.to_bytes())?;
+        writer.write_all(&(values.len() as u64).to_le_bytes())?;
+        for value in values.iter() {
+            write_bytes(writer, value.to_bytes())?;
+        }
+    }
+    writer.write_all(&(snapshot.sorted.len() as u64).to_le_bytes())?;
+    for (name, members) in snapshot.sorted.iter() {
+        write_bytes(writer, name.to_bytes())?;
+        writer.write_all(&(members.len() as u64).to_le_bytes())?;
+        for (member, score) in members.iter() {
+            write_bytes(writer, member.to_bytes())?;
+            writer.write_all(&score.to_le_bytes())?;
+        }
+    }
+    writer.write_all(&(snapshot.documents.len() as u64).to_le_bytes())?;
+    for (name, document) in snapshot.documents.iter() {
+        write_bytes(writer, name.to_bytes())?;
+        write_bytes(
+            writer,
+            &serde_json::to_vec(document).map_err(io::Error::other)?,
+        )?;
+    }
+    writer.write_all(&(snapshot.expiry.len() as u64).to_le_bytes())?;
+    for (name, deadline) in snapshot.expiry.iter() {
+        write_bytes(writer, name.to_bytes())?;
+        writer.write_all(&to_millis(*deadline).to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Read the snapshot in the file at the given path, which the secret decrypts
+/// if it is encrypted.
+pub(crate) fn load_snapshot<K, V>(
+    path: &Path,
+    secret: Option<&Secret>,
+) -> io::Result<SnapshotContents<K, V>>
+where
+    K: Persistent,
+    V: Persistent,
+{
+    read_snapshot(crypt::open_file(path, secret)?)
+}
+
+fn read_snapshot<R, K, V>(mut reader: R) -> io::Result<SnapshotContents<K, V>>
+where
+    R: Read,
+    K: Persistent,
+    V: Persistent,
+{
+    let mut header = [0u8; 5];
+    reader.read_exact(&mut header).map_err(truncated)?;
+    if &header[..4] != SNAPSHOT_MAGIC {
+        return Err(invalid("not a snapshot file"));
+    }
+    let version = header[4];
+    if !(1..=SNAPSHOT_VERSION).contains(&version) {
+        return Err(invalid(format!(
+            "unsupported snapshot version {}",
+            header[4]
+        )));
+    }
+    let length = read_u64(&mut reader)?;
+    let mut entries = Vec::new();
+    for _ in 0..length {
+        let name = read_bytes(&mut reader)?.ok_or_else(truncated_snapshot)?;
+        let value = read_bytes(&mut reader)?.ok_or_else(truncated_snapshot)?;
+        entries.push((decode(name)?, decode(value)?));
+    }
+    let length = read_u64(&mut reader)?;
+    let mut counts = Vec::new();
+    for _ in 0..length {
+        let value = read_bytes(&mut reader)?.ok_or_else(truncated_snapshot)?;
+        let count = read_u64(&mut reader)? as i64;
+        counts.push((decode(value)?, count));
+    }
+    let mut hashes = Vec::new();
+    if version > 1 {
+        let length = read_u64(&mut reader)?;
+        for _ in 0..length {
+            let name = read_bytes(&mut reader)?.ok_or_else(truncated_snapshot)?;
+            let mut fields = Vec::new();
+            for _ in 0..read_u64(&mut reader)? {
+                let field = read_bytes(&mut reader)?.ok_or_else(truncated_snapshot)?;
+                let value = read_bytes(&mut reader)?.ok_or_else(truncated_snapshot)?;
+                fields.push((decode(field)?, decode(value)?));
+            }
+            hashes.push((decode(name)?, fields));
+        }
+    }
+    let mut streams = Vec::new();
+    if version > 2 {
+        let length = read_u64(&mut reader)?;
+        for _ in 0..length {
+            let name = read_bytes(&mut reader)?.ok_or_else(truncated_snapshot)?;
+            let mut stream = Vec::new();
+            for _ in 0..read_u64(&mut reader)? {
+                let mut id = [0u8; 16];
+                reader.read_exact(&mut id).map_err(truncated)?;
+                let id = StreamId::from_bytes(&id).ok_or_else(truncated_snapshot)?;
+                let mut fields = Vec::new();
+                for _ in 0..read_u64(&mut reader)? {
+                    let field = read_bytes(&mut reader)?.ok_or_else(truncated_snapshot)?;
+                    let value = read_bytes(&mut reader)?.ok_or_else(truncated_snapshot)?;
+                    fields.push((decode(field)?, decode(value)?));
+                }
+                stream.push((id, fields));
+            }
+            streams.push((decode(name)?, stream));
+        }
+    }
+    let mut lists = Vec::new();
+    if version > 3 {
+        let length = read_u64(&mut reader)?;
+        for _ in 0..length {
+            let name = read_bytes(&mut reader)?.ok_or_else(truncated_snapshot)?;
+            let mut values = Vec::new();
+            for _ in 0..read_u64(&mut reader)? {
+                let value = read_bytes(&mut reader)?.ok_or_else(truncated_snapshot)?;
+                values.push(decode(value)?);
+            }
+            lists.push((decode(name)?, values));
+        }
+    }
+    let mut sorted = Vec::new();
+    if version > 3 {
+        let length = read_u64(&mut reader)?;
+        for _ in 0..length {
+            let name = read_bytes(&mut reader)?.ok_or_else(truncated_snapshot)?;
+            let mut members = Vec::new();
+            for _ in 0..read_u64(&mut reader)? {
+                let member = read_bytes(&mut reader)?.ok_or_else(truncated_snapshot)?;
+                let score = f64::from_bits(read_u64(&mut reader)?);
+                if score.is_nan() {
+                    return Err(invalid("sorted set member without a score"));
+                }
+                members.push((decode(member)?, score));
+            }
+            sorted.push((decode(name)?, members));
+        }
+    }
+    let mut documents = Vec::new();
+    if version > 3 {
+        let length = read_u64(&mut reader)?;
+        for _ in 0..length {
+            let name = read_bytes(&mut reader)?.ok_or_else(truncated_snapshot)?;
+            let text = read_bytes(&mut reader)?.ok_or_else(truncated_snapshot)?;
+            documents.push((decode(name)?, decode_json(&text)?));
+        }
+    }
+    let mut expiry = Vec::new();
+    if version > 3 {
+        let length = read_u64(&mut reader)?;
+        for _ in 0..length {
+            let name = read_bytes(&mut reader)?.ok_or_else(truncated_snapshot)?;
+            let millis = read_u64(&mut reader)?;
+            expiry.push((decode(name)?, UNIX_EPOCH + Duration::from_millis(millis)));
+        }
+    }
+    Ok(SnapshotContents {
+        entries,
+        counts,
+        hashes,
+        streams,
+        lists,
+        sorted,
+        documents,
+        expiry,
+    })
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes).map_err(truncated)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub(crate) fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(msg: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn truncated_snapshot() -> io::Error {
+    invalid("snapshot is truncated")
+}
+
+fn truncated(err: io::Error) -> io::Error {
+    if err.kind() == io::ErrorKind::UnexpectedEof {
+        truncated_snapshot()
+    } else {
+        err
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records() {
+        let mut buffer: Vec<u8> = Vec::new();
+        write_record(&mut buffer, OP_SET, b"a", Some(b"10")).unwrap();
+        write_record(&mut buffer, OP_UNSET, b"a", None).unwrap();
+        write_record(&mut buffer, OP_SET, "ключ".as_bytes(), Some(b"")).unwrap();
+        write_record(&mut buffer, OP_HSET, b"h", Some(b"f")).unwrap();
+        write_bytes(&mut buffer, b"1").unwrap();
+        write_record(&mut buffer, OP_HDEL, b"h", Some(b"f")).unwrap();
+        let id = StreamId { millis: 7, seq: 1 };
+        write_record(&mut buffer, OP_XADD, b"s", Some(&id.to_bytes())).unwrap();
+        write_count(&mut buffer, 1).unwrap();
+        write_bytes(&mut buffer, b"f").unwrap();
+        write_bytes(&mut buffer, b"2").unwrap();
+        write_record(&mut buffer, OP_LPUSH, b"l", None).unwrap();
+        write_count(&mut buffer, 2).unwrap();
+        write_bytes(&mut buffer, b"x").unwrap();
+        write_bytes(&mut buffer, b"y").unwrap();
+        write_record(&mut buffer, OP_RPOP, b"l", None).unwrap();
+        write_record(&mut buffer, OP_ZADD, b"z", Some(b"m")).unwrap();
+        buffer.extend_from_slice(&1.5f64.to_le_bytes());
+        write_record(&mut buffer, OP_ZREM, b"z", Some(b"m")).unwrap();
+        write_record(&mut buffer, OP_JSON, b"j", Some(br#"{"a":[1]}"#)).unwrap();
+        write_record(&mut buffer, OP_EXPIRE, b"j", None).unwrap();
+        buffer.extend_from_slice(&1500u64.to_le_bytes());
+        write_record(&mut buffer, OP_PERSIST, b"j", None).unwrap();
+        let complete = buffer.len();
+        // a record cut short is ignored
+        write_record(&mut buffer, OP_SET, b"b", Some(b"20")).unwrap();
+        buffer.truncate(buffer.len() - 1);
+        let (mutations, length): (Vec<Mutation<String, CompactString>>, u64) =
+            read_log(&buffer[..], None).unwrap();
+        assert_eq!(mutations.len(), 13);
+        assert_eq!(length, complete as u64);
+        assert!(matches!(&mutations[0], Mutation::Set(k, v) if k == "a" && v == "10"));
+        assert!(matches!(&mutations[1], Mutation::Unset(k) if k == "a"));
+        assert!(matches!(&mutations[2], Mutation::Set(k, v) if k == "ключ" && v.is_empty()));
+        assert!(
+            matches!(&mutations[3], Mutation::HSet(k, f, v) if k == "h" && f == "f" && v == "1")
+        );
+        assert!(matches!(&mutations[4], Mutation::HDel(k, f) if k == "h" && f == "f"));
+        assert!(
+            matches!(&mutations[5], Mutation::XAdd(k, i, f) if k == "s" && *i == id && f.len() == 1)
+        );
+        assert!(matches!(&mutations[6], Mutation::LPush(k, v) if k == "l" && v == &["x", "y"]));
+        assert!(matches!(&mutations[7], Mutation::RPop(k) if k == "l"));
+        assert!(
+            matches!(&mutations[8], Mutation::ZAdd(k, m, s) if k == "z" && m == "m" && *s == 1.5)
+        );
+        assert!(matches!(&mutations[9], Mutation::ZRem(k, m) if k == "z" && m == "m"));
+        assert!(matches!(&mutations[10], Mutation::Json(k, d) if k == "j" && d["a"][0] == 1));
+        let deadline = UNIX_EPOCH + Duration::from_millis(1500);
+        assert!(matches!(&mutations[11], Mutation::Expire(k, t) if k == "j" && *t == deadline));
+        assert!(matches!(&mutations[12], Mutation::Persist(k) if k == "j"));
+        // so is a time cut short
+        let (mutations, _): (Vec<Mutation<String, String>>, u64) =
+            read_log(&buffer[..complete - 6 - 1], None).unwrap();
+        assert_eq!(mutations.len(), 11);
+        // and a score cut short, and a push cut short within its values
+        let tail = (1 + 5 + 4 + 9) + (1 + 5 + 8) + (1 + 5);
+        let (mutations, _): (Vec<Mutation<String, String>>, u64) =
+            read_log(&buffer[..complete - tail - 14], None).unwrap();
+        assert_eq!(mutations.len(), 8);
+        let (mutations, length): (Vec<Mutation<String, String>>, u64) =
+            read_log(&buffer[..complete - tail - 41], None).unwrap();
+        assert_eq!(mutations.len(), 6);
+        assert!(length < complete as u64);
+    }
+
+    #[test]
+    fn test_batches() {
+        let mut records: Vec<u8> = Vec::new();
+        write_record(&mut records, OP_SET, b"a", Some(b"10")).unwrap();
+        write_record(&mut records, OP_UNSET, b"b", None).unwrap();
+        let mut buffer: Vec<u8> = Vec::new();
+        write_record(&mut buffer, OP_SET, b"c", Some(b"30")).unwrap();
+        write_record(&mut buffer, OP_BATCH, &records, None).unwrap();
+        buffer.extend_from_slice(&checksum(&records).to_le_bytes());
+        let (mutations, length): (Vec<Mutation<String, String>>, u64) =
+            read_log(&buffer[..], None).unwrap();
+        assert_eq!(mutations.len(), 3);
+        assert_eq!(length, buffer.len() as u64);
+        // a batch cut short or torn is ignored as a whole
+        let (mutations, length): (Vec<Mutation<String, String>>, u64) =
+            read_log(&buffer[..buffer.len() - 1], None).unwrap();
+        assert_eq!(mutations.len(), 1);
+        assert!(length < buffer.len() as u64);
+        let last = buffer.len() - 5;
+        buffer[last] ^= 0xff;
+        let (mutations, _): (Vec<Mutation<String, String>>, u64) =
+            read_log(&buffer[..], None).unwrap();
+        assert_eq!(mutations.len(), 1);
+    }
+
+    #[test]
+    fn test_every_second() {
+        let path = std::env::temp_dir().join(format!("simpledb-fsync-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let (mut log, _): (WriteAheadLog<String, String>, _) =
+            WriteAheadLog::open(&path, None).unwrap();
+        log.set_durability(Durability::EverySecond);
+        let stop = log.syncer.clone().unwrap();
+        log.set(&"a".into(), &"10".into());
+        log.flush();
+        assert!(!log.failed());
+        log.set_durability(Durability::Sync);
+        assert!(stop.load(Ordering::Relaxed));
+        assert!(log.syncer.is_none());
+        log.set_durability(Durability::EverySecond);
+        let stop = log.syncer.clone().unwrap();
+        drop(log);
+        assert!(stop.load(Ordering::Relaxed));
+        let (_, (mutations, report)): (WriteAheadLog<String, String>, _) =
+            WriteAheadLog::open(&path, None).unwrap();
+        // the change and the time of its batch
+        assert_eq!(mutations.len(), 2);
+        assert_eq!(report.replayed, 1);
+        assert_eq!(report.discarded, 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let names: Vec<String> = vec!["a".into(), "b".into()];
+        let values: Vec<String> = vec!["10".into(), "10".into()];
+        let path = std::env::temp_dir().join(format!("simpledb-snap-{}", std::process::id()));
+        let id = StreamId { millis: 3, seq: 0 };
+        let fields = vec![(names[1].clone(), values[1].clone())];
+        let document = serde_json::json!({"b": [true, null]});
+        let deadline = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let snapshot = SnapshotRefs {
+            entries: names.iter().zip(values.iter()).collect(),
+            counts: vec![(&values[0], 2)],
+            hashes: vec![(&names[0], vec![(&names[1], &values[0])])],
+            streams: vec![(&names[1], vec![(&id, &fields)])],
+            lists: vec![(&names[0], vec![&values[1], &values[0]])],
+            sorted: vec![(&names[1], vec![(&names[0], -2.5)])],
+            documents: vec![(&names[0], &document)],
+            expiry: vec![(&names[1], deadline)],
+        };
+        save_snapshot(&path, None, &snapshot).unwrap();
+        let loaded: SnapshotContents<String, CompactString> = load_snapshot(&path, None).unwrap();
+        assert_eq!(
+            loaded.entries,
+            vec![("a".into(), "10".into()), ("b".into(), "10".into())]
+        );
+        assert_eq!(loaded.counts, vec![("10".into(), 2)]);
+        assert_eq!(
+            loaded.hashes,
+            vec![("a".into(), vec![("b".into(), "10".into())])]
+        );
+        assert_eq!(
+            loaded.streams,
+            vec![("b".into(), vec![(id, vec![("b".into(), "10".into())])])]
+        );
+        assert_eq!(
+            loaded.lists,
+            vec![("a".into(), vec!["10".into(), "10".into()])]
+        );
+        assert_eq!(loaded.sorted, vec![("b".into(), vec![("a".into(), -2.5)])]);
+        assert_eq!(loaded.documents, vec![("a".into(), document)]);
+        assert_eq!(loaded.expiry, vec![("b".into(), deadline)]);
+        // the older formats have no hashes, streams or lists
+        let old = b"SDBS\x01\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
+        let loaded: SnapshotContents<String, String> = read_snapshot(&old[..]).unwrap();
+        assert!(loaded.entries.is_empty() && loaded.hashes.is_empty());
+        let old = b"SDBS\x02\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
+        let loaded: SnapshotContents<String, String> = read_snapshot(&old[..]).unwrap();
+        assert!(loaded.streams.is_empty());
+        let old = b"SDBS\x03\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
+        let loaded: SnapshotContents<String, String> = read_snapshot(&old[..]).unwrap();
+        assert!(loaded.lists.is_empty() && loaded.expiry.is_empty());
+        // a truncated snapshot is an error rather than partial data
+        let bytes = fs::read(&path).unwrap();
+        let result: io::Result<SnapshotContents<String, String>> =
+            read_snapshot(&bytes[..bytes.len() - 3]);
+        assert_eq!(result.unwrap_err().to_string(), "snapshot is truncated");
+        let result: io::Result<SnapshotContents<String, String>> = read_snapshot(&b"SDBX\x01"[..]);
+        assert_eq!(result.unwrap_err().to_string(), "not a snapshot file");
+        let result: io::Result<SnapshotContents<String, String>> = read_snapshot(&b"SDBS\x09"[..]);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "unsupported snapshot version 9"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_encrypted_log() {
+        let path = std::env::temp_dir().join(format!("simpledb-sealed-log-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let secret = Secret::passphrase("correct horse");
+        type Log = WriteAheadLog<String, String>;
+        {
+            let (mut log, _): (Log, _) = WriteAheadLog::open(&path, Some(&secret)).unwrap();
+            log.set(&"a".into(), &"plaintext".into());
+            log.begin_batch();
+            log.hset(&"h".into(), &"f".into(), &"1".into());
+            log.unset(&"b".into());
+            log.end_batch();
+            log.flush();
+            assert!(!log.failed());
+        }
+        let bytes = fs::read(&path).unwrap();
+        assert!(bytes.starts_with(b"SDBE"));
+        assert!(!bytes.windows(9).any(|w| w == b"plaintext"));
+        let size = bytes.len() as u64;
+        assert!(Log::open(&path, None).is_err());
+        let wrong = Secret::passphrase("battery staple");
+        assert!(Log::open(&path, Some(&wrong)).is_err());
+        assert_eq!(fs::metadata(&path).unwrap().len(), size);
+        // a torn batch is dropped as in any other log
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[OP_SEALED, 40, 0]).unwrap();
+        drop(file);
+        let (mut log, (mutations, report)): (Log, _) =
+            WriteAheadLog::open(&path, Some(&secret)).unwrap();
+        assert_eq!(mutations.len(), 5);
+        assert_eq!(report.replayed, 3);
+        assert_eq!(report.discarded, 3);
+        assert_eq!(log.records(), 2);
+        // the rewritten log is encrypted with the same key
+        let mut rewritten = log.begin_rewrite().unwrap();
+        rewritten.set(&"a".into(), &"rewritten".into());
+        log.set(&"c".into(), &"meanwhile".into());
+        rewritten.flush();
+        log.flush();
+        log.finish_rewrite(rewritten).unwrap();
+        drop(log);
+        let bytes = fs::read(&path).unwrap();
+        assert!(!bytes.windows(9).any(|w| w == b"rewritten"));
+        let (_, (mutations, report)): (Log, _) = WriteAheadLog::open(&path, Some(&secret)).unwrap();
+        assert_eq!(mutations.len(), 4);
+        assert_eq!(report.discarded, 0);
+        // plain records are not taken from an encrypted log, nor sealed ones
+        // from a plain log, and a plain log is not given sealed records
+        let mut buffer = Vec::new();
+        write_batch(&mut buffer, None, b"").unwrap();
+        let (mutations, length) =
+            read_log::<_, String, String>(&buffer[..], Some(&Cipher::new(&secret).unwrap()))
+                .unwrap();
+        assert!(mutations.is_empty());
+        assert_eq!(length, 0);
+        fs::write(&path, &buffer).unwrap();
+        let err = Log::open(&path, Some(&secret)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let cipher = Cipher::new(&secret).unwrap();
+        let mut buffer = Vec::new();
+        write_batch(&mut buffer, Some(&cipher), b"").unwrap();
+        assert!(read_log::<_, String, String>(&buffer[..], None).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_log() {
+        // garbage ends the log rather than failing to read it
+        let (mutations, length): (Vec<Mutation<String, String>>, u64) =
+            read_log(&[9u8, 0, 0][..], None).unwrap();
+        assert!(mutations.is_empty());
+        assert_eq!(length, 0);
+        // whereas a complete record that cannot be decoded is an error
+        let mut buffer: Vec<u8> = Vec::new();
+        write_record(&mut buffer, OP_UNSET, &[0xff, 0xfe], None).unwrap();
+        let result: io::Result<(Vec<Mutation<String, String>>, u64)> = read_log(&buffer[..], None);
+        assert!(result.is_err());
+        let mut buffer: Vec<u8> = Vec::new();
+        write_record(&mut buffer, OP_JSON, b"j", Some(b"{")).unwrap();
+        let result: io::Result<(Vec<Mutation<String, String>>, u64)> = read_log(&buffer[..], None);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,103 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! Saving snapshots on a schedule, as with the `save` setting of Redis: every
+//! so often the committed state is saved if enough keys have changed since it
+//! was last saved. Taking a snapshot is cheap, so it can be done with the
+//! database locked, and the snapshot written to disk once the lock has been
+//! released and other threads are free to carry on.
+
+use crate::store::{Database, Snapshot};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+///
+/// When to save snapshots of a database, and what it held when one was last
+/// saved.
+///
+pub struct SnapshotSchedule<K = String, V = String> {
+    interval: Duration,
+    changes: usize,
+    // committed state when last saved, or when the schedule began
+    saved: Snapshot<K, V>,
+    // when the changes are next counted
+    due: Instant,
+}
+
+impl<K, V> SnapshotSchedule<K, V>
+where
+    K: Ord + Clone,
+    V: Hash + Eq + Clone,
+{
+    /// Begin a schedule that saves the committed state of the database every
+    /// `interval`, if at least `changes` keys have changed since the state it
+    /// last saved. What the database holds now is taken as already saved.
+    pub fn new(database: &Database<K, V>, interval: Duration, changes: usize) -> Self {
+        Self {
+            interval,
+            changes: changes.max(1),
+            saved: database.freeze_snapshot(),
+            due: Instant::now() + interval,
+        }
+    }
+
+    /// Returns when the schedule next calls for the changes to be counted.
+    pub fn due(&self) -> Instant {
+        self.due
+    }
+
+    /// Once the interval has passed, returns the committed state of the
+    /// database to be saved if enough keys have changed, starting the next
+    /// interval either way. The caller should pass the snapshot to `saved()`
+    /// once it has been written, so that the changes are counted from then
+    /// on, while after a failure they continue to count toward the next try.
+    pub fn poll(&mut self, database: &Database<K, V>) -> Option<Snapshot<K, V>> {
+        let now = Instant::now();
+        if now < self.due {
+            return None;
+        }
+        self.due = now + self.interval;
+        let snapshot = database.freeze_snapshot();
+        if snapshot.changes_since(&self.saved) >= self.changes {
+            Some(snapshot)
+        } else {
+            None
+        }
+    }
+
+    /// Record that the snapshot returned by `poll()` has been saved.
+    pub fn saved(&mut self, snapshot: Snapshot<K, V>) {
+        self.saved = snapshot;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        let mut db = Database::new();
+        db.set("a", "1");
+        let mut schedule = SnapshotSchedule::new(&db, Duration::ZERO, 2);
+        assert!(schedule.poll(&db).is_none());
+        db.set("b", "2");
+        assert!(schedule.poll(&db).is_none());
+        // uncommitted changes are not counted
+        db.begin();
+        db.set("c", "3");
+        assert!(schedule.poll(&db).is_none());
+        db.commit().unwrap();
+        let snapshot = schedule.poll(&db).unwrap();
+        assert_eq!(snapshot.len(), 3);
+        // until saved, the changes still count
+        assert!(schedule.poll(&db).is_some());
+        schedule.saved(snapshot);
+        assert!(schedule.poll(&db).is_none());
+        let mut schedule = SnapshotSchedule::new(&db, Duration::from_secs(3600), 1);
+        db.set("d", "4");
+        assert!(schedule.poll(&db).is_none());
+        assert!(schedule.due() > Instant::now());
+    }
+}
@@ -0,0 +1,1314 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! Network server speaking enough of the Redis serialization protocol (RESP)
+//! for Redis clients, including `redis-cli`, to get, set, and count values.
+//! Each connection is served by its own thread, with all of them sharing the
+//! same databases, of which each connection may `SELECT` its own. Commands
+//! may be sent either as arrays of bulk strings, which is what clients send,
+//! or inline as words separated by spaces, quoted as needed in the same way
+//! as the commands of the REPL.
+//!
+//! Clients coordinate as in Redis, optimistically: a connection may `WATCH`
+//! keys, queue commands after `MULTI`, and have `EXEC` run them all at once,
+//! unless another connection changed a watched key in the meantime, in which
+//! case none are run and the reply is nil.
+//!
+//! A server started with `serve_with()` passes the commands it shares with
+//! the REPL through the given `Pipeline` of interceptors, which see them as
+//! parsed by `command::parse_words()` and carried out by `command::execute()`,
+//! with the response sent back in the form the server uses for that command.
+//! The commands that only the server offers, those of transactions and
+//! databases such as `MULTI` and `SELECT`, those whose arguments the REPL
+//! takes differently, such as `GETEX`, and those written in a form that the
+//! REPL does not accept, such as `scan 0 match a*`, are not intercepted.
+
+use crate::command::{self, score_bound, Pipeline, Response};
+use crate::databases::Databases;
+use crate::error::Error;
+use crate::parser;
+use crate::persist::Persistent;
+use crate::store::{Database, Snapshot};
+use crate::stream::StreamId;
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// limits on requests, to keep a misbehaving client from exhausting memory
+const MAX_ARGUMENTS: usize = 1024 * 1024;
+const MAX_BULK_LENGTH: usize = 512 * 1024 * 1024;
+
+// commands of the server that the REPL also has, and replies to in the same
+// form, which are passed through the interceptors
+const INTERCEPTED: &[&str] = &[
+    "GET",
+    "SET",
+    "SETNX",
+    "COPY",
+    "GETDEL",
+    "APPEND",
+    "INCR",
+    "DECR",
+    "INCRBY",
+    "EXISTS",
+    "NUMEQUALTO",
+    "LPUSH",
+    "RPUSH",
+    "LPOP",
+    "RPOP",
+    "LRANGE",
+    "LLEN",
+    "HSET",
+    "HGET",
+    "HDEL",
+    "HGETALL",
+    "HLEN",
+    "ZADD",
+    "ZSCORE",
+    "ZREM",
+    "ZRANGE",
+    "ZRANGEBYSCORE",
+    "XADD",
+    "XRANGE",
+    "XLEN",
+    "PFADD",
+    "PFCOUNT",
+    "PFMERGE",
+    "SETBIT",
+    "GETBIT",
+    "BITCOUNT",
+    "JSON.GET",
+    "JSON.SET",
+    "DBSIZE",
+    "RANDOMKEY",
+    "FLUSHALL",
+    "SCAN",
+];
+
+///
+/// A reply to a command, in the forms defined by the protocol.
+///
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Status(status) => write!(out, "+{}\r\n", status),
+            Reply::Error(msg) => write!(out, "-{}\r\n", msg),
+            Reply::Integer(n) => write!(out, ":{}\r\n", n),
+            Reply::Bulk(None) => out.write_all(b"$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                write!(out, "${}\r\n", bytes.len())?;
+                out.write_all(bytes)?;
+                out.write_all(b"\r\n")
+            }
+            Reply::Array(items) => {
+                write!(out, "*{}\r\n", items.len())?;
+                for item in items.iter() {
+                    item.write_to(out)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Accept connections on the listener until it fails, serving each one on a
+/// separate thread.
+pub fn serve<V>(
+    listener: TcpListener,
+    databases: Arc<Mutex<Databases<String, V>>>,
+) -> io::Result<()>
+where
+    V: Hash
+        + Eq
+        + Clone
+        + From<String>
+        + AsRef<str>
+        + Borrow<str>
+        + Persistent
+        + Send
+        + Sync
+        + 'static,
+{
+    serve_with(listener, databases, Pipeline::new())
+}
+
+/// Accept connections as `serve()` does, passing the commands shared with
+/// the REPL through the interceptors of the pipeline, whichever connection
+/// sent them.
+pub fn serve_with<V>(
+    listener: TcpListener,
+    databases: Arc<Mutex<Databases<String, V>>>,
+    pipeline: Pipeline,
+) -> io::Result<()>
+where
+    V: Hash
+        + Eq
+        + Clone
+        + From<String>
+        + AsRef<str>
+        + Borrow<str>
+        + Persistent
+        + Send
+        + Sync
+        + 'static,
+{
+    let pipeline = Arc::new(Mutex::new(pipeline));
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let databases = databases.clone();
+        let pipeline = pipeline.clone();
+        thread::spawn(move || {
+            // errors such as the client going away only affect this connection
+            let _ = serve_connection(stream, &databases, &pipeline);
+        });
+    }
+    Ok(())
+}
+
+fn serve_connection<V>(
+    stream: TcpStream,
+    databases: &Mutex<Databases<String, V>>,
+    pipeline: &Mutex<Pipeline>,
+) -> io::Result<()>
+where
+    V: Hash + Eq + Clone + From<String> + AsRef<str> + Borrow<str> + Persistent,
+{
+    let reader = BufReader::new(stream.try_clone()?);
+    let writer = BufWriter::new(stream);
+    handle(reader, writer, databases, pipeline)
+}
+
+/// Read commands from the client and write the replies until the client
+/// disconnects or sends QUIT.
+fn handle<R, W, V>(
+    mut reader: R,
+    mut writer: W,
+    shared: &Mutex<Databases<String, V>>,
+    pipeline: &Mutex<Pipeline>,
+) -> io::Result<()>
+where
+    R: BufRead,
+    W: Write,
+    V: Hash + Eq + Clone + From<String> + AsRef<str> + Borrow<str> + Persistent,
+{
+    // the database chosen by SELECT
+    let mut selected = 0;
+    // commands queued since MULTI, if it was sent
+    let mut queued: Option<Vec<Vec<Vec<u8>>>> = None;
+    // keys being watched, each with its database and the state it was in
+    let mut watched: Vec<(usize, String, Snapshot<String, V>)> = Vec::new();
+    loop {
+        let args = match read_request(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                // the stream can no longer be followed, so give up on it
+                Reply::Error(format!("ERR Protocol error: {}", err)).write_to(&mut writer)?;
+                return writer.flush();
+            }
+            Err(err) => return Err(err),
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+        let command = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+        let mut databases = shared.lock().unwrap();
+        let reply = match command.as_str() {
+            _ if quit => Reply::Status("OK"),
+            "MULTI" if queued.is_some() => Reply::Error("ERR MULTI calls can not be nested".into()),
+            "MULTI" => {
+                queued = Some(Vec::new());
+                Reply::Status("OK")
+            }
+            "EXEC" => match queued.take() {
+                None => Reply::Error("ERR EXEC without MULTI".into()),
+                Some(commands) => {
+                    let changed = watched
+                        .drain(..)
+                        .any(|(index, name, then)| databases[index].changed_since(&then, &name));
+                    if changed {
+                        Reply::Bulk(None)
+                    } else {
+                        // the lock is held throughout, so no other client
+                        // sees the commands partly done
+                        let replies = commands
+                            .iter()
+                            .map(|args| dispatch(&mut databases, &mut selected, args, pipeline))
+                            .collect();
+                        Reply::Array(replies)
+                    }
+                }
+            },
+            "DISCARD" => match queued.take() {
+                None => Reply::Error("ERR DISCARD without MULTI".into()),
+                Some(_) => {
+                    watched.clear();
+                    Reply::Status("OK")
+                }
+            },
+            "WATCH" if queued.is_some() => {
+                Reply::Error("ERR WATCH inside MULTI is not allowed".into())
+            }
+            "WATCH" if args.len() < 2 => {
+                Reply::Error("ERR wrong number of arguments for 'watch' command".into())
+            }
+            "WATCH" => {
+                let snapshot = databases[selected].freeze_snapshot();
+                for name in args[1..].iter() {
+                    let name = String::from_utf8_lossy(name).into_owned();
+                    watched.push((selected, name, snapshot.clone()));
+                }
+                Reply::Status("OK")
+            }
+            _ if queued.is_some() => {
+                queued.as_mut().unwrap().push(args);
+                Reply::Status("QUEUED")
+            }
+            "UNWATCH" => {
+                watched.clear();
+                Reply::Status("OK")
+            }
+            // the new log is written without holding the lock, so that other
+            // clients carry on meanwhile; only the first database is logged
+            "BGREWRITEAOF" => match databases[0].begin_rewrite_aof() {
+                Ok(mut rewrite) => {
+                    drop(databases);
+                    rewrite.write();
+                    databases = shared.lock().unwrap();
+                    match databases[0].finish_rewrite_aof(rewrite) {
+                        Ok(()) => Reply::Status("OK"),
+                        Err(err) => failure(err),
+                    }
+                }
+                Err(err) => failure(err),
+            },
+            _ => dispatch(&mut databases, &mut selected, &args, pipeline),
+        };
+        drop(databases);
+        reply.write_to(&mut writer)?;
+        writer.flush()?;
+        if quit {
+            return Ok(());
+        }
+    }
+}
+
+/// Carry out a command that may change the selected database. The pipeline
+/// is locked only while the databases are, so that the two are always
+/// locked in the same order.
+fn dispatch<V>(
+    databases: &mut Databases<String, V>,
+    selected: &mut usize,
+    args: &[Vec<u8>],
+    pipeline: &Mutex<Pipeline>,
+) -> Reply
+where
+    V: Hash + Eq + Clone + From<String> + AsRef<str> + Borrow<str> + Persistent,
+{
+    if args[0].eq_ignore_ascii_case(b"SELECT") {
+        let selector = args.get(1).and_then(|arg| std::str::from_utf8(arg).ok());
+        match args.len() {
+            2 => match selector.and_then(|selector| databases.find(selector)) {
+                Some(index) if index != 0 && databases.is_persisted() => Reply::Error(
+                    "ERR only database 0 is persisted, another cannot be selected".into(),
+                ),
+                Some(index) => {
+                    *selected = index;
+                    Reply::Status("OK")
+                }
+                None => Reply::Error("ERR DB index is out of range".into()),
+            },
+            _ => Reply::Error("ERR wrong number of arguments for 'select' command".into()),
+        }
+    } else if args[0].eq_ignore_ascii_case(b"MOVE") {
+        let args: Vec<&str> = args[1..]
+            .iter()
+            .filter_map(|arg| std::str::from_utf8(arg).ok())
+            .collect();
+        match args.as_slice() {
+            [_, _] if *selected == 0 && databases.is_persisted() => Reply::Error(
+                "ERR only database 0 is persisted, keys cannot be moved out of it".into(),
+            ),
+            [name, selector] => match databases.find(selector) {
+                Some(index) => Reply::Integer(databases.move_key(*selected, index, *name) as i64),
+                None => Reply::Error("ERR DB index is out of range".into()),
+            },
+            _ => Reply::Error("ERR wrong number of arguments for 'move' command".into()),
+        }
+    } else {
+        let database = &mut databases[*selected];
+        let mut pipeline = pipeline.lock().unwrap();
+        intercept(&mut pipeline, database, args).unwrap_or_else(|| execute(database, args))
+    }
+}
+
+/// Carry out the command through the pipeline, if it has interceptors and the
+/// command is one that the REPL shares, or return `None` for the server to
+/// carry it out itself.
+fn intercept<V>(
+    pipeline: &mut Pipeline,
+    database: &mut Database<String, V>,
+    args: &[Vec<u8>],
+) -> Option<Reply>
+where
+    V: Hash + Eq + Clone + From<String> + AsRef<str> + Borrow<str> + Persistent,
+{
+    if pipeline.is_empty() {
+        return None;
+    }
+    let mut words: Vec<&str> = Vec::with_capacity(args.len());
+    for arg in args.iter() {
+        words.push(std::str::from_utf8(arg).ok()?);
+    }
+    let name = words[0].to_ascii_uppercase();
+    if !INTERCEPTED.contains(&name.as_str()) {
+        return None;
+    }
+    words[0] = &name;
+    let command = command::parse_words(&words).ok()??;
+    Some(to_reply(pipeline.execute(database, command)))
+}
+
+/// Returns the reply that the server would give in place of the response.
+fn to_reply(response: Response) -> Reply {
+    let bulk = |value: String| Reply::Bulk(Some(value.into_bytes()));
+    let array = |values: Vec<String>| Reply::Array(values.into_iter().map(bulk).collect());
+    match response {
+        Response::Done | Response::End => Reply::Status("OK"),
+        Response::Value(value) => Reply::Bulk(value.map(String::into_bytes)),
+        Response::Values(values) => Reply::Array(
+            values
+                .into_iter()
+                .map(|value| Reply::Bulk(value.map(String::into_bytes)))
+                .collect(),
+        ),
+        Response::Integer(n) => Reply::Integer(n),
+        Response::List(values) | Response::Keys(values) => array(values),
+        Response::Page(cursor, names) => Reply::Array(vec![bulk(cursor), array(names)]),
+        Response::Entries(entries) => Reply::Array(
+            entries
+                .into_iter()
+                .map(|(id, fields)| {
+                    let fields = fields.into_iter().flat_map(|(f, v)| [f, v]).collect();
+                    Reply::Array(vec![bulk(id), array(fields)])
+                })
+                .collect(),
+        ),
+        Response::Changes(changes) => Reply::Array(
+            changes
+                .into_iter()
+                .map(|change| {
+                    let old = change.old.unwrap_or_else(|| "NULL".into());
+                    let new = change.new.unwrap_or_else(|| "NULL".into());
+                    bulk(format!("{}: {} -> {}", change.name, old, new))
+                })
+                .collect(),
+        ),
+        Response::Error(msg) if msg == Error::WrongType.to_string() => failure(Error::WrongType),
+        Response::Error(msg) => Reply::Error(format!("ERR {}", msg)),
+    }
+}
+
+/// Carry out a single command.
+fn execute<V>(database: &mut Database<String, V>, args: &[Vec<u8>]) -> Reply
+where
+    V: Hash + Eq + Clone + From<String> + AsRef<str> + Borrow<str> + Persistent,
+{
+    let mut strings: Vec<&str> = Vec::with_capacity(args.len());
+    for arg in args.iter() {
+        match std::str::from_utf8(arg) {
+            Ok(arg) => strings.push(arg),
+            Err(_) => return Reply::Error("ERR arguments must be valid UTF-8".into()),
+        }
+    }
+    let name = strings[0].to_ascii_uppercase();
+    let args = &strings[1..];
+    let arity = |valid: bool| -> Result<(), Reply> {
+        if valid {
+            Ok(())
+        } else {
+            Err(Reply::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name.to_ascii_lowercase()
+            )))
+        }
+    };
+    let result = match name.as_str() {
+        "PING" => arity(args.len() <= 1).map(|_| match args.first() {
+            Some(msg) => Reply::Bulk(Some(msg.as_bytes().to_vec())),
+            None => Reply::Status("PONG"),
+        }),
+        "GET" => arity(args.len() == 1).map(|_| {
+            let value = database.get_ref(args[0]);
+            Reply::Bulk(value.map(|v| v.as_ref().as_bytes().to_vec()))
+        }),
+        "SET" => arity(args.len() == 2).map(|_| {
+            database.set(args[0], args[1].to_owned());
+            Reply::Status("OK")
+        }),
+        "GETDEL" => arity(args.len() == 1).map(|_| {
+            let value = database.get_del(args[0]);
+            Reply::Bulk(value.map(|v| v.as_ref().as_bytes().to_vec()))
+        }),
+        "GETEX" => arity(!args.is_empty() && args.len() <= 3).and_then(|_| {
+            let option = args.get(1).map(|option| option.to_ascii_uppercase());
+            let ttl = match (option.as_deref(), args.get(2)) {
+                (None, _) => {
+                    let value = database.get_ref(args[0]);
+                    return Ok(Reply::Bulk(value.map(|v| v.as_ref().as_bytes().to_vec())));
+                }
+                (Some("PERSIST"), None) => None,
+                (Some(unit @ ("EX" | "PX")), Some(time)) => {
+                    let time: u64 = time.parse().map_err(|_| failure(Error::NotAnInteger))?;
+                    if time == 0 {
+                        return Err(Reply::Error(
+                            "ERR invalid expire time in 'getex' command".into(),
+                        ));
+                    }
+                    Some(match unit {
+                        "EX" => Duration::from_secs(time),
+                        _ => Duration::from_millis(time),
+                    })
+                }
+                _ => return Err(Reply::Error("ERR syntax error".into())),
+            };
+            let value = database
+                .get_and_touch_ttl(args[0], ttl)
+                .map_err(|_| Reply::Error("ERR invalid expire time in 'getex' command".into()))?;
+            Ok(Reply::Bulk(value.map(|v| v.as_ref().as_bytes().to_vec())))
+        }),
+        "APPEND" => {
+            arity(args.len() == 2).map(|_| Reply::Integer(database.append(args[0], args[1]) as i64))
+        }
+        "SETNX" => arity(args.len() == 2)
+            .map(|_| Reply::Integer(database.set_nx(args[0], args[1].to_owned()) as i64)),
+        "COPY" => arity(args.len() == 2 || args.len() == 3).and_then(|_| {
+            let replace = match args.get(2) {
+                Some(option) if option.eq_ignore_ascii_case("REPLACE") => true,
+                Some(_) => return Err(Reply::Error("ERR syntax error".into())),
+                None => false,
+            };
+            let copied = database.copy(args[0], args[1], replace);
+            Ok(Reply::Integer(copied as i64))
+        }),
+        "MGET" => arity(!args.is_empty()).map(|_| {
+            let values = args
+                .iter()
+                .map(|name| {
+                    let value = database.get_ref(*name);
+                    Reply::Bulk(value.map(|v| v.as_ref().as_bytes().to_vec()))
+                })
+                .collect();
+            Reply::Array(values)
+        }),
+        "MSET" => arity(!args.is_empty() && args.len().is_multiple_of(2)).map(|_| {
+            let pairs = args.chunks(2).map(|pair| (pair[0], pair[1].to_owned()));
+            database.mset(pairs);
+            Reply::Status("OK")
+        }),
+        "INCR" | "DECR" | "INCRBY" | "DECRBY" => {
+            let by_amount = name.ends_with("BY");
+            arity(args.len() == if by_amount { 2 } else { 1 }).and_then(|_| {
+                let amount = match by_amount {
+                    true => args[1].parse::<i64>().map_err(|_| {
+                        Reply::Error("ERR value is not an integer or out of range".into())
+                    })?,
+                    false => 1,
+                };
+                let amount = match name.starts_with("DECR") {
+                    true => amount
+                        .checked_neg()
+                        .ok_or_else(|| failure(Error::NotAnInteger))?,
+                    false => amount,
+                };
+                database
+                    .incr_by(args[0], amount)
+                    .map(Reply::Integer)
+                    .map_err(failure)
+            })
+        }
+        "LPUSH" | "RPUSH" => arity(args.len() >= 2).and_then(|_| {
+            let values = args[1..].iter().map(|v| v.to_string());
+            let len = match name.as_str() {
+                "LPUSH" => database.lpush(args[0], values),
+                _ => database.rpush(args[0], values),
+            };
+            len.map(|len| Reply::Integer(len as i64)).map_err(failure)
+        }),
+        "LPOP" | "RPOP" => arity(args.len() == 1).and_then(|_| {
+            let value = match name.as_str() {
+                "LPOP" => database.lpop(args[0]),
+                _ => database.rpop(args[0]),
+            };
+            let value = value.map_err(failure)?;
+            Ok(Reply::Bulk(value.map(|v| v.as_ref().as_bytes().to_vec())))
+        }),
+        "LRANGE" => arity(args.len() == 3).and_then(|_| {
+            let (start, stop) = match (args[1].parse(), args[2].parse()) {
+                (Ok(start), Ok(stop)) => (start, stop),
+                _ => return Err(failure(Error::NotAnInteger)),
+            };
+            let values = database.lrange(args[0], start, stop).map_err(failure)?;
+            let values = values
+                .iter()
+                .map(|v| Reply::Bulk(Some(v.as_ref().as_bytes().to_vec())))
+                .collect();
+            Ok(Reply::Array(values))
+        }),
+        "LLEN" => arity(args.len() == 1).and_then(|_| {
+            let len = database.llen(args[0]).map_err(failure)?;
+            Ok(Reply::Integer(len as i64))
+        }),
+        "HSET" => arity(args.len() >= 3 && !args.len().is_multiple_of(2)).and_then(|_| {
+            let fields = args[1..]
+                .chunks(2)
+                .map(|pair| (pair[0], pair[1].to_owned()));
+            let added = database.hset(args[0], fields).map_err(failure)?;
+            Ok(Reply::Integer(added as i64))
+        }),
+        "HGET" => arity(args.len() == 2).and_then(|_| {
+            let value = database.hget(args[0], args[1]).map_err(failure)?;
+            Ok(Reply::Bulk(value.map(|v| v.as_ref().as_bytes().to_vec())))
+        }),
+        "HDEL" => arity(args.len() >= 2).and_then(|_| {
+            let removed = database.hdel(args[0], &args[1..]).map_err(failure)?;
+            Ok(Reply::Integer(removed as i64))
+        }),
+        "HGETALL" => arity(args.len() == 1).and_then(|_| {
+            let fields = database.hgetall(args[0]).map_err(failure)?;
+            let replies = fields
+                .iter()
+                .flat_map(|(field, value)| [field.as_bytes(), value.as_ref().as_bytes()])
+                .map(|bytes| Reply::Bulk(Some(bytes.to_vec())))
+                .collect();
+            Ok(Reply::Array(replies))
+        }),
+        "HLEN" => arity(args.len() == 1).and_then(|_| {
+            let len = database.hlen(args[0]).map_err(failure)?;
+            Ok(Reply::Integer(len as i64))
+        }),
+        "ZADD" => arity(args.len() >= 3 && !args.len().is_multiple_of(2)).and_then(|_| {
+            let mut members = Vec::new();
+            for pair in args[1..].chunks(2) {
+                match score_bound(pair[0]) {
+                    Some(Bound::Included(score)) => members.push((score, pair[1])),
+                    _ => return Err(failure(Error::NotAFloat)),
+                }
+            }
+            let added = database.zadd(args[0], members).map_err(failure)?;
+            Ok(Reply::Integer(added as i64))
+        }),
+        "ZSCORE" => arity(args.len() == 2).and_then(|_| {
+            let score = database.zscore(args[0], args[1]).map_err(failure)?;
+            Ok(Reply::Bulk(score.map(|s| s.to_string().into_bytes())))
+        }),
+        "ZREM" => arity(args.len() >= 2).and_then(|_| {
+            let removed = database.zrem(args[0], &args[1..]).map_err(failure)?;
+            Ok(Reply::Integer(removed as i64))
+        }),
+        "ZRANGE" | "ZRANGEBYSCORE" => arity(args.len() == 3 || args.len() == 4).and_then(|_| {
+            let with_scores = match args.get(3) {
+                Some(option) if option.eq_ignore_ascii_case("WITHSCORES") => true,
+                Some(_) => return Err(Reply::Error("ERR syntax error".into())),
+                None => false,
+            };
+            let members = if name == "ZRANGE" {
+                let (start, stop) = match (args[1].parse(), args[2].parse()) {
+                    (Ok(start), Ok(stop)) => (start, stop),
+                    _ => return Err(failure(Error::NotAnInteger)),
+                };
+                database.zrange(args[0], start, stop)
+            } else {
+                let (min, max) = match (score_bound(args[1]), score_bound(args[2])) {
+                    (Some(min), Some(max)) => (min, max),
+                    _ => return Err(Reply::Error("ERR min or max is not a float".into())),
+                };
+                database.zrange_by_score(args[0], min, max)
+            };
+            let replies = members
+                .map_err(failure)?
+                .into_iter()
+                .flat_map(|(member, score)| {
+                    let score = with_scores.then(|| score.to_string());
+                    std::iter::once(member).chain(score)
+                })
+                .map(|text| Reply::Bulk(Some(text.into_bytes())))
+                .collect();
+            Ok(Reply::Array(replies))
+        }),
+        "XADD" => arity(args.len() >= 4 && args.len().is_multiple_of(2)).and_then(|_| {
+            if args[1] != "*" {
+                return Err(Reply::Error(
+                    "ERR only automatic stream IDs (*) are supported".into(),
+                ));
+            }
+            let fields = args[2..]
+                .chunks(2)
+                .map(|pair| (pair[0], pair[1].to_owned()));
+            let id = database.xadd(args[0], fields).map_err(failure)?;
+            Ok(Reply::Bulk(Some(id.to_string().into_bytes())))
+        }),
+        "XRANGE" => arity(args.len() == 3).and_then(|_| {
+            let (start, end) = match (
+                StreamId::parse_bound(args[1], false),
+                StreamId::parse_bound(args[2], true),
+            ) {
+                (Some(start), Some(end)) => (start, end),
+                _ => {
+                    return Err(Reply::Error(
+                        "ERR Invalid stream ID specified as stream command argument".into(),
+                    ))
+                }
+            };
+            let entries = database.xrange(args[0], start, end).map_err(failure)?;
+            let bulk = |bytes: &[u8]| Reply::Bulk(Some(bytes.to_vec()));
+            let replies = entries
+                .iter()
+                .map(|(id, fields)| {
+                    let fields = fields
+                        .iter()
+                        .flat_map(|(field, value)| {
+                            [bulk(field.as_bytes()), bulk(value.as_ref().as_bytes())]
+                        })
+                        .collect();
+                    Reply::Array(vec![bulk(id.to_string().as_bytes()), Reply::Array(fields)])
+                })
+                .collect();
+            Ok(Reply::Array(replies))
+        }),
+        "XLEN" => arity(args.len() == 1).and_then(|_| {
+            let len = database.xlen(args[0]).map_err(failure)?;
+            Ok(Reply::Integer(len as i64))
+        }),
+        "PFADD" => arity(!args.is_empty()).and_then(|_| {
+            let changed = database.pfadd(args[0], &args[1..]).map_err(failure)?;
+            Ok(Reply::Integer(changed as i64))
+        }),
+        "PFCOUNT" => arity(!args.is_empty()).and_then(|_| {
+            let count = database.pfcount(args).map_err(failure)?;
+            Ok(Reply::Integer(count as i64))
+        }),
+        "PFMERGE" => arity(!args.is_empty()).and_then(|_| {
+            database.pfmerge(args[0], &args[1..]).map_err(failure)?;
+            Ok(Reply::Status("OK"))
+        }),
+        "SETBIT" => arity(args.len() == 3).and_then(|_| {
+            let offset = args[1].parse().map_err(|_| {
+                Reply::Error("ERR bit offset is not an integer or out of range".into())
+            })?;
+            let bit = match args[2] {
+                "0" => false,
+                "1" => true,
+                _ => {
+                    return Err(Reply::Error(
+                        "ERR bit is not an integer or out of range".into(),
+                    ))
+                }
+            };
+            let old = database.setbit(args[0], offset, bit).map_err(failure)?;
+            Ok(Reply::Integer(old as i64))
+        }),
+        "GETBIT" => arity(args.len() == 2).and_then(|_| {
+            let offset = args[1].parse().map_err(|_| {
+                Reply::Error("ERR bit offset is not an integer or out of range".into())
+            })?;
+            let bit = database.getbit(args[0], offset).map_err(failure)?;
+            Ok(Reply::Integer(bit as i64))
+        }),
+        "BITCOUNT" => arity(args.len() == 1 || args.len() == 3).and_then(|_| {
+            let (start, stop) = match args.get(1..) {
+                Some([start, stop]) => match (start.parse(), stop.parse()) {
+                    (Ok(start), Ok(stop)) => (start, stop),
+                    _ => return Err(failure(Error::NotAnInteger)),
+                },
+                _ => (0, -1),
+            };
+            let count = database.bitcount(args[0], start, stop).map_err(failure)?;
+            Ok(Reply::Integer(count as i64))
+        }),
+        "DEL" => arity(!args.is_empty()).map(|_| {
+            let mut removed = 0;
+            for name in args.iter() {
+                if database.exists(*name) {
+                    database.delete(*name);
+                    removed += 1;
+                }
+            }
+            Reply::Integer(removed)
+        }),
+        "EXISTS" => arity(!args.is_empty()).map(|_| {
+            let found = args.iter().filter(|name| database.exists(**name)).count();
+            Reply::Integer(found as i64)
+        }),
+        "JSON.GET" => arity(args.len() == 1 || args.len() == 2).and_then(|_| {
+            let path = args.get(1).copied().unwrap_or("$");
+            let value = database.json_get(args[0], path).map_err(failure)?;
+            Ok(Reply::Bulk(
+                value.map(|value| value.to_string().into_bytes()),
+            ))
+        }),
+        "JSON.SET" => arity(args.len() == 3).and_then(|_| {
+            let value = serde_json::from_str(args[2])
+                .map_err(|err| Reply::Error(format!("ERR invalid JSON: {}", err)))?;
+            match database
+                .json_set(args[0], args[1], value)
+                .map_err(failure)?
+            {
+                true => Ok(Reply::Status("OK")),
+                false => Ok(Reply::Bulk(None)),
+            }
+        }),
+        "TYPE" => arity(args.len() == 1)
+            .map(|_| Reply::Status(database.kind(args[0]).map_or("none", |kind| kind.name()))),
+        "VALUETYPE" => arity(args.len() == 1).and_then(|_| {
+            let kind = database.inferred_type(args[0]).map_err(failure)?;
+            Ok(Reply::Status(kind.unwrap_or("none")))
+        }),
+        "DBSIZE" => arity(args.is_empty()).map(|_| Reply::Integer(database.len() as i64)),
+        "RANDOMKEY" => arity(args.is_empty())
+            .map(|_| Reply::Bulk(database.random_key().map(|name| name.as_bytes().to_vec()))),
+        "FLUSHALL" => arity(args.len() <= 1).and_then(|_| match args.first() {
+            // the database is cleared all at once regardless
+            Some(mode) if !["ASYNC", "SYNC"].contains(&mode.to_ascii_uppercase().as_str()) => {
+                Err(Reply::Error("ERR syntax error".into()))
+            }
+            _ => {
+                database.clear();
+                Ok(Reply::Status("OK"))
+            }
+        }),
+        "NUMEQUALTO" => {
+            arity(args.len() == 1).map(|_| Reply::Integer(database.count(args[0]) as i64))
+        }
+        "SCAN" => arity(args.len() % 2 == 1).and_then(|_| {
+            let (mut count, mut pattern) = (10, "*");
+            for option in args[1..].chunks(2) {
+                match option[0].to_ascii_uppercase().as_str() {
+                    "MATCH" => pattern = option[1],
+                    "COUNT" => match option[1].parse() {
+                        Ok(n) if n > 0 => count = n,
+                        _ => {
+                            return Err(Reply::Error(
+                                "ERR value is not an integer or out of range".into(),
+                            ))
+                        }
+                    },
+                    _ => return Err(Reply::Error("ERR syntax error".into())),
+                }
+            }
+            let (keys, next) = database
+                .scan(args[0], count, pattern)
+                .ok_or_else(|| Reply::Error("ERR invalid cursor".into()))?;
+            let keys = keys
+                .into_iter()
+                .map(|name| Reply::Bulk(Some(name.as_bytes().to_vec())))
+                .collect();
+            Ok(Reply::Array(vec![
+                Reply::Bulk(Some(next.into_bytes())),
+                Reply::Array(keys),
+            ]))
+        }),
+        // sent by redis-cli when it starts, to which no details suffice
+        "COMMAND" => Ok(Reply::Array(Vec::new())),
+        _ => Err(Reply::Error(format!(
+            "ERR unknown command '{}'",
+            strings[0]
+        ))),
+    };
+    result.unwrap_or_else(|err| err)
+}
+
+/// The reply for an operation of the database that failed.
+fn failure(err: Error) -> Reply {
+    match err {
+        Error::WrongType => {
+            Reply::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into())
+        }
+        err => Reply::Error(format!("ERR {}", err)),
+    }
+}
+
+/// Read the arguments of the next command, returning `None` at the end of
+/// the input.
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let Some(count) = line.strip_prefix(b"*") else {
+        // inline command, whose words may be quoted as in the REPL
+        let line = std::str::from_utf8(&line).map_err(|_| invalid("invalid UTF-8 in request"))?;
+        let args = parser::tokenize_bytes(line).map_err(|err| invalid(&err.to_string()))?;
+        return Ok(Some(args));
+    };
+    let count = parse_length(count, MAX_ARGUMENTS)?;
+    let mut args = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let header = read_line(reader)?.ok_or_else(unexpected_end)?;
+        let length = match header.strip_prefix(b"$") {
+            Some(length) => parse_length(length, MAX_BULK_LENGTH)?,
+            None => return Err(invalid("expected '$'")),
+        };
+        let mut arg = Vec::new();
+        reader.take(length as u64).read_to_end(&mut arg)?;
+        let mut crlf = [0u8; 2];
+        if arg.len() < length || reader.read_exact(&mut crlf).is_err() {
+            return Err(unexpected_end());
+        }
+        if &crlf != b"\r\n" {
+            return Err(invalid("expected CRLF after bulk string"));
+        }
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Read a line without its terminator.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    }
+    Ok(Some(line))
+}
+
+fn parse_length(digits: &[u8], max: usize) -> io::Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse::<usize>().ok())
+        .filter(|length| *length <= max)
+        .ok_or_else(|| invalid("invalid length"))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+fn unexpected_end() -> io::Error {
+    invalid("unexpected end of request")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::Interceptor;
+    use crate::store::CompactString;
+
+    fn converse(input: &[u8]) -> String {
+        let output = converse_with(input, Pipeline::new());
+        // the commands passed through interceptors are answered the same
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Box::new(Pass));
+        assert_eq!(converse_with(input, pipeline), output);
+        output
+    }
+
+    fn converse_with(input: &[u8], pipeline: Pipeline) -> String {
+        let databases: Mutex<Databases<String, CompactString>> = Mutex::new(Databases::new(2));
+        let mut output: Vec<u8> = Vec::new();
+        handle(input, &mut output, &databases, &Mutex::new(pipeline)).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    // lets every command through as it is
+    struct Pass;
+    impl Interceptor for Pass {}
+
+    #[test]
+    fn test_interceptors() {
+        use std::sync::Arc;
+
+        // refuses keys starting with an underscore, keeps values in upper
+        // case, and records the commands that were answered
+        struct Guard(Arc<Mutex<Vec<String>>>);
+        impl Interceptor for Guard {
+            fn before(&mut self, command: &mut command::Command) -> Option<Response> {
+                match command {
+                    command::Command::Get(name) | command::Command::Set(name, _)
+                        if name.starts_with('_') =>
+                    {
+                        Some(Response::Error(format!("{} is reserved", name)))
+                    }
+                    command::Command::Set(_, value) => {
+                        *value = value.to_uppercase();
+                        None
+                    }
+                    _ => None,
+                }
+            }
+
+            fn after(&mut self, command: &command::Command, _response: &Response) {
+                self.0.lock().unwrap().push(format!("{:?}", command));
+            }
+        }
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Box::new(Guard(log.clone())));
+        let output = converse_with(
+            b"SET a shout\r\n*2\r\n$3\r\nget\r\n$1\r\na\r\nSET _a x\r\nGET _a\r\n\
+              LPUSH _l x\r\nDEL a\r\nMULTI\r\nSET b quiet\r\nEXEC\r\nGET b\r\n",
+            pipeline,
+        );
+        assert_eq!(
+            output,
+            "+OK\r\n$5\r\nSHOUT\r\n-ERR _a is reserved\r\n-ERR _a is reserved\r\n:1\r\n:1\r\n\
+             +OK\r\n+QUEUED\r\n*1\r\n+OK\r\n$5\r\nQUIET\r\n"
+        );
+        // the commands only the server has pass by the interceptors
+        let log = log.lock().unwrap();
+        assert_eq!(log.len(), 7);
+        assert_eq!(log[0], r#"Set("a", "SHOUT")"#);
+        assert_eq!(log[1], r#"Get("a")"#);
+        assert_eq!(log[4], r#"Push("_l", ["x"], true)"#);
+        assert_eq!(log[5], r#"Set("b", "QUIET")"#);
+    }
+
+    #[test]
+    fn test_commands() {
+        let input = b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$2\r\n10\r\n\
+            *2\r\n$3\r\nget\r\n$1\r\na\r\n\
+            *2\r\n$3\r\nGET\r\n$1\r\nb\r\n\
+            SET \"b\" '10'\r\n\
+            MSET c 20 d 30\r\n\
+            MGET a c e\r\n\
+            DEL c d\r\n\
+            SETNX n 1\r\n\
+            SETNX n 2\r\n\
+            COPY n m\r\n\
+            COPY n m replace\r\n\
+            GETDEL m\r\n\
+            APPEND m xyz\r\n\
+            DEL n m\r\n\
+            INCR n\r\n\
+            INCRBY n 9\r\n\
+            DECRBY n 3\r\n\
+            DECR n\r\n\
+            INCR a\r\n\
+            DEL n\r\n\
+            NUMEQUALTO 10\r\n\
+            EXISTS a b c\r\n\
+            DEL a c\r\n\
+            EXISTS a\r\n\
+            DBSIZE\r\n\
+            RANDOMKEY\r\n\
+            FLUSHALL\r\n\
+            DBSIZE\r\n\
+            RANDOMKEY\r\n\
+            SET b 10\r\n\
+            PING\r\n\
+            \r\n\
+            SCAN 0 MATCH b* COUNT 5\r\n\
+            COMMAND DOCS\r\n\
+            QUIT\r\n\
+            GET b\r\n";
+        let expected =
+            "+OK\r\n$2\r\n10\r\n$-1\r\n+OK\r\n+OK\r\n*3\r\n$2\r\n10\r\n$2\r\n20\r\n$-1\r\n:2\r\n:1\r\n:0\r\n:1\r\n:1\r\n$1\r\n1\r\n:3\r\n:2\r\n:1\r\n:10\r\n:7\r\n:6\r\n:11\r\n:1\r\n:1\r\n:2\r\n:1\r\n:0\r\n:1\r\n$1\r\nb\r\n+OK\r\n:0\r\n$-1\r\n+OK\r\n+PONG\r\n*2\r\n$1\r\n0\r\n*1\r\n$1\r\nb\r\n*0\r\n+OK\r\n";
+        assert_eq!(converse(input), expected);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            converse(b"FLY away\r\nGET\r\nSET a\r\n"),
+            "-ERR unknown command 'FLY'\r\n\
+             -ERR wrong number of arguments for 'get' command\r\n\
+             -ERR wrong number of arguments for 'set' command\r\n"
+        );
+        // an amount whose negation overflows is refused
+        assert_eq!(
+            converse(b"DECRBY n -9223372036854775808\r\nDECRBY n 9223372036854775807\r\n"),
+            "-ERR value is not an integer or out of range\r\n\
+             :-9223372036854775807\r\n"
+        );
+        assert_eq!(
+            converse(b"GET \"a\r\n"),
+            "-ERR Protocol error: unterminated double quote\r\n"
+        );
+        assert_eq!(
+            converse(b"*1\r\n$x\r\n"),
+            "-ERR Protocol error: invalid length\r\n"
+        );
+        assert_eq!(
+            converse(b"*2\r\n$3\r\nGET\r\n"),
+            "-ERR Protocol error: unexpected end of request\r\n"
+        );
+        assert_eq!(
+            converse(b"*2\r\n$3\r\nGET\r\n$1\r\n\xff\r\n"),
+            "-ERR arguments must be valid UTF-8\r\n"
+        );
+        // binary literals of inline commands
+        assert_eq!(
+            converse(b"SET a x'6869'\r\nGET b64'YQ=='\r\nGET x'ff'\r\nGET x'f'\r\n"),
+            "+OK\r\n$2\r\nhi\r\n\
+             -ERR arguments must be valid UTF-8\r\n\
+             -ERR Protocol error: invalid hex literal\r\n"
+        );
+        assert_eq!(
+            converse(b"BGREWRITEAOF\r\n"),
+            "-ERR the database has no log\r\n"
+        );
+    }
+
+    #[test]
+    fn test_lists() {
+        assert_eq!(
+            converse(
+                b"RPUSH l b c\r\nLPUSH l a\r\nLRANGE l 0 -1\r\nLPOP l\r\nRPOP l\r\nLLEN l\r\n\
+                  SET s 1\r\nLLEN s\r\nLRANGE l x 1\r\n"
+            ),
+            ":2\r\n:3\r\n*3\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n$1\r\na\r\n$1\r\nc\r\n:1\r\n+OK\r\n\
+             -WRONGTYPE Operation against a key holding the wrong kind of value\r\n\
+             -ERR value is not an integer or out of range\r\n"
+        );
+    }
+
+    #[test]
+    fn test_hashes() {
+        assert_eq!(
+            converse(
+                b"HSET h b 2 a 1\r\nHGET h a\r\nHGETALL h\r\nHDEL h a c\r\nHLEN h\r\n\
+                  SET s 1\r\nHGET s a\r\nDEL h\r\nHLEN h\r\nHSET h a\r\n"
+            ),
+            ":2\r\n$1\r\n1\r\n*4\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\n2\r\n:1\r\n:1\r\n+OK\r\n\
+             -WRONGTYPE Operation against a key holding the wrong kind of value\r\n\
+             :1\r\n:0\r\n\
+             -ERR wrong number of arguments for 'hset' command\r\n"
+        );
+    }
+
+    #[test]
+    fn test_sorted_sets() {
+        assert_eq!(
+            converse(
+                b"ZADD z 2 b 1 a\r\nZSCORE z b\r\nZRANGE z 0 -1 WITHSCORES\r\nZREM z a\r\n\
+                  ZRANGEBYSCORE z (1 +inf\r\nZADD z x c\r\nZRANGEBYSCORE z a 1\r\nTYPE z\r\nTYPE q\r\n"
+            ),
+            ":2\r\n$1\r\n2\r\n*4\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\n2\r\n:1\r\n\
+             *1\r\n$1\r\nb\r\n\
+             -ERR value is not a valid float\r\n\
+             -ERR min or max is not a float\r\n+zset\r\n+none\r\n"
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        assert_eq!(
+            converse(
+                b"JSON.SET d $ \"{\\\"a\\\": [1]}\"\r\nJSON.SET d $.a[0] 2\r\nJSON.GET d $.a\r\n\
+                  JSON.SET d $.b.c 1\r\nJSON.SET d $ {\r\nTYPE d\r\nGET d\r\n"
+            ),
+            "+OK\r\n+OK\r\n$3\r\n[2]\r\n$-1\r\n\
+             -ERR invalid JSON: EOF while parsing an object at line 1 column 1\r\n\
+             +json\r\n$-1\r\n"
+        );
+    }
+
+    #[test]
+    fn test_streams() {
+        let reply = converse(b"XADD s * a 1\r\nXLEN s\r\nXRANGE s - +\r\nXRANGE s 0 0\r\n");
+        let lines: Vec<&str> = reply.split("\r\n").collect();
+        let id = lines[1];
+        assert!(id.parse::<StreamId>().is_ok());
+        let expected = format!(
+            "${}\r\n{}\r\n:1\r\n*1\r\n*2\r\n${}\r\n{}\r\n*2\r\n$1\r\na\r\n$1\r\n1\r\n*0\r\n",
+            id.len(),
+            id,
+            id.len(),
+            id
+        );
+        assert_eq!(reply, expected);
+        assert_eq!(
+            converse(b"XADD s 1-1 a 1\r\nXRANGE s x +\r\nSET v 1\r\nXLEN v\r\nXADD s *\r\n"),
+            "-ERR only automatic stream IDs (*) are supported\r\n\
+             -ERR Invalid stream ID specified as stream command argument\r\n\
+             +OK\r\n\
+             -WRONGTYPE Operation against a key holding the wrong kind of value\r\n\
+             -ERR wrong number of arguments for 'xadd' command\r\n"
+        );
+    }
+
+    #[test]
+    fn test_hyperloglog() {
+        assert_eq!(
+            converse(
+                b"PFADD h a b c\r\nPFADD h a\r\nPFADD g d\r\nPFCOUNT h g\r\nPFMERGE m h g\r\n\
+                  PFCOUNT m\r\nSET s 1\r\nPFADD s a\r\nPFCOUNT\r\n"
+            ),
+            ":1\r\n:0\r\n:1\r\n:4\r\n+OK\r\n:4\r\n+OK\r\n\
+             -WRONGTYPE Operation against a key holding the wrong kind of value\r\n\
+             -ERR wrong number of arguments for 'pfcount' command\r\n"
+        );
+    }
+
+    #[test]
+    fn test_getex() {
+        assert_eq!(
+            converse(
+                b"SET a 1\r\nGETEX a EX 60\r\nGETEX a px 500\r\nGETEX a PERSIST\r\nGETEX a\r\n\
+                  GETEX b EX 60\r\nGETEX a EX 0\r\nGETEX a EX x\r\nGETEX a KEEP\r\nGETEX\r\n"
+            ),
+            "+OK\r\n$1\r\n1\r\n$1\r\n1\r\n$1\r\n1\r\n$1\r\n1\r\n$-1\r\n\
+             -ERR invalid expire time in 'getex' command\r\n\
+             -ERR value is not an integer or out of range\r\n\
+             -ERR syntax error\r\n\
+             -ERR wrong number of arguments for 'getex' command\r\n"
+        );
+        // the time-to-live is given to the key
+        let databases: Mutex<Databases<String, CompactString>> = Mutex::new(Databases::new(1));
+        let mut output: Vec<u8> = Vec::new();
+        handle(
+            &b"SET a 1\r\nGETEX a PX 1\r\n"[..],
+            &mut output,
+            &databases,
+            &Mutex::new(Pipeline::new()),
+        )
+        .unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(databases.lock().unwrap()[0].get("a"), None);
+        // a time too far off is refused, and the server carries on
+        output.clear();
+        handle(
+            &b"SET a 1\r\nGETEX a EX 18446744073709551615\r\nPING\r\n"[..],
+            &mut output,
+            &databases,
+            &Mutex::new(Pipeline::new()),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "+OK\r\n-ERR invalid expire time in 'getex' command\r\n+PONG\r\n"
+        );
+        assert!(!databases.is_poisoned());
+        assert_eq!(databases.lock().unwrap()[0].ttl("a").unwrap(), None);
+    }
+
+    #[test]
+    fn test_value_type() {
+        assert_eq!(
+            converse(
+                b"SET n 42\r\nSET f 1.5\r\nSET b true\r\nSET s hello\r\nLPUSH l a\r\n\
+                  VALUETYPE n\r\nVALUETYPE f\r\nVALUETYPE b\r\nVALUETYPE s\r\nVALUETYPE x\r\n\
+                  VALUETYPE l\r\nVALUETYPE\r\n"
+            ),
+            "+OK\r\n+OK\r\n+OK\r\n+OK\r\n:1\r\n\
+             +integer\r\n+float\r\n+boolean\r\n+string\r\n+none\r\n\
+             -WRONGTYPE Operation against a key holding the wrong kind of value\r\n\
+             -ERR wrong number of arguments for 'valuetype' command\r\n"
+        );
+    }
+
+    #[test]
+    fn test_bitmap() {
+        assert_eq!(
+            converse(
+                b"SETBIT b 0 1\r\nSETBIT b 9 1\r\nSETBIT b 9 1\r\nGETBIT b 0\r\nGETBIT b 1\r\n\
+                  BITCOUNT b\r\nBITCOUNT b 1 -1\r\nSETBIT b x 1\r\nSETBIT b 0 2\r\n\
+                  LPUSH l a\r\nGETBIT l 0\r\nBITCOUNT b 0\r\n"
+            ),
+            ":0\r\n:0\r\n:1\r\n:1\r\n:0\r\n:2\r\n:1\r\n\
+             -ERR bit offset is not an integer or out of range\r\n\
+             -ERR bit is not an integer or out of range\r\n\
+             :1\r\n\
+             -WRONGTYPE Operation against a key holding the wrong kind of value\r\n\
+             -ERR wrong number of arguments for 'bitcount' command\r\n"
+        );
+    }
+
+    #[test]
+    fn test_select() {
+        assert_eq!(
+            converse(b"SET a 1\r\nSELECT 1\r\nGET a\r\nSET a 2\r\nSELECT 0\r\nGET a\r\nMOVE a 1\r\nSET b 1\r\nMOVE b 1\r\nSELECT 2\r\nSELECT\r\n"),
+            "+OK\r\n+OK\r\n$-1\r\n+OK\r\n+OK\r\n$1\r\n1\r\n:0\r\n+OK\r\n:1\r\n\
+             -ERR DB index is out of range\r\n\
+             -ERR wrong number of arguments for 'select' command\r\n"
+        );
+    }
+
+    #[test]
+    fn test_select_persisted() {
+        let path = std::env::temp_dir().join(format!("simpledb-select-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let database: Database<String, CompactString> = Database::open(&path).unwrap();
+        let databases = Mutex::new(Databases::with_first(database, 2));
+        let mut output: Vec<u8> = Vec::new();
+        let input = b"SET a 1\r\nSELECT 1\r\nMOVE a 1\r\nSELECT 0\r\nGET a\r\n";
+        handle(
+            &input[..],
+            &mut output,
+            &databases,
+            &Mutex::new(Pipeline::new()),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "+OK\r\n-ERR only database 0 is persisted, another cannot be selected\r\n\
+             -ERR only database 0 is persisted, keys cannot be moved out of it\r\n\
+             +OK\r\n$1\r\n1\r\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_transactions() {
+        assert_eq!(
+            converse(b"MULTI\r\nSET a 1\r\nINCR a\r\nMULTI\r\nEXEC\r\nGET a\r\n"),
+            "+OK\r\n+QUEUED\r\n+QUEUED\r\n-ERR MULTI calls can not be nested\r\n\
+             *2\r\n+OK\r\n:2\r\n$1\r\n2\r\n"
+        );
+        // a watched key changed before EXEC aborts the queued commands
+        assert_eq!(
+            converse(b"WATCH a b\r\nSET a 1\r\nMULTI\r\nSET b 2\r\nEXEC\r\nGET b\r\n"),
+            "+OK\r\n+OK\r\n+OK\r\n+QUEUED\r\n$-1\r\n$-1\r\n"
+        );
+        assert_eq!(
+            converse(b"WATCH a\r\nRPUSH l x\r\nMULTI\r\nSET b 2\r\nEXEC\r\nWATCH a\r\nUNWATCH\r\nSET a 1\r\nMULTI\r\nEXEC\r\n"),
+            "+OK\r\n:1\r\n+OK\r\n+QUEUED\r\n*1\r\n+OK\r\n+OK\r\n+OK\r\n+OK\r\n+OK\r\n*0\r\n"
+        );
+        assert_eq!(
+            converse(
+                b"EXEC\r\nDISCARD\r\nWATCH\r\nMULTI\r\nWATCH a\r\nSET a 1\r\nDISCARD\r\nGET a\r\n"
+            ),
+            "-ERR EXEC without MULTI\r\n-ERR DISCARD without MULTI\r\n\
+             -ERR wrong number of arguments for 'watch' command\r\n+OK\r\n\
+             -ERR WATCH inside MULTI is not allowed\r\n+QUEUED\r\n+OK\r\n$-1\r\n"
+        );
+    }
+
+    #[test]
+    fn test_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let databases: Arc<Mutex<Databases>> = Arc::new(Mutex::new(Databases::new(2)));
+        let shared = databases.clone();
+        thread::spawn(move || serve(listener, shared));
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(b"SET x 1\r\nGET x\r\nQUIT\r\n").unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "+OK\r\n$1\r\n1\r\n+OK\r\n");
+        assert_eq!(databases.lock().unwrap()[0].get("x"), Some("1".into()));
+    }
+}
@@ -0,0 +1,242 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! A set of members ordered by score, such as players ranked on a leaderboard
+//! or events ordered by time. Like the rest of the store it is made of
+//! persistent maps, so that transactions can copy it cheaply.
+
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::ops::Bound;
+
+///
+/// Score of a member, ordered so that it can be used as a key. Scores are
+/// never NaN, which the sorted set refuses.
+///
+#[derive(Clone, Copy, Debug)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+///
+/// Members with their scores, visited in order of score and then of member.
+///
+#[derive(Clone)]
+pub struct SortedSet<K> {
+    scores: im::OrdMap<K, Score>,
+    members: im::OrdMap<Score, im::OrdSet<K>>,
+}
+
+impl<K> PartialEq for SortedSet<K>
+where
+    K: Ord,
+{
+    fn eq(&self, other: &Self) -> bool {
+        // the members by score follow from the scores
+        self.scores == other.scores
+    }
+}
+
+impl<K> Default for SortedSet<K>
+where
+    K: Ord + Clone,
+{
+    fn default() -> Self {
+        Self {
+            scores: im::OrdMap::new(),
+            members: im::OrdMap::new(),
+        }
+    }
+}
+
+impl<K> SortedSet<K>
+where
+    K: Ord + Clone,
+{
+    /// Give the member the score, returning true if it was not already in
+    /// the set. The score must not be NaN.
+    pub fn insert(&mut self, member: K, score: f64) -> bool {
+        debug_assert!(!score.is_nan(), "score must be a number");
+        let score = Score(score);
+        let old = self.scores.insert(member.clone(), score);
+        if let Some(old) = old {
+            if old == score {
+                return false;
+            }
+            self.unlink(&member, old);
+        }
+        self.members.entry(score).or_default().insert(member);
+        old.is_none()
+    }
+
+    /// Returns the score of the member, if it is in the set.
+    pub fn score<Q>(&self, member: &Q) -> Option<f64>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.scores.get(member).map(|score| score.0)
+    }
+
+    /// Remove the member, returning true if it was in the set.
+    pub fn remove<Q>(&mut self, member: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.unlink(member, score);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove the member from those having the score.
+    fn unlink<Q>(&mut self, member: &Q, score: Score)
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        if let Some(members) = self.members.get_mut(&score) {
+            members.remove(member);
+            if members.is_empty() {
+                self.members.remove(&score);
+            }
+        }
+    }
+
+    /// Returns the number of members.
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    /// Returns true if there are no members.
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    /// Visit every member with its score, lowest score first.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, f64)> {
+        self.members
+            .iter()
+            .flat_map(|(score, members)| members.iter().map(move |member| (member, score.0)))
+    }
+
+    /// Visit the members that were added, given a new score, or removed since
+    /// the older set, in member order, with their scores in this set or
+    /// `None` if they were removed.
+    pub fn changes_since<'a>(
+        &'a self,
+        older: &'a Self,
+    ) -> impl Iterator<Item = (&'a K, Option<f64>)> {
+        use im::ordmap::DiffItem;
+        older.scores.diff(&self.scores).map(|item| match item {
+            DiffItem::Add(member, score) => (member, Some(score.0)),
+            DiffItem::Update { new, .. } => (new.0, Some(new.1 .0)),
+            DiffItem::Remove(member, _) => (member, None),
+        })
+    }
+
+    /// Visit the members whose scores lie within the bounds, lowest first.
+    pub fn range_by_score(
+        &self,
+        min: Bound<f64>,
+        max: Bound<f64>,
+    ) -> impl Iterator<Item = (&K, f64)> {
+        // an empty range would make the map panic
+        let empty = match (min, max) {
+            (Bound::Included(a), Bound::Included(b)) => a > b,
+            (Bound::Included(a) | Bound::Excluded(a), Bound::Excluded(b))
+            | (Bound::Excluded(a), Bound::Included(b)) => a >= b,
+            _ => false,
+        };
+        let range = if empty {
+            None
+        } else {
+            Some(self.members.range((min.map(Score), max.map(Score))))
+        };
+        range
+            .into_iter()
+            .flatten()
+            .flat_map(|(score, members)| members.iter().map(move |member| (member, score.0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sorted_set() {
+        let mut set: SortedSet<String> = SortedSet::default();
+        assert!(set.insert("b".into(), 2.0));
+        assert!(set.insert("a".into(), 2.0));
+        assert!(set.insert("c".into(), -1.5));
+        assert!(!set.insert("c".into(), 3.0));
+        assert_eq!(set.len(), 3);
+        assert_eq!(set.score("c"), Some(3.0));
+        assert_eq!(set.score("z"), None);
+        let all: Vec<(&String, f64)> = set.iter().collect();
+        assert_eq!(
+            all,
+            vec![(&"a".into(), 2.0), (&"b".into(), 2.0), (&"c".into(), 3.0)]
+        );
+        let some: Vec<&String> = set
+            .range_by_score(Bound::Excluded(2.0), Bound::Unbounded)
+            .map(|(member, _)| member)
+            .collect();
+        assert_eq!(some, vec!["c"]);
+        assert_eq!(
+            set.range_by_score(Bound::Included(3.0), Bound::Excluded(3.0))
+                .count(),
+            0
+        );
+        assert_eq!(
+            set.range_by_score(Bound::Included(f64::NEG_INFINITY), Bound::Included(2.0))
+                .count(),
+            2
+        );
+        let older = set.clone();
+        assert!(set.remove("a"));
+        assert!(!set.remove("a"));
+        assert_eq!(set.iter().count(), 2);
+        set.insert("b".into(), 4.0);
+        set.insert("d".into(), 0.0);
+        set.insert("c".into(), 3.0);
+        let changes: Vec<(&String, Option<f64>)> = set.changes_since(&older).collect();
+        assert_eq!(
+            changes,
+            vec![
+                (&"a".into(), None),
+                (&"b".into(), Some(4.0)),
+                (&"d".into(), Some(0.0))
+            ]
+        );
+        set.remove("d");
+        set.remove("b");
+        set.remove("c");
+        assert!(set.is_empty());
+        assert!(set.members.is_empty());
+    }
+}
@@ -0,0 +1,124 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! Export of the committed values of a database to a SQLite file, for the
+//! many analysis tools that speak SQL, with the `sqlite` feature.
+//!
+//! The values are written to a table with the columns `key TEXT PRIMARY KEY`
+//! and `value TEXT`, which is created if the file does not have it and is
+//! otherwise emptied first, all within a single SQLite transaction so that
+//! readers see either the old rows or the new. Keys and values that are not
+//! UTF-8 are written as blobs, which SQLite allows in any column. Lists,
+//! hashes, and the other kinds of data are not exported.
+
+use crate::error::{Error, Result};
+use crate::persist::Persistent;
+use crate::store::Snapshot;
+use rusqlite::types::{ToSqlOutput, ValueRef};
+use rusqlite::Connection;
+use std::hash::Hash;
+use std::path::Path;
+
+/// Write the values of the snapshot to the table of the SQLite file at the
+/// given path, creating either as needed, and return the number of values
+/// written.
+pub fn export<K, V, P>(snapshot: &Snapshot<K, V>, path: P, table: &str) -> Result<usize>
+where
+    K: Ord + Clone + Persistent,
+    V: Hash + Eq + Clone + Persistent,
+    P: AsRef<Path>,
+{
+    if table.is_empty() {
+        return Err(Error::Parse("the table must have a name".into()));
+    }
+    let mut connection = Connection::open(path)?;
+    // quoted, so that any name may be used
+    let table = format!("\"{}\"", table.replace('"', "\"\""));
+    let transaction = connection.transaction()?;
+    transaction.execute(
+        &format!(
+            "CREATE TABLE IF NOT EXISTS {} (key TEXT PRIMARY KEY, value TEXT)",
+            table
+        ),
+        [],
+    )?;
+    transaction.execute(&format!("DELETE FROM {}", table), [])?;
+    let mut count = 0;
+    {
+        let sql = format!("INSERT INTO {} (key, value) VALUES (?1, ?2)", table);
+        let mut insert = transaction.prepare(&sql)?;
+        for (name, value) in snapshot.iter() {
+            insert.execute([column(name.to_bytes()), column(value.to_bytes())])?;
+            count += 1;
+        }
+    }
+    transaction.commit()?;
+    Ok(count)
+}
+
+/// Returns the bytes as text if they are UTF-8, and otherwise as a blob.
+fn column(bytes: &[u8]) -> ToSqlOutput<'_> {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => ToSqlOutput::Borrowed(ValueRef::Text(text.as_bytes())),
+        Err(_) => ToSqlOutput::Borrowed(ValueRef::Blob(bytes)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Database;
+
+    #[test]
+    fn test_export() {
+        let path = std::env::temp_dir().join(format!("simpledb-export-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut db: Database = Database::new();
+        db.set("a", "10");
+        db.set("b", "20");
+        db.rpush("l", ["x"]).unwrap();
+        // only the committed state is exported
+        db.begin();
+        db.set("c", "30");
+        assert_eq!(export(&db.freeze_snapshot(), &path, "kv").unwrap(), 2);
+        db.commit().unwrap();
+        db.delete("a");
+        assert_eq!(export(&db.freeze_snapshot(), &path, "kv").unwrap(), 2);
+        assert_eq!(
+            export(&db.freeze_snapshot(), &path, "odd \"name\"").unwrap(),
+            2
+        );
+        let connection = Connection::open(&path).unwrap();
+        let mut select = connection
+            .prepare("SELECT key, value FROM kv ORDER BY key")
+            .unwrap();
+        let rows: Vec<(String, String)> = select
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![("b".into(), "20".into()), ("c".into(), "30".into())]
+        );
+        let count: i64 = connection
+            .query_row("SELECT COUNT(*) FROM \"odd \"\"name\"\"\"", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(count, 2);
+        // bytes that are not text are kept as they are
+        let mut bytes: Database<String, Vec<u8>> = Database::default();
+        bytes.set("raw".to_owned(), vec![0xff, 0x00]);
+        assert_eq!(export(&bytes.freeze_snapshot(), &path, "raw").unwrap(), 1);
+        let value: Vec<u8> = connection
+            .query_row("SELECT value FROM raw", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(value, vec![0xff, 0x00]);
+        assert!(export(&bytes.freeze_snapshot(), &path, "").is_err());
+        drop(select);
+        drop(connection);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
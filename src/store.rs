@@ -4,374 +4,5939 @@
 
 //! A simple in-memory key/value with nested transactions and a function for
 //! getting the number of occurrences of a particular value. Keys and values are
-//! strings.
+//! strings by default, but any ordered key type and hashable value type may be
+//! used. For workloads with mostly short values, `CompactString` keeps each
+//! value inline rather than requiring a heap allocation per value.
 
-use std::collections::HashMap;
+use crate::backup::{Fingerprint, Manifest};
+use crate::changeset::{Change, Changeset, KeyDiff};
+use crate::crypt::Secret;
+use crate::error::{Error, Result};
+use crate::glob;
+use crate::index::{ExpiryIndex, LengthIndex, ValueIndex};
+use crate::jsonpath;
+use crate::observer::{CommitHook, Observer, RollbackHook};
+use crate::persist::{
+    self, Durability, HashEntries, Mutation, Persistent, RecoveryReport, RestorePoint,
+    SnapshotRefs, WriteAheadLog,
+};
+use crate::sortedset::SortedSet;
+use crate::stream::{Entry, EntryRefs, Stream, StreamId};
+pub use compact_str::CompactString;
+use rand::seq::IteratorRandom;
+use rayon::prelude::*;
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::hash::{BuildHasher, Hash};
+use std::io::{self, Read, Write};
+use std::ops::{Bound, Deref, DerefMut, RangeInclusive};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 ///
-/// A simple key/value store that counts values.
+/// The kind of data held by a key.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// An ordinary value.
+    Value,
+    /// A list of values.
+    List,
+    /// A hash of fields to values.
+    Hash,
+    /// A set of members ordered by score.
+    SortedSet,
+    /// A JSON document.
+    Json,
+    /// An append-only stream of entries.
+    Stream,
+}
+
+impl Kind {
+    /// Returns the name that the `TYPE` command reports, as in Redis.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::Value => "string",
+            Kind::List => "list",
+            Kind::Hash => "hash",
+            Kind::SortedSet => "zset",
+            Kind::Json => "json",
+            Kind::Stream => "stream",
+        }
+    }
+}
+
+///
+/// A simple key/value store that counts values. The maps are persistent data
+/// structures, making a copy of the store cheap as the copy shares all of its
+/// structure with the original until either one is modified. Keeping the keys
+/// ordered also allows finding the differences between two copies in time
+/// proportional to the number of changes. Keys may instead hold lists,
+/// hashes of fields to values, sorted sets, JSON documents, or streams, which
+/// are kept apart from the values and are not counted.
 ///
 #[derive(Clone)]
-struct CountingStore {
-    values: HashMap<String, Option<String>>,
-    counts: HashMap<String, u32>,
+struct CountingStore<K, V> {
+    values: im::OrdMap<K, V>,
+    lists: im::OrdMap<K, im::Vector<V>>,
+    hashes: im::OrdMap<K, im::OrdMap<K, V>>,
+    sorted: im::OrdMap<K, SortedSet<K>>,
+    documents: im::OrdMap<K, Arc<serde_json::Value>>,
+    streams: im::OrdMap<K, Stream<K, V>>,
+    counts: ValueIndex<V>,
+    expiry: ExpiryIndex<K>,
+    lengths: Option<LengthIndex<K, V>>,
 }
 
-impl CountingStore {
+impl<K, V> CountingStore<K, V>
+where
+    K: Ord + Clone,
+    V: Hash + Eq + Clone,
+{
     /// Construct a new counting store.
     pub fn new() -> Self {
         Self {
-            values: HashMap::new(),
-            counts: HashMap::new(),
+            values: im::OrdMap::new(),
+            lists: im::OrdMap::new(),
+            hashes: im::OrdMap::new(),
+            sorted: im::OrdMap::new(),
+            documents: im::OrdMap::new(),
+            streams: im::OrdMap::new(),
+            counts: ValueIndex::new(),
+            expiry: ExpiryIndex::new(),
+            lengths: None,
         }
     }
 
-    /// Returns true if the key exists at all, which includes deleted keys.
-    pub fn contains(&self, name: &str) -> bool {
-        self.values.contains_key(name)
+    /// Returns the keys whose data, of whatever kind, differs between this
+    /// store and the other, in key order.
+    fn changed_keys(&self, other: &Self) -> Vec<K> {
+        let mut names = BTreeSet::new();
+        names.extend(
+            self.values
+                .diff(&other.values)
+                .map(|item| diff_key(item).clone()),
+        );
+        names.extend(
+            self.lists
+                .diff(&other.lists)
+                .map(|item| diff_key(item).clone()),
+        );
+        names.extend(
+            self.hashes
+                .diff(&other.hashes)
+                .map(|item| diff_key(item).clone()),
+        );
+        names.extend(
+            self.sorted
+                .diff(&other.sorted)
+                .map(|item| diff_key(item).clone()),
+        );
+        names.extend(
+            self.documents
+                .diff(&other.documents)
+                .map(|item| diff_key(item).clone()),
+        );
+        names.extend(
+            self.streams
+                .diff(&other.streams)
+                .map(|item| diff_key(item).clone()),
+        );
+        names.into_iter().collect()
     }
 
-    /// Retrieve the value for the given key, if any.
-    pub fn get(&self, name: &str) -> Option<String> {
-        let cloned = self.values.get(name).cloned();
-        cloned.flatten()
+    /// Start keeping track of the keys by the length of their values.
+    pub fn index_lengths(&mut self, length_of: fn(&V) -> usize) {
+        let mut lengths = LengthIndex::new(length_of);
+        for (name, value) in self.values.iter() {
+            lengths.insert(name.clone(), value);
+        }
+        self.lengths = Some(lengths);
     }
 
-    /// Save the value using the given key.
-    pub fn set<T: Into<String>>(&mut self, name: T, value: T) {
-        // update count for new value
-        let name_str = name.into();
-        let value_str = value.into();
-        if let Some(c) = self.counts.get_mut(&value_str) {
-            *c += 1;
+    /// Retrieve a reference to the value for the given key, if any.
+    pub fn get_ref<Q>(&self, name: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.values.get(name)
+    }
+
+    /// Returns the number of keys that hold anything, whether or not they
+    /// have expired.
+    pub fn len(&self) -> usize {
+        self.values.len()
+            + self.lists.len()
+            + self.hashes.len()
+            + self.sorted.len()
+            + self.documents.len()
+            + self.streams.len()
+    }
+
+    /// Visit the keys that hold anything, of every kind, in key order,
+    /// starting from the bound and whether or not they have expired.
+    pub fn names<'a, Q>(&'a self, start: Bound<&Q>) -> impl Iterator<Item = &'a K> + 'a
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        type Names<'a, K> = std::iter::Peekable<Box<dyn Iterator<Item = &'a K> + 'a>>;
+        fn names_of<'a, K, T>(names: impl Iterator<Item = (&'a K, &'a T)> + 'a) -> Names<'a, K>
+        where
+            K: 'a,
+            T: 'a,
+        {
+            let names: Box<dyn Iterator<Item = &'a K> + 'a> = Box::new(names.map(|(name, _)| name));
+            names.peekable()
+        }
+        let range = (start, Bound::Unbounded);
+        let mut sources: Vec<Names<'a, K>> = vec![
+            names_of(self.values.range(range)),
+            names_of(self.lists.range(range)),
+            names_of(self.hashes.range(range)),
+            names_of(self.sorted.range(range)),
+            names_of(self.documents.range(range)),
+            names_of(self.streams.range(range)),
+        ];
+        // each key is held by only one of the maps, so merging them visits
+        // every key once
+        std::iter::from_fn(move || {
+            let (_, source) = sources
+                .iter_mut()
+                .enumerate()
+                .filter_map(|(index, source)| source.peek().map(|name| (*name, index)))
+                .min()?;
+            sources[source].next()
+        })
+    }
+
+    /// Returns true if no key holds anything of any kind.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+            && self.lists.is_empty()
+            && self.hashes.is_empty()
+            && self.sorted.is_empty()
+            && self.documents.is_empty()
+            && self.streams.is_empty()
+            && self.expiry.is_empty()
+    }
+
+    /// Returns true if the key holds anything, whether or not it has expired.
+    pub fn contains<Q>(&self, name: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.kind(name).is_some()
+    }
+
+    /// Returns the kind of data held by the key, if any, whether or not it
+    /// has expired.
+    pub fn kind<Q>(&self, name: &Q) -> Option<Kind>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        if self.values.contains_key(name) {
+            Some(Kind::Value)
+        } else if self.lists.contains_key(name) {
+            Some(Kind::List)
+        } else if self.hashes.contains_key(name) {
+            Some(Kind::Hash)
+        } else if self.sorted.contains_key(name) {
+            Some(Kind::SortedSet)
+        } else if self.documents.contains_key(name) {
+            Some(Kind::Json)
+        } else if self.streams.contains_key(name) {
+            Some(Kind::Stream)
         } else {
-            self.counts.insert(value_str.clone(), 1);
+            None
+        }
+    }
+
+    /// Returns true if the key holds a kind of data other than the one given.
+    pub fn holds_other<Q>(&self, name: &Q, kind: Kind) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.kind(name).is_some_and(|held| held != kind)
+    }
+
+    /// Save the value using the given key, replacing whatever else it held,
+    /// and which then never expires.
+    pub fn set(&mut self, name: K, value: V) {
+        if !self.expiry.is_empty() {
+            self.expiry.remove(&name);
+        }
+        if !self.lists.is_empty() {
+            self.lists.remove(&name);
+        }
+        if !self.hashes.is_empty() {
+            self.hashes.remove(&name);
+        }
+        if !self.sorted.is_empty() {
+            self.sorted.remove(&name);
+        }
+        if !self.documents.is_empty() {
+            self.documents.remove(&name);
+        }
+        if !self.streams.is_empty() {
+            self.streams.remove(&name);
+        }
+        // update count for new value
+        self.counts.increment(&value);
+        if let Some(lengths) = self.lengths.as_mut() {
+            if let Some(old) = self.values.get(&name) {
+                lengths.remove(&name, old);
+            }
+            lengths.insert(name.clone(), &value);
         }
         // update count for the old value, if any
-        self.delete(&name_str);
-        self.values.insert(name_str, Some(value_str));
+        if let Some(old) = self.values.insert(name, value) {
+            self.counts.decrement(&old);
+        }
     }
 
-    /// Removes the value with the given key from the store by overwriting it
-    /// with a `None`.
-    pub fn delete(&mut self, name: &str) {
-        if let Some(v) = self.values.get_mut(name) {
-            if let Some(value) = v.take() {
-                if let Some(c) = self.counts.get_mut(&value) {
-                    *c -= 1;
-                }
+    /// Removes whatever the key holds from the store.
+    pub fn delete<Q>(&mut self, name: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let removed = if let Some(value) = self.values.remove(name) {
+            self.counts.decrement(&value);
+            if let Some(lengths) = self.lengths.as_mut() {
+                lengths.remove(name, &value);
+            }
+            true
+        } else {
+            self.lists.remove(name).is_some()
+                || self.hashes.remove(name).is_some()
+                || self.sorted.remove(name).is_some()
+                || self.documents.remove(name).is_some()
+                || self.streams.remove(name).is_some()
+        };
+        if removed && !self.expiry.is_empty() {
+            self.expiry.remove(name);
+        }
+    }
+
+    /// Add the values to the front of the list held by the key, one at a
+    /// time, or to the end of it, creating the list if necessary, and return
+    /// the new length of the list.
+    pub fn push(&mut self, name: K, values: impl IntoIterator<Item = V>, front: bool) -> usize {
+        let list = self.lists.entry(name).or_default();
+        for value in values {
+            if front {
+                list.push_front(value);
+            } else {
+                list.push_back(value);
             }
+        }
+        list.len()
+    }
+
+    /// Remove and return the first or the last value of the list held by the
+    /// key, if any. A list that becomes empty is removed.
+    pub fn pop<Q>(&mut self, name: &Q, front: bool) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let list = self.lists.get_mut(name)?;
+        let value = if front {
+            list.pop_front()
+        } else {
+            list.pop_back()
+        };
+        if list.is_empty() {
+            self.delete(name);
+        }
+        value
+    }
+
+    /// Give the member of the sorted set held by the key the score, creating
+    /// the set if necessary, and returning true if the member is new.
+    pub fn zadd(&mut self, name: K, member: K, score: f64) -> bool {
+        self.sorted.entry(name).or_default().insert(member, score)
+    }
+
+    /// Remove the member from the sorted set held by the key, returning true
+    /// if it was there. A sorted set left without members is removed.
+    pub fn zrem<Q>(&mut self, name: &Q, member: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let Some(set) = self.sorted.get_mut(name) else {
+            return false;
+        };
+        let removed = set.remove(member);
+        if set.is_empty() {
+            self.delete(name);
+        }
+        removed
+    }
+
+    /// Add the entry to the stream held by the key, creating the stream if
+    /// necessary.
+    pub fn xadd(&mut self, name: K, id: StreamId, fields: Vec<(K, V)>) {
+        self.streams.entry(name).or_default().insert(id, fields);
+    }
+
+    /// Set the field of the hash held by the key to the value, creating the
+    /// hash if necessary, and returning true if the field is new.
+    pub fn hset(&mut self, name: K, field: K, value: V) -> bool {
+        let hash = self.hashes.entry(name).or_default();
+        hash.insert(field, value).is_none()
+    }
+
+    /// Remove the field from the hash held by the key, returning true if it
+    /// was there. A hash left without fields is removed.
+    pub fn hdel<Q>(&mut self, name: &Q, field: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let Some(hash) = self.hashes.get_mut(name) else {
+            return false;
+        };
+        let removed = hash.remove(field).is_some();
+        if hash.is_empty() {
+            self.delete(name);
+        }
+        removed
+    }
+
+    /// Returns true if the key has a time-to-live that has run out.
+    pub fn is_expired<Q>(&self, name: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        !self.expiry.is_empty()
+            && self
+                .expiry
+                .get(name)
+                .is_some_and(|deadline| deadline <= SystemTime::now())
+    }
+
+    /// Returns the number of keys that have expired but not yet been
+    /// removed.
+    pub fn expired_len(&self) -> usize {
+        if self.expiry.is_empty() {
+            0
         } else {
-            // for the sake of transactions, make the key disappear
-            self.values.insert(name.into(), None);
+            self.expiry
+                .expired(SystemTime::now())
+                .filter(|name| self.contains(*name))
+                .count()
         }
     }
 
+    /// Visit every key and its value in key order, skipping expired keys.
+    pub fn live(&self) -> impl Iterator<Item = (&K, &V)> {
+        let now = (!self.expiry.is_empty()).then(SystemTime::now);
+        self.values.iter().filter(move |(name, _)| match now {
+            Some(now) => self.expiry.get(*name).is_none_or(|deadline| deadline > now),
+            None => true,
+        })
+    }
+
     /// Returns the number of occurrences of the given value.
-    pub fn count(&self, value: &str) -> u32 {
-        *self.counts.get(value).or(Some(&0)).unwrap()
+    pub fn count<Q>(&self, value: &Q) -> i64
+    where
+        V: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.counts.count(value)
+    }
+
+    /// Returns the number of distinct values.
+    pub fn distinct(&self) -> usize {
+        self.counts.distinct()
     }
 
-    /// Removes all deleted entries.
-    pub fn compact(&mut self) {
-        self.values.retain(|_, v| v.is_some());
+    /// Visit each value that occurs along with its count.
+    pub fn value_counts(&self) -> impl Iterator<Item = (&V, i64)> {
+        self.counts.iter()
     }
 }
 
 ///
-/// Combination of a counting store and local metadata to track changes without
-/// altering the parent transaction, if any.
+/// A snapshot of the store for each level of nesting. Starting a transaction
+/// saves a copy of the current store as the parent, which rolling back will
+/// restore, while committing simply forgets the saved copies.
 ///
 #[derive(Clone)]
-struct Transaction {
-    store: CountingStore,
-    parent: Option<Box<Transaction>>,
-    counts: HashMap<String, i64>,
+struct Transaction<K, V> {
+    store: CountingStore<K, V>,
+    parent: Option<Box<Transaction<K, V>>>,
+    // name of the savepoint that this layer begins, if it is one
+    savepoint: Option<String>,
+    // name given to the transaction by `begin_named()`, if any
+    name: Option<String>,
+    // moment after which the transaction is rolled back, if any
+    deadline: Option<Instant>,
 }
 
-impl<'a> Transaction {
+impl<K, V> Transaction<K, V>
+where
+    K: Ord + Clone,
+    V: Hash + Eq + Clone,
+{
     /// Construct a new transaction.
     pub fn new() -> Self {
         Self {
             store: CountingStore::new(),
             parent: None,
-            counts: HashMap::new(),
+            savepoint: None,
+            name: None,
+            deadline: None,
         }
     }
 
-    /// Set the parent transaction.
-    pub fn parent(mut self, parent: Transaction) -> Self {
+    /// Set the parent transaction, making this transaction start from the
+    /// contents of the parent.
+    pub fn parent(mut self, parent: Transaction<K, V>) -> Self {
+        self.store = parent.store.clone();
         self.parent = Some(Box::new(parent));
         self
     }
 
-    /// Retrieve the value for the given key, if any.
-    pub fn get(&self, name: &str) -> Option<String> {
-        if self.store.contains(name) {
-            self.store.get(name)
-        } else {
-            if let Some(parent) = self.parent.as_ref() {
-                parent.get(name)
-            } else {
-                None
-            }
-        }
+    /// Retrieve a reference to the value for the given key, if any.
+    pub fn get_ref<Q>(&self, name: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.store.get_ref(name)
     }
 
     /// Save the value using the given key in the transaction.
-    pub fn set<T: Into<String>>(&mut self, name: T, value: T) {
-        let name_str: String = name.into();
-        self.delete(&name_str);
-        let value_str: String = value.into();
-        self.store.set(&name_str, &value_str)
+    pub fn set(&mut self, name: K, value: V) {
+        self.store.set(name, value)
     }
 
     /// Removes the value with the given key from the transaction.
-    pub fn delete(&mut self, name: &str) {
-        if !self.store.contains(name) {
-            if let Some(parent) = self.parent.as_ref() {
-                if let Some(value) = parent.get(name) {
-                    if let Some(c) = self.counts.get_mut(&value) {
-                        *c -= 1;
-                    } else {
-                        self.counts.insert(value.clone(), -1);
-                    }
-                }
+    pub fn delete<Q>(&mut self, name: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.store.delete(name);
+    }
+
+    /// Returns the number of occurrences of the given value.
+    pub fn count<Q>(&self, value: &Q) -> u32
+    where
+        V: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        std::cmp::max(self.store.count(value), 0) as u32
+    }
+
+    /// Returns the number of parent transactions, not counting savepoints.
+    pub fn depth(&self) -> usize {
+        let mut depth = 0;
+        let mut current = self;
+        while let Some(parent) = current.parent.as_ref() {
+            if current.savepoint.is_none() {
+                depth += 1;
             }
+            current = parent;
         }
-        self.store.delete(name);
+        depth
+    }
+
+    /// Discard any savepoints, making the innermost transaction current.
+    fn unwind_savepoints(&mut self) {
+        while self.savepoint.is_some() {
+            let parent = self.parent.take().expect("savepoint within a transaction");
+            *self = *parent;
+        }
+    }
+
+    /// Returns the outermost transaction, which holds the committed state.
+    pub fn base(&self) -> &Transaction<K, V> {
+        let mut current = self;
+        while let Some(parent) = current.parent.as_ref() {
+            current = parent;
+        }
+        current
+    }
+
+    /// Start indexing value lengths in this and all parent transactions.
+    pub fn index_lengths(&mut self, length_of: fn(&V) -> usize) {
+        self.store.index_lengths(length_of);
+        if let Some(parent) = self.parent.as_mut() {
+            parent.index_lengths(length_of);
+        }
+    }
+}
+
+///
+/// Immutable view of the committed contents of a database at the moment it
+/// was taken. Taking a snapshot is cheap as it shares its structure with the
+/// database, which remains free to change, making snapshots suitable for
+/// exporting or backing up the data at leisure, even from another thread.
+///
+#[derive(Clone)]
+pub struct Snapshot<K = String, V = String> {
+    store: CountingStore<K, V>,
+    // encrypts the snapshot when saved, as for the database it came from
+    secret: Option<Secret>,
+}
+
+impl<K, V> Snapshot<K, V>
+where
+    K: Ord + Clone,
+    V: Hash + Eq + Clone,
+{
+    /// Retrieve a reference to the value for the given key, if any.
+    pub fn get<Q>(&self, name: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.store.get_ref(name)
     }
 
     /// Returns the number of occurrences of the given value.
-    pub fn count(&self, value: &str) -> u32 {
-        let count = self.store.count(value);
-        let local_count = *self.counts.get(value).or(Some(&0)).unwrap();
-        let parent_count = if let Some(parent) = self.parent.as_ref() {
-            parent.count(value)
-        } else {
-            0
+    pub fn count<Q>(&self, value: &Q) -> u32
+    where
+        V: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        std::cmp::max(self.store.count(value), 0) as u32
+    }
+
+    /// Returns the number of keys that hold anything, of whatever kind.
+    pub fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    /// Returns true if no keys hold anything.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Visit every key and its value in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.store.values.iter()
+    }
+
+    /// Returns the number of keys whose data, of whatever kind, differs
+    /// between the earlier snapshot and this one.
+    pub fn changes_since(&self, earlier: &Snapshot<K, V>) -> usize {
+        earlier.store.changed_keys(&self.store).len()
+    }
+}
+
+impl<K, V> Snapshot<K, V>
+where
+    K: Ord + Clone + Persistent,
+    V: Hash + Eq + Clone + Persistent,
+{
+    /// Write the snapshot to a file, which `Database::load_snapshot()` can
+    /// later read. The file is replaced only once it has been written in its
+    /// entirety. The snapshot of an encrypted database is encrypted.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let store = &self.store;
+        let hashes: Vec<(&K, HashEntries<K, V>)> = store
+            .hashes
+            .iter()
+            .map(|(name, hash)| (name, hash.iter().collect()))
+            .collect();
+        let streams: Vec<(&K, EntryRefs<K, V>)> = store
+            .streams
+            .iter()
+            .map(|(name, stream)| (name, stream.iter().collect()))
+            .collect();
+        let snapshot = SnapshotRefs {
+            entries: store.values.iter().collect(),
+            counts: store.counts.iter().collect(),
+            hashes,
+            streams,
+            lists: store
+                .lists
+                .iter()
+                .map(|(name, list)| (name, list.iter().collect()))
+                .collect(),
+            sorted: store
+                .sorted
+                .iter()
+                .map(|(name, set)| (name, set.iter().collect()))
+                .collect(),
+            documents: store
+                .documents
+                .iter()
+                .map(|(name, document)| (name, &**document))
+                .collect(),
+            expiry: store.expiry.iter().collect(),
         };
-        std::cmp::max((count + parent_count) as i64 + local_count, 0) as u32
+        persist::save_snapshot(path.as_ref(), self.secret.as_ref(), &snapshot)?;
+        Ok(())
+    }
+}
+
+fn encode_hex(text: &str) -> String {
+    text.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<String> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
     }
+    let bytes: Option<Vec<u8>> = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect();
+    String::from_utf8(bytes?).ok()
 }
 
 ///
-/// In-memory key/value store that supports nested transactions.
+/// How importing keys treats those already in the database.
 ///
-pub struct Database {
-    transaction: Transaction,
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Keep existing keys, overwriting any that are also imported.
+    Merge,
+    /// Remove all existing keys before importing.
+    Replace,
 }
 
-impl Database {
-    /// Construct a new database.
-    pub fn new() -> Self {
+///
+/// In-memory key/value store that supports nested transactions, optionally
+/// persisted to a write-ahead log.
+///
+pub struct Database<K = String, V = String> {
+    transaction: Transaction<K, V>,
+    observers: Vec<Box<dyn Observer<K>>>,
+    log: Option<WriteAheadLog<K, V>>,
+    // encrypts the log, snapshots, and backups, if any
+    secret: Option<Secret>,
+}
+
+impl<K, V> Default for Database<K, V>
+where
+    K: Ord + Clone,
+    V: Hash + Eq + Clone,
+{
+    fn default() -> Self {
         Self {
             transaction: Transaction::new(),
+            observers: Vec::new(),
+            log: None,
+            secret: None,
         }
     }
+}
 
-    /// Retrieve the value for the given key, if any.
-    pub fn get(&self, name: &str) -> Option<String> {
-        self.transaction.get(name)
+impl Database {
+    /// Construct a new database with string keys and values. For other types
+    /// use `Database::default()`.
+    pub fn new() -> Self {
+        Self::default()
     }
+}
 
-    /// Save the value using the given key.
-    pub fn set<T: Into<String>>(&mut self, name: T, value: T) {
-        self.transaction.set(name, value)
+impl<K, V> Database<K, V>
+where
+    K: Ord + Clone + Persistent,
+    V: Hash + Eq + Clone + Persistent,
+{
+    /// Open a database that is persisted to the write-ahead log at the given
+    /// path, creating the log if it does not exist. The changes recorded in
+    /// the log are replayed, and from then on every change is appended to the
+    /// log once it has been committed.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::recover(path).map(|(db, _)| db)
     }
 
-    /// Removes the value with the given key.
-    pub fn delete(&mut self, name: &str) {
-        self.transaction.delete(name)
+    /// Open a database as `open()` does, also returning what was recovered
+    /// from the log. Anything at the end of the log that is not a complete
+    /// record with a matching checksum, as left by a crash while it was being
+    /// written, is removed from the log and reported as discarded.
+    pub fn recover<P: AsRef<Path>>(path: P) -> Result<(Self, RecoveryReport)> {
+        Self::recover_with(path.as_ref(), None)
     }
 
-    /// Returns the number of occurrences of the given value.
-    pub fn count(&self, value: &str) -> u32 {
-        self.transaction.count(value)
+    /// Open a database as `open()` does, with its log encrypted by keys
+    /// derived from the secret, as are the snapshots and backups saved from
+    /// it. A new log is encrypted from the start, while a log that already
+    /// holds changes not encrypted is refused, as is the wrong secret.
+    pub fn open_encrypted<P: AsRef<Path>>(path: P, secret: Secret) -> Result<Self> {
+        Self::recover_encrypted(path, secret).map(|(db, _)| db)
     }
 
-    /// Start a new transaction.
-    pub fn begin(&mut self) {
-        let mut transaction = Transaction::new();
-        transaction = transaction.parent(self.transaction.clone());
-        self.transaction = transaction;
+    /// Open an encrypted database as `recover()` does.
+    pub fn recover_encrypted<P: AsRef<Path>>(
+        path: P,
+        secret: Secret,
+    ) -> Result<(Self, RecoveryReport)> {
+        Self::recover_with(path.as_ref(), Some(secret))
     }
 
-    /// Commit _all_ open transactions.
-    pub fn commit(&mut self) -> bool {
-        let mut changed = false;
-        while let Some(mut transaction) = self.transaction.parent.take() {
-            for (key, value) in self.transaction.store.values.iter() {
-                if let Some(v) = value {
-                    transaction.set(key, v);
-                } else {
-                    transaction.delete(key);
+    fn recover_with(path: &Path, secret: Option<Secret>) -> Result<(Self, RecoveryReport)> {
+        let (log, (mutations, report)) = WriteAheadLog::open(path, secret.as_ref())?;
+        let mut db = Self::default();
+        db.replay(mutations);
+        db.log = Some(log);
+        db.secret = secret;
+        Ok((db, report))
+    }
+
+    /// Make the changes read back from a log to the committed state.
+    fn replay(&mut self, mutations: Vec<Mutation<K, V>>) {
+        let db = self;
+        for mutation in mutations.into_iter() {
+            match mutation {
+                Mutation::Set(name, value) => db.transaction.set(name, value),
+                Mutation::Unset(name) => db.transaction.delete(&name),
+                Mutation::HSet(name, field, value) => {
+                    db.transaction.store.hset(name, field, value);
+                }
+                Mutation::HDel(name, field) => {
+                    db.transaction.store.hdel(&name, &field);
+                }
+                Mutation::XAdd(name, id, fields) => {
+                    db.transaction.store.xadd(name, id, fields);
                 }
+                Mutation::LPush(name, values) => {
+                    db.transaction.store.push(name, values, true);
+                }
+                Mutation::RPush(name, values) => {
+                    db.transaction.store.push(name, values, false);
+                }
+                Mutation::LPop(name) => {
+                    db.transaction.store.pop(&name, true);
+                }
+                Mutation::RPop(name) => {
+                    db.transaction.store.pop(&name, false);
+                }
+                Mutation::ZAdd(name, member, score) => {
+                    db.transaction.store.zadd(name, member, score);
+                }
+                Mutation::ZRem(name, member) => {
+                    db.transaction.store.zrem(&name, &member);
+                }
+                Mutation::Json(name, document) => {
+                    db.transaction
+                        .store
+                        .documents
+                        .insert(name, Arc::new(document));
+                }
+                Mutation::Expire(name, deadline) => {
+                    if db.transaction.store.contains(&name) {
+                        db.transaction.store.expiry.insert(name, deadline);
+                    }
+                }
+                Mutation::Persist(name) => {
+                    db.transaction.store.expiry.remove(&name);
+                }
+                Mutation::Time(_) | Mutation::Base => {}
             }
-            self.transaction = *transaction;
-            changed = true;
         }
-        self.transaction.store.compact();
-        changed
     }
 
-    /// Rollback the current transaction. Returns true if rollback was
-    /// successful or false if there is no open tranaction.
-    pub fn rollback(&mut self) -> bool {
-        if let Some(transaction) = self.transaction.parent.take() {
-            self.transaction = *transaction;
-            true
-        } else {
-            false
-        }
+    /// Write the committed state of the database to a snapshot file.
+    pub fn save_snapshot<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.freeze_snapshot().save(path)
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
-    #[test]
-    fn test_counting_store() {
-        let mut store = CountingStore::new();
-        assert_eq!(store.count("value"), 0);
-        assert_eq!(store.get("name1"), None);
-        store.set("name1", "value");
-        assert_eq!(store.get("name1"), Some("value".into()));
-        assert_eq!(store.count("value"), 1);
-        store.set("name2", "value");
-        assert_eq!(store.count("value"), 2);
-        store.set("name3", "value");
-        assert_eq!(store.count("value"), 3);
-        store.delete("name3");
-        assert_eq!(store.get("name3"), None);
-        assert_eq!(store.count("value"), 2);
-        store.delete("name2");
-        assert_eq!(store.get("name2"), None);
-        assert_eq!(store.count("value"), 1);
-        store.delete("name1");
-        assert_eq!(store.get("name1"), None);
-        assert_eq!(store.count("value"), 0);
+    /// Construct a database from the contents of a snapshot file.
+    pub fn load_snapshot<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::load_snapshot_with(path.as_ref(), None)
     }
 
-    #[test]
-    fn test_transactions() {
-        let mut first = Transaction::new();
-        first.set("name2", "value");
-        first.set("name1", "value1");
-        let mut second = Transaction::new();
-        second = second.parent(first.clone());
-        second.set("name1", "value2");
-        second.set("name3", "value");
-        assert_eq!(second.get("name1"), Some("value2".into()));
-        assert_eq!(first.get("name1"), Some("value1".into()));
-        assert_eq!(second.count("value"), 2);
-        assert_eq!(first.count("value"), 1);
-        second.delete("name3");
-        assert_eq!(second.count("value"), 1);
-        second.delete("name2");
-        assert_eq!(second.count("value"), 0);
-        assert_eq!(first.count("value"), 1);
+    /// Construct a database from the contents of a snapshot file, which the
+    /// secret decrypts if it is encrypted, and encrypt the snapshots and
+    /// backups saved from it.
+    pub fn load_encrypted_snapshot<P: AsRef<Path>>(path: P, secret: Secret) -> Result<Self> {
+        Self::load_snapshot_with(path.as_ref(), Some(secret))
     }
 
-    #[test]
-    fn test_commit_rollback() {
-        let mut db = Database::new();
-        assert_eq!(db.rollback(), false);
-        assert_eq!(db.commit(), false);
-        db.begin();
-        db.set("a", "foo");
-        assert_eq!(db.commit(), true);
-        db.begin();
-        assert_eq!(db.rollback(), true);
+    fn load_snapshot_with(path: &Path, secret: Option<Secret>) -> Result<Self> {
+        let contents = persist::load_snapshot(path, secret.as_ref())?;
+        let mut db = Self {
+            secret,
+            ..Default::default()
+        };
+        let store = &mut db.transaction.store;
+        store.values = contents.entries.into_iter().collect();
+        for (value, count) in contents.counts.into_iter() {
+            store.counts.adjust_owned(value, count);
+        }
+        store.hashes = contents
+            .hashes
+            .into_iter()
+            .map(|(name, fields)| (name, fields.into_iter().collect::<im::OrdMap<K, V>>()))
+            .collect();
+        for (name, entries) in contents.streams.into_iter() {
+            for (id, fields) in entries.into_iter() {
+                store.xadd(name.clone(), id, fields);
+            }
+        }
+        store.lists = contents
+            .lists
+            .into_iter()
+            .map(|(name, values)| (name, values.into_iter().collect::<im::Vector<V>>()))
+            .collect();
+        for (name, members) in contents.sorted.into_iter() {
+            for (member, score) in members.into_iter() {
+                store.zadd(name.clone(), member, score);
+            }
+        }
+        store.documents = contents
+            .documents
+            .into_iter()
+            .map(|(name, document)| (name, Arc::new(document)))
+            .collect();
+        for (name, deadline) in contents.expiry.into_iter() {
+            store.expiry.insert(name, deadline);
+        }
+        Ok(db)
     }
 
-    #[test]
-    fn test_example_1() {
-        let mut db = Database::new();
-        assert_eq!(db.get("a"), None);
-        db.set("a", "foo");
-        db.set("b", "foo");
-        assert_eq!(db.count("foo"), 2);
-        assert_eq!(db.count("bar"), 0);
-        db.delete("a");
-        assert_eq!(db.count("foo"), 1);
-        db.set("b", "baz");
-        assert_eq!(db.count("foo"), 0);
-        assert_eq!(db.get("b"), Some("baz".into()));
-        assert_eq!(db.get("B"), None);
+    /// Replace the log with one holding only what is needed to restore the
+    /// committed state, which is far shorter than a log that has been
+    /// appended to for a long time. Changes committed while the new log is
+    /// written are added to it before it atomically takes the place of the
+    /// old one, so nothing is lost if this fails part way.
+    pub fn rewrite_aof(&mut self) -> Result<()> {
+        let mut rewrite = self.begin_rewrite_aof()?;
+        rewrite.write();
+        self.finish_rewrite_aof(rewrite)
     }
 
-    #[test]
-    fn test_example_2() {
-        let mut db = Database::new();
-        db.set("a", "foo");
-        db.set("a", "foo");
-        assert_eq!(db.count("foo"), 1);
-        assert_eq!(db.get("a"), Some("foo".into()));
-        db.delete("a");
-        assert_eq!(db.get("a"), None);
-        assert_eq!(db.count("foo"), 0);
+    /// Start rewriting the log, returning the committed state to be written
+    /// by `LogRewrite::write()`, which needs no access to the database so that
+    /// changes can continue to be made and logged meanwhile. Fails if the
+    /// database has no log, or it is already being rewritten.
+    pub fn begin_rewrite_aof(&mut self) -> Result<LogRewrite<K, V>> {
+        let log = self
+            .log
+            .as_mut()
+            .ok_or_else(|| io::Error::other("the database has no log"))?;
+        Ok(LogRewrite {
+            log: log.begin_rewrite()?,
+            store: self.transaction.base().store.clone(),
+        })
     }
 
-    #[test]
-    fn test_example_3() {
-        let mut db = Database::new();
-        db.begin();
-        db.set("a", "foo");
-        assert_eq!(db.get("a"), Some("foo".into()));
-        db.begin();
-        db.set("a", "bar");
-        assert_eq!(db.get("a"), Some("bar".into()));
-        db.set("a", "baz");
-        db.rollback();
-        assert_eq!(db.get("a"), Some("foo".into()));
-        db.rollback();
-        assert_eq!(db.get("a"), None);
+    /// Replace the log with the one that was written, after adding to it the
+    /// changes logged since `begin_rewrite_aof()`.
+    pub fn finish_rewrite_aof(&mut self, rewrite: LogRewrite<K, V>) -> Result<()> {
+        let log = self
+            .log
+            .as_mut()
+            .ok_or_else(|| io::Error::other("the database has no log"))?;
+        log.finish_rewrite(rewrite.log)?;
+        Ok(())
     }
 
-    #[test]
-    fn test_example_4() {
-        let mut db = Database::new();
-        db.set("a", "foo");
-        db.set("b", "baz");
-        db.begin();
-        assert_eq!(db.get("a"), Some("foo".into()));
-        db.set("a", "bar");
-        assert_eq!(db.count("bar"), 1);
-        db.begin();
-        assert_eq!(db.count("bar"), 1);
-        db.delete("a");
-        assert_eq!(db.get("a"), None);
-        assert_eq!(db.count("bar"), 0);
-        db.rollback();
-        assert_eq!(db.get("a"), Some("bar".into()));
-        assert_eq!(db.count("bar"), 1);
-        db.commit();
-        assert_eq!(db.get("a"), Some("bar".into()));
-        assert_eq!(db.get("b"), Some("baz".into()));
+    /// Back up the committed state in full, as a snapshot in the directory,
+    /// and return the path of the manifest written beside it, from which
+    /// `restore_backup()` restores the backup and `backup_incremental()`
+    /// continues it.
+    pub fn backup<P: AsRef<Path>>(&self, out_dir: P) -> Result<PathBuf> {
+        let dir = fs::canonicalize(out_dir)?;
+        let snapshot = dir.join("0.snapshot");
+        self.save_snapshot(&snapshot)?;
+        let manifest = Manifest {
+            files: vec![snapshot],
+            fingerprints: fingerprints(&self.transaction.base().store)
+                .into_iter()
+                .map(|(name, fingerprint)| (name.to_bytes().to_vec(), fingerprint))
+                .collect(),
+        };
+        let path = dir.join("0.manifest");
+        manifest.save(&path, self.secret.as_ref())?;
+        Ok(path)
+    }
+
+    /// Back up only the keys of the committed state that changed since the
+    /// backup of the given manifest, writing to the directory an increment
+    /// that holds their data and removes the keys that no longer exist, and
+    /// return the path of a new manifest that adds the increment to the
+    /// files of the old one. Each increment is numbered by its place in the
+    /// chain, so a directory should hold only one chain.
+    pub fn backup_incremental<P, Q>(&self, base_manifest: P, out_dir: Q) -> Result<PathBuf>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let base = Manifest::load(base_manifest.as_ref(), self.secret.as_ref())?;
+        let dir = fs::canonicalize(out_dir)?;
+        let number = base.files.len();
+        let increment = dir.join(format!("{}.log", number));
+        let store = &self.transaction.base().store;
+        let current = fingerprints(store);
+        let mut log: WriteAheadLog<K, V> = WriteAheadLog::create(&increment, self.secret.as_ref())?;
+        {
+            let names: BTreeSet<&[u8]> = current.keys().map(|name| name.to_bytes()).collect();
+            for (name, _) in base.fingerprints.iter() {
+                if !names.contains(&name[..]) {
+                    log.unset(&persist::decode(name.clone())?);
+                }
+            }
+        }
+        let previous: HashMap<&[u8], u64> = base
+            .fingerprints
+            .iter()
+            .map(|(name, fingerprint)| (&name[..], *fingerprint))
+            .collect();
+        for (&name, fingerprint) in current.iter() {
+            if previous.get(name.to_bytes()) == Some(fingerprint) {
+                continue;
+            }
+            // whatever the key held before is replaced as a whole
+            log.unset(name);
+            if let Some(value) = store.values.get(name) {
+                log.set(name, value);
+            }
+            if let Some(list) = store.lists.get(name) {
+                log.push(name, list.iter(), false);
+            }
+            if let Some(set) = store.sorted.get(name) {
+                for (member, score) in set.iter() {
+                    log.zadd(name, member, score);
+                }
+            }
+            if let Some(document) = store.documents.get(name) {
+                log.json(name, document);
+            }
+            if let Some(hash) = store.hashes.get(name) {
+                for (field, value) in hash.iter() {
+                    log.hset(name, field, value);
+                }
+            }
+            if let Some(stream) = store.streams.get(name) {
+                for (id, fields) in stream.iter() {
+                    log.xadd(name, *id, fields);
+                }
+            }
+            if let Some(deadline) = store.expiry.get(name) {
+                log.expire(name, deadline);
+            }
+        }
+        log.set_durability(Durability::Sync);
+        log.flush();
+        if let Some(err) = log.take_error() {
+            return Err(err.into());
+        }
+        let mut files = base.files;
+        files.push(increment);
+        let manifest = Manifest {
+            files,
+            fingerprints: current
+                .into_iter()
+                .map(|(name, fingerprint)| (name.to_bytes().to_vec(), fingerprint))
+                .collect(),
+        };
+        let path = dir.join(format!("{}.manifest", number));
+        manifest.save(&path, self.secret.as_ref())?;
+        Ok(path)
+    }
+
+    /// Construct a database from the backup of the given manifest, loading
+    /// its snapshot and applying each of its increments in turn.
+    pub fn restore_backup<P: AsRef<Path>>(manifest: P) -> Result<Self> {
+        Self::restore_backup_with(manifest.as_ref(), None)
+    }
+
+    /// Construct a database from an encrypted backup as `restore_backup()`
+    /// does, and encrypt the snapshots and backups saved from it.
+    pub fn restore_encrypted_backup<P: AsRef<Path>>(manifest: P, secret: Secret) -> Result<Self> {
+        Self::restore_backup_with(manifest.as_ref(), Some(secret))
+    }
+
+    fn restore_backup_with(manifest: &Path, secret: Option<Secret>) -> Result<Self> {
+        let manifest = Manifest::load(manifest, secret.as_ref())?;
+        let Some((snapshot, increments)) = manifest.files.split_first() else {
+            return Err(persist::invalid("backup has no snapshot").into());
+        };
+        let mut db = Self::load_snapshot_with(snapshot, secret)?;
+        for increment in increments.iter() {
+            db.replay(persist::read_changes(increment, db.secret.as_ref())?);
+        }
+        Ok(db)
+    }
+
+    /// Restore the data of the database to what the log says it was at the
+    /// point in its history, such as just before an accidental `FLUSHALL`.
+    /// The keys that differ are replaced as a whole, by a change made like
+    /// any other, within any open transaction, so that once committed the
+    /// restoration is itself logged and may in turn be undone. Only what the
+    /// log records is restored: values, lists, sorted sets, documents,
+    /// hashes, and streams, and their times to live. Fails if the database
+    /// has no log, or the point comes before the log was last rewritten.
+    pub fn restore_to(&mut self, point: RestorePoint) -> Result<()> {
+        let log = self
+            .log
+            .as_mut()
+            .ok_or_else(|| io::Error::other("the database has no log"))?;
+        log.flush();
+        let mut restored = Self::default();
+        restored.replay(persist::read_until(
+            log.path(),
+            self.secret.as_ref(),
+            point,
+        )?);
+        let restored = restored.transaction.store;
+        self.begin();
+        let changed: Vec<K> = {
+            let then = fingerprints(&restored);
+            let now = fingerprints(&self.transaction.store);
+            let names: BTreeSet<&&K> = then.keys().chain(now.keys()).collect();
+            names
+                .into_iter()
+                .filter(|name| then.get(**name) != now.get(**name))
+                .map(|name| (*name).clone())
+                .collect()
+        };
+        for name in changed.iter() {
+            self.transaction.delete(name);
+            if let Some(value) = restored.values.get(name) {
+                self.transaction.set(name.clone(), value.clone());
+            }
+            if let Some(list) = restored.lists.get(name) {
+                let store = &mut self.transaction.store;
+                store.lists.insert(name.clone(), list.clone());
+            }
+            if let Some(set) = restored.sorted.get(name) {
+                let store = &mut self.transaction.store;
+                store.sorted.insert(name.clone(), set.clone());
+            }
+            if let Some(document) = restored.documents.get(name) {
+                let store = &mut self.transaction.store;
+                store.documents.insert(name.clone(), document.clone());
+            }
+            if let Some(hash) = restored.hashes.get(name) {
+                for (field, value) in hash.iter() {
+                    let store = &mut self.transaction.store;
+                    store.hset(name.clone(), field.clone(), value.clone());
+                }
+            }
+            if let Some(stream) = restored.streams.get(name) {
+                for (id, fields) in stream.iter() {
+                    let store = &mut self.transaction.store;
+                    store.xadd(name.clone(), *id, fields.clone());
+                }
+            }
+            if let Some(deadline) = restored.expiry.get(name) {
+                let store = &mut self.transaction.store;
+                store.expiry.insert(name.clone(), deadline);
+            }
+        }
+        self.commit_one()
+    }
+}
+
+/// Returns the fingerprint of what each key holds, of the kinds of data that
+/// are persisted, and of when it expires, in key order.
+fn fingerprints<K, V>(store: &CountingStore<K, V>) -> BTreeMap<&K, u64>
+where
+    K: Ord + Clone + Persistent,
+    V: Clone + Persistent,
+{
+    let mut fingerprints = BTreeMap::new();
+    for (name, value) in store.values.iter() {
+        let mut fingerprint = Fingerprint::new(b'v');
+        fingerprint.add(value.to_bytes());
+        fingerprints.insert(name, fingerprint.finish());
+    }
+    for (name, list) in store.lists.iter() {
+        let mut fingerprint = Fingerprint::new(b'l');
+        for value in list.iter() {
+            fingerprint.add(value.to_bytes());
+        }
+        fingerprints.insert(name, fingerprint.finish());
+    }
+    for (name, set) in store.sorted.iter() {
+        let mut fingerprint = Fingerprint::new(b'z');
+        for (member, score) in set.iter() {
+            fingerprint.add(member.to_bytes());
+            fingerprint.add(&score.to_le_bytes());
+        }
+        fingerprints.insert(name, fingerprint.finish());
+    }
+    for (name, document) in store.documents.iter() {
+        let mut fingerprint = Fingerprint::new(b'j');
+        fingerprint.add(document.to_string().as_bytes());
+        fingerprints.insert(name, fingerprint.finish());
+    }
+    for (name, hash) in store.hashes.iter() {
+        let mut fingerprint = Fingerprint::new(b'h');
+        for (field, value) in hash.iter() {
+            fingerprint.add(field.to_bytes());
+            fingerprint.add(value.to_bytes());
+        }
+        fingerprints.insert(name, fingerprint.finish());
+    }
+    for (name, stream) in store.streams.iter() {
+        let mut fingerprint = Fingerprint::new(b's');
+        for (id, fields) in stream.iter() {
+            fingerprint.add(&id.to_bytes());
+            for (field, value) in fields.iter() {
+                fingerprint.add(field.to_bytes());
+                fingerprint.add(value.to_bytes());
+            }
+        }
+        fingerprints.insert(name, fingerprint.finish());
+    }
+    for (name, deadline) in store.expiry.iter() {
+        if let Some(held) = fingerprints.get_mut(name) {
+            let mut fingerprint = Fingerprint::new(b't');
+            fingerprint.add(&held.to_le_bytes());
+            fingerprint.add(&persist::to_millis(deadline).to_le_bytes());
+            *held = fingerprint.finish();
+        }
+    }
+    fingerprints
+}
+
+///
+/// New log being written by `Database::rewrite_aof()`, with the committed
+/// state as it was when the rewrite began.
+///
+pub struct LogRewrite<K, V> {
+    log: WriteAheadLog<K, V>,
+    store: CountingStore<K, V>,
+}
+
+impl<K, V> LogRewrite<K, V>
+where
+    K: Ord + Clone,
+    V: Clone,
+{
+    /// Write the state to the new log, a record for each value, each list,
+    /// each member of a sorted set, each document, each field of a hash, each
+    /// entry of a stream, and each time-to-live, as a single batch that is
+    /// the base of the log, so that it counts as one change and the database
+    /// cannot be restored to a point before it. Any failure is reported by
+    /// `Database::finish_rewrite_aof()`.
+    pub fn write(&mut self) {
+        let (log, store) = (&mut self.log, &self.store);
+        log.begin_batch();
+        log.base();
+        for (name, value) in store.values.iter() {
+            log.set(name, value);
+        }
+        for (name, list) in store.lists.iter() {
+            log.push(name, list.iter(), false);
+        }
+        for (name, set) in store.sorted.iter() {
+            for (member, score) in set.iter() {
+                log.zadd(name, member, score);
+            }
+        }
+        for (name, document) in store.documents.iter() {
+            log.json(name, document);
+        }
+        for (name, hash) in store.hashes.iter() {
+            for (field, value) in hash.iter() {
+                log.hset(name, field, value);
+            }
+        }
+        for (name, stream) in store.streams.iter() {
+            for (id, fields) in stream.iter() {
+                log.xadd(name, *id, fields);
+            }
+        }
+        for (name, deadline) in store.expiry.iter() {
+            log.expire(name, deadline);
+        }
+        log.end_batch();
+        log.flush();
+    }
+}
+
+impl<K, V> Database<K, V>
+where
+    K: Ord + Clone,
+    V: Hash + Eq + Clone,
+{
+    /// Set how far changes are written to the log before they are considered
+    /// made, by default only as far as the operating system.
+    pub fn set_durability(&mut self, durability: Durability) {
+        if let Some(log) = self.log.as_mut() {
+            log.set_durability(durability);
+        }
+    }
+
+    /// Encrypt the snapshots and backups saved from now on with keys derived
+    /// from the secret, or stop encrypting them given `None`, such as for a
+    /// database that has no log. Whether the log is encrypted is settled
+    /// when it is opened, by `open_encrypted()`.
+    pub fn set_encryption(&mut self, secret: Option<Secret>) {
+        self.secret = secret;
+    }
+
+    /// Returns the secret with which the database is encrypted, if any.
+    pub fn encryption(&self) -> Option<&Secret> {
+        self.secret.as_ref()
+    }
+
+    /// Returns the number of batches written to the log, each the changes of
+    /// a transaction or a single change, so that `restore_to()` given it as
+    /// `RestorePoint::Lsn` returns to the state as it is now committed.
+    /// `None` if the database has no log.
+    pub fn lsn(&self) -> Option<u64> {
+        self.log.as_ref().map(WriteAheadLog::records)
+    }
+
+    /// Returns the first error that occurred writing to the log, if it has
+    /// not already been returned. After an error nothing more is written to
+    /// the log, though the database continues to work in memory.
+    pub fn take_log_error(&mut self) -> Option<io::Error> {
+        self.log.as_mut().and_then(WriteAheadLog::take_error)
+    }
+
+    /// Inject the faults chosen by the hook into the writing of the log, if
+    /// the database has one, so that tests can exercise recovery from short
+    /// and torn writes, damaged records, and failed syncs.
+    #[cfg(any(test, feature = "fault-injection"))]
+    pub fn set_fault_hook<H: crate::persist::FaultHook + 'static>(&mut self, hook: H) {
+        if let Some(log) = self.log.as_mut() {
+            log.set_fault_hook(Box::new(hook));
+        }
+    }
+
+    /// Append the differences between the given committed state and the
+    /// current state to the log, if any. The values are written first, then
+    /// the removed lists, sorted sets, documents, and streams, so that a key
+    /// which changed from one kind to another is removed before what it now
+    /// holds is recorded. A list or a document that changed is written again
+    /// as a whole. The times to live come last, as setting or removing a key
+    /// also removes its time-to-live when the log is replayed.
+    fn log_changes(&mut self, committed: &CountingStore<K, V>) {
+        use im::ordmap::DiffItem;
+        if let Some(log) = self.log.as_mut() {
+            let store = &self.transaction.store;
+            log.begin_batch();
+            // keys that were set or removed, and so no longer expire
+            let mut cleared: BTreeSet<&K> = BTreeSet::new();
+            for item in committed.values.diff(&store.values) {
+                let name = match item {
+                    DiffItem::Add(name, value) => {
+                        log.set(name, value);
+                        name
+                    }
+                    DiffItem::Update { new, .. } => {
+                        log.set(new.0, new.1);
+                        new.0
+                    }
+                    DiffItem::Remove(name, _) => {
+                        log.unset(name);
+                        name
+                    }
+                };
+                cleared.insert(name);
+            }
+            let mut removed: Vec<&K> = Vec::new();
+            removed.extend(committed.lists.diff(&store.lists).filter_map(removed_key));
+            removed.extend(committed.sorted.diff(&store.sorted).filter_map(removed_key));
+            removed.extend(
+                committed
+                    .documents
+                    .diff(&store.documents)
+                    .filter_map(removed_key),
+            );
+            removed.extend(
+                committed
+                    .streams
+                    .diff(&store.streams)
+                    .filter_map(removed_key),
+            );
+            for name in removed {
+                // setting a value already replaced whatever the key held
+                if !store.values.contains_key(name) {
+                    log.unset(name);
+                }
+                cleared.insert(name);
+            }
+            for item in committed.hashes.diff(&store.hashes) {
+                match item {
+                    DiffItem::Add(name, hash) => {
+                        for (field, value) in hash.iter() {
+                            log.hset(name, field, value);
+                        }
+                    }
+                    DiffItem::Update { old, new } => {
+                        for change in old.1.diff(new.1) {
+                            match change {
+                                DiffItem::Add(field, value) => log.hset(new.0, field, value),
+                                DiffItem::Update { new: field, .. } => {
+                                    log.hset(new.0, field.0, field.1)
+                                }
+                                DiffItem::Remove(field, _) => log.hdel(new.0, field),
+                            }
+                        }
+                    }
+                    // setting a value already replaced the hash
+                    DiffItem::Remove(name, _) if store.values.contains_key(name) => {}
+                    DiffItem::Remove(name, _) => {
+                        log.unset(name);
+                        cleared.insert(name);
+                    }
+                }
+            }
+            for item in committed.lists.diff(&store.lists) {
+                match item {
+                    DiffItem::Add(name, list) => log.push(name, list.iter(), false),
+                    DiffItem::Update { new, .. } => {
+                        log.unset(new.0);
+                        log.push(new.0, new.1.iter(), false);
+                        cleared.insert(new.0);
+                    }
+                    DiffItem::Remove(..) => {}
+                }
+            }
+            for item in committed.sorted.diff(&store.sorted) {
+                let (name, changes): (&K, Vec<(&K, Option<f64>)>) = match item {
+                    DiffItem::Add(name, set) => {
+                        let members = set.iter().map(|(member, score)| (member, Some(score)));
+                        (name, members.collect())
+                    }
+                    DiffItem::Update { old, new } => (new.0, new.1.changes_since(old.1).collect()),
+                    DiffItem::Remove(..) => continue,
+                };
+                for (member, score) in changes {
+                    match score {
+                        Some(score) => log.zadd(name, member, score),
+                        None => log.zrem(name, member),
+                    }
+                }
+            }
+            for item in committed.documents.diff(&store.documents) {
+                match item {
+                    DiffItem::Add(name, document) => log.json(name, document),
+                    DiffItem::Update { new, .. } => log.json(new.0, new.1),
+                    DiffItem::Remove(..) => {}
+                }
+            }
+            for item in committed.streams.diff(&store.streams) {
+                let (name, entries) = match item {
+                    DiffItem::Add(name, stream) => (name, stream.iter().collect()),
+                    DiffItem::Update { old, new } => match new.1.added_since(old.1) {
+                        Some(entries) => (new.0, entries),
+                        None => {
+                            // deleted and added again within the transaction
+                            log.unset(new.0);
+                            cleared.insert(new.0);
+                            (new.0, new.1.iter().collect())
+                        }
+                    },
+                    DiffItem::Remove(..) => continue,
+                };
+                for (id, fields) in entries {
+                    log.xadd(name, *id, fields);
+                }
+            }
+            for (name, deadline) in store.expiry.changes_since(&committed.expiry) {
+                match deadline {
+                    Some(deadline) => log.expire(name, deadline),
+                    None if cleared.contains(name) => {}
+                    None => log.persist(name),
+                }
+            }
+            for name in cleared {
+                if let Some(deadline) = store.expiry.get(name) {
+                    // an unchanged time is written again after the change
+                    if committed.expiry.get(name) == Some(deadline) {
+                        log.expire(name, deadline);
+                    }
+                }
+            }
+            log.end_batch();
+            log.flush();
+        }
+    }
+
+    /// Attach an observer that will be notified of every operation.
+    pub fn add_observer(&mut self, observer: Box<dyn Observer<K>>) {
+        self.observers.push(observer);
+    }
+
+    /// Call the function after every commit of all open transactions with
+    /// the keys whose data the commit changed, such as to invalidate an
+    /// external cache. Committing only the innermost of several
+    /// transactions changes nothing yet, so it does not call the function.
+    pub fn on_commit<F>(&mut self, hook: F)
+    where
+        F: Fn(&[K]) + Send + 'static,
+        K: 'static,
+    {
+        self.observers.push(Box::new(CommitHook(hook)));
+    }
+
+    /// Call the function after every rollback with the keys whose changes
+    /// were discarded.
+    pub fn on_rollback<F>(&mut self, hook: F)
+    where
+        F: Fn(&[K]) + Send + 'static,
+        K: 'static,
+    {
+        self.observers.push(Box::new(RollbackHook(hook)));
+    }
+
+    /// Returns the current time if anyone is observing, otherwise `None` to
+    /// avoid the cost of reading the clock.
+    fn start_timer(&self) -> Option<Instant> {
+        if self.observers.is_empty() {
+            None
+        } else {
+            Some(Instant::now())
+        }
+    }
+
+    /// Retrieve the value for the given key, if any.
+    pub fn get<Q>(&self, name: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        self.get_ref(name).cloned()
+    }
+
+    /// Retrieve a reference to the value for the given key, if any, without
+    /// making a copy of the value.
+    pub fn get_ref<Q>(&self, name: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        let timer = self.start_timer();
+        let result = self
+            .transaction
+            .get_ref(name)
+            .filter(|_| !self.transaction.store.is_expired(name));
+        if let Some(started) = timer {
+            let elapsed = started.elapsed();
+            let name = name.to_owned();
+            for observer in self.observers.iter() {
+                observer.on_get(&name, result.is_some(), elapsed);
+            }
+        }
+        result
+    }
+
+    /// Returns true if the key has a value, as seen from within any open
+    /// transactions.
+    pub fn exists<Q>(&self, name: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let store = &self.transaction.store;
+        store.contains(name) && !store.is_expired(name)
+    }
+
+    /// Returns the kind of data held by the key, or `None` if it holds
+    /// nothing.
+    pub fn kind<Q>(&self, name: &Q) -> Option<Kind>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let store = &self.transaction.store;
+        store.kind(name).filter(|_| !store.is_expired(name))
+    }
+
+    /// Save the value using the given key. Owned keys and values are moved into
+    /// the database while borrowed ones are copied exactly once.
+    pub fn set(&mut self, name: impl Into<K>, value: impl Into<V>) {
+        self.remove_expired();
+        let timer = self.start_timer();
+        let name: K = name.into();
+        let value: V = value.into();
+        if self.transaction.parent.is_none() {
+            if let Some(log) = self.log.as_mut() {
+                log.set(&name, &value);
+                log.flush();
+            }
+        }
+        if let Some(started) = timer {
+            self.transaction.set(name.clone(), value);
+            let elapsed = started.elapsed();
+            for observer in self.observers.iter() {
+                observer.on_set(&name, elapsed);
+            }
+        } else {
+            self.transaction.set(name, value);
+        }
+    }
+
+    /// Save the value only if the key has none, returning whether it was
+    /// saved.
+    pub fn set_nx(&mut self, name: impl Into<K>, value: impl Into<V>) -> bool {
+        let name: K = name.into();
+        if self.get_ref::<K>(&name).is_some() {
+            return false;
+        }
+        self.set(name, value);
+        true
+    }
+
+    /// Replace the value of the key only if it currently has the expected
+    /// value, returning whether it was replaced.
+    pub fn compare_and_swap<Q, E>(&mut self, name: &Q, expected: &E, value: impl Into<V>) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+        V: Borrow<E>,
+        E: Eq + ?Sized,
+    {
+        match self.get_ref(name) {
+            Some(current) if current.borrow() == expected => {
+                self.set(name.to_owned(), value);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Save the value of the source key under the destination key as well,
+    /// along with any time-to-live, returning whether it was copied. Nothing
+    /// is copied if the source has no value, or if the destination has one
+    /// and `overwrite` is false.
+    pub fn copy<Q>(&mut self, source: &Q, destination: impl Into<K>, overwrite: bool) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        let Some(value) = self.get(source) else {
+            return false;
+        };
+        let destination: K = destination.into();
+        if !overwrite && self.get_ref::<K>(&destination).is_some() {
+            return false;
+        }
+        let deadline = self.transaction.store.expiry.get(source);
+        self.set(destination.clone(), value);
+        if let Some(deadline) = deadline {
+            self.set_deadline(destination, deadline);
+        }
+        true
+    }
+
+    /// Remove the key, returning the value it had, if any.
+    pub fn get_del<Q>(&mut self, name: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        let value = self.get(name)?;
+        self.delete(name);
+        Some(value)
+    }
+
+    /// Retrieve the values for the given keys, in the same order.
+    pub fn mget<Q>(&self, names: &[&Q]) -> Vec<Option<V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        names.iter().map(|name| self.get(*name)).collect()
+    }
+
+    /// Save all of the values. Outside of a transaction they are committed
+    /// together, so the log records either all of them or none.
+    pub fn mset<N, W>(&mut self, pairs: impl IntoIterator<Item = (N, W)>)
+    where
+        N: Into<K>,
+        W: Into<V>,
+    {
+        let outermost = self.transaction.parent.is_none();
+        if outermost {
+            self.begin();
+        }
+        for (name, value) in pairs.into_iter() {
+            self.set(name, value);
+        }
+        if outermost {
+            self.commit().expect("transaction was started");
+        }
+    }
+
+    /// Removes the value with the given key.
+    pub fn delete<Q>(&mut self, name: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        self.remove_expired();
+        let timer = self.start_timer();
+        if self.transaction.parent.is_none() {
+            if let Some(log) = self.log.as_mut() {
+                if self.transaction.store.contains(name) {
+                    log.unset(&name.to_owned());
+                    log.flush();
+                }
+            }
+        }
+        self.transaction.delete(name);
+        if let Some(started) = timer {
+            let elapsed = started.elapsed();
+            let name = name.to_owned();
+            for observer in self.observers.iter() {
+                observer.on_delete(&name, elapsed);
+            }
+        }
+    }
+
+    /// Returns the number of occurrences of the given value.
+    pub fn count<Q>(&self, value: &Q) -> u32
+    where
+        V: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let count = self.transaction.count(value);
+        let store = &self.transaction.store;
+        if store.expiry.is_empty() {
+            return count;
+        }
+        // expired keys linger until the next change, so discount them
+        let expired = store
+            .expiry
+            .expired(SystemTime::now())
+            .filter(|name| store.values.get(name).map(Borrow::borrow) == Some(value))
+            .count();
+        count - expired as u32
+    }
+
+    /// Make the key expire once the given time has passed, after which it is
+    /// treated as having no value. Setting the key again removes the
+    /// time-to-live. The time at which the key expires is what the log and
+    /// snapshots record, so a key whose time passed while the database was
+    /// closed is gone once it is opened. Returns false if the key has no
+    /// value. Fails with `Error::InvalidExpireTime` if the time-to-live is
+    /// too long for that time to be represented.
+    pub fn expire<Q>(&mut self, name: &Q, ttl: Duration) -> Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        let deadline = SystemTime::now()
+            .checked_add(ttl)
+            .ok_or(Error::InvalidExpireTime)?;
+        self.remove_expired();
+        if !self.transaction.store.contains(name) {
+            return Ok(false);
+        }
+        self.set_deadline(name.to_owned(), deadline);
+        Ok(true)
+    }
+
+    /// Remove the time-to-live of the key, returning false if it had none.
+    pub fn persist<Q>(&mut self, name: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.remove_expired();
+        let Some((name, _)) = self.transaction.store.expiry.remove_with_key(name) else {
+            return false;
+        };
+        if self.transaction.parent.is_none() {
+            if let Some(log) = self.log.as_mut() {
+                log.persist(&name);
+                log.flush();
+            }
+        }
+        true
+    }
+
+    /// Returns the value of the key after giving it the time-to-live, or
+    /// removing any it has given `None`, in one step, so that a cache can
+    /// extend the life of an entry each time it is read. The time is logged
+    /// as by `expire()` and `persist()`. Returns `None` if the key has no
+    /// value, which is left as it is. Fails with `Error::InvalidExpireTime`,
+    /// leaving the key as it is, if the time-to-live is too long to be
+    /// represented.
+    pub fn get_and_touch_ttl<Q>(&mut self, name: &Q, ttl: Option<Duration>) -> Result<Option<V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        let Some(value) = self.get(name) else {
+            return Ok(None);
+        };
+        match ttl {
+            Some(ttl) => self.expire(name, ttl)?,
+            None => self.persist(name),
+        };
+        Ok(Some(value))
+    }
+
+    /// Returns the time remaining until the key expires, or `None` if it
+    /// never expires. Fails with `Error::KeyNotFound` if the key has no value.
+    pub fn ttl<Q>(&self, name: &Q) -> Result<Option<Duration>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        if !self.exists(name) {
+            return Err(Error::KeyNotFound);
+        }
+        let deadline = self.transaction.store.expiry.get(name);
+        Ok(deadline.and_then(|deadline| deadline.duration_since(SystemTime::now()).ok()))
+    }
+
+    /// Save the value without removing the time-to-live of the key.
+    pub(crate) fn replace_keeping_ttl<Q>(&mut self, name: &Q, value: impl Into<V>)
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        let deadline = self.transaction.store.expiry.get(name);
+        self.set(name.to_owned(), value);
+        if let Some(deadline) = deadline {
+            self.set_deadline(name.to_owned(), deadline);
+        }
+    }
+
+    /// Set the time at which the key expires, logging it if no transaction
+    /// is open.
+    fn set_deadline(&mut self, name: K, deadline: SystemTime) {
+        if self.transaction.parent.is_none() {
+            if let Some(log) = self.log.as_mut() {
+                log.expire(&name, deadline);
+                log.flush();
+            }
+        }
+        self.transaction.store.expiry.insert(name, deadline);
+    }
+
+    /// Add the values to the front of the list held by the key, creating the
+    /// list if the key has no value, and returning the new length of the
+    /// list. The values are added one at a time, so the last ends up first.
+    /// Fails with `Error::WrongType` if the key holds a value that is not a
+    /// list.
+    pub fn lpush<I>(&mut self, name: impl Into<K>, values: I) -> Result<usize>
+    where
+        I: IntoIterator,
+        I::Item: Into<V>,
+    {
+        self.push(name.into(), values.into_iter().map(Into::into), true)
+    }
+
+    /// Add the values to the end of the list held by the key, creating the
+    /// list if the key has no value, and returning the new length of the list.
+    pub fn rpush<I>(&mut self, name: impl Into<K>, values: I) -> Result<usize>
+    where
+        I: IntoIterator,
+        I::Item: Into<V>,
+    {
+        self.push(name.into(), values.into_iter().map(Into::into), false)
+    }
+
+    fn push(&mut self, name: K, values: impl Iterator<Item = V>, front: bool) -> Result<usize> {
+        self.remove_expired();
+        if self.transaction.store.holds_other(&name, Kind::List) {
+            return Err(Error::WrongType);
+        }
+        let values: Vec<V> = values.collect();
+        if self.transaction.parent.is_none() {
+            if let Some(log) = self.log.as_mut() {
+                log.push(&name, values.iter(), front);
+                log.flush();
+            }
+        }
+        Ok(self.transaction.store.push(name, values, front))
+    }
+
+    /// Remove and return the first value of the list held by the key, if any.
+    /// A list that becomes empty is removed.
+    pub fn lpop<Q>(&mut self, name: &Q) -> Result<Option<V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.pop(name, true)
+    }
+
+    /// Remove and return the last value of the list held by the key, if any.
+    pub fn rpop<Q>(&mut self, name: &Q) -> Result<Option<V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.pop(name, false)
+    }
+
+    fn pop<Q>(&mut self, name: &Q, front: bool) -> Result<Option<V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.remove_expired();
+        let store = &mut self.transaction.store;
+        if store.holds_other(name, Kind::List) {
+            return Err(Error::WrongType);
+        }
+        if self.transaction.parent.is_none() {
+            if let Some(log) = self.log.as_mut() {
+                if let Some((name, _)) = store.lists.get_key_value(name) {
+                    log.pop(name, front);
+                    log.flush();
+                }
+            }
+        }
+        Ok(self.transaction.store.pop(name, front))
+    }
+
+    /// Returns the values of the list held by the key from `start` to `stop`
+    /// inclusive, where negative positions count back from the end, so that
+    /// `0` and `-1` give the whole list. A key with no value is an empty list.
+    pub fn lrange<Q>(&self, name: &Q, start: i64, stop: i64) -> Result<Vec<V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let Some(list) = self.list(name)? else {
+            return Ok(Vec::new());
+        };
+        let len = list.len() as i64;
+        let start = if start < 0 {
+            (len + start).max(0)
+        } else {
+            start
+        };
+        let stop = if stop < 0 {
+            len + stop
+        } else {
+            stop.min(len - 1)
+        };
+        if start > stop {
+            return Ok(Vec::new());
+        }
+        let values = list.iter().skip(start as usize);
+        Ok(values.take((stop - start + 1) as usize).cloned().collect())
+    }
+
+    /// Returns the length of the list held by the key, zero if it has none.
+    pub fn llen<Q>(&self, name: &Q) -> Result<usize>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        Ok(self.list(name)?.map_or(0, im::Vector::len))
+    }
+
+    /// Returns the list held by the key, if it has one that has not expired.
+    fn list<Q>(&self, name: &Q) -> Result<Option<&im::Vector<V>>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let store = &self.transaction.store;
+        if store.is_expired(name) {
+            Ok(None)
+        } else if store.holds_other(name, Kind::List) {
+            Err(Error::WrongType)
+        } else {
+            Ok(store.lists.get(name))
+        }
+    }
+
+    /// Set the fields of the hash held by the key to the values, creating
+    /// the hash if the key has no value, and returning the number of fields
+    /// that were added rather than updated. Fails with `Error::WrongType` if
+    /// the key holds a value that is not a hash.
+    pub fn hset<F, W>(
+        &mut self,
+        name: impl Into<K>,
+        fields: impl IntoIterator<Item = (F, W)>,
+    ) -> Result<usize>
+    where
+        F: Into<K>,
+        W: Into<V>,
+    {
+        self.remove_expired();
+        let name: K = name.into();
+        let store = &self.transaction.store;
+        if store.holds_other(&name, Kind::Hash) {
+            return Err(Error::WrongType);
+        }
+        let logging = self.transaction.parent.is_none();
+        let mut added = 0;
+        for (field, value) in fields {
+            let (field, value): (K, V) = (field.into(), value.into());
+            if logging {
+                if let Some(log) = self.log.as_mut() {
+                    log.hset(&name, &field, &value);
+                }
+            }
+            if self.transaction.store.hset(name.clone(), field, value) {
+                added += 1;
+            }
+        }
+        if logging {
+            if let Some(log) = self.log.as_mut() {
+                log.flush();
+            }
+        }
+        Ok(added)
+    }
+
+    /// Returns the value of the field of the hash held by the key, if any.
+    pub fn hget<Q>(&self, name: &Q, field: &Q) -> Result<Option<V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        Ok(self.hash(name)?.and_then(|hash| hash.get(field)).cloned())
+    }
+
+    /// Remove the fields from the hash held by the key, returning the number
+    /// that were there. A hash left without fields is removed.
+    pub fn hdel<Q>(&mut self, name: &Q, fields: &[&Q]) -> Result<usize>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        self.remove_expired();
+        if self.hash(name)?.is_none() {
+            return Ok(0);
+        }
+        let logging = self.transaction.parent.is_none();
+        let mut removed = 0;
+        for field in fields.iter() {
+            if self.transaction.store.hdel(name, *field) {
+                removed += 1;
+                if logging {
+                    if let Some(log) = self.log.as_mut() {
+                        log.hdel(&name.to_owned(), &(*field).to_owned());
+                    }
+                }
+            }
+        }
+        if logging {
+            if let Some(log) = self.log.as_mut() {
+                log.flush();
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Returns the fields and values of the hash held by the key, in field
+    /// order. A key with no value is an empty hash.
+    pub fn hgetall<Q>(&self, name: &Q) -> Result<Vec<(K, V)>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let pairs = self.hash(name)?.into_iter().flat_map(|hash| hash.iter());
+        Ok(pairs.map(|(f, v)| (f.clone(), v.clone())).collect())
+    }
+
+    /// Returns the number of fields in the hash held by the key, zero if it
+    /// has none.
+    pub fn hlen<Q>(&self, name: &Q) -> Result<usize>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        Ok(self.hash(name)?.map_or(0, im::OrdMap::len))
+    }
+
+    /// Returns the hash held by the key, if it has one that has not expired.
+    fn hash<Q>(&self, name: &Q) -> Result<Option<&im::OrdMap<K, V>>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let store = &self.transaction.store;
+        if store.is_expired(name) {
+            Ok(None)
+        } else if store.holds_other(name, Kind::Hash) {
+            Err(Error::WrongType)
+        } else {
+            Ok(store.hashes.get(name))
+        }
+    }
+
+    /// Give each member of the sorted set held by the key its score, creating
+    /// the set if the key has no value, and returning the number of members
+    /// that were added rather than given a new score. Fails with
+    /// `Error::NotAFloat` if a score is NaN, and with `Error::WrongType` if
+    /// the key holds a value that is not a sorted set.
+    pub fn zadd<M>(
+        &mut self,
+        name: impl Into<K>,
+        members: impl IntoIterator<Item = (f64, M)>,
+    ) -> Result<usize>
+    where
+        M: Into<K>,
+    {
+        self.remove_expired();
+        let name: K = name.into();
+        let members: Vec<(f64, K)> = members
+            .into_iter()
+            .map(|(score, member)| (score, member.into()))
+            .collect();
+        let store = &mut self.transaction.store;
+        if store.holds_other(&name, Kind::SortedSet) {
+            return Err(Error::WrongType);
+        }
+        if members.iter().any(|(score, _)| score.is_nan()) {
+            return Err(Error::NotAFloat);
+        }
+        if self.transaction.parent.is_none() {
+            if let Some(log) = self.log.as_mut() {
+                for (score, member) in members.iter() {
+                    log.zadd(&name, member, *score);
+                }
+                log.flush();
+            }
+        }
+        let set = self.transaction.store.sorted.entry(name).or_default();
+        let mut added = 0;
+        for (score, member) in members.into_iter() {
+            if set.insert(member, score) {
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    /// Returns the score of the member of the sorted set held by the key.
+    pub fn zscore<Q>(&self, name: &Q, member: &Q) -> Result<Option<f64>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        Ok(self.sorted_set(name)?.and_then(|set| set.score(member)))
+    }
+
+    /// Remove the members from the sorted set held by the key, returning the
+    /// number that were there. A sorted set left without members is removed.
+    pub fn zrem<Q>(&mut self, name: &Q, members: &[&Q]) -> Result<usize>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        self.remove_expired();
+        if self.sorted_set(name)?.is_none() {
+            return Ok(0);
+        }
+        let logging = self.transaction.parent.is_none();
+        let mut removed = 0;
+        for member in members.iter() {
+            if self.transaction.store.zrem(name, *member) {
+                removed += 1;
+                if logging {
+                    if let Some(log) = self.log.as_mut() {
+                        log.zrem(&name.to_owned(), &(*member).to_owned());
+                    }
+                }
+            }
+        }
+        if logging {
+            if let Some(log) = self.log.as_mut() {
+                log.flush();
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Returns the members of the sorted set held by the key, with their
+    /// scores, from rank `start` to `stop` inclusive, lowest score first.
+    /// Negative ranks count back from the highest score, as in `lrange()`.
+    pub fn zrange<Q>(&self, name: &Q, start: i64, stop: i64) -> Result<Vec<(K, f64)>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let Some(set) = self.sorted_set(name)? else {
+            return Ok(Vec::new());
+        };
+        let len = set.len() as i64;
+        let start = if start < 0 {
+            (len + start).max(0)
+        } else {
+            start
+        };
+        let stop = if stop < 0 {
+            len + stop
+        } else {
+            stop.min(len - 1)
+        };
+        if start > stop {
+            return Ok(Vec::new());
+        }
+        let members = set
+            .iter()
+            .skip(start as usize)
+            .take((stop - start + 1) as usize);
+        Ok(members
+            .map(|(member, score)| (member.clone(), score))
+            .collect())
+    }
+
+    /// Returns the members of the sorted set held by the key whose scores lie
+    /// within the bounds, with their scores, lowest score first.
+    pub fn zrange_by_score<Q>(
+        &self,
+        name: &Q,
+        min: Bound<f64>,
+        max: Bound<f64>,
+    ) -> Result<Vec<(K, f64)>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let Some(set) = self.sorted_set(name)? else {
+            return Ok(Vec::new());
+        };
+        let members = set.range_by_score(min, max);
+        Ok(members
+            .map(|(member, score)| (member.clone(), score))
+            .collect())
+    }
+
+    /// Replace the part of the JSON document held by the key that is found
+    /// along the path with the value, returning false if the path leads
+    /// nowhere. A key with no value may be given a document only at the root
+    /// path `$`. Only the parts of the document along the path are visited,
+    /// rather than the whole document being parsed and written out again.
+    /// Fails with `Error::Parse` if the path is not valid, and with
+    /// `Error::WrongType` if the key holds a value that is not a document.
+    /// Outside of a transaction the whole of the document is written to the
+    /// log whenever it changes.
+    pub fn json_set(
+        &mut self,
+        name: impl Into<K>,
+        path: &str,
+        value: serde_json::Value,
+    ) -> Result<bool> {
+        let path = jsonpath::parse(path)?;
+        self.remove_expired();
+        let name: K = name.into();
+        let store = &mut self.transaction.store;
+        if store.holds_other(&name, Kind::Json) {
+            return Err(Error::WrongType);
+        }
+        let set = match store.documents.get_mut(&name) {
+            Some(document) => jsonpath::set(Arc::make_mut(document), &path, value),
+            None if path.is_empty() => {
+                store.documents.insert(name.clone(), Arc::new(value));
+                true
+            }
+            None => false,
+        };
+        if set && self.transaction.parent.is_none() {
+            if let Some(log) = self.log.as_mut() {
+                log.json(&name, &self.transaction.store.documents[&name]);
+                log.flush();
+            }
+        }
+        Ok(set)
+    }
+
+    /// Returns the part of the JSON document held by the key that is found
+    /// along the path, if any.
+    pub fn json_get<Q>(&self, name: &Q, path: &str) -> Result<Option<serde_json::Value>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let path = jsonpath::parse(path)?;
+        let store = &self.transaction.store;
+        if store.is_expired(name) {
+            return Ok(None);
+        }
+        if store.holds_other(name, Kind::Json) {
+            return Err(Error::WrongType);
+        }
+        let document = store.documents.get(name);
+        Ok(document.and_then(|document| jsonpath::get(document, &path).cloned()))
+    }
+
+    /// Add an entry with the fields and values to the end of the stream held
+    /// by the key, creating the stream if the key has no value, and return
+    /// the identifier of the entry, which is greater than that of every entry
+    /// before it. Fails with `Error::WrongType` if the key holds a value that
+    /// is not a stream.
+    pub fn xadd<F, W>(
+        &mut self,
+        name: impl Into<K>,
+        fields: impl IntoIterator<Item = (F, W)>,
+    ) -> Result<StreamId>
+    where
+        F: Into<K>,
+        W: Into<V>,
+    {
+        self.remove_expired();
+        let name: K = name.into();
+        let store = &self.transaction.store;
+        if store.holds_other(&name, Kind::Stream) {
+            return Err(Error::WrongType);
+        }
+        let id = StreamId::next(store.streams.get(&name).and_then(Stream::last_id));
+        let fields: Vec<(K, V)> = fields
+            .into_iter()
+            .map(|(field, value)| (field.into(), value.into()))
+            .collect();
+        if self.transaction.parent.is_none() {
+            if let Some(log) = self.log.as_mut() {
+                log.xadd(&name, id, &fields);
+                log.flush();
+            }
+        }
+        self.transaction.store.xadd(name, id, fields);
+        Ok(id)
+    }
+
+    /// Returns the entries of the stream held by the key whose identifiers
+    /// lie between `start` and `end` inclusive, in the order they were added.
+    /// Use `StreamId::MIN` and `StreamId::MAX` for the whole stream.
+    pub fn xrange<Q>(&self, name: &Q, start: StreamId, end: StreamId) -> Result<Vec<Entry<K, V>>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let entries = self
+            .stream(name)?
+            .into_iter()
+            .flat_map(|s| s.range(start, end));
+        Ok(entries.map(|(id, fields)| (*id, fields.clone())).collect())
+    }
+
+    /// Returns the number of entries in the stream held by the key, zero if
+    /// it has none.
+    pub fn xlen<Q>(&self, name: &Q) -> Result<usize>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        Ok(self.stream(name)?.map_or(0, Stream::len))
+    }
+
+    /// Returns the stream held by the key, if it has one that has not
+    /// expired.
+    fn stream<Q>(&self, name: &Q) -> Result<Option<&Stream<K, V>>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let store = &self.transaction.store;
+        if store.is_expired(name) {
+            Ok(None)
+        } else if store.holds_other(name, Kind::Stream) {
+            Err(Error::WrongType)
+        } else {
+            Ok(store.streams.get(name))
+        }
+    }
+
+    /// Returns the sorted set held by the key, if it has one that has not
+    /// expired.
+    fn sorted_set<Q>(&self, name: &Q) -> Result<Option<&SortedSet<K>>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let store = &self.transaction.store;
+        if store.is_expired(name) {
+            Ok(None)
+        } else if store.holds_other(name, Kind::SortedSet) {
+            Err(Error::WrongType)
+        } else {
+            Ok(store.sorted.get(name))
+        }
+    }
+
+    /// Remove the keys whose time-to-live has run out, notifying observers of
+    /// the eviction, and logging it if no transaction is open.
+    fn remove_expired(&mut self) {
+        if self.transaction.store.expiry.is_empty() {
+            return;
+        }
+        let expired: Vec<K> = self
+            .transaction
+            .store
+            .expiry
+            .expired(SystemTime::now())
+            .cloned()
+            .collect();
+        for name in expired.iter() {
+            self.transaction.delete(name);
+            if self.transaction.parent.is_none() {
+                if let Some(log) = self.log.as_mut() {
+                    log.unset(name);
+                    log.flush();
+                }
+            }
+            for observer in self.observers.iter() {
+                observer.on_evict(name);
+            }
+        }
+    }
+
+    /// Returns the number of keys that hold anything, of whatever kind.
+    pub fn len(&self) -> usize {
+        let store = &self.transaction.store;
+        store.len() - store.expired_len()
+    }
+
+    /// Returns true if no keys hold anything.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Visit every key and its value in key order, as seen from within the
+    /// open transactions, skipping keys that have expired.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.transaction.store.live()
+    }
+
+    /// Returns a key chosen at random, each having the same chance whatever
+    /// kind of data it holds, or `None` if no keys hold anything. This takes
+    /// time proportional to the number of keys.
+    pub fn random_key(&self) -> Option<&K> {
+        let store = &self.transaction.store;
+        store
+            .names::<K>(Bound::Unbounded)
+            .filter(|name| !store.is_expired::<K>(name))
+            .choose(&mut rand::thread_rng())
+    }
+
+    /// Returns the keys that match the glob pattern, in key order, whatever
+    /// kind of data they hold, as seen from within the open transactions. Any literal text at the start of
+    /// the pattern narrows the search to the keys with that prefix.
+    pub fn keys(&self, pattern: &str) -> Vec<&K>
+    where
+        K: Borrow<str>,
+    {
+        let store = &self.transaction.store;
+        let prefix = match pattern.find(['*', '?', '[', '\\']) {
+            Some(end) => &pattern[..end],
+            None => pattern,
+        };
+        store
+            .names(Bound::Included(prefix))
+            .take_while(|name| (*name).borrow().starts_with(prefix))
+            .filter(|name| glob::matches(pattern, (*name).borrow()) && !store.is_expired::<K>(name))
+            .collect()
+    }
+
+    /// Visit up to `count` keys in key order, starting from the cursor,
+    /// returning those that match the glob pattern along with the cursor from
+    /// which to continue. The cursor `"0"` starts a scan and is returned once
+    /// all keys have been visited. Keys that exist for the whole of the scan
+    /// are returned exactly once, however the database changes in between.
+    /// Returns `None` if the cursor is not one that was returned by a scan.
+    pub fn scan(&self, cursor: &str, count: usize, pattern: &str) -> Option<(Vec<&K>, String)>
+    where
+        K: Borrow<str>,
+    {
+        let store = &self.transaction.store;
+        // the cursor is the next key to visit, as hexadecimal
+        let start = match cursor {
+            "0" => String::new(),
+            _ => decode_hex(cursor)?,
+        };
+        let mut visited = store.names(Bound::Included(start.as_str()));
+        let keys: Vec<&K> = visited
+            .by_ref()
+            .take(count.max(1))
+            .filter(|name| glob::matches(pattern, (*name).borrow()) && !store.is_expired::<K>(name))
+            .collect();
+        let next = match visited.next() {
+            Some(name) => encode_hex((*name).borrow()),
+            None => "0".into(),
+        };
+        Some((keys, next))
+    }
+
+    /// Returns the exact number of distinct values, found by visiting every
+    /// value in the count index.
+    pub fn distinct_value_count(&self) -> usize {
+        self.transaction.store.distinct()
+    }
+
+    /// Capture the committed state of the database, ignoring any changes made
+    /// in open transactions.
+    pub fn freeze_snapshot(&self) -> Snapshot<K, V> {
+        Snapshot {
+            store: self.transaction.base().store.clone(),
+            secret: self.secret.clone(),
+        }
+    }
+
+    /// Returns true if the key holds anything other than what it held in the
+    /// snapshot, whatever kind of data that is, as seen from within any open
+    /// transactions. A key changed and then changed back is not seen as
+    /// changed.
+    pub fn changed_since<Q>(&self, snapshot: &Snapshot<K, V>, name: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let (then, now) = (&snapshot.store, &self.transaction.store);
+        then.values.get(name) != now.values.get(name)
+            || then.lists.get(name) != now.lists.get(name)
+            || then.hashes.get(name) != now.hashes.get(name)
+            || then.sorted.get(name) != now.sorted.get(name)
+            || then.documents.get(name) != now.documents.get(name)
+            || then.streams.get(name) != now.streams.get(name)
+    }
+
+    /// Returns every value that occurs along with the number of keys having
+    /// that value, most common first.
+    pub fn value_counts(&self) -> Vec<(&V, u32)> {
+        let mut counts: Vec<(&V, u32)> = self
+            .transaction
+            .store
+            .value_counts()
+            .map(|(value, count)| (value, count as u32))
+            .collect();
+        counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        counts
+    }
+
+    /// Returns the number of open transactions.
+    pub fn transaction_depth(&self) -> usize {
+        self.transaction.depth()
+    }
+
+    /// Returns true if a transaction is open.
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.parent.is_some()
+    }
+
+    /// Remove every key, whatever kind of data it holds, along with its
+    /// time-to-live. Within a transaction this may be rolled back
+    /// like any other change.
+    pub fn clear(&mut self) {
+        let store = &self.transaction.store;
+        let names: Vec<K> = store
+            .values
+            .keys()
+            .chain(store.lists.keys())
+            .chain(store.hashes.keys())
+            .chain(store.sorted.keys())
+            .chain(store.documents.keys())
+            .chain(store.streams.keys())
+            .cloned()
+            .collect();
+        for name in names.iter() {
+            self.delete(name);
+        }
+    }
+
+    /// Returns the number of keys whose data of any kind has been changed by
+    /// the open transactions, relative to the committed state.
+    pub fn pending_count(&self) -> usize {
+        let (base, store) = (&self.transaction.base().store, &self.transaction.store);
+        base.values.diff(&store.values).count()
+            + base.lists.diff(&store.lists).count()
+            + base.sorted.diff(&store.sorted).count()
+            + base.documents.diff(&store.documents).count()
+            + base.hashes.diff(&store.hashes).count()
+            + base.streams.diff(&store.streams).count()
+    }
+
+    /// Returns the changes that the open transactions have made to values,
+    /// relative to the committed state, so that they may be applied to
+    /// another database with `apply_changeset()`. Changes to lists, hashes,
+    /// and the other kinds of data are not included.
+    pub fn changeset(&self) -> Changeset<K, V> {
+        let base = &self.transaction.base().store;
+        Changeset {
+            changes: changes(&base.values, &self.transaction.store.values),
+        }
+    }
+
+    /// Returns the keys whose data of any kind, or time-to-live, differs
+    /// between the committed state of this database and that of the newer
+    /// one, such as to audit what a batch job changed between two snapshots.
+    pub fn diff(&self, newer: &Self) -> KeyDiff<K> {
+        let (older, newer) = (
+            &self.transaction.base().store,
+            &newer.transaction.base().store,
+        );
+        let mut names: BTreeSet<&K> = BTreeSet::new();
+        names.extend(older.values.diff(&newer.values).map(diff_name));
+        names.extend(older.lists.diff(&newer.lists).map(diff_name));
+        names.extend(older.sorted.diff(&newer.sorted).map(diff_name));
+        names.extend(older.documents.diff(&newer.documents).map(diff_name));
+        names.extend(older.hashes.diff(&newer.hashes).map(diff_name));
+        names.extend(older.streams.diff(&newer.streams).map(diff_name));
+        names.extend(
+            newer
+                .expiry
+                .changes_since(&older.expiry)
+                .map(|(name, _)| name),
+        );
+        let mut diff = KeyDiff {
+            added: Vec::new(),
+            removed: Vec::new(),
+            changed: Vec::new(),
+        };
+        for name in names {
+            match (older.contains(name), newer.contains(name)) {
+                (false, _) => diff.added.push(name.clone()),
+                (_, false) => diff.removed.push(name.clone()),
+                _ => diff.changed.push(name.clone()),
+            }
+        }
+        diff
+    }
+
+    /// Returns the changes to values made by the innermost transaction,
+    /// including any savepoints within it, relative to the state it began
+    /// from, which are what `rollback()` would discard. Empty if there is no
+    /// open transaction.
+    pub fn pending(&self) -> Vec<Change<K, V>> {
+        self.changes_within(self.transaction_depth())
+    }
+
+    /// Returns the changes to values made within the transaction at the given
+    /// depth, including any savepoints and transactions within it, relative
+    /// to the state it began from. Empty if it is no longer open.
+    fn changes_within(&self, depth: usize) -> Vec<Change<K, V>> {
+        if depth == 0 || self.transaction_depth() < depth {
+            return Vec::new();
+        }
+        let mut layer = &self.transaction;
+        while layer.depth() > depth || layer.savepoint.is_some() {
+            layer = layer.parent.as_ref().unwrap();
+        }
+        match layer.parent.as_ref() {
+            Some(parent) => changes(&parent.store.values, &self.transaction.store.values),
+            None => Vec::new(),
+        }
+    }
+
+    /// Apply the changes, such as those of a transaction on another database,
+    /// as a transaction of their own, within the current transaction if
+    /// there is one, so that otherwise they are committed and logged
+    /// together. Nothing is changed, and the result is `Error::Conflict`, if
+    /// any key no longer holds the old value of its change.
+    pub fn apply_changeset(&mut self, changeset: &Changeset<K, V>) -> Result<()> {
+        for change in changeset.changes.iter() {
+            if self.get_ref(&change.name) != change.old.as_ref() {
+                return Err(Error::Conflict);
+            }
+        }
+        self.begin();
+        for change in changeset.changes.iter() {
+            match change.new.as_ref() {
+                Some(value) => self.set(change.name.clone(), value.clone()),
+                None => self.delete(&change.name),
+            }
+        }
+        self.commit_one()
+    }
+
+    /// Start a new transaction.
+    pub fn begin(&mut self) {
+        self.remove_expired();
+        let parent = std::mem::replace(&mut self.transaction, Transaction::new());
+        self.transaction = Transaction::new().parent(parent);
+    }
+
+    /// Start a new transaction with the given name, so that it and every
+    /// transaction begun within it may be rolled back together by
+    /// `rollback_named()`.
+    pub fn begin_named(&mut self, name: &str) {
+        self.begin();
+        self.transaction.name = Some(name.to_owned());
+    }
+
+    /// Start a new transaction that must end within the given time. Once it
+    /// has passed, the next call to `check_deadline()`, `commit()`,
+    /// `commit_one()`, `savepoint()`, `rollback_to()`, or `prepare()` rolls
+    /// back the transaction, along with any begun within it, and fails with
+    /// `Error::TransactionExpired`.
+    ///
+    /// No other method checks the deadline: reads and writes made after it
+    /// has passed still go to the expired transaction, and are thrown away
+    /// with it at the next check. Rolling back in the middle of a write
+    /// instead would send that write to the enclosing transaction, or
+    /// commit it outright if there is none, without the caller knowing. A
+    /// caller that needs to stop at once should call `check_deadline()`
+    /// before each step, as `command::execute()` does for every command.
+    pub fn begin_with(&mut self, timeout: Duration) {
+        self.begin();
+        self.transaction.deadline = Instant::now().checked_add(timeout);
+    }
+
+    /// Roll back any open transaction whose deadline has passed, along with
+    /// those begun within it, failing with `Error::TransactionExpired` if
+    /// there was one.
+    pub fn check_deadline(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let mut depth = self.transaction_depth();
+        let mut expired = None;
+        let mut current = &self.transaction;
+        while let Some(parent) = current.parent.as_ref() {
+            if current.savepoint.is_none() {
+                if current.deadline.is_some_and(|deadline| deadline <= now) {
+                    expired = Some(depth);
+                }
+                depth -= 1;
+            }
+            current = parent;
+        }
+        let Some(depth) = expired else {
+            return Ok(());
+        };
+        while self.transaction_depth() >= depth {
+            self.rollback()?;
+        }
+        Err(Error::TransactionExpired)
+    }
+
+    /// Commit _all_ open transactions and return how many there were, failing
+    /// with `Error::NoTransaction` if there are none.
+    pub fn commit(&mut self) -> Result<usize> {
+        self.check_deadline()?;
+        self.remove_expired();
+        let timer = self.start_timer();
+        let depth = self.transaction_depth();
+        let Some(parent) = self.transaction.parent.take() else {
+            return Err(Error::NoTransaction);
+        };
+        self.transaction.savepoint = None;
+        self.transaction.name = None;
+        self.transaction.deadline = None;
+        if self.log.is_some() {
+            self.log_changes(&parent.base().store);
+        }
+        if let Some(started) = timer {
+            let elapsed = started.elapsed();
+            let names = parent.base().store.changed_keys(&self.transaction.store);
+            for observer in self.observers.iter() {
+                observer.on_commit(elapsed);
+                observer.on_commit_keys(&names);
+            }
+        }
+        Ok(depth)
+    }
+
+    /// Commit only the innermost transaction into the one that encloses it,
+    /// which stays open and may yet be rolled back, so that nested units of
+    /// work can be composed. With a single transaction open this is the same
+    /// as `commit()`. Fails with `Error::NoTransaction` if there is none.
+    pub fn commit_one(&mut self) -> Result<()> {
+        if self.transaction.parent.is_none() {
+            return Err(Error::NoTransaction);
+        }
+        self.check_deadline()?;
+        // the savepoints of the innermost transaction are committed with it
+        let store = std::mem::replace(&mut self.transaction.store, CountingStore::new());
+        self.transaction.unwind_savepoints();
+        self.transaction.store = store;
+        let parent = self.transaction.parent.as_ref().unwrap();
+        if parent.parent.is_none() {
+            return self.commit().map(|_| ());
+        }
+        self.remove_expired();
+        let mut parent = *self.transaction.parent.take().unwrap();
+        parent.store = std::mem::replace(&mut self.transaction.store, CountingStore::new());
+        self.transaction = parent;
+        Ok(())
+    }
+
+    /// Commit the given number of innermost transactions, each into the one
+    /// enclosing it, so that committing as many as are open is the same as
+    /// `commit()`. Fails with `Error::NoTransaction`, committing nothing, if
+    /// fewer are open.
+    pub fn commit_n(&mut self, count: usize) -> Result<()> {
+        if count > self.transaction_depth() {
+            return Err(Error::NoTransaction);
+        }
+        for _ in 0..count {
+            self.commit_one()?;
+        }
+        Ok(())
+    }
+
+    /// Mark the current state of the innermost transaction with the name, so
+    /// that `rollback_to()` may later discard the changes made since without
+    /// discarding those made before. A savepoint is not a transaction of its
+    /// own: it is committed or rolled back along with the transaction that
+    /// holds it, and is not counted by `transaction_depth()`. Reusing a name
+    /// marks a new savepoint that hides the older one. Fails with
+    /// `Error::NoTransaction` if there is no open transaction.
+    pub fn savepoint(&mut self, name: &str) -> Result<()> {
+        if self.transaction.parent.is_none() {
+            return Err(Error::NoTransaction);
+        }
+        self.check_deadline()?;
+        self.begin();
+        self.transaction.savepoint = Some(name.to_owned());
+        Ok(())
+    }
+
+    /// Discard the changes made since the named savepoint of the innermost
+    /// transaction, along with any savepoints marked after it. The savepoint
+    /// itself remains, so that it may be rolled back to again. Fails with
+    /// `Error::NoSavepoint` if the innermost transaction has no savepoint of
+    /// that name.
+    pub fn rollback_to(&mut self, name: &str) -> Result<()> {
+        self.check_deadline()?;
+        let mut current = &self.transaction;
+        loop {
+            match current.savepoint.as_deref() {
+                Some(found) if found == name => break,
+                Some(_) => current = current.parent.as_ref().unwrap(),
+                None => return Err(Error::NoSavepoint(name.to_owned())),
+            }
+        }
+        while self.transaction.savepoint.as_deref() != Some(name) {
+            self.transaction = *self.transaction.parent.take().unwrap();
+        }
+        self.transaction.store = self.transaction.parent.as_ref().unwrap().store.clone();
+        Ok(())
+    }
+
+    /// Run the closure within a new transaction, committing it into the
+    /// enclosing state if the closure returns `Ok` and rolling it back if
+    /// the closure returns `Err` or panics, in which case the panic resumes
+    /// once the transaction is gone. Any transactions the closure begins but
+    /// leaves open are rolled back first either way.
+    pub fn with_transaction<T, E, F>(&mut self, f: F) -> std::result::Result<T, E>
+    where
+        F: FnOnce(&mut Self) -> std::result::Result<T, E>,
+        E: From<Error>,
+    {
+        self.begin();
+        let depth = self.transaction_depth();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(self)));
+        while self.transaction_depth() > depth {
+            self.rollback()?;
+        }
+        // the closure may itself have ended the transaction
+        let open = self.transaction_depth() == depth;
+        match result {
+            Ok(Ok(value)) => {
+                if open {
+                    self.commit_one()?;
+                }
+                Ok(value)
+            }
+            Ok(Err(err)) => {
+                if open {
+                    self.rollback()?;
+                }
+                Err(err)
+            }
+            Err(payload) => {
+                if open {
+                    let _ = self.rollback();
+                }
+                panic::resume_unwind(payload)
+            }
+        }
+    }
+
+    /// Roll back the innermost transaction with the given name along with
+    /// every transaction begun within it, failing with
+    /// `Error::NoNamedTransaction` if no open transaction has that name.
+    pub fn rollback_named(&mut self, name: &str) -> Result<()> {
+        let mut current = &self.transaction;
+        while current.name.as_deref() != Some(name) {
+            match current.parent.as_ref() {
+                Some(parent) => current = parent,
+                None => return Err(Error::NoNamedTransaction(name.to_owned())),
+            }
+        }
+        loop {
+            self.transaction.unwind_savepoints();
+            let found = self.transaction.name.as_deref() == Some(name);
+            self.rollback()?;
+            if found {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Prepare the innermost transaction to be committed, as the first phase
+    /// of a commit coordinated with other resources. Fails if there is no
+    /// open transaction, if it is past its deadline, or if the log can no
+    /// longer be written, so that the coordinator can abort everywhere.
+    /// Otherwise the transaction as it is now is what `commit_prepared()`
+    /// will commit, and it must not be changed in the meantime.
+    pub fn prepare(&mut self) -> Result<PreparedTx<K, V>> {
+        if self.transaction.parent.is_none() {
+            return Err(Error::NoTransaction);
+        }
+        self.check_deadline()?;
+        if self.log.as_ref().is_some_and(WriteAheadLog::failed) {
+            let msg = "the log can no longer be written";
+            return Err(Error::Io(io::Error::other(msg)));
+        }
+        Ok(PreparedTx {
+            depth: self.transaction_depth(),
+            store: self.transaction.store.clone(),
+        })
+    }
+
+    /// Commit the prepared transaction as `commit_one()` does. Fails with
+    /// `Error::Conflict`, leaving the transaction open, if it has been
+    /// changed or ended since it was prepared.
+    pub fn commit_prepared(&mut self, prepared: PreparedTx<K, V>) -> Result<()> {
+        self.check_prepared(&prepared)?;
+        self.commit_one()
+    }
+
+    /// Roll back the prepared transaction. Fails with `Error::Conflict` if
+    /// it has been changed or ended since it was prepared, in which case
+    /// nothing is rolled back.
+    pub fn abort_prepared(&mut self, prepared: PreparedTx<K, V>) -> Result<()> {
+        self.check_prepared(&prepared)?;
+        self.rollback().map(|_| ())
+    }
+
+    /// Ensure the innermost transaction is still as it was prepared.
+    fn check_prepared(&self, prepared: &PreparedTx<K, V>) -> Result<()> {
+        if self.transaction_depth() != prepared.depth
+            || !prepared
+                .store
+                .changed_keys(&self.transaction.store)
+                .is_empty()
+        {
+            return Err(Error::Conflict);
+        }
+        Ok(())
+    }
+
+    /// Start a new transaction that is rolled back when the returned guard is
+    /// dropped, unless it is committed through the guard first, so that
+    /// returning early or with `?` cannot leave the transaction open. The
+    /// guard derefs to the database for everything else.
+    pub fn begin_guarded(&mut self) -> TransactionGuard<'_, K, V> {
+        self.begin();
+        let depth = self.transaction_depth();
+        TransactionGuard {
+            database: self,
+            depth,
+            committed: false,
+        }
+    }
+
+    /// Rollback the current transaction, along with its savepoints, and
+    /// return the number of transactions rolled back, which is always one.
+    /// Fails with `Error::NoTransaction` if there is no open transaction.
+    pub fn rollback(&mut self) -> Result<usize> {
+        let timer = self.start_timer();
+        // savepoints hold the latest changes, which are discarded as well
+        let discarded = timer.map(|_| self.transaction.store.clone());
+        self.transaction.unwind_savepoints();
+        if let Some(transaction) = self.transaction.parent.take() {
+            self.transaction = *transaction;
+            if let (Some(started), Some(discarded)) = (timer, discarded) {
+                let elapsed = started.elapsed();
+                let names = self.transaction.store.changed_keys(&discarded);
+                for observer in self.observers.iter() {
+                    observer.on_rollback(elapsed);
+                    observer.on_rollback_keys(&names);
+                }
+            }
+            Ok(1)
+        } else {
+            Err(Error::NoTransaction)
+        }
+    }
+}
+
+impl<K, V> Database<K, V>
+where
+    K: Ord + Clone,
+    V: Hash + Eq + Clone + AsRef<[u8]>,
+{
+    /// Retrieve the value for the given key as text, if it has a value that
+    /// is valid UTF-8. This suits a database of bytes, such as
+    /// `Database<String, Vec<u8>>`, which holds serialized data and images
+    /// as readily as text, and whose `get()` returns the raw bytes.
+    pub fn get_str<Q>(&self, name: &Q) -> Option<&str>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        let value = self.get_ref(name)?;
+        std::str::from_utf8(value.as_ref()).ok()
+    }
+}
+
+impl<K, V> Database<K, V>
+where
+    K: Ord + Clone + AsRef<str> + From<String>,
+    V: Hash + Eq + Clone + AsRef<str> + From<String>,
+{
+    /// Write the keys and values, as seen from within any open transactions,
+    /// as a JSON object. The keys holding hashes follow, each as an object of
+    /// its fields and values.
+    pub fn export_json<W: Write>(&self, writer: W) -> Result<()> {
+        let mut writer = io::BufWriter::new(writer);
+        let store = &self.transaction.store;
+        let hashes = store
+            .hashes
+            .iter()
+            .filter(|(name, _)| !store.is_expired::<K>(name));
+        let mut written = 0;
+        writer.write_all(b"{")?;
+        for (name, value) in self.iter() {
+            writer.write_all(if written == 0 { b"\n  " } else { b",\n  " })?;
+            serde_json::to_writer(&mut writer, name.as_ref())?;
+            writer.write_all(b": ")?;
+            serde_json::to_writer(&mut writer, value.as_ref())?;
+            written += 1;
+        }
+        for (name, hash) in hashes {
+            writer.write_all(if written == 0 { b"\n  " } else { b",\n  " })?;
+            serde_json::to_writer(&mut writer, name.as_ref())?;
+            writer.write_all(b": ")?;
+            let fields: BTreeMap<&str, &str> =
+                hash.iter().map(|(f, v)| (f.as_ref(), v.as_ref())).collect();
+            serde_json::to_writer(&mut writer, &fields)?;
+            written += 1;
+        }
+        writer.write_all(if written == 0 { b"}\n" } else { b"\n}\n" })?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Read a JSON object of string values and save each of them, returning
+    /// the number of keys imported. A value that is itself an object of
+    /// strings becomes a hash. The changes are made in the current
+    /// transaction, if any. Nothing is changed if the input is not valid.
+    pub fn import_json<R: Read>(&mut self, reader: R, mode: ImportMode) -> Result<usize> {
+        let pairs: HashMap<String, serde_json::Value> =
+            serde_json::from_reader(io::BufReader::new(reader))?;
+        let valid = |value: &serde_json::Value| match value {
+            serde_json::Value::String(_) => true,
+            serde_json::Value::Object(fields) => fields.values().all(|v| v.is_string()),
+            _ => false,
+        };
+        if let Some((name, _)) = pairs.iter().find(|(_, value)| !valid(value)) {
+            let msg = format!("value of {} is not a string or an object of strings", name);
+            return Err(Error::Parse(msg));
+        }
+        if mode == ImportMode::Replace {
+            self.clear();
+        }
+        let count = pairs.len();
+        for (name, value) in pairs.into_iter() {
+            match value {
+                serde_json::Value::Object(fields) => {
+                    let name = K::from(name);
+                    self.delete(&name);
+                    let fields = fields.into_iter().filter_map(|(field, value)| match value {
+                        serde_json::Value::String(value) => Some((K::from(field), value)),
+                        _ => None,
+                    });
+                    self.hset(name, fields)?;
+                }
+                serde_json::Value::String(value) => self.set(name, value),
+                _ => unreachable!("values were checked above"),
+            }
+        }
+        Ok(count)
+    }
+}
+
+impl<K, V> Database<K, V>
+where
+    K: Ord + Clone,
+    V: Hash + Eq + Clone + AsRef<str> + From<String>,
+{
+    /// Add the suffix to the end of the value of the key, or save the suffix
+    /// if the key has no value, returning the new length in bytes. Any
+    /// time-to-live is kept.
+    pub fn append<Q>(&mut self, name: &Q, suffix: &str) -> usize
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        let mut value = match self.get_ref(name) {
+            Some(value) => value.as_ref().to_owned(),
+            None => String::new(),
+        };
+        value.push_str(suffix);
+        let length = value.len();
+        self.replace_keeping_ttl(name, value);
+        length
+    }
+
+    /// Add one to the integer value of the key, returning the new value.
+    pub fn incr<Q>(&mut self, name: &Q) -> Result<i64>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        self.incr_by(name, 1)
+    }
+
+    /// Subtract one from the integer value of the key, returning the new value.
+    pub fn decr<Q>(&mut self, name: &Q) -> Result<i64>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        self.incr_by(name, -1)
+    }
+
+    /// Add the amount to the integer value of the key, returning the new
+    /// value. A key with no value is taken to be zero, and any time-to-live
+    /// is kept. Fails with `Error::NotAnInteger`, leaving the value as it
+    /// was, if the value is not an integer or the sum would overflow.
+    pub fn incr_by<Q>(&mut self, name: &Q, amount: i64) -> Result<i64>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        let current = match self.get_ref(name) {
+            Some(value) => value.as_ref().parse().map_err(|_| Error::NotAnInteger)?,
+            None => 0i64,
+        };
+        let updated = current.checked_add(amount).ok_or(Error::NotAnInteger)?;
+        self.replace_keeping_ttl(name, updated.to_string());
+        Ok(updated)
+    }
+}
+
+impl<K, V> Database<K, V>
+where
+    K: Ord + Clone,
+    V: Hash + Eq + Clone + AsRef<[u8]>,
+{
+    /// Keep an index of keys by the byte length of their values, making
+    /// `keys_by_len()` proportional to the number of keys found rather than
+    /// the size of the database, at the cost of an ordered set entry per key.
+    pub fn enable_length_index(&mut self) {
+        if self.transaction.store.lengths.is_none() {
+            self.transaction.index_lengths(|value| value.as_ref().len());
+        }
+    }
+
+    /// Returns the keys whose values have a byte length within the range,
+    /// ordered by length and then by key, such as to find oversized entries.
+    pub fn keys_by_len(&self, lengths: RangeInclusive<usize>) -> Vec<&K> {
+        let store = &self.transaction.store;
+        if let Some(index) = store.lengths.as_ref() {
+            return index.range(lengths).collect();
+        }
+        let mut found: Vec<(usize, &K)> = store
+            .values
+            .iter()
+            .map(|(name, value)| (value.as_ref().len(), name))
+            .filter(|(length, _)| lengths.contains(length))
+            .collect();
+        // keys are visited in order, so a stable sort keeps them ordered
+        found.sort_by_key(|(length, _)| *length);
+        found.into_iter().map(|(_, name)| name).collect()
+    }
+}
+
+impl<K, V> Database<K, V>
+where
+    K: Hash + Ord + Clone + Send + Sync,
+    V: Hash + Eq + Clone + Send + Sync,
+{
+    /// Load a large number of key/value pairs using all available cores. When
+    /// a key appears more than once the last value wins, just as if each pair
+    /// had been set in turn. Observers are not notified of the individual
+    /// values. This is fastest when the database is empty, as the counts are
+    /// then computed in parallel; otherwise the pairs are deduplicated in
+    /// parallel and then applied to the current transaction one at a time.
+    pub fn import_parallel<I>(&mut self, pairs: I)
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let pairs: Vec<(K, V)> = pairs.into_iter().collect();
+        let committed = if self.log.is_some() && self.transaction.parent.is_none() {
+            Some(self.transaction.store.clone())
+        } else {
+            None
+        };
+        let shard_count = rayon::current_num_threads().max(1);
+        let hasher = RandomState::new();
+        // Partition each chunk of the input by the hash of the key, keeping
+        // the input order within each partition, so that every occurrence of
+        // a key lands in the same shard in the order it was given.
+        let chunk_size = pairs.len().div_ceil(shard_count).max(1);
+        let partitioned: Vec<Vec<Vec<(K, V)>>> = pairs
+            .into_par_iter()
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let mut buckets: Vec<Vec<(K, V)>> = Vec::with_capacity(shard_count);
+                buckets.resize_with(shard_count, Vec::new);
+                for (key, value) in chunk.into_iter() {
+                    let shard = (hasher.hash_one(&key) as usize) % shard_count;
+                    buckets[shard].push((key, value));
+                }
+                buckets
+            })
+            .collect();
+        let mut shards: Vec<Vec<(K, V)>> = Vec::with_capacity(shard_count);
+        shards.resize_with(shard_count, Vec::new);
+        for buckets in partitioned.into_iter() {
+            for (shard, mut bucket) in buckets.into_iter().enumerate() {
+                shards[shard].append(&mut bucket);
+            }
+        }
+        // build the per-shard maps and count tables
+        let built: Vec<(HashMap<K, V>, ValueIndex<V>)> = shards
+            .into_par_iter()
+            .map(|shard| {
+                let mut values: HashMap<K, V> = HashMap::with_capacity(shard.len());
+                for (key, value) in shard.into_iter() {
+                    values.insert(key, value);
+                }
+                let mut counts: ValueIndex<V> = ValueIndex::new();
+                for value in values.values() {
+                    counts.increment(value);
+                }
+                (values, counts)
+            })
+            .collect();
+        let base = &mut self.transaction;
+        if base.store.is_empty() && base.store.lengths.is_none() {
+            for (values, counts) in built.into_iter() {
+                base.store.values.extend(values);
+                for (value, count) in counts.into_iter() {
+                    base.store.counts.adjust_owned(value, count);
+                }
+            }
+        } else {
+            for (values, _) in built.into_iter() {
+                for (key, value) in values.into_iter() {
+                    base.set(key, value);
+                }
+            }
+        }
+        if let Some(committed) = committed {
+            self.log_changes(&committed);
+        }
+    }
+}
+
+/// Returns the changes from the older values to the newer, in key order.
+/// Returns the key of a difference between two maps.
+fn diff_name<'a, K, V>(item: im::ordmap::DiffItem<'a, K, V>) -> &'a K {
+    use im::ordmap::DiffItem;
+    match item {
+        DiffItem::Add(name, _) | DiffItem::Remove(name, _) => name,
+        DiffItem::Update { new, .. } => new.0,
+    }
+}
+
+fn changes<K, V>(older: &im::OrdMap<K, V>, newer: &im::OrdMap<K, V>) -> Vec<Change<K, V>>
+where
+    K: Ord + Clone,
+    V: Clone + PartialEq,
+{
+    use im::ordmap::DiffItem;
+    older
+        .diff(newer)
+        .map(|item| match item {
+            DiffItem::Add(name, value) => Change {
+                name: name.clone(),
+                old: None,
+                new: Some(value.clone()),
+            },
+            DiffItem::Update { old, new } => Change {
+                name: new.0.clone(),
+                old: Some(old.1.clone()),
+                new: Some(new.1.clone()),
+            },
+            DiffItem::Remove(name, value) => Change {
+                name: name.clone(),
+                old: Some(value.clone()),
+                new: None,
+            },
+        })
+        .collect()
+}
+
+/// Returns the key of a difference between two maps if it was removed.
+fn removed_key<'a, K, V>(item: im::ordmap::DiffItem<'a, K, V>) -> Option<&'a K> {
+    match item {
+        im::ordmap::DiffItem::Remove(name, _) => Some(name),
+        _ => None,
+    }
+}
+
+/// Returns the key of an item of the differences between two maps.
+fn diff_key<'a, K, V>(item: im::ordmap::DiffItem<'a, K, V>) -> &'a K {
+    use im::ordmap::DiffItem;
+    match item {
+        DiffItem::Add(name, _) | DiffItem::Remove(name, _) => name,
+        DiffItem::Update { new, .. } => new.0,
+    }
+}
+
+///
+/// Transaction that `Database::prepare()` found ready to be committed, to be
+/// passed to `Database::commit_prepared()` or `Database::abort_prepared()`.
+///
+pub struct PreparedTx<K, V> {
+    // depth of the prepared transaction
+    depth: usize,
+    // contents of the transaction when it was prepared
+    store: CountingStore<K, V>,
+}
+
+///
+/// Transaction begun by `Database::begin_guarded()`, which is rolled back on
+/// drop unless committed. Any transactions begun through the guard and left
+/// open are rolled back along with it.
+///
+pub struct TransactionGuard<'a, K, V>
+where
+    K: Ord + Clone,
+    V: Hash + Eq + Clone,
+{
+    database: &'a mut Database<K, V>,
+    // depth of the guarded transaction
+    depth: usize,
+    // set once the transaction has been committed
+    committed: bool,
+}
+
+impl<K, V> TransactionGuard<'_, K, V>
+where
+    K: Ord + Clone,
+    V: Hash + Eq + Clone,
+{
+    /// Commit the guarded transaction into the enclosing state, first rolling
+    /// back any transactions begun within it that are still open. Fails with
+    /// `Error::NoTransaction` if the transaction was already ended, such as
+    /// by committing all transactions through the database.
+    pub fn commit(mut self) -> Result<()> {
+        self.committed = true;
+        while self.database.transaction_depth() > self.depth {
+            self.database.rollback()?;
+        }
+        if self.database.transaction_depth() < self.depth {
+            return Err(Error::NoTransaction);
+        }
+        self.database.commit_one()
+    }
+
+    /// Roll back the guarded transaction now rather than when dropped.
+    pub fn rollback(self) {
+        drop(self)
+    }
+
+    /// Returns the changes to values made within the guarded transaction,
+    /// including any transactions begun within it, relative to the state it
+    /// began from, to be applied to another database with
+    /// `Database::apply_changeset()`. Empty once the transaction has ended.
+    pub fn to_changeset(&self) -> Changeset<K, V> {
+        Changeset {
+            changes: self.database.changes_within(self.depth),
+        }
+    }
+}
+
+impl<K, V> Drop for TransactionGuard<'_, K, V>
+where
+    K: Ord + Clone,
+    V: Hash + Eq + Clone,
+{
+    fn drop(&mut self) {
+        if !self.committed {
+            while self.database.transaction_depth() >= self.depth {
+                if self.database.rollback().is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl<K, V> Deref for TransactionGuard<'_, K, V>
+where
+    K: Ord + Clone,
+    V: Hash + Eq + Clone,
+{
+    type Target = Database<K, V>;
+
+    fn deref(&self) -> &Self::Target {
+        self.database
+    }
+}
+
+impl<K, V> DerefMut for TransactionGuard<'_, K, V>
+where
+    K: Ord + Clone,
+    V: Hash + Eq + Clone,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.database
+    }
+}
+
+///
+/// Handle to a database shared between threads. Each handle has its own
+/// transactions: beginning one takes a snapshot of the committed state, which
+/// the handle then reads and changes without seeing the changes of others,
+/// and committing applies the keys changed by the handle to the database, the
+/// last commit to change a key winning. Outside of a transaction every
+/// operation goes directly to the database.
+///
+/// Cloning a handle yields another handle to the same database that has no
+/// open transactions, which is how each thread should get its own.
+///
+/// Lists serve as work queues between threads: one thread waits in
+/// `blpop()` while others add values with `rpush()`. So that waiting threads
+/// see them at once, lists are not part of the transactions of a handle.
+///
+pub struct SharedDatabase<K = String, V = String> {
+    database: Arc<Mutex<Database<K, V>>>,
+    // signalled whenever values are added to a list
+    pushed: Arc<Condvar>,
+    // open transactions of this handle; the base is the committed state as
+    // it was when the outermost transaction began
+    transaction: Option<Transaction<K, V>>,
+}
+
+impl<K, V> Clone for SharedDatabase<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            database: self.database.clone(),
+            pushed: self.pushed.clone(),
+            transaction: None,
+        }
+    }
+}
+
+impl<K, V> SharedDatabase<K, V>
+where
+    K: Ord + Clone,
+    V: Hash + Eq + Clone,
+{
+    /// Share the database, which must not have any open transactions.
+    pub fn new(database: Database<K, V>) -> Self {
+        assert_eq!(database.transaction_depth(), 0, "transaction is open");
+        Self {
+            database: Arc::new(Mutex::new(database)),
+            pushed: Arc::new(Condvar::new()),
+            transaction: None,
+        }
+    }
+
+    /// Retrieve the value for the given key, if any.
+    pub fn get<Q>(&self, name: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        match self.transaction.as_ref() {
+            Some(transaction) => transaction.get_ref(name).cloned(),
+            None => self.database.lock().unwrap().get(name),
+        }
+    }
+
+    /// Save the value using the given key.
+    pub fn set(&mut self, name: impl Into<K>, value: impl Into<V>) {
+        match self.transaction.as_mut() {
+            Some(transaction) => transaction.set(name.into(), value.into()),
+            None => self.database.lock().unwrap().set(name, value),
+        }
+    }
+
+    /// Removes the value with the given key.
+    pub fn delete<Q>(&mut self, name: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        match self.transaction.as_mut() {
+            Some(transaction) => transaction.delete(name),
+            None => self.database.lock().unwrap().delete(name),
+        }
+    }
+
+    /// Returns the number of occurrences of the given value.
+    pub fn count<Q>(&self, value: &Q) -> u32
+    where
+        V: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.transaction.as_ref() {
+            Some(transaction) => transaction.count(value),
+            None => self.database.lock().unwrap().count(value),
+        }
+    }
+
+    /// Returns the number of transactions open on this handle.
+    pub fn transaction_depth(&self) -> usize {
+        self.transaction.as_ref().map_or(0, Transaction::depth)
+    }
+
+    /// Returns true if a transaction is open on this handle.
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    /// Start a new transaction on this handle.
+    pub fn begin(&mut self) {
+        let parent = match self.transaction.take() {
+            Some(transaction) => transaction,
+            None => Transaction {
+                store: self.database.lock().unwrap().transaction.store.clone(),
+                parent: None,
+                savepoint: None,
+                name: None,
+                deadline: None,
+            },
+        };
+        self.transaction = Some(Transaction::new().parent(parent));
+    }
+
+    /// Commit _all_ transactions open on this handle.
+    pub fn commit(&mut self) -> Result<()> {
+        let Some(transaction) = self.transaction.take() else {
+            return Err(Error::NoTransaction);
+        };
+        let base = &transaction.base().store.values;
+        let mut database = self.database.lock().unwrap();
+        // applying the changes as a transaction logs them together
+        database.begin();
+        for item in base.diff(&transaction.store.values) {
+            match item {
+                im::ordmap::DiffItem::Add(name, value) => database.set(name.clone(), value.clone()),
+                im::ordmap::DiffItem::Update { new, .. } => {
+                    database.set(new.0.clone(), new.1.clone())
+                }
+                im::ordmap::DiffItem::Remove(name, _) => database.delete(name),
+            }
+        }
+        database.commit().map(|_| ())
+    }
+
+    /// Add the values to the front of the list held by the key, waking any
+    /// threads waiting in `blpop()`, and returning the new length of the list.
+    /// This takes effect at once, even within a transaction.
+    pub fn lpush<I>(&mut self, name: impl Into<K>, values: I) -> Result<usize>
+    where
+        I: IntoIterator,
+        I::Item: Into<V>,
+    {
+        let len = self.database.lock().unwrap().lpush(name, values)?;
+        self.pushed.notify_all();
+        Ok(len)
+    }
+
+    /// Add the values to the end of the list held by the key, waking any
+    /// threads waiting in `blpop()`, and returning the new length of the list.
+    /// This takes effect at once, even within a transaction.
+    pub fn rpush<I>(&mut self, name: impl Into<K>, values: I) -> Result<usize>
+    where
+        I: IntoIterator,
+        I::Item: Into<V>,
+    {
+        let len = self.database.lock().unwrap().rpush(name, values)?;
+        self.pushed.notify_all();
+        Ok(len)
+    }
+
+    /// Remove and return the first value of the list held by the key, waiting
+    /// for one to be added if the list is empty, for at most the given time,
+    /// after which `None` is returned. Pass `Duration::MAX` to wait for as
+    /// long as it takes. Like pushing, this takes effect at once, even within
+    /// a transaction.
+    pub fn blpop<Q>(&mut self, name: &Q, timeout: Duration) -> Result<Option<V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let deadline = Instant::now().checked_add(timeout);
+        let mut database = self.database.lock().unwrap();
+        loop {
+            if let Some(value) = database.lpop(name)? {
+                return Ok(Some(value));
+            }
+            database = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(None);
+                    }
+                    self.pushed
+                        .wait_timeout(database, deadline - now)
+                        .unwrap()
+                        .0
+                }
+                None => self.pushed.wait(database).unwrap(),
+            };
+        }
+    }
+
+    /// Roll back the innermost transaction on this handle, failing with
+    /// `Error::NoTransaction` if there is no open transaction.
+    pub fn rollback(&mut self) -> Result<()> {
+        match self.transaction.take().and_then(|t| t.parent) {
+            Some(parent) => {
+                // the outermost parent is merely the committed snapshot
+                if parent.parent.is_some() {
+                    self.transaction = Some(*parent);
+                }
+                Ok(())
+            }
+            None => Err(Error::NoTransaction),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persist::{Fault, FaultPoint};
+
+    #[test]
+    fn test_counting_store() {
+        let mut store: CountingStore<String, String> = CountingStore::new();
+        assert_eq!(store.count("value"), 0);
+        assert_eq!(store.get_ref("name1"), None);
+        store.set("name1".into(), "value".into());
+        assert_eq!(store.get_ref("name1").map(String::as_str), Some("value"));
+        assert_eq!(store.count("value"), 1);
+        store.set("name2".into(), "value".into());
+        assert_eq!(store.count("value"), 2);
+        store.set("name3".into(), "value".into());
+        assert_eq!(store.count("value"), 3);
+        store.delete("name3");
+        assert_eq!(store.get_ref("name3"), None);
+        assert_eq!(store.count("value"), 2);
+        store.delete("name2");
+        assert_eq!(store.get_ref("name2"), None);
+        assert_eq!(store.count("value"), 1);
+        store.delete("name1");
+        assert_eq!(store.get_ref("name1"), None);
+        assert_eq!(store.count("value"), 0);
+    }
+
+    #[test]
+    fn test_transactions() {
+        let mut first: Transaction<String, String> = Transaction::new();
+        first.set("name2".into(), "value".into());
+        first.set("name1".into(), "value1".into());
+        let mut second = Transaction::new();
+        second = second.parent(first.clone());
+        second.set("name1".into(), "value2".into());
+        second.set("name3".into(), "value".into());
+        assert_eq!(second.get_ref("name1").map(String::as_str), Some("value2"));
+        assert_eq!(first.get_ref("name1").map(String::as_str), Some("value1"));
+        assert_eq!(second.count("value"), 2);
+        assert_eq!(first.count("value"), 1);
+        second.delete("name3");
+        assert_eq!(second.count("value"), 1);
+        second.delete("name2");
+        assert_eq!(second.count("value"), 0);
+        assert_eq!(first.count("value"), 1);
+    }
+
+    #[test]
+    fn test_commit_rollback() {
+        let mut db = Database::new();
+        assert!(db.rollback().is_err());
+        assert!(db.commit().is_err());
+        db.begin();
+        db.set("a", "foo");
+        assert!(db.commit().is_ok());
+        db.begin();
+        assert!(db.rollback().is_ok());
+    }
+
+    #[test]
+    fn test_example_1() {
+        let mut db = Database::new();
+        assert_eq!(db.get("a"), None);
+        db.set("a", "foo");
+        db.set("b", "foo");
+        assert_eq!(db.count("foo"), 2);
+        assert_eq!(db.count("bar"), 0);
+        db.delete("a");
+        assert_eq!(db.count("foo"), 1);
+        db.set("b", "baz");
+        assert_eq!(db.count("foo"), 0);
+        assert_eq!(db.get("b"), Some("baz".into()));
+        assert_eq!(db.get("B"), None);
+    }
+
+    #[test]
+    fn test_example_2() {
+        let mut db = Database::new();
+        db.set("a", "foo");
+        db.set("a", "foo");
+        assert_eq!(db.count("foo"), 1);
+        assert_eq!(db.get("a"), Some("foo".into()));
+        db.delete("a");
+        assert_eq!(db.get("a"), None);
+        assert_eq!(db.count("foo"), 0);
+    }
+
+    #[test]
+    fn test_example_3() {
+        let mut db = Database::new();
+        db.begin();
+        db.set("a", "foo");
+        assert_eq!(db.get("a"), Some("foo".into()));
+        db.begin();
+        db.set("a", "bar");
+        assert_eq!(db.get("a"), Some("bar".into()));
+        db.set("a", "baz");
+        db.rollback().unwrap();
+        assert_eq!(db.get("a"), Some("foo".into()));
+        db.rollback().unwrap();
+        assert_eq!(db.get("a"), None);
+    }
+
+    #[test]
+    fn test_example_4() {
+        let mut db = Database::new();
+        db.set("a", "foo");
+        db.set("b", "baz");
+        db.begin();
+        assert_eq!(db.get("a"), Some("foo".into()));
+        db.set("a", "bar");
+        assert_eq!(db.count("bar"), 1);
+        db.begin();
+        assert_eq!(db.count("bar"), 1);
+        db.delete("a");
+        assert_eq!(db.get("a"), None);
+        assert_eq!(db.count("bar"), 0);
+        db.rollback().unwrap();
+        assert_eq!(db.get("a"), Some("bar".into()));
+        assert_eq!(db.count("bar"), 1);
+        db.commit().unwrap();
+        assert_eq!(db.get("a"), Some("bar".into()));
+        assert_eq!(db.get("b"), Some("baz".into()));
+    }
+
+    #[test]
+    fn test_shadow_delete_count() {
+        let mut db = Database::new();
+        db.set("a", "foo");
+        db.set("b", "foo");
+        db.begin();
+        assert_eq!(db.get("a"), Some("foo".into()));
+        assert_eq!(db.get("b"), Some("foo".into()));
+        assert_eq!(db.count("foo"), 2);
+        db.set("a", "bar");
+        assert_eq!(db.count("bar"), 1);
+        assert_eq!(db.count("foo"), 1);
+        db.delete("a");
+        assert_eq!(db.count("bar"), 0);
+        assert_eq!(db.count("foo"), 1);
+        db.delete("a");
+        assert_eq!(db.count("bar"), 0);
+        assert_eq!(db.count("foo"), 1);
+        db.delete("b");
+        assert_eq!(db.count("bar"), 0);
+        assert_eq!(db.count("foo"), 0);
+    }
+
+    #[test]
+    fn test_get_ref() {
+        let mut db = Database::new();
+        db.set("a", "foo");
+        db.begin();
+        db.set("b", "bar");
+        db.delete("a");
+        assert_eq!(db.get_ref("a"), None);
+        assert_eq!(db.get_ref("b").map(String::as_str), Some("bar"));
+        db.rollback().unwrap();
+        assert_eq!(db.get_ref("a").map(String::as_str), Some("foo"));
+        assert_eq!(db.get_ref("b"), None);
+    }
+
+    #[test]
+    fn test_commit_nested() {
+        let mut db = Database::new();
+        db.set("a", "foo");
+        db.set("b", "foo");
+        db.set("c", "bar");
+        db.begin();
+        db.set("a", "bar");
+        db.delete("c");
+        db.begin();
+        db.set("a", "baz");
+        db.set("c", "foo");
+        db.begin();
+        db.delete("b");
+        db.set("d", "qux");
+        assert!(db.commit().is_ok());
+        assert!(db.rollback().is_err());
+        assert_eq!(db.get("a"), Some("baz".into()));
+        assert_eq!(db.get("b"), None);
+        assert_eq!(db.get("c"), Some("foo".into()));
+        assert_eq!(db.get("d"), Some("qux".into()));
+        assert_eq!(db.count("foo"), 1);
+        assert_eq!(db.count("bar"), 0);
+        assert_eq!(db.count("baz"), 1);
+    }
+
+    #[test]
+    fn test_counts_released() {
+        let mut db = Database::new();
+        for i in 0..100 {
+            db.set(format!("key{}", i), format!("value{}", i));
+        }
+        for i in 0..100 {
+            db.delete(&format!("key{}", i));
+        }
+        db.set("a", "foo");
+        assert_eq!(db.transaction.store.counts.clone().into_iter().count(), 1);
+        assert_eq!(db.get("a"), Some("foo".into()));
+        assert_eq!(db.count("foo"), 1);
+    }
+
+    #[test]
+    fn test_distinct_value_count() {
+        let mut db = Database::new();
+        assert_eq!(db.distinct_value_count(), 0);
+        db.set("a", "10");
+        db.set("b", "10");
+        db.set("c", "20");
+        assert_eq!(db.distinct_value_count(), 2);
+        db.begin();
+        db.set("c", "10");
+        db.set("d", "30");
+        db.delete("d");
+        assert_eq!(db.distinct_value_count(), 1);
+        db.rollback().unwrap();
+        assert_eq!(db.distinct_value_count(), 2);
+        // heavy churn leaves no zero counts behind
+        for i in 0..1000 {
+            db.set("x", format!("{}", i));
+        }
+        db.delete("x");
+        assert_eq!(db.distinct_value_count(), 2);
+        assert_eq!(db.transaction.store.counts.clone().into_iter().count(), 2);
+    }
+
+    #[test]
+    fn test_value_counts() {
+        let mut db = Database::new();
+        db.set("a", "10");
+        db.set("b", "20");
+        db.set("c", "20");
+        db.set("d", "30");
+        db.begin();
+        db.delete("d");
+        db.set("e", "20");
+        let counts: Vec<(&str, u32)> = db
+            .value_counts()
+            .into_iter()
+            .map(|(v, c)| (v.as_str(), c))
+            .collect();
+        assert_eq!(counts, vec![("20", 3), ("10", 1)]);
+        db.rollback().unwrap();
+        assert_eq!(db.value_counts().len(), 3);
+    }
+
+    #[test]
+    fn test_freeze_snapshot() {
+        let mut db = Database::new();
+        db.set("a", "10");
+        db.set("b", "10");
+        db.begin();
+        db.set("c", "10");
+        let snapshot = db.freeze_snapshot();
+        // pending changes are not part of the committed state
+        assert_eq!(snapshot.get("c"), None);
+        db.commit().unwrap();
+        db.delete("a");
+        let exporter = std::thread::spawn(move || {
+            let pairs: Vec<(String, String)> = snapshot
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+            (pairs, snapshot.count("10"), snapshot.len())
+        });
+        db.set("b", "20");
+        let (pairs, count, len) = exporter.join().unwrap();
+        assert_eq!(
+            pairs,
+            vec![("a".into(), "10".into()), ("b".into(), "10".into())]
+        );
+        assert_eq!(count, 2);
+        assert_eq!(len, 2);
+        assert_eq!(db.get("a"), None);
+        assert_eq!(db.count("10"), 1);
+    }
+
+    #[test]
+    fn test_open_log() {
+        let path = std::env::temp_dir().join(format!("simpledb-wal-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut db: Database = Database::open(&path).unwrap();
+            db.set("a", "10");
+            db.set("b", "10");
+            db.delete("b");
+            db.delete("never");
+            db.begin();
+            db.set("c", "20");
+            db.begin();
+            db.set("a", "30");
+            db.rollback().unwrap();
+            db.commit().unwrap();
+            // uncommitted changes are lost
+            db.begin();
+            db.set("d", "40");
+            db.import_parallel(vec![("e".to_owned(), "50".to_owned())]);
+            db.rollback().unwrap();
+            db.import_parallel(vec![("f".to_owned(), "60".to_owned())]);
+            assert!(db.take_log_error().is_none());
+        }
+        let mut db: Database = Database::open(&path).unwrap();
+        let pairs: Vec<(&str, &str)> = db.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(pairs, vec![("a", "10"), ("c", "20"), ("f", "60")]);
+        assert_eq!(db.count("10"), 1);
+        db.delete("a");
+        drop(db);
+        let db: Database<String, CompactString> = Database::open(&path).unwrap();
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.get_ref("a"), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_file() {
+        let path = std::env::temp_dir().join(format!("simpledb-dbsnap-{}", std::process::id()));
+        let mut db: Database<String, CompactString> = Database::default();
+        db.set("a", "10");
+        db.set("b", "10");
+        db.set("c", "20");
+        db.hset("h", [("f", "30")]).unwrap();
+        db.begin();
+        db.set("d", "10");
+        db.save_snapshot(&path).unwrap();
+        let loaded: Database<String, CompactString> = Database::load_snapshot(&path).unwrap();
+        assert_eq!(loaded.len(), 4);
+        assert_eq!(loaded.hget("h", "f").unwrap(), Some("30".into()));
+        assert_eq!(loaded.get_ref("d"), None);
+        assert_eq!(loaded.count("10"), 2);
+        assert_eq!(loaded.count("20"), 1);
+        assert_eq!(loaded.distinct_value_count(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_recover() {
+        let path = std::env::temp_dir().join(format!("simpledb-recover-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut db: Database = Database::open(&path).unwrap();
+            db.set("a", "10");
+            db.begin();
+            db.set("b", "20");
+            db.set("c", "30");
+            db.commit().unwrap();
+        }
+        let whole = std::fs::metadata(&path).unwrap().len();
+        // a record torn by a crash part way through the records of its batch
+        {
+            let mut db: Database = Database::open(&path).unwrap();
+            db.set_fault_hook(|point| match point {
+                FaultPoint::Write { len, .. } if len > 8 => Some(Fault::Torn(2)),
+                _ => None,
+            });
+            db.set("d", "40");
+            assert!(db.take_log_error().is_some());
+        }
+        let (db, report): (Database, _) = Database::recover(&path).unwrap();
+        assert_eq!(report.replayed, 3);
+        assert_eq!(report.discarded, 1 + 4 + 2);
+        assert_eq!(db.len(), 3);
+        drop(db);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), whole);
+        // a record whose checksum does not match is discarded as well
+        {
+            let mut db: Database = Database::open(&path).unwrap();
+            db.set_fault_hook(|point| match point {
+                FaultPoint::Write { len, .. } if len > 8 => Some(Fault::Flip(len - 1)),
+                _ => None,
+            });
+            db.begin();
+            db.set("d", "40");
+            db.set("e", "50");
+            db.commit().unwrap();
+            assert!(db.take_log_error().is_none());
+        }
+        let (db, report): (Database, _) = Database::recover(&path).unwrap();
+        assert_eq!(report.replayed, 3);
+        assert!(report.discarded > 0);
+        assert_eq!(db.get("a"), Some("10".into()));
+        assert_eq!(db.get("d"), None);
+        drop(db);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), whole);
+        let (_, report): (Database, _) = Database::recover(&path).unwrap();
+        assert_eq!(report.discarded, 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_faults() {
+        let path = std::env::temp_dir().join(format!("simpledb-faults-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut db: Database = Database::open(&path).unwrap();
+            // writes cut short are written again until they are whole
+            db.set_fault_hook(|point| match point {
+                FaultPoint::Write { .. } => Some(Fault::Short(1)),
+                FaultPoint::Sync => None,
+            });
+            db.set("a", "10");
+            assert!(db.take_log_error().is_none());
+            // a failed sync stops the log, though what was flushed remains
+            db.set_fault_hook(|point| (point == FaultPoint::Sync).then_some(Fault::Fail));
+            db.set_durability(Durability::Sync);
+            db.set("b", "20");
+            let err = db.take_log_error().unwrap();
+            assert_eq!(err.to_string(), "injected sync failure");
+            db.set("c", "30");
+            assert_eq!(db.len(), 3);
+        }
+        {
+            let (mut db, report): (Database, _) = Database::recover(&path).unwrap();
+            assert_eq!(report.replayed, 2);
+            assert_eq!(report.discarded, 0);
+            assert_eq!(db.get("c"), None);
+            // as does a failed write, leaving what was written before it
+            let mut writes = 0;
+            db.set_fault_hook(move |point| {
+                writes += 1;
+                (point != FaultPoint::Sync && writes > 1).then_some(Fault::Fail)
+            });
+            db.set("c", "30");
+            assert!(db.take_log_error().is_some());
+        }
+        {
+            let (mut db, report): (Database, _) = Database::recover(&path).unwrap();
+            assert_eq!(report.replayed, 2);
+            assert_eq!(report.discarded, 1);
+            assert_eq!(db.get("b"), Some("20".into()));
+            // a write cut short to nothing fails rather than being retried
+            db.set_fault_hook(|point| (point != FaultPoint::Sync).then_some(Fault::Short(0)));
+            db.set("d", "40");
+            let err = db.take_log_error().unwrap();
+            assert_eq!(err.to_string(), "injected write failure");
+        }
+        let (db, report): (Database, _) = Database::recover(&path).unwrap();
+        assert_eq!(report.replayed, 2);
+        assert_eq!(report.discarded, 0);
+        assert_eq!(db.get("d"), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_backups() {
+        let dir = std::env::temp_dir().join(format!("simpledb-backup-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let mut db: Database = Database::default();
+        db.set("a", "10");
+        db.set("b", "20");
+        db.hset("h", [("f", "1"), ("g", "2")]).unwrap();
+        let full = db.backup(&dir).unwrap();
+        db.set("b", "21");
+        db.delete("a");
+        db.hdel("h", &["g"]).unwrap();
+        db.xadd("s", [("kind", "login")]).unwrap();
+        db.rpush("l", ["1", "2"]).unwrap();
+        db.zadd("z", [(1.5, "m")]).unwrap();
+        db.json_set("j", "$", serde_json::json!({"a": 1})).unwrap();
+        db.expire("l", Duration::from_secs(3600)).unwrap();
+        // uncommitted changes are not backed up
+        db.begin();
+        db.set("c", "30");
+        let first = db.backup_incremental(&full, &dir).unwrap();
+        db.rollback().unwrap();
+        // an increment holds only the keys that changed
+        let size = std::fs::metadata(dir.join("1.log")).unwrap().len();
+        db.set("h", "value");
+        let second = db.backup_incremental(&first, &dir).unwrap();
+        assert!(std::fs::metadata(dir.join("2.log")).unwrap().len() < size);
+        let restored: Database = Database::restore_backup(&full).unwrap();
+        assert_eq!(restored.get("a"), Some("10".into()));
+        assert_eq!(restored.hget("h", "g").unwrap(), Some("2".into()));
+        let restored: Database = Database::restore_backup(&first).unwrap();
+        let pairs: Vec<(&str, &str)> = restored
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(pairs, vec![("b", "21")]);
+        assert_eq!(restored.hget("h", "f").unwrap(), Some("1".into()));
+        assert_eq!(restored.hget("h", "g").unwrap(), None);
+        assert_eq!(restored.xlen("s").unwrap(), 1);
+        assert_eq!(restored.lrange("l", 0, -1).unwrap(), vec!["1", "2"]);
+        assert!(restored.ttl("l").unwrap().is_some());
+        assert_eq!(restored.zscore("z", "m").unwrap(), Some(1.5));
+        let document = restored.json_get("j", "$.a").unwrap();
+        assert_eq!(document, Some(serde_json::json!(1)));
+        let restored: Database = Database::restore_backup(&second).unwrap();
+        assert_eq!(restored.get("h"), Some("value".into()));
+        assert!(matches!(restored.hget("h", "f"), Err(Error::WrongType)));
+        // a damaged increment fails the restore
+        let mut bytes = std::fs::read(dir.join("2.log")).unwrap();
+        bytes.pop();
+        std::fs::write(dir.join("2.log"), &bytes).unwrap();
+        assert!(Database::<String, String>::restore_backup(&second).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_encrypted() {
+        let dir = std::env::temp_dir().join(format!("simpledb-encrypted-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let secret = Secret::passphrase("correct horse");
+        let log = dir.join("db.log");
+        let snapshot = dir.join("db.snapshot");
+        {
+            let mut db: Database = Database::open_encrypted(&log, secret.clone()).unwrap();
+            db.set("a", "plaintext");
+            db.hset("h", [("f", "plaintext")]).unwrap();
+            db.rewrite_aof().unwrap();
+            db.set("b", "20");
+            db.save_snapshot(&snapshot).unwrap();
+            let full = db.backup(&dir).unwrap();
+            db.set("c", "plaintext");
+            db.backup_incremental(&full, &dir).unwrap();
+            db.restore_to(RestorePoint::Lsn(1)).unwrap();
+            assert!(!db.exists("c"));
+            assert!(db.take_log_error().is_none());
+        }
+        // nothing in the directory holds the data in plaintext
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let bytes = std::fs::read(entry.unwrap().path()).unwrap();
+            assert!(bytes.starts_with(b"SDBE"));
+            assert!(!bytes.windows(9).any(|w| w == b"plaintext"));
+        }
+        let db: Database = Database::open_encrypted(&log, secret.clone()).unwrap();
+        assert_eq!(db.get("a"), Some("plaintext".into()));
+        assert_eq!(db.get("b"), None);
+        assert!(Database::<String, String>::open(&log).is_err());
+        let wrong = Secret::passphrase("battery staple");
+        assert!(Database::<String, String>::open_encrypted(&log, wrong.clone()).is_err());
+        let db: Database = Database::load_encrypted_snapshot(&snapshot, secret.clone()).unwrap();
+        assert_eq!(db.get("b"), Some("20".into()));
+        assert!(Database::<String, String>::load_snapshot(&snapshot).is_err());
+        let manifest = dir.join("1.manifest");
+        let db: Database = Database::restore_encrypted_backup(&manifest, secret.clone()).unwrap();
+        assert_eq!(db.get("c"), Some("plaintext".into()));
+        assert_eq!(db.hget("h", "f").unwrap(), Some("plaintext".into()));
+        assert!(Database::<String, String>::restore_backup(&manifest).is_err());
+        // a database without a log may encrypt what it saves
+        let mut db = Database::new();
+        db.set("a", "plaintext");
+        db.set_encryption(Some(secret.clone()));
+        db.freeze_snapshot().save(&snapshot).unwrap();
+        assert!(Database::<String, String>::load_snapshot(&snapshot).is_err());
+        db.set_encryption(None);
+        db.save_snapshot(&snapshot).unwrap();
+        assert!(Database::<String, String>::load_snapshot(&snapshot).is_ok());
+        // a log that is not encrypted is refused rather than mixed with one
+        let plain = dir.join("plain.log");
+        Database::<String, String>::open(&plain)
+            .unwrap()
+            .set("a", "10");
+        assert!(Database::<String, String>::open_encrypted(&plain, secret).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_restore_to() {
+        let path = std::env::temp_dir().join(format!("simpledb-pitr-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut db: Database = Database::open(&path).unwrap();
+        assert_eq!(db.lsn(), Some(0));
+        db.set("a", "10");
+        db.hset("h", [("f", "1")]).unwrap();
+        db.xadd("s", [("kind", "login")]).unwrap();
+        let lsn = db.lsn().unwrap();
+        assert_eq!(lsn, 3);
+        std::thread::sleep(Duration::from_millis(5));
+        let before = std::time::SystemTime::now();
+        std::thread::sleep(Duration::from_millis(5));
+        db.begin();
+        db.set("a", "20");
+        db.delete("h");
+        db.set("b", "30");
+        db.commit().unwrap();
+        db.rpush("l", ["x"]).unwrap();
+        db.restore_to(RestorePoint::Lsn(lsn)).unwrap();
+        assert_eq!(db.get("a"), Some("10".into()));
+        assert_eq!(db.get("b"), None);
+        assert_eq!(db.hget("h", "f").unwrap(), Some("1".into()));
+        assert_eq!(db.xlen("s").unwrap(), 1);
+        assert_eq!(db.llen("l").unwrap(), 0);
+        // the restoration is logged like any other change, and can be undone
+        assert_eq!(db.lsn(), Some(6));
+        db.restore_to(RestorePoint::Lsn(5)).unwrap();
+        assert_eq!(db.get("b"), Some("30".into()));
+        assert_eq!(db.llen("l").unwrap(), 1);
+        db.restore_to(RestorePoint::Time(before)).unwrap();
+        assert_eq!(db.get("b"), None);
+        drop(db);
+        let mut db: Database = Database::open(&path).unwrap();
+        assert_eq!(db.get("a"), Some("10".into()));
+        assert_eq!(db.lsn(), Some(8));
+        db.restore_to(RestorePoint::Lsn(0)).unwrap();
+        assert!(db.is_empty());
+        std::fs::remove_file(&path).unwrap();
+        assert!(Database::new().restore_to(RestorePoint::Lsn(0)).is_err());
+    }
+
+    #[test]
+    fn test_restore_after_rewrite() {
+        let path = std::env::temp_dir().join(format!("simpledb-pitr-aof-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut db: Database = Database::open(&path).unwrap();
+        db.set("a", "10");
+        db.set("a", "20");
+        db.rpush("l", ["x"]).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        let before = std::time::SystemTime::now();
+        std::thread::sleep(Duration::from_millis(5));
+        // the rewritten log is a single change
+        db.rewrite_aof().unwrap();
+        assert_eq!(db.lsn(), Some(1));
+        std::thread::sleep(Duration::from_millis(5));
+        let rewritten = std::time::SystemTime::now();
+        std::thread::sleep(Duration::from_millis(5));
+        db.set("b", "30");
+        db.restore_to(RestorePoint::Time(rewritten)).unwrap();
+        assert_eq!(db.get("a"), Some("20".into()));
+        assert_eq!(db.get("b"), None);
+        assert_eq!(db.llen("l").unwrap(), 1);
+        db.restore_to(RestorePoint::Lsn(2)).unwrap();
+        assert_eq!(db.get("b"), Some("30".into()));
+        // the log no longer holds what came before the rewrite
+        assert!(db.restore_to(RestorePoint::Lsn(0)).is_err());
+        assert!(db.restore_to(RestorePoint::Time(before)).is_err());
+        assert_eq!(db.get("b"), Some("30".into()));
+        drop(db);
+        let db: Database = Database::open(&path).unwrap();
+        assert_eq!(db.get("b"), Some("30".into()));
+        assert_eq!(db.lsn(), Some(4));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rewrite_aof() {
+        let path = std::env::temp_dir().join(format!("simpledb-rewrite-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut db: Database = Database::open(&path).unwrap();
+            for n in 0..100 {
+                db.set("a", n.to_string());
+            }
+            db.set("b", "10");
+            db.delete("b");
+            db.hset("h", [("f", "1"), ("g", "2")]).unwrap();
+            db.hdel("h", &["g"]).unwrap();
+            db.xadd("s", [("kind", "login")]).unwrap();
+            let before = std::fs::metadata(&path).unwrap().len();
+            let mut rewrite = db.begin_rewrite_aof().unwrap();
+            assert!(db.begin_rewrite_aof().is_err());
+            // changes made while the new log is written are not lost
+            db.set("c", "30");
+            db.begin();
+            db.set("d", "40");
+            rewrite.write();
+            db.finish_rewrite_aof(rewrite).unwrap();
+            db.commit().unwrap();
+            db.rewrite_aof().unwrap();
+            assert!(std::fs::metadata(&path).unwrap().len() < before);
+            db.set("e", "50");
+        }
+        let db: Database = Database::open(&path).unwrap();
+        let pairs: Vec<(&str, &str)> = db.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(
+            pairs,
+            vec![("a", "99"), ("c", "30"), ("d", "40"), ("e", "50")]
+        );
+        assert_eq!(db.hget("h", "f").unwrap(), Some("1".into()));
+        assert_eq!(db.hget("h", "g").unwrap(), None);
+        assert_eq!(db.xlen("s").unwrap(), 1);
+        std::fs::remove_file(&path).unwrap();
+        assert!(Database::new().rewrite_aof().is_err());
+    }
+
+    #[test]
+    fn test_bytes() {
+        let path = std::env::temp_dir().join(format!("simpledb-bytes-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let image: Vec<u8> = vec![0x89, b'P', b'N', b'G', 0, 0xff];
+        {
+            let mut db: Database<String, Vec<u8>> = Database::open(&path).unwrap();
+            db.set("image", image.clone());
+            db.set("text", "hello");
+            db.hset("h", [("raw", vec![0xfe])]).unwrap();
+            assert_eq!(db.count(&image[..]), 1);
+        }
+        let db: Database<String, Vec<u8>> = Database::open(&path).unwrap();
+        assert_eq!(db.get("image"), Some(image));
+        assert_eq!(db.get_str("image"), None);
+        assert_eq!(db.get_str("text"), Some("hello"));
+        assert_eq!(db.hget("h", "raw").unwrap(), Some(vec![0xfe]));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_json() {
+        let mut db = Database::new();
+        let mut buffer: Vec<u8> = Vec::new();
+        db.export_json(&mut buffer).unwrap();
+        assert_eq!(String::from_utf8(buffer).unwrap(), "{}\n");
+        db.set("a", "10");
+        db.set("quote\"d", "line\nbreak");
+        let mut buffer: Vec<u8> = Vec::new();
+        db.export_json(&mut buffer).unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        assert_eq!(
+            text,
+            "{\n  \"a\": \"10\",\n  \"quote\\\"d\": \"line\\nbreak\"\n}\n"
+        );
+        let mut hashed = Database::new();
+        hashed.hset("h", [("b", "2"), ("a", "1")]).unwrap();
+        let mut buffer: Vec<u8> = Vec::new();
+        hashed.export_json(&mut buffer).unwrap();
+        let exported = String::from_utf8(buffer).unwrap();
+        assert_eq!(exported, "{\n  \"h\": {\"a\":\"1\",\"b\":\"2\"}\n}\n");
+        let mut copy = Database::new();
+        copy.set("h", "0");
+        copy.import_json(exported.as_bytes(), ImportMode::Merge)
+            .unwrap();
+        assert_eq!(copy.hlen("h").unwrap(), 2);
+        assert!(copy
+            .import_json(&b"{\"h\": {\"a\": 1}}"[..], ImportMode::Merge)
+            .is_err());
+
+        let mut other = Database::new();
+        other.set("a", "99");
+        other.set("z", "1");
+        let count = other
+            .import_json(text.as_bytes(), ImportMode::Merge)
+            .unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(other.get("a"), Some("10".into()));
+        assert_eq!(other.get("z"), Some("1".into()));
+        assert_eq!(other.get("quote\"d"), Some("line\nbreak".into()));
+
+        other.begin();
+        other
+            .import_json(&b"{\"b\": \"20\"}"[..], ImportMode::Replace)
+            .unwrap();
+        assert_eq!(other.len(), 1);
+        assert_eq!(other.count("20"), 1);
+        other.rollback().unwrap();
+        assert_eq!(other.len(), 3);
+        // invalid input leaves the database unchanged
+        assert!(other
+            .import_json(&b"{\"n\": 5}"[..], ImportMode::Replace)
+            .is_err());
+        assert!(other.import_json(&b"[1,"[..], ImportMode::Merge).is_err());
+        assert_eq!(other.len(), 3);
+    }
+
+    #[test]
+    fn test_len_and_iter() {
+        let mut db = Database::new();
+        assert!(db.is_empty());
+        db.set("b", "10");
+        db.set("a", "10");
+        db.begin();
+        db.set("c", "20");
+        db.delete("b");
+        assert_eq!(db.len(), 2);
+        let pairs: Vec<(&str, &str)> = db.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(pairs, vec![("a", "10"), ("c", "20")]);
+        db.rollback().unwrap();
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.iter().next().map(|(k, _)| k.as_str()), Some("a"));
+        assert!(!db.is_empty());
+    }
+
+    #[test]
+    fn test_keys_by_len() {
+        for indexed in [false, true] {
+            let mut db = Database::new();
+            if indexed {
+                db.enable_length_index();
+            }
+            db.set("a", "xxx");
+            db.set("b", "x");
+            db.set("c", "xxxxxxxx");
+            db.begin();
+            if indexed {
+                // enabling again keeps using the same index
+                db.enable_length_index();
+            }
+            db.set("d", "xx");
+            db.set("a", "xxxxxxxxxx");
+            db.delete("b");
+            let keys: Vec<&String> = db.keys_by_len(2..=9);
+            assert_eq!(keys, vec!["d", "c"]);
+            db.rollback().unwrap();
+            let keys: Vec<&String> = db.keys_by_len(0..=5);
+            assert_eq!(keys, vec!["b", "a"]);
+            assert!(db.keys_by_len(100..=200).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_import_parallel() {
+        let mut pairs: Vec<(String, String)> = (0..10_000)
+            .map(|i| (format!("key{}", i), format!("value{}", i % 10)))
+            .collect();
+        // later occurrences of a key replace earlier ones
+        pairs.push(("key0".into(), "other".into()));
+        let mut db = Database::new();
+        db.import_parallel(pairs.clone());
+        assert_eq!(db.get("key0"), Some("other".into()));
+        assert_eq!(db.get("key9999"), Some("value9".into()));
+        assert_eq!(db.count("value0"), 999);
+        assert_eq!(db.count("value1"), 1000);
+        assert_eq!(db.count("other"), 1);
+
+        // importing within a transaction can be rolled back
+        let mut db = Database::new();
+        db.set("key1", "value1");
+        db.begin();
+        db.import_parallel(pairs);
+        assert_eq!(db.count("value1"), 1000);
+        db.rollback().unwrap();
+        assert_eq!(db.count("value1"), 1);
+        assert_eq!(db.get("key2"), None);
+
+        // a key holding another kind of data is replaced, as by set()
+        let mut db = Database::new();
+        db.hset("key0", [("field", "value")]).unwrap();
+        db.import_parallel(vec![("key0".to_owned(), "value".to_owned())]);
+        assert_eq!(db.get("key0"), Some("value".into()));
+        db.delete("key0");
+        assert_eq!(db.hlen("key0").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_deep_nesting() {
+        let mut db = Database::new();
+        for i in 0..1000 {
+            db.set(format!("key{}", i), "foo");
+        }
+        for i in 0..100 {
+            db.begin();
+            db.set(format!("key{}", i), "bar");
+        }
+        assert_eq!(db.count("foo"), 900);
+        assert_eq!(db.count("bar"), 100);
+        for _ in 0..50 {
+            assert!(db.rollback().is_ok());
+        }
+        assert_eq!(db.count("foo"), 950);
+        assert_eq!(db.get("key49"), Some("bar".into()));
+        assert_eq!(db.get("key50"), Some("foo".into()));
+        assert!(db.commit().is_ok());
+        assert!(db.rollback().is_err());
+        assert_eq!(db.count("bar"), 50);
+    }
+
+    #[test]
+    fn test_commit_one() {
+        let mut db = Database::new();
+        assert!(matches!(db.commit_one(), Err(Error::NoTransaction)));
+        db.begin();
+        db.set("a", "10");
+        db.begin();
+        db.set("b", "20");
+        db.commit_one().unwrap();
+        assert_eq!(db.transaction_depth(), 1);
+        assert_eq!(db.get("b"), Some("20".into()));
+        // the enclosing transaction may still discard the inner one's work
+        db.rollback().unwrap();
+        assert_eq!(db.get("a"), None);
+        assert_eq!(db.get("b"), None);
+        db.begin();
+        db.begin();
+        db.set("c", "30");
+        db.commit_one().unwrap();
+        db.commit_one().unwrap();
+        assert_eq!(db.transaction_depth(), 0);
+        assert_eq!(db.get("c"), Some("30".into()));
+        assert_eq!(db.count("30"), 1);
+    }
+
+    #[test]
+    fn test_with_transaction() {
+        let mut db = Database::new();
+        let value: Result<u32> = db.with_transaction(|tx| {
+            tx.set("a", "10");
+            Ok(1)
+        });
+        assert_eq!(value.unwrap(), 1);
+        assert_eq!(db.transaction_depth(), 0);
+        assert_eq!(db.get("a"), Some("10".into()));
+        let result: Result<()> = db.with_transaction(|tx| {
+            tx.set("a", "20");
+            tx.begin();
+            tx.set("b", "20");
+            Err(Error::KeyNotFound)
+        });
+        assert!(matches!(result, Err(Error::KeyNotFound)));
+        assert_eq!(db.transaction_depth(), 0);
+        assert_eq!(db.get("a"), Some("10".into()));
+        assert_eq!(db.get("b"), None);
+        // nested within an open transaction, only the inner one is committed
+        db.begin();
+        db.with_transaction(|tx| -> Result<()> {
+            tx.set("c", "30");
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(db.transaction_depth(), 1);
+        db.rollback().unwrap();
+        assert_eq!(db.get("c"), None);
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _: Result<()> = db.with_transaction(|tx| {
+                tx.set("d", "40");
+                panic!("oops");
+            });
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(db.transaction_depth(), 0);
+        assert_eq!(db.get("d"), None);
+    }
+
+    #[test]
+    fn test_two_phase_commit() {
+        let mut db = Database::new();
+        assert!(matches!(db.prepare(), Err(Error::NoTransaction)));
+        db.begin();
+        db.set("a", "10");
+        let prepared = db.prepare().unwrap();
+        db.commit_prepared(prepared).unwrap();
+        assert_eq!(db.transaction_depth(), 0);
+        assert_eq!(db.get("a"), Some("10".into()));
+        db.begin();
+        db.set("a", "20");
+        let prepared = db.prepare().unwrap();
+        db.abort_prepared(prepared).unwrap();
+        assert_eq!(db.get("a"), Some("10".into()));
+        // changes after preparing are refused
+        db.begin();
+        let prepared = db.prepare().unwrap();
+        db.set("b", "20");
+        assert!(matches!(db.commit_prepared(prepared), Err(Error::Conflict)));
+        assert_eq!(db.transaction_depth(), 1);
+        let prepared = db.prepare().unwrap();
+        db.begin();
+        assert!(matches!(db.abort_prepared(prepared), Err(Error::Conflict)));
+        db.rollback().unwrap();
+        db.begin_with(Duration::ZERO);
+        assert!(matches!(db.prepare(), Err(Error::TransactionExpired)));
+    }
+
+    #[test]
+    fn test_transaction_guard() {
+        let mut db = Database::new();
+        {
+            let mut tx = db.begin_guarded();
+            tx.set("a", "10");
+            assert_eq!(tx.transaction_depth(), 1);
+        }
+        assert_eq!(db.transaction_depth(), 0);
+        assert_eq!(db.get("a"), None);
+        let mut tx = db.begin_guarded();
+        tx.set("a", "10");
+        // transactions left open within the guard are rolled back first
+        tx.begin();
+        tx.set("b", "20");
+        tx.commit().unwrap();
+        assert_eq!(db.transaction_depth(), 0);
+        assert_eq!(db.get("a"), Some("10".into()));
+        assert_eq!(db.get("b"), None);
+        db.begin();
+        let mut tx = db.begin_guarded();
+        tx.set("c", "30");
+        tx.rollback();
+        assert_eq!(db.transaction_depth(), 1);
+        let mut tx = db.begin_guarded();
+        tx.set("c", "30");
+        tx.commit().unwrap();
+        assert_eq!(db.transaction_depth(), 1);
+        assert_eq!(db.get("c"), Some("30".into()));
+        // ending the transaction behind the guard's back
+        let mut tx = db.begin_guarded();
+        Database::commit(&mut tx).unwrap();
+        assert!(matches!(tx.commit(), Err(Error::NoTransaction)));
+        assert_eq!(db.transaction_depth(), 0);
     }
 
     #[test]
-    fn test_shadow_delete_count() {
+    fn test_named_transactions() {
+        let mut db = Database::new();
+        db.set("a", "10");
+        db.begin();
+        db.set("a", "20");
+        db.begin_named("import");
+        db.set("b", "20");
+        db.begin();
+        db.set("c", "20");
+        db.savepoint("x").unwrap();
+        db.begin_named("inner");
+        db.set("d", "20");
+        assert!(matches!(
+            db.rollback_named("export"),
+            Err(Error::NoNamedTransaction(_))
+        ));
+        assert_eq!(db.transaction_depth(), 4);
+        db.rollback_named("import").unwrap();
+        assert_eq!(db.transaction_depth(), 1);
+        assert_eq!(db.get("a"), Some("20".into()));
+        assert_eq!(db.get("b"), None);
+        assert_eq!(db.count("20"), 1);
+        assert!(matches!(
+            db.rollback_named("import"),
+            Err(Error::NoNamedTransaction(_))
+        ));
+        // names do not survive a commit
+        db.begin_named("import");
+        db.commit().unwrap();
+        assert!(matches!(
+            db.rollback_named("import"),
+            Err(Error::NoNamedTransaction(_))
+        ));
+        assert_eq!(db.get("a"), Some("20".into()));
+    }
+
+    #[test]
+    fn test_transaction_deadline() {
+        let mut db = Database::new();
+        assert!(db.check_deadline().is_ok());
+        db.begin_with(Duration::from_secs(60));
+        db.set("a", "10");
+        assert!(db.check_deadline().is_ok());
+        db.commit().unwrap();
+        assert_eq!(db.get("a"), Some("10".into()));
+        db.begin();
+        db.set("a", "20");
+        db.begin_with(Duration::ZERO);
+        db.set("b", "20");
+        db.begin();
+        db.set("c", "20");
+        // the expired transaction goes, along with those within it
+        assert!(matches!(db.commit(), Err(Error::TransactionExpired)));
+        assert_eq!(db.transaction_depth(), 1);
+        assert_eq!(db.get("a"), Some("20".into()));
+        assert_eq!(db.get("b"), None);
+        assert_eq!(db.get("c"), None);
+        assert!(db.check_deadline().is_ok());
+        db.commit().unwrap();
+        assert_eq!(db.get("a"), Some("20".into()));
+        db.begin_with(Duration::MAX);
+        assert!(db.check_deadline().is_ok());
+        db.rollback().unwrap();
+    }
+
+    #[test]
+    fn test_transaction_deadline_unchecked() {
+        // reads and writes do not check the deadline, they go to the expired
+        // transaction and are lost with it at the next check
+        let mut db = Database::new();
+        db.set("a", "10");
+        db.begin_with(Duration::ZERO);
+        db.set("a", "20");
+        db.set("b", "20");
+        db.rpush("l", ["x"]).unwrap();
+        db.incr("n").unwrap();
+        assert_eq!(db.get("a"), Some("20".into()));
+        assert_eq!(db.llen("l").unwrap(), 1);
+        assert_eq!(db.transaction_depth(), 1);
+        assert!(matches!(
+            db.check_deadline(),
+            Err(Error::TransactionExpired)
+        ));
+        assert_eq!(db.transaction_depth(), 0);
+        assert_eq!(db.get("a"), Some("10".into()));
+        assert_eq!(db.get("b"), None);
+        assert_eq!(db.llen("l").unwrap(), 0);
+        assert_eq!(db.get("n"), None);
+        // writes after the rollback are committed as usual
+        db.set("b", "30");
+        assert_eq!(db.get("b"), Some("30".into()));
+        // savepoints check the deadline as commits do
+        db.begin_with(Duration::ZERO);
+        db.set("c", "1");
+        assert!(matches!(db.savepoint("s"), Err(Error::TransactionExpired)));
+        assert_eq!(db.get("c"), None);
+        db.begin_with(Duration::ZERO);
+        assert!(matches!(db.commit_one(), Err(Error::TransactionExpired)));
+        assert_eq!(db.transaction_depth(), 0);
+    }
+
+    #[test]
+    fn test_changed_since() {
+        let mut db = Database::new();
+        db.set("a", "10");
+        db.rpush("l", ["x"]).unwrap();
+        let snapshot = db.freeze_snapshot();
+        assert!(!db.changed_since(&snapshot, "a"));
+        db.rpush("l", ["y"]).unwrap();
+        assert!(db.changed_since(&snapshot, "l"));
+        db.zadd("z", [(1.0, "m")]).unwrap();
+        assert!(db.changed_since(&snapshot, "z"));
+        assert!(!db.changed_since(&snapshot, "none"));
+        // only the contents matter, not whether they have been replaced
+        db.set("a", "20");
+        assert!(db.changed_since(&snapshot, "a"));
+        db.set("a", "10");
+        assert!(!db.changed_since(&snapshot, "a"));
+    }
+
+    #[test]
+    fn test_diff() {
+        let dir = std::env::temp_dir();
+        let before = dir.join(format!("simpledb-diff-before-{}", std::process::id()));
+        let after = dir.join(format!("simpledb-diff-after-{}", std::process::id()));
+        let mut db = Database::new();
+        db.set("same", "1");
+        db.set("value", "1");
+        db.set("kind", "1");
+        db.set("ttl", "1");
+        db.set("gone", "1");
+        db.rpush("list", ["a"]).unwrap();
+        db.save_snapshot(&before).unwrap();
+        db.set("value", "2");
+        db.delete("kind");
+        db.rpush("kind", ["a"]).unwrap();
+        db.expire("ttl", Duration::from_secs(3600)).unwrap();
+        db.delete("gone");
+        db.rpush("list", ["b"]).unwrap();
+        db.hset("new", [("f", "1")]).unwrap();
+        // only the committed state is compared
+        db.begin();
+        db.set("open", "1");
+        db.save_snapshot(&after).unwrap();
+        let older: Database = Database::load_snapshot(&before).unwrap();
+        let newer: Database = Database::load_snapshot(&after).unwrap();
+        let diff = older.diff(&newer);
+        assert_eq!(diff.added, vec!["new"]);
+        assert_eq!(diff.removed, vec!["gone"]);
+        assert_eq!(diff.changed, vec!["kind", "list", "ttl", "value"]);
+        let diff = newer.diff(&older);
+        assert_eq!(diff.added, vec!["gone"]);
+        assert_eq!(diff.removed, vec!["new"]);
+        assert!(older.diff(&older).changed.is_empty());
+        std::fs::remove_file(&before).unwrap();
+        std::fs::remove_file(&after).unwrap();
+    }
+
+    #[test]
+    fn test_changeset() {
+        let mut primary = Database::new();
+        let mut mirror = Database::new();
+        for db in [&mut primary, &mut mirror] {
+            db.set("a", "10");
+            db.set("b", "20");
+        }
+        primary.begin();
+        primary.set("a", "11");
+        primary.delete("b");
+        primary.set("c", "30");
+        primary.set("d", "40");
+        primary.delete("d");
+        let changeset = primary.changeset();
+        assert_eq!(changeset.changes.len(), 3);
+        assert_eq!(
+            changeset.changes[0],
+            Change {
+                name: "a".into(),
+                old: Some("10".into()),
+                new: Some("11".into()),
+            }
+        );
+        let mut buffer: Vec<u8> = Vec::new();
+        changeset.write_to(&mut buffer).unwrap();
+        let changeset = Changeset::read_from(buffer.as_slice()).unwrap();
+        mirror.apply_changeset(&changeset).unwrap();
+        assert_eq!(mirror.get("a"), Some("11".into()));
+        assert_eq!(mirror.get("b"), None);
+        assert_eq!(mirror.get("c"), Some("30".into()));
+        assert_eq!(mirror.count("30"), 1);
+        // applying it again finds the keys no longer as expected
+        assert!(matches!(
+            mirror.apply_changeset(&changeset),
+            Err(Error::Conflict)
+        ));
+        assert_eq!(mirror.get("c"), Some("30".into()));
+        primary.commit().unwrap();
+        assert!(primary.changeset().changes.is_empty());
+        // a guarded transaction has only its own changes
+        primary.begin();
+        primary.set("e", "50");
+        let mut guard = primary.begin_guarded();
+        guard.set("a", "12");
+        guard.begin();
+        guard.delete("c");
+        let changeset = guard.to_changeset();
+        assert_eq!(changeset.changes.len(), 2);
+        assert_eq!(changeset.changes[1].name, "c");
+        // committing the guard rolls back the transaction left open in it
+        guard.commit().unwrap();
+        assert_eq!(primary.changeset().changes.len(), 2);
+    }
+
+    #[test]
+    fn test_apply_changeset_log() {
+        let path = std::env::temp_dir().join(format!("simpledb-mirror-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut primary = Database::new();
+        primary.begin();
+        primary.set("a", "10");
+        primary.set("b", "20");
+        let changeset = primary.changeset();
+        // the changes are applied and logged as a single transaction
+        let mut mirror: Database = Database::open(&path).unwrap();
+        mirror.apply_changeset(&changeset).unwrap();
+        assert_eq!(mirror.lsn(), Some(1));
+        assert_eq!(mirror.transaction_depth(), 0);
+        // or within the one that is open
+        mirror.begin();
+        let changeset = Changeset {
+            changes: vec![Change {
+                name: "a".to_owned(),
+                old: Some("10".into()),
+                new: None,
+            }],
+        };
+        mirror.apply_changeset(&changeset).unwrap();
+        assert_eq!(mirror.transaction_depth(), 1);
+        mirror.rollback().unwrap();
+        drop(mirror);
+        let mirror: Database = Database::open(&path).unwrap();
+        assert_eq!(mirror.get("a"), Some("10".into()));
+        assert_eq!(mirror.get("b"), Some("20".into()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_pending() {
+        let mut db = Database::new();
+        db.set("a", "10");
+        assert!(db.pending().is_empty());
+        db.begin();
+        db.set("b", "20");
+        db.begin();
+        db.set("a", "11");
+        db.savepoint("x").unwrap();
+        db.delete("b");
+        // only the changes of the innermost transaction are pending
+        let pending = db.pending();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].old, Some("10".into()));
+        assert_eq!(pending[0].new, Some("11".into()));
+        assert_eq!(pending[1].name, "b");
+        assert_eq!(pending[1].old, Some("20".into()));
+        assert_eq!(pending[1].new, None);
+        db.rollback().unwrap();
+        assert_eq!(db.pending().len(), 1);
+        assert_eq!(db.pending()[0].old, None);
+    }
+
+    #[test]
+    fn test_commit_n() {
+        let mut db = Database::new();
+        db.begin();
+        db.set("a", "10");
+        db.begin();
+        db.set("b", "20");
+        db.begin();
+        db.set("c", "30");
+        assert!(matches!(db.commit_n(4), Err(Error::NoTransaction)));
+        assert_eq!(db.transaction_depth(), 3);
+        db.commit_n(2).unwrap();
+        assert_eq!(db.transaction_depth(), 1);
+        assert_eq!(db.get("c"), Some("30".into()));
+        db.rollback().unwrap();
+        assert_eq!(db.get("a"), None);
+        db.begin();
+        db.set("a", "10");
+        db.commit_n(1).unwrap();
+        assert_eq!(db.transaction_depth(), 0);
+        assert_eq!(db.get("a"), Some("10".into()));
+        assert!(db.commit_n(0).is_ok());
+        // commit and rollback report the number of transactions they ended
+        db.begin();
+        db.begin();
+        db.begin();
+        assert_eq!(db.rollback().unwrap(), 1);
+        assert_eq!(db.commit().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_savepoints() {
+        let mut db = Database::new();
+        assert!(matches!(db.savepoint("a"), Err(Error::NoTransaction)));
+        db.begin();
+        db.set("a", "10");
+        db.savepoint("one").unwrap();
+        db.set("b", "20");
+        db.savepoint("two").unwrap();
+        db.set("c", "30");
+        assert_eq!(db.transaction_depth(), 1);
+        db.rollback_to("one").unwrap();
+        assert_eq!(db.get("a"), Some("10".into()));
+        assert_eq!(db.get("b"), None);
+        assert_eq!(db.get("c"), None);
+        assert_eq!(db.count("30"), 0);
+        // the later savepoint is gone, the named one remains
+        assert!(matches!(db.rollback_to("two"), Err(Error::NoSavepoint(_))));
+        db.set("b", "21");
+        db.rollback_to("one").unwrap();
+        assert_eq!(db.get("b"), None);
+        // savepoints belong to the transaction that holds them
+        db.begin();
+        assert!(matches!(db.rollback_to("one"), Err(Error::NoSavepoint(_))));
+        db.savepoint("inner").unwrap();
+        db.set("d", "40");
+        db.commit_one().unwrap();
+        assert_eq!(db.transaction_depth(), 1);
+        assert_eq!(db.get("d"), Some("40".into()));
+        db.rollback_to("one").unwrap();
+        assert_eq!(db.get("d"), None);
+        db.set("e", "50");
+        db.commit().unwrap();
+        assert_eq!(db.transaction_depth(), 0);
+        assert_eq!(db.get("a"), Some("10".into()));
+        assert_eq!(db.get("e"), Some("50".into()));
+        // rollback discards the whole transaction, savepoints and all
+        db.begin();
+        db.set("f", "60");
+        db.savepoint("x").unwrap();
+        db.set("g", "70");
+        db.rollback().unwrap();
+        assert_eq!(db.transaction_depth(), 0);
+        assert_eq!(db.get("f"), None);
+        assert!(matches!(db.savepoint("x"), Err(Error::NoTransaction)));
+    }
+
+    #[test]
+    fn test_depth_and_pending() {
         let mut db = Database::new();
         db.set("a", "foo");
         db.set("b", "foo");
+        assert_eq!(db.transaction_depth(), 0);
+        assert_eq!(db.pending_count(), 0);
+        assert!(!db.in_transaction());
         db.begin();
-        assert_eq!(db.get("a"), Some("foo".into()));
-        assert_eq!(db.get("b"), Some("foo".into()));
-        assert_eq!(db.count("foo"), 2);
+        assert!(db.in_transaction());
         db.set("a", "bar");
-        assert_eq!(db.count("bar"), 1);
-        assert_eq!(db.count("foo"), 1);
+        db.set("c", "bar");
+        db.begin();
+        db.delete("b");
+        // setting a key back to its committed value is not a change
+        db.set("a", "foo");
+        assert_eq!(db.transaction_depth(), 2);
+        assert_eq!(db.pending_count(), 2);
+        db.rollback().unwrap();
+        assert_eq!(db.transaction_depth(), 1);
+        assert_eq!(db.pending_count(), 2);
+        db.commit().unwrap();
+        assert_eq!(db.transaction_depth(), 0);
+        assert_eq!(db.pending_count(), 0);
+    }
+
+    #[test]
+    fn test_owned_and_borrowed() {
+        let mut db = Database::new();
+        let name = String::from("a");
+        db.set(name, "foo");
+        db.set("b", String::from("foo"));
+        assert_eq!(db.count("foo"), 2);
+        db.delete("c");
+        db.begin();
         db.delete("a");
-        assert_eq!(db.count("bar"), 0);
+        db.delete(&String::from("c"));
         assert_eq!(db.count("foo"), 1);
+    }
+
+    #[test]
+    fn test_generic_types() {
+        #[derive(Clone, Debug, Hash, PartialEq, Eq)]
+        enum Color {
+            Red,
+            Blue,
+        }
+        let mut db: Database<u32, Color> = Database::default();
+        db.set(1u32, Color::Red);
+        db.set(2u32, Color::Red);
+        db.begin();
+        db.set(2u32, Color::Blue);
+        assert_eq!(db.count(&Color::Red), 1);
+        assert_eq!(db.get(&2), Some(Color::Blue));
+        db.rollback().unwrap();
+        assert_eq!(db.count(&Color::Red), 2);
+        db.delete(&1);
+        assert_eq!(db.get(&1), None);
+    }
+
+    #[test]
+    fn test_long_values() {
+        // values too long to be stored inline
+        let long = "a value that is far too long to fit within the inline buffer";
+        let mut db = Database::new();
+        db.set("a", long);
+        db.set("b", "short");
+        db.begin();
+        db.set("c", long);
+        assert_eq!(db.get("a"), Some(long.into()));
+        assert_eq!(db.count(long), 2);
+        db.commit().unwrap();
         db.delete("a");
-        assert_eq!(db.count("bar"), 0);
-        assert_eq!(db.count("foo"), 1);
-        db.delete("b");
-        assert_eq!(db.count("bar"), 0);
+        assert_eq!(db.count(long), 1);
+        assert_eq!(db.get("c"), Some(long.into()));
+    }
+
+    #[derive(Clone, Default)]
+    struct RecordingObserver {
+        events: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl Observer for RecordingObserver {
+        fn on_get(&self, name: &String, found: bool, _elapsed: std::time::Duration) {
+            let mut events = self.events.lock().unwrap();
+            events.push(format!("get {} {}", name, found));
+        }
+
+        fn on_set(&self, name: &String, _elapsed: std::time::Duration) {
+            self.events.lock().unwrap().push(format!("set {}", name));
+        }
+
+        fn on_commit(&self, _elapsed: std::time::Duration) {
+            self.events.lock().unwrap().push("commit".into());
+        }
+    }
+
+    #[test]
+    fn test_observer() {
+        let observer = RecordingObserver::default();
+        let mut db = Database::new();
+        db.add_observer(Box::new(observer.clone()));
+        db.set("a", "foo");
+        assert_eq!(db.get("a"), Some("foo".into()));
+        assert_eq!(db.get("b"), None);
+        // no transaction means nothing to report
+        assert!(db.commit().is_err());
+        db.begin();
+        db.delete("a");
+        db.commit().unwrap();
+        let events = observer.events.lock().unwrap();
+        assert_eq!(
+            *events,
+            vec!["set a", "get a true", "get b false", "commit"]
+        );
+    }
+
+    #[test]
+    fn test_commit_hooks() {
+        let committed = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let discarded = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut db = Database::new();
+        let keys = committed.clone();
+        db.on_commit(move |names: &[String]| keys.lock().unwrap().push(names.to_vec()));
+        let keys = discarded.clone();
+        db.on_rollback(move |names: &[String]| keys.lock().unwrap().push(names.to_vec()));
+        db.set("a", "10");
+        db.begin();
+        db.set("b", "20");
+        db.rpush("l", ["x"]).unwrap();
+        db.begin();
+        db.delete("a");
+        db.savepoint("s").unwrap();
+        db.set("c", "30");
+        db.rollback().unwrap();
+        db.set("a", "10");
+        db.commit().unwrap();
+        assert_eq!(*discarded.lock().unwrap(), vec![vec!["a", "c"]]);
+        assert_eq!(*committed.lock().unwrap(), vec![vec!["b", "l"]]);
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut db = Database::new();
+        for name in ["user:1", "user:2", "user:10", "session:1", "us"] {
+            db.set(name, "x");
+        }
+        assert_eq!(db.keys("user:*"), vec!["user:1", "user:10", "user:2"]);
+        assert_eq!(db.keys("user:?"), vec!["user:1", "user:2"]);
+        assert_eq!(db.keys("*:1"), vec!["session:1", "user:1"]);
+        assert_eq!(db.keys("[su]*"), db.keys("*"));
+        assert_eq!(db.keys("us"), vec!["us"]);
+        assert!(db.keys("nope*").is_empty());
+        // keys removed in a transaction are not listed
+        db.begin();
+        db.delete("user:2");
+        db.set("user:3", "x");
+        assert_eq!(db.keys("user:?"), vec!["user:1", "user:3"]);
+        db.rollback().unwrap();
+        assert_eq!(db.keys("user:?"), vec!["user:1", "user:2"]);
+        // keys holding other kinds of data are listed too
+        db.lpush("user:4", ["x"]).unwrap();
+        db.hset("user:5", [("f", "x")]).unwrap();
+        db.zadd("sorted", [(1.0, "x")]).unwrap();
+        assert_eq!(
+            db.keys("user:?"),
+            vec!["user:1", "user:2", "user:4", "user:5"]
+        );
+        assert_eq!(db.keys("s*"), vec!["session:1", "sorted"]);
+        assert_eq!(db.len(), 8);
+    }
+
+    #[test]
+    fn test_random_key() {
+        let mut db: Database = Database::new();
+        assert_eq!(db.random_key(), None);
+        db.set("a", "1");
+        db.set("b", "2");
+        db.set("c", "3");
+        db.expire("c", Duration::ZERO).unwrap();
+        db.hset("d", [("f", "4")]).unwrap();
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..200 {
+            seen.insert(db.random_key().unwrap().clone());
+        }
+        assert_eq!(seen.len(), 3);
+        assert!(seen.contains("d"));
+        assert!(!seen.contains("c"));
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut db = Database::new();
+        for n in 0..25 {
+            db.set(format!("key:{:02}", n), "x");
+        }
+        let mut cursor = "0".to_owned();
+        let mut found: Vec<String> = Vec::new();
+        let mut pages = 0;
+        loop {
+            let (keys, next) = db.scan(&cursor, 10, "*").unwrap();
+            found.extend(keys.into_iter().cloned());
+            pages += 1;
+            if pages == 2 {
+                // changes between pages do not disturb the scan
+                db.delete("key:00");
+                db.set("key:24a", "x");
+            }
+            if next == "0" {
+                break;
+            }
+            cursor = next;
+        }
+        assert_eq!(pages, 3);
+        assert_eq!(found.len(), 26);
+        assert_eq!(found[10], "key:10");
+        assert_eq!(found.last().map(String::as_str), Some("key:24a"));
+        // the pattern filters the keys visited
+        let (keys, next) = db.scan("0", 10, "key:0?").unwrap();
+        assert_eq!(keys.len(), 9);
+        assert_ne!(next, "0");
+        assert!(db.scan("zz", 10, "*").is_none());
+        assert!(db.scan("abc", 10, "*").is_none());
+        // keys of every kind are visited, in key order
+        db.clear();
+        db.set("a", "x");
+        db.lpush("b", ["x"]).unwrap();
+        db.set("c", "x");
+        db.xadd("d", [("f", "x")]).unwrap();
+        let (keys, next) = db.scan("0", 3, "*").unwrap();
+        assert_eq!(keys, vec!["a", "b", "c"]);
+        let (keys, next) = db.scan(&next, 3, "*").unwrap();
+        assert_eq!(keys, vec!["d"]);
+        assert_eq!(next, "0");
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut db = Database::new();
+        let hour = Duration::from_secs(3600);
+        assert!(!db.expire("a", hour).unwrap());
+        db.set("a", "10");
+        db.set("b", "10");
+        assert!(db.expire("a", hour).unwrap());
+        assert!(db.ttl("a").unwrap().unwrap() > Duration::from_secs(3590));
+        assert!(db.persist("a"));
+        assert!(!db.persist("a"));
+        assert_eq!(db.ttl("a").unwrap(), None);
+        // an expired key has no value and is not counted
+        db.expire("b", Duration::ZERO).unwrap();
+        assert_eq!(db.get("b"), None);
+        assert_eq!(db.count("10"), 1);
+        assert!(!db.expire("b", hour).unwrap());
+        assert_eq!(db.len(), 1);
+        assert!(matches!(db.ttl("b"), Err(Error::KeyNotFound)));
+        // setting a key removes its time-to-live
+        db.expire("a", hour).unwrap();
+        db.set("a", "20");
+        assert_eq!(db.ttl("a").unwrap(), None);
+        // a time-to-live too long to represent fails and changes nothing
+        assert!(matches!(
+            db.expire("a", Duration::MAX),
+            Err(Error::InvalidExpireTime)
+        ));
+        assert!(matches!(
+            db.expire("none", Duration::MAX),
+            Err(Error::InvalidExpireTime)
+        ));
+        assert_eq!(db.ttl("a").unwrap(), None);
+        // reading a key can refresh or remove its time-to-live
+        let minute = Duration::from_secs(60);
+        let touch = |db: &mut Database, ttl| db.get_and_touch_ttl("a", ttl).unwrap();
+        assert_eq!(touch(&mut db, Some(hour)), Some("20".into()));
+        assert!(db.ttl("a").unwrap().unwrap() > minute);
+        db.begin();
+        assert_eq!(touch(&mut db, Some(minute)), Some("20".into()));
+        assert!(db.ttl("a").unwrap().unwrap() <= minute);
+        db.rollback().unwrap();
+        assert!(db.ttl("a").unwrap().unwrap() > minute);
+        assert!(matches!(
+            db.get_and_touch_ttl("a", Some(Duration::MAX)),
+            Err(Error::InvalidExpireTime)
+        ));
+        assert!(db.ttl("a").unwrap().unwrap() > minute);
+        assert_eq!(touch(&mut db, None), Some("20".into()));
+        assert_eq!(db.ttl("a").unwrap(), None);
+        assert_eq!(db.get_and_touch_ttl("b", Some(hour)).unwrap(), None);
+        assert!(!db.exists("b"));
+    }
+
+    #[test]
+    fn test_mget_mset() {
+        let mut db = Database::new();
+        db.mset([("a", "10"), ("b", "20"), ("c", "10")]);
+        assert_eq!(db.transaction_depth(), 0);
+        assert_eq!(
+            db.mget(&["a", "z", "b"]),
+            vec![Some("10".into()), None, Some("20".into())]
+        );
+        assert_eq!(db.count("10"), 2);
+        db.begin();
+        db.mset([("a", "20"), ("d", "30")]);
+        assert_eq!(db.transaction_depth(), 1);
+        assert_eq!(db.count("20"), 2);
+        db.rollback().unwrap();
+        assert_eq!(db.mget(&["a", "d"]), vec![Some("10".into()), None]);
+    }
+
+    #[test]
+    fn test_conditional_set() {
+        let mut db = Database::new();
+        assert!(db.set_nx("a", "10"));
+        assert!(!db.set_nx("a", "20"));
+        assert_eq!(db.get("a"), Some("10".into()));
+        assert!(!db.compare_and_swap("a", "20", "30"));
+        assert!(db.compare_and_swap("a", "10", "30"));
+        assert_eq!(db.get("a"), Some("30".into()));
+        assert_eq!(db.count("10"), 0);
+        assert!(!db.compare_and_swap("b", "10", "30"));
+        assert_eq!(db.get("b"), None);
+        // an expired key counts as absent
+        db.expire("a", Duration::ZERO).unwrap();
+        assert!(db.set_nx("a", "40"));
+    }
+
+    #[test]
+    fn test_copy() {
+        let mut db = Database::new();
+        assert!(!db.copy("a", "b", true));
+        db.set("a", "10");
+        db.set("c", "20");
+        assert!(db.copy("a", "b", false));
+        assert_eq!(db.get("b"), Some("10".into()));
+        assert_eq!(db.count("10"), 2);
+        assert!(!db.copy("a", "c", false));
+        assert_eq!(db.get("c"), Some("20".into()));
+        assert!(db.copy("a", "c", true));
+        assert_eq!(db.count("10"), 3);
+        assert_eq!(db.count("20"), 0);
+        db.expire("a", Duration::from_secs(3600)).unwrap();
+        assert!(db.copy("a", "d", false));
+        assert!(db.ttl("d").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut db = Database::new();
+        db.set("a", "10");
+        db.set("b", "10");
+        db.expire("b", Duration::from_secs(3600)).unwrap();
+        db.begin();
+        db.clear();
+        assert!(db.is_empty());
+        assert_eq!(db.count("10"), 0);
+        db.rollback().unwrap();
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.count("10"), 2);
+        assert!(db.ttl("b").unwrap().is_some());
+        db.clear();
+        assert!(db.is_empty());
+        assert_eq!(db.distinct_value_count(), 0);
+    }
+
+    #[test]
+    fn test_lists() {
+        let mut db = Database::new();
+        assert_eq!(db.rpush("a", ["2", "3"]).unwrap(), 2);
+        assert_eq!(db.lpush("a", ["1", "0"]).unwrap(), 4);
+        let all: Vec<String> = db.lrange("a", 0, -1).unwrap();
+        assert_eq!(all, vec!["0", "1", "2", "3"]);
+        assert_eq!(db.lrange("a", 1, 2).unwrap(), vec!["1", "2"]);
+        assert_eq!(db.lrange("a", -2, 10).unwrap(), vec!["2", "3"]);
+        assert!(db.lrange("a", 3, 1).unwrap().is_empty());
+        assert!(db.lrange("z", 0, -1).unwrap().is_empty());
+        assert_eq!(db.llen("a").unwrap(), 4);
+        assert!(db.exists("a"));
+        // lists are not counted as values
+        assert_eq!(db.count("1"), 0);
+        assert_eq!(db.len(), 1);
+        db.begin();
+        assert_eq!(db.lpop("a").unwrap(), Some("0".into()));
+        assert_eq!(db.rpop("a").unwrap(), Some("3".into()));
+        assert_eq!(db.llen("a").unwrap(), 2);
+        db.rollback().unwrap();
+        assert_eq!(db.llen("a").unwrap(), 4);
+        // values and lists do not mix
+        db.set("b", "10");
+        assert!(matches!(db.lpush("b", ["1"]), Err(Error::WrongType)));
+        assert!(matches!(db.llen("b"), Err(Error::WrongType)));
+        // an emptied list is removed
+        db.rpush("c", ["1"]).unwrap();
+        assert_eq!(db.lpop("c").unwrap(), Some("1".into()));
+        assert_eq!(db.lpop("c").unwrap(), None);
+        assert!(!db.exists("c"));
+        // setting or removing the key discards the list
+        db.set("a", "20");
+        assert_eq!(db.get("a"), Some("20".into()));
+        assert!(matches!(db.llen("a"), Err(Error::WrongType)));
+        db.rpush("d", ["1"]).unwrap();
+        db.delete("d");
+        assert!(!db.exists("d"));
+        db.rpush("e", ["1"]).unwrap();
+        assert!(db.expire("e", Duration::ZERO).unwrap());
+        assert_eq!(db.llen("e").unwrap(), 0);
+        assert!(!db.exists("e"));
+    }
+
+    #[test]
+    fn test_hashes() {
+        let mut db = Database::new();
+        assert_eq!(db.hset("h", [("name", "ann"), ("age", "30")]).unwrap(), 2);
+        assert_eq!(db.hset("h", [("age", "31"), ("town", "ely")]).unwrap(), 1);
+        assert_eq!(db.hget("h", "age").unwrap(), Some("31".into()));
+        assert_eq!(db.hget("h", "zip").unwrap(), None);
+        assert_eq!(db.hget("z", "age").unwrap(), None);
+        assert_eq!(db.hlen("h").unwrap(), 3);
+        let fields: Vec<(String, String)> = db.hgetall("h").unwrap();
+        assert_eq!(fields[0], ("age".into(), "31".into()));
+        assert_eq!(fields.len(), 3);
+        assert!(db.exists("h"));
+        // hashes are not counted as values
+        assert_eq!(db.count("31"), 0);
+        assert_eq!(db.len(), 1);
+        db.begin();
+        assert_eq!(db.hdel("h", &["name", "zip"]).unwrap(), 1);
+        db.begin();
+        db.hset("h", [("name", "bob")]).unwrap();
+        db.rollback().unwrap();
+        assert_eq!(db.hget("h", "name").unwrap(), None);
+        db.rollback().unwrap();
+        assert_eq!(db.hget("h", "name").unwrap(), Some("ann".into()));
+        // values, lists, and hashes do not mix
+        db.set("a", "10");
+        db.rpush("l", ["1"]).unwrap();
+        assert!(matches!(db.hset("a", [("f", "1")]), Err(Error::WrongType)));
+        assert!(matches!(db.hget("l", "f"), Err(Error::WrongType)));
+        assert!(matches!(db.llen("h"), Err(Error::WrongType)));
+        // an emptied hash is removed, as is one replaced by a value
+        db.hset("e", [("f", "1")]).unwrap();
+        assert_eq!(db.hdel("e", &["f"]).unwrap(), 1);
+        assert!(!db.exists("e"));
+        db.set("h", "20");
+        assert!(matches!(db.hlen("h"), Err(Error::WrongType)));
+        db.clear();
+        assert!(!db.exists("l"));
+    }
+
+    #[test]
+    fn test_sorted_sets() {
+        let mut db = Database::new();
+        let added = db.zadd("z", [(3.0, "c"), (1.0, "a"), (2.0, "b")]).unwrap();
+        assert_eq!(added, 3);
+        assert_eq!(db.zadd("z", [(0.5, "c"), (4.0, "d")]).unwrap(), 1);
+        assert_eq!(db.zscore("z", "c").unwrap(), Some(0.5));
+        assert_eq!(db.zscore("z", "q").unwrap(), None);
+        let names = |members: Vec<(String, f64)>| -> Vec<String> {
+            members.into_iter().map(|(member, _)| member).collect()
+        };
+        assert_eq!(names(db.zrange("z", 0, -1).unwrap()), ["c", "a", "b", "d"]);
+        assert_eq!(names(db.zrange("z", -2, 10).unwrap()), ["b", "d"]);
+        assert!(db.zrange("y", 0, -1).unwrap().is_empty());
+        let ranged = db.zrange_by_score("z", Bound::Excluded(1.0), Bound::Included(4.0));
+        assert_eq!(names(ranged.unwrap()), ["b", "d"]);
+        assert!(matches!(
+            db.zadd("z", [(f64::NAN, "e")]),
+            Err(Error::NotAFloat)
+        ));
+        db.begin();
+        assert_eq!(db.zrem("z", &["a", "q"]).unwrap(), 1);
+        db.zadd("z", [(9.0, "e")]).unwrap();
+        assert_eq!(db.zrange("z", 0, -1).unwrap().len(), 4);
+        db.rollback().unwrap();
+        assert_eq!(names(db.zrange("z", 0, -1).unwrap()), ["c", "a", "b", "d"]);
+        // sorted sets do not mix with other kinds of data
+        db.set("s", "10");
+        assert!(matches!(db.zadd("s", [(1.0, "a")]), Err(Error::WrongType)));
+        assert!(matches!(db.zscore("s", "a"), Err(Error::WrongType)));
+        assert!(matches!(db.hlen("z"), Err(Error::WrongType)));
+        // an emptied sorted set is removed
+        assert_eq!(db.zrem("z", &["a", "b", "c", "d"]).unwrap(), 4);
+        assert!(!db.exists("z"));
+    }
+
+    #[test]
+    fn test_json_documents() {
+        use serde_json::json;
+        let mut db = Database::new();
+        assert!(!db.json_set("d", "$.name", json!("ann")).unwrap());
+        assert!(db.json_set("d", "$", json!({"tags": ["a", "b"]})).unwrap());
+        assert!(db.json_set("d", "$.name", json!("ann")).unwrap());
+        assert_eq!(db.json_get("d", "$.tags[-1]").unwrap(), Some(json!("b")));
+        assert_eq!(db.json_get("d", "$.size").unwrap(), None);
+        assert_eq!(db.json_get("z", "$").unwrap(), None);
+        assert_eq!(db.kind("d"), Some(Kind::Json));
+        db.begin();
+        assert!(db.json_set("d", "$.tags[0]", json!("c")).unwrap());
+        assert_eq!(db.json_get("d", "$.tags[0]").unwrap(), Some(json!("c")));
+        db.rollback().unwrap();
+        assert_eq!(db.json_get("d", "$.tags[0]").unwrap(), Some(json!("a")));
+        assert!(matches!(db.json_get("d", "tags"), Err(Error::Parse(_))));
+        db.set("s", "10");
+        assert!(matches!(
+            db.json_set("s", "$", json!(1)),
+            Err(Error::WrongType)
+        ));
+        db.delete("d");
+        assert!(!db.exists("d"));
+    }
+
+    #[test]
+    fn test_streams() {
+        let mut db = Database::new();
+        let first = db.xadd("s", [("kind", "login"), ("user", "ann")]).unwrap();
+        let second = db.xadd("s", [("kind", "logout")]).unwrap();
+        assert!(second > first);
+        assert_eq!(db.xlen("s").unwrap(), 2);
+        assert_eq!(db.kind("s"), Some(Kind::Stream));
+        let all = db.xrange("s", StreamId::MIN, StreamId::MAX).unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].0, first);
+        assert_eq!(
+            all[0].1,
+            vec![
+                ("kind".into(), "login".into()),
+                ("user".into(), "ann".into())
+            ]
+        );
+        let later = db.xrange("s", second, StreamId::MAX).unwrap();
+        assert_eq!(later.len(), 1);
+        assert!(db.xrange("s", second, first).unwrap().is_empty());
+        assert!(db
+            .xrange("z", StreamId::MIN, StreamId::MAX)
+            .unwrap()
+            .is_empty());
+        db.begin();
+        db.xadd("s", [("kind", "login")]).unwrap();
+        assert_eq!(db.pending_count(), 1);
+        db.rollback().unwrap();
+        assert_eq!(db.xlen("s").unwrap(), 2);
+        // streams do not mix with other kinds of data, nor are they counted
+        db.set("v", "login");
+        assert!(matches!(db.xadd("v", [("a", "b")]), Err(Error::WrongType)));
+        assert!(matches!(db.xlen("v"), Err(Error::WrongType)));
+        assert_eq!(db.count("login"), 1);
+    }
+
+    #[test]
+    fn test_stream_log() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("simpledb-xwal-{}", std::process::id()));
+        let snapshot = dir.join(format!("simpledb-xsnap-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let ids = {
+            let mut db: Database = Database::open(&path).unwrap();
+            let first = db.xadd("s", [("a", "1")]).unwrap();
+            db.set("x", "10");
+            db.begin();
+            let second = db.xadd("s", [("b", "2")]).unwrap();
+            db.delete("x");
+            db.xadd("x", [("c", "3")]).unwrap();
+            db.xadd("t", [("d", "4")]).unwrap();
+            db.commit().unwrap();
+            db.begin();
+            db.delete("t");
+            db.xadd("t", [("e", "5")]).unwrap();
+            db.set("x", "20");
+            db.commit().unwrap();
+            assert!(db.take_log_error().is_none());
+            vec![first, second]
+        };
+        let db: Database = Database::open(&path).unwrap();
+        let entries = db.xrange("s", StreamId::MIN, StreamId::MAX).unwrap();
+        let found: Vec<StreamId> = entries.iter().map(|(id, _)| *id).collect();
+        assert_eq!(found, ids);
+        assert_eq!(db.get("x"), Some("20".into()));
+        let entries = db.xrange("t", StreamId::MIN, StreamId::MAX).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].1, vec![("e".into(), "5".into())]);
+        db.save_snapshot(&snapshot).unwrap();
+        let loaded: Database = Database::load_snapshot(&snapshot).unwrap();
+        assert_eq!(loaded.xlen("s").unwrap(), 2);
+        assert_eq!(loaded.kind("t"), Some(Kind::Stream));
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&snapshot).unwrap();
+    }
+
+    #[test]
+    fn test_hash_log() {
+        let path = std::env::temp_dir().join(format!("simpledb-hwal-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut db: Database = Database::open(&path).unwrap();
+            db.hset("h", [("a", "1"), ("b", "2")]).unwrap();
+            db.hdel("h", &["a"]).unwrap();
+            db.set("s", "10");
+            db.hset("x", [("a", "1")]).unwrap();
+            db.begin();
+            db.hset("h", [("c", "3")]).unwrap();
+            db.delete("s");
+            db.hset("s", [("a", "1")]).unwrap();
+            db.begin();
+            db.set("x", "20");
+            db.commit().unwrap();
+            db.begin();
+            db.hset("h", [("d", "4")]).unwrap();
+            db.rollback().unwrap();
+            assert!(db.take_log_error().is_none());
+        }
+        let db: Database = Database::open(&path).unwrap();
+        let fields: Vec<(String, String)> = db.hgetall("h").unwrap();
+        assert_eq!(
+            fields,
+            vec![("b".into(), "2".into()), ("c".into(), "3".into())]
+        );
+        assert_eq!(db.hget("s", "a").unwrap(), Some("1".into()));
+        assert_eq!(db.get("x"), Some("20".into()));
+        assert_eq!(db.len(), 3);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_list_log() {
+        let path = std::env::temp_dir().join(format!("simpledb-lwal-{}", std::process::id()));
+        let snapshot = path.with_extension("snapshot");
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut db: Database = Database::open(&path).unwrap();
+            db.rpush("l", ["1", "2", "3"]).unwrap();
+            db.lpush("l", ["0"]).unwrap();
+            db.rpop("l").unwrap();
+            db.set("x", "10");
+            db.hset("h", [("f", "1")]).unwrap();
+            db.rpush("e", ["1"]).unwrap();
+            db.lpop("e").unwrap();
+            db.begin();
+            db.lpop("l").unwrap();
+            db.rpush("l", ["4"]).unwrap();
+            db.delete("x");
+            db.rpush("x", ["5"]).unwrap();
+            db.delete("h");
+            db.lpush("h", ["6"]).unwrap();
+            db.commit().unwrap();
+            db.begin();
+            db.rpush("l", ["7"]).unwrap();
+            db.rollback().unwrap();
+            assert!(db.take_log_error().is_none());
+            db.save_snapshot(&snapshot).unwrap();
+        }
+        let check = |db: &Database| {
+            assert_eq!(db.lrange("l", 0, -1).unwrap(), vec!["1", "2", "4"]);
+            assert_eq!(db.lrange("x", 0, -1).unwrap(), vec!["5"]);
+            assert_eq!(db.lrange("h", 0, -1).unwrap(), vec!["6"]);
+            assert!(!db.exists("e"));
+            assert_eq!(db.len(), 3);
+        };
+        let mut db: Database = Database::open(&path).unwrap();
+        check(&db);
+        check(&Database::load_snapshot(&snapshot).unwrap());
+        // the rewritten log holds the lists as well
+        db.rewrite_aof().unwrap();
+        drop(db);
+        check(&Database::open(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&snapshot).unwrap();
+    }
+
+    #[test]
+    fn test_sorted_set_log() {
+        let path = std::env::temp_dir().join(format!("simpledb-zwal-{}", std::process::id()));
+        let snapshot = path.with_extension("snapshot");
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut db: Database = Database::open(&path).unwrap();
+            db.zadd("z", [(1.0, "a"), (2.0, "b"), (3.0, "c")]).unwrap();
+            db.zrem("z", &["b"]).unwrap();
+            db.zadd("e", [(1.0, "a")]).unwrap();
+            db.zrem("e", &["a"]).unwrap();
+            db.set("x", "10");
+            db.begin();
+            db.zadd("z", [(-1.0, "c"), (4.0, "d")]).unwrap();
+            db.zrem("z", &["a"]).unwrap();
+            db.delete("x");
+            db.zadd("x", [(0.5, "y")]).unwrap();
+            db.commit().unwrap();
+            db.begin();
+            db.zadd("z", [(5.0, "f")]).unwrap();
+            db.rollback().unwrap();
+            assert!(db.take_log_error().is_none());
+            db.save_snapshot(&snapshot).unwrap();
+        }
+        let check = |db: &Database| {
+            let members = db.zrange("z", 0, -1).unwrap();
+            assert_eq!(members, vec![("c".into(), -1.0), ("d".into(), 4.0)]);
+            assert_eq!(db.zscore("x", "y").unwrap(), Some(0.5));
+            assert!(!db.exists("e"));
+            assert_eq!(db.len(), 2);
+        };
+        let mut db: Database = Database::open(&path).unwrap();
+        check(&db);
+        check(&Database::load_snapshot(&snapshot).unwrap());
+        db.rewrite_aof().unwrap();
+        drop(db);
+        check(&Database::open(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&snapshot).unwrap();
+    }
+
+    #[test]
+    fn test_json_log() {
+        let path = std::env::temp_dir().join(format!("simpledb-jwal-{}", std::process::id()));
+        let snapshot = path.with_extension("snapshot");
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut db: Database = Database::open(&path).unwrap();
+            let document = serde_json::json!({"a": [1, 2], "b": "x"});
+            db.json_set("j", "$", document).unwrap();
+            db.json_set("j", "$.a[0]", serde_json::json!(10)).unwrap();
+            db.json_set("e", "$", serde_json::json!(1)).unwrap();
+            db.delete("e");
+            db.set("x", "10");
+            db.begin();
+            db.json_set("j", "$.c", serde_json::json!(null)).unwrap();
+            db.delete("x");
+            db.json_set("x", "$", serde_json::json!([true])).unwrap();
+            db.commit().unwrap();
+            db.begin();
+            db.json_set("j", "$.b", serde_json::json!("y")).unwrap();
+            db.rollback().unwrap();
+            assert!(db.take_log_error().is_none());
+            db.save_snapshot(&snapshot).unwrap();
+        }
+        let check = |db: &Database| {
+            let document = serde_json::json!({"a": [10, 2], "b": "x", "c": null});
+            assert_eq!(db.json_get("j", "$").unwrap(), Some(document));
+            assert_eq!(
+                db.json_get("x", "$").unwrap(),
+                Some(serde_json::json!([true]))
+            );
+            assert!(!db.exists("e"));
+            assert_eq!(db.len(), 2);
+        };
+        let mut db: Database = Database::open(&path).unwrap();
+        check(&db);
+        check(&Database::load_snapshot(&snapshot).unwrap());
+        db.rewrite_aof().unwrap();
+        drop(db);
+        check(&Database::open(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&snapshot).unwrap();
+    }
+
+    #[test]
+    fn test_expiry_log() {
+        let path = std::env::temp_dir().join(format!("simpledb-twal-{}", std::process::id()));
+        let snapshot = path.with_extension("snapshot");
+        let _ = std::fs::remove_file(&path);
+        let hour = Duration::from_secs(3600);
+        {
+            let mut db: Database = Database::open(&path).unwrap();
+            db.set("a", "1");
+            db.expire("a", hour).unwrap();
+            db.set("b", "2");
+            db.expire("b", hour).unwrap();
+            assert!(db.persist("b"));
+            db.set("c", "3");
+            db.expire("c", hour).unwrap();
+            db.incr("c").unwrap();
+            db.rpush("l", ["1", "2"]).unwrap();
+            db.expire("l", hour).unwrap();
+            db.set("e", "4");
+            db.expire("e", hour).unwrap();
+            db.begin();
+            db.set("d", "5");
+            db.expire("d", hour).unwrap();
+            db.append("e", "0");
+            db.lpop("l").unwrap();
+            db.rpush("l", ["2"]).unwrap();
+            db.commit().unwrap();
+            db.begin();
+            db.persist("a");
+            db.rollback().unwrap();
+            db.copy("c", "f", false);
+            db.rpush("g", ["1"]).unwrap();
+            db.expire("g", Duration::ZERO).unwrap();
+            assert!(db.take_log_error().is_none());
+            db.save_snapshot(&snapshot).unwrap();
+        }
+        let check = |db: &Database| {
+            for name in ["a", "c", "d", "e", "f", "l"] {
+                let ttl = db.ttl(name).unwrap().unwrap();
+                assert!(ttl > Duration::from_secs(3500) && ttl <= hour);
+            }
+            assert_eq!(db.ttl("b").unwrap(), None);
+            assert_eq!(db.get("c"), Some("4".into()));
+            assert_eq!(db.get("e"), Some("40".into()));
+            // a time that has passed is still passed
+            assert!(!db.exists("g"));
+            assert_eq!(db.len(), 7);
+        };
+        let mut db: Database = Database::open(&path).unwrap();
+        check(&db);
+        check(&Database::load_snapshot(&snapshot).unwrap());
+        db.rewrite_aof().unwrap();
+        drop(db);
+        check(&Database::open(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&snapshot).unwrap();
+    }
+
+    #[test]
+    fn test_exists() {
+        let mut db = Database::new();
+        db.set("a", "10");
+        db.set("b", "20");
+        db.begin();
+        db.delete("a");
+        db.set("c", "30");
+        assert!(!db.exists("a"));
+        assert!(db.exists("b"));
+        assert!(db.exists("c"));
+        db.rollback().unwrap();
+        assert!(db.exists("a"));
+        assert!(!db.exists("c"));
+        db.expire("b", Duration::ZERO).unwrap();
+        assert!(!db.exists("b"));
+    }
+
+    #[test]
+    fn test_get_del() {
+        let mut db = Database::new();
+        db.set("a", "10");
+        db.begin();
+        assert_eq!(db.get_del("a"), Some("10".into()));
+        assert_eq!(db.get_del("a"), None);
+        assert_eq!(db.count("10"), 0);
+        db.rollback().unwrap();
+        assert_eq!(db.get("a"), Some("10".into()));
+    }
+
+    #[test]
+    fn test_append() {
+        let mut db = Database::new();
+        assert_eq!(db.append("a", "foo"), 3);
+        assert_eq!(db.append("a", "bär"), 7);
+        assert_eq!(db.get("a"), Some("foobär".into()));
         assert_eq!(db.count("foo"), 0);
+        assert_eq!(db.count("foobär"), 1);
+        db.expire("a", Duration::from_secs(3600)).unwrap();
+        db.append("a", "!");
+        assert!(db.ttl("a").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_incr() {
+        let mut db = Database::new();
+        assert_eq!(db.incr("a").unwrap(), 1);
+        assert_eq!(db.incr_by("a", 41).unwrap(), 42);
+        assert_eq!(db.decr("a").unwrap(), 41);
+        assert_eq!(db.get("a"), Some("41".into()));
+        assert_eq!(db.count("41"), 1);
+        assert_eq!(db.count("42"), 0);
+        assert_eq!(db.decr("b").unwrap(), -1);
+        db.set("c", "ten");
+        assert!(matches!(db.incr("c"), Err(Error::NotAnInteger)));
+        assert_eq!(db.get("c"), Some("ten".into()));
+        db.set("d", i64::MAX.to_string());
+        assert!(matches!(db.incr("d"), Err(Error::NotAnInteger)));
+        // the time-to-live survives the increment
+        db.expire("a", Duration::from_secs(3600)).unwrap();
+        db.incr("a").unwrap();
+        assert!(db.ttl("a").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut db = Database::new();
+        db.set("c", "3");
+        db.set("a", "1");
+        db.set("b", "2");
+        db.begin();
+        db.delete("b");
+        db.set("d", "4");
+        db.expire("c", Duration::ZERO).unwrap();
+        let entries: Vec<(&str, &str)> = db.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(entries, vec![("a", "1"), ("d", "4")]);
+        assert_eq!(db.len(), 2);
+        db.rollback().unwrap();
+        let names: Vec<&String> = db.iter().map(|(k, _)| k).collect();
+        assert_eq!(names, vec!["a", "b", "c"]);
+        assert_eq!(db.len(), 3);
+        assert!(!db.is_empty());
+    }
+
+    #[test]
+    fn test_expire_transactions() {
+        let mut db = Database::new();
+        let hour = Duration::from_secs(3600);
+        db.set("a", "10");
+        db.begin();
+        db.expire("a", Duration::ZERO).unwrap();
+        assert_eq!(db.get("a"), None);
+        db.rollback().unwrap();
+        assert_eq!(db.get("a"), Some("10".into()));
+        assert_eq!(db.ttl("a").unwrap(), None);
+        db.expire("a", hour).unwrap();
+        db.begin();
+        db.persist("a");
+        assert_eq!(db.ttl("a").unwrap(), None);
+        db.rollback().unwrap();
+        assert!(db.ttl("a").unwrap().is_some());
+        db.begin();
+        db.expire("a", Duration::ZERO).unwrap();
+        db.commit().unwrap();
+        assert_eq!(db.get("a"), None);
+        assert_eq!(db.count("10"), 0);
+    }
+
+    #[test]
+    fn test_shared_database() {
+        let mut first = SharedDatabase::new(Database::new());
+        first.set("a", "10");
+        let mut second = first.clone();
+        assert_eq!(second.get("a"), Some("10".into()));
+        // each handle sees only its own uncommitted changes
+        first.begin();
+        first.set("a", "20");
+        first.set("b", "20");
+        second.begin();
+        second.delete("a");
+        second.set("c", "20");
+        assert_eq!(first.count("20"), 2);
+        assert_eq!(second.count("20"), 1);
+        assert_eq!(second.get("b"), None);
+        assert!(first.commit().is_ok());
+        assert_eq!(first.transaction_depth(), 0);
+        assert!(!first.in_transaction());
+        assert!(second.in_transaction());
+        assert_eq!(first.count("20"), 2);
+        // the snapshot of the second handle is unaffected by the commit
+        assert_eq!(second.get("b"), None);
+        second.begin();
+        second.set("d", "20");
+        assert!(second.rollback().is_ok());
+        assert_eq!(second.get("d"), None);
+        assert!(second.commit().is_ok());
+        assert!(second.commit().is_err());
+        assert!(second.rollback().is_err());
+        assert_eq!(first.get("a"), None);
+        assert_eq!(first.get("b"), Some("20".into()));
+        assert_eq!(first.count("20"), 2);
+    }
+
+    #[test]
+    fn test_blocking_pop() {
+        let mut consumer = SharedDatabase::new(Database::new());
+        let mut producer = consumer.clone();
+        let timeout = Duration::from_millis(10);
+        assert_eq!(consumer.blpop("q", timeout).unwrap(), None);
+        producer.rpush("q", ["a", "b"]).unwrap();
+        assert_eq!(consumer.blpop("q", timeout).unwrap(), Some("a".into()));
+        let waiting = std::thread::spawn(move || {
+            let first = consumer.blpop("q", Duration::MAX).unwrap();
+            let second = consumer.blpop("q", Duration::MAX).unwrap();
+            (first, second)
+        });
+        std::thread::sleep(timeout);
+        producer.begin();
+        producer.lpush("q", ["c"]).unwrap();
+        let (first, second) = waiting.join().unwrap();
+        assert_eq!(first, Some("b".into()));
+        assert_eq!(second, Some("c".into()));
+        producer.rollback().unwrap();
+        producer.set("v", "1");
+        assert!(matches!(
+            producer.blpop("v", timeout),
+            Err(Error::WrongType)
+        ));
+    }
+
+    #[test]
+    fn test_shared_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SharedDatabase>();
+        let shared = SharedDatabase::new(Database::new());
+        let handles: Vec<_> = (0..4)
+            .map(|id| {
+                let mut db = shared.clone();
+                std::thread::spawn(move || {
+                    for n in 0..100 {
+                        db.begin();
+                        db.set(format!("{}-{}", id, n), "value");
+                        if n % 2 == 0 {
+                            db.commit().unwrap();
+                        } else {
+                            db.rollback().unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles.into_iter() {
+            handle.join().unwrap();
+        }
+        assert_eq!(shared.count("value"), 200);
+        assert_eq!(shared.get("3-98"), Some("value".into()));
+        assert_eq!(shared.get("3-99"), None);
     }
 }
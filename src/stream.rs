@@ -0,0 +1,231 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! Streams, which are append-only logs of entries, each a list of fields and
+//! values, such as events recorded as they happen. Every entry is identified
+//! by the time in milliseconds at which it was added and a sequence number
+//! that tells apart the entries added within the same millisecond, written
+//! as `<millis>-<seq>`. Identifiers only ever increase, even if the clock
+//! goes backwards.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+///
+/// Identifier of an entry in a stream.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    /// Milliseconds since the Unix epoch when the entry was added.
+    pub millis: u64,
+    /// Position among the entries added in the same millisecond.
+    pub seq: u64,
+}
+
+impl StreamId {
+    /// The lowest possible identifier, written `-` in a range.
+    pub const MIN: StreamId = StreamId { millis: 0, seq: 0 };
+    /// The highest possible identifier, written `+` in a range.
+    pub const MAX: StreamId = StreamId {
+        millis: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    /// Returns the identifier for an entry added now to a stream whose last
+    /// entry has the given identifier.
+    pub fn next(last: Option<StreamId>) -> StreamId {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        match last {
+            Some(last) if now <= last.millis => StreamId {
+                millis: last.millis,
+                seq: last.seq + 1,
+            },
+            _ => StreamId {
+                millis: now,
+                seq: 0,
+            },
+        }
+    }
+
+    /// Parse one end of a range: `-` or `+`, a full identifier, or only the
+    /// milliseconds, which stand for the first entry of that millisecond at
+    /// the start of a range and the last at the end.
+    pub fn parse_bound(text: &str, end: bool) -> Option<StreamId> {
+        match text {
+            "-" => Some(StreamId::MIN),
+            "+" => Some(StreamId::MAX),
+            _ if text.contains('-') => text.parse().ok(),
+            _ => {
+                let millis = text.parse().ok()?;
+                let seq = if end { u64::MAX } else { 0 };
+                Some(StreamId { millis, seq })
+            }
+        }
+    }
+
+    /// Returns the identifier as sixteen bytes, for the log and snapshots.
+    pub(crate) fn to_bytes(self) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        bytes[..8].copy_from_slice(&self.millis.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.seq.to_le_bytes());
+        bytes
+    }
+
+    /// Reconstruct an identifier from the bytes written by `to_bytes()`.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<StreamId> {
+        let millis = u64::from_le_bytes(bytes.get(..8)?.try_into().ok()?);
+        let seq = u64::from_le_bytes(bytes.get(8..16)?.try_into().ok()?);
+        Some(StreamId { millis, seq }).filter(|_| bytes.len() == 16)
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.millis, self.seq)
+    }
+}
+
+impl FromStr for StreamId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (millis, seq) = text.split_once('-').unwrap_or((text, "0"));
+        Ok(StreamId {
+            millis: millis.parse()?,
+            seq: seq.parse()?,
+        })
+    }
+}
+
+/// An entry of a stream: its identifier, and its fields and values.
+pub type Entry<K, V> = (StreamId, Vec<(K, V)>);
+
+/// Entries of a stream borrowed from it, in order.
+pub(crate) type EntryRefs<'a, K, V> = Vec<(&'a StreamId, &'a Vec<(K, V)>)>;
+
+///
+/// The entries of a stream in the order they were added. Like the rest of
+/// the store this is a persistent map, cheap for transactions to copy.
+///
+#[derive(Clone, PartialEq)]
+pub(crate) struct Stream<K, V> {
+    entries: im::OrdMap<StreamId, Vec<(K, V)>>,
+}
+
+impl<K, V> Default for Stream<K, V>
+where
+    K: Clone,
+    V: Clone,
+{
+    fn default() -> Self {
+        Self {
+            entries: im::OrdMap::new(),
+        }
+    }
+}
+
+impl<K, V> Stream<K, V>
+where
+    K: Clone,
+    V: Clone,
+{
+    /// Returns the identifier of the last entry, if any.
+    pub fn last_id(&self) -> Option<StreamId> {
+        self.entries.get_max().map(|(id, _)| *id)
+    }
+
+    /// Add the entry with the given identifier.
+    pub fn insert(&mut self, id: StreamId, fields: Vec<(K, V)>) {
+        self.entries.insert(id, fields);
+    }
+
+    /// Returns the number of entries.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Visit every entry in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&StreamId, &Vec<(K, V)>)> {
+        self.entries.iter()
+    }
+
+    /// Visit the entries from `start` to `end` inclusive, in order.
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+    ) -> impl Iterator<Item = (&StreamId, &Vec<(K, V)>)> {
+        // an empty range would make the map panic
+        let range = (start <= end).then(|| self.entries.range(start..=end));
+        range.into_iter().flatten()
+    }
+
+    /// Returns the entries added since the given stream, which this one is
+    /// a later copy of, or `None` if entries were removed in between.
+    pub fn added_since<'a>(&'a self, earlier: &'a Stream<K, V>) -> Option<EntryRefs<'a, K, V>>
+    where
+        K: Ord,
+        V: PartialEq,
+    {
+        let mut added = Vec::new();
+        for item in earlier.entries.diff(&self.entries) {
+            match item {
+                im::ordmap::DiffItem::Add(id, fields) => added.push((id, fields)),
+                _ => return None,
+            }
+        }
+        Some(added)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_id() {
+        let first = StreamId::next(None);
+        let second = StreamId::next(Some(first));
+        assert!(second > first);
+        let future = StreamId {
+            millis: u64::MAX - 1,
+            seq: 5,
+        };
+        assert_eq!(
+            StreamId::next(Some(future)),
+            StreamId {
+                millis: u64::MAX - 1,
+                seq: 6
+            }
+        );
+        assert_eq!("5-3".parse::<StreamId>().unwrap().to_string(), "5-3");
+        assert_eq!(StreamId::parse_bound("5", true).unwrap().seq, u64::MAX);
+        assert_eq!(StreamId::parse_bound("5", false).unwrap().seq, 0);
+        assert_eq!(StreamId::parse_bound("+", false), Some(StreamId::MAX));
+        assert_eq!(StreamId::parse_bound("x", false), None);
+        assert_eq!(StreamId::from_bytes(&future.to_bytes()), Some(future));
+        assert_eq!(StreamId::from_bytes(&[0; 15]), None);
+    }
+
+    #[test]
+    fn test_stream() {
+        let mut stream: Stream<String, String> = Stream::default();
+        let id = |seq| StreamId { millis: 1, seq };
+        stream.insert(id(0), vec![("a".into(), "1".into())]);
+        let earlier = stream.clone();
+        stream.insert(id(1), vec![("a".into(), "2".into())]);
+        stream.insert(id(2), vec![]);
+        assert_eq!(stream.len(), 3);
+        assert_eq!(stream.last_id(), Some(id(2)));
+        assert_eq!(stream.range(id(1), StreamId::MAX).count(), 2);
+        assert_eq!(stream.range(id(2), id(1)).count(), 0);
+        let added = stream.added_since(&earlier).unwrap();
+        assert_eq!(added.len(), 2);
+        assert!(earlier.added_since(&stream).is_none());
+        assert_eq!(stream.iter().count(), 3);
+    }
+}
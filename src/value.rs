@@ -0,0 +1,295 @@
+//
+// Copyright (c) 2022 Nathan Fiedler
+//
+
+//! Values that keep their type, being text, integers, floats, or booleans, so
+//! that numeric workloads need not parse and format text on every change. A
+//! `Database<String, Value>` stores them natively, counting an integer and
+//! the text of its digits as different values.
+
+use crate::error::{Error, Result};
+use crate::store::{Database, Kind};
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+///
+/// A value of one of several types.
+///
+#[derive(Clone, Debug)]
+pub enum Value {
+    /// Text.
+    Str(String),
+    /// A signed integer.
+    Int(i64),
+    /// A floating point number.
+    Float(f64),
+    /// Either `true` or `false`.
+    Bool(bool),
+}
+
+impl Value {
+    /// Returns the value that the text most likely represents: an integer, a
+    /// float, `true` or `false`, and otherwise the text itself.
+    pub fn infer(text: &str) -> Value {
+        if let Ok(n) = text.parse() {
+            Value::Int(n)
+        } else if let Some(f) = text.parse::<f64>().ok().filter(|f| f.is_finite()) {
+            Value::Float(f)
+        } else {
+            match text {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                _ => Value::Str(text.to_owned()),
+            }
+        }
+    }
+
+    /// Returns the name of the type of the value.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "string",
+            Value::Int(_) => "integer",
+            Value::Float(_) => "float",
+            Value::Bool(_) => "boolean",
+        }
+    }
+
+    /// Returns the value as a number, if it is one or is text that
+    /// represents one.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int(n) => Some(*n as f64),
+            Value::Float(f) => Some(*f),
+            Value::Str(text) => match Value::infer(text) {
+                Value::Int(n) => Some(n as f64),
+                Value::Float(f) => Some(f),
+                _ => None,
+            },
+            Value::Bool(_) => None,
+        }
+    }
+
+    /// Compare two values, numerically if both are numbers or text that
+    /// represents numbers, and otherwise only if they are of the same type.
+    /// Returns `None` if they cannot be compared.
+    pub fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+            (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+            _ => match (self.as_f64(), other.as_f64()) {
+                (Some(a), Some(b)) => a.partial_cmp(&b),
+                _ => match (self, other) {
+                    (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+                    _ => None,
+                },
+            },
+        }
+    }
+
+    /// Add the amount, keeping integers as integers and floats as floats,
+    /// and treating text that represents a number as that number.
+    fn add(&self, amount: i64) -> Result<Value> {
+        match self {
+            Value::Int(n) => n
+                .checked_add(amount)
+                .map(Value::Int)
+                .ok_or(Error::NotAnInteger),
+            Value::Float(f) => Ok(Value::Float(f + amount as f64)),
+            Value::Str(text) => match Value::infer(text) {
+                Value::Str(_) | Value::Bool(_) => Err(Error::NotAnInteger),
+                number => number.add(amount),
+            },
+            Value::Bool(_) => Err(Error::NotAnInteger),
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            // by their bits, so that equal values also hash alike
+            (Value::Float(a), Value::Float(b)) => a.to_bits() == b.to_bits(),
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Value::Str(text) => text.hash(state),
+            Value::Int(n) => n.hash(state),
+            Value::Float(f) => f.to_bits().hash(state),
+            Value::Bool(b) => b.hash(state),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Str(text) => write!(f, "{}", text),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Bool(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(text: &str) -> Self {
+        Value::Str(text.to_owned())
+    }
+}
+
+impl From<String> for Value {
+    fn from(text: String) -> Self {
+        Value::Str(text)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Int(n)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(f: f64) -> Self {
+        Value::Float(f)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl<K> Database<K, Value>
+where
+    K: Ord + Clone,
+{
+    /// Add the amount to the value of the key, which is taken to be zero if
+    /// the key has no value, and return the result. Integers stay integers,
+    /// floats stay floats, and text that represents a number becomes that
+    /// number. Fails with `Error::NotAnInteger` for other values or if an
+    /// integer would overflow. Any time-to-live of the key is kept.
+    pub fn incr_value<Q>(&mut self, name: &Q, amount: i64) -> Result<Value>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        let updated = match self.get_ref(name) {
+            Some(value) => value.add(amount)?,
+            None => Value::Int(amount),
+        };
+        self.replace_keeping_ttl(name, updated.clone());
+        Ok(updated)
+    }
+
+    /// Returns the name of the type of the value of the key, if it has one.
+    pub fn value_type<Q>(&self, name: &Q) -> Option<&'static str>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        self.get_ref(name).map(Value::type_name)
+    }
+}
+
+impl<K, V> Database<K, V>
+where
+    K: Ord + Clone,
+    V: Hash + Eq + Clone + AsRef<str>,
+{
+    /// Returns the name of the type that the text of the value of the key
+    /// represents, as chosen by `Value::infer()`, if it has a value. Fails
+    /// with `Error::WrongType` if the key holds something other than a value.
+    pub fn inferred_type<Q>(&self, name: &Q) -> Result<Option<&'static str>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        match self.get_ref(name) {
+            Some(value) => Ok(Some(Value::infer(value.as_ref()).type_name())),
+            None if self.kind(name).is_some_and(|kind| kind != Kind::Value) => {
+                Err(Error::WrongType)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_infer() {
+        assert_eq!(Value::infer("42"), Value::Int(42));
+        assert_eq!(Value::infer("-1.5"), Value::Float(-1.5));
+        assert_eq!(Value::infer("true"), Value::Bool(true));
+        assert_eq!(Value::infer("inf"), Value::Str("inf".into()));
+        assert_eq!(Value::infer("ten"), Value::Str("ten".into()));
+        assert_eq!(Value::Float(2.0).to_string(), "2");
+        assert_eq!(
+            Value::Int(1).compare(&Value::Float(1.5)),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            Value::Str("10".into()).compare(&Value::Int(9)),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(
+            Value::Str("b".into()).compare(&"a".into()),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(Value::Bool(true).compare(&Value::Int(1)), None);
+        assert_ne!(Value::Int(1), Value::Str("1".into()));
+    }
+
+    #[test]
+    fn test_database() {
+        let mut db: Database<String, Value> = Database::default();
+        db.set("n", 10);
+        db.set("f", 0.5);
+        db.set("s", "7");
+        db.set("b", true);
+        assert_eq!(db.incr_value("n", 5).unwrap(), Value::Int(15));
+        assert_eq!(db.incr_value("f", 1).unwrap(), Value::Float(1.5));
+        assert_eq!(db.incr_value("s", 1).unwrap(), Value::Int(8));
+        assert_eq!(db.incr_value("new", -1).unwrap(), Value::Int(-1));
+        assert!(matches!(db.incr_value("b", 1), Err(Error::NotAnInteger)));
+        db.set("max", i64::MAX);
+        assert!(db.incr_value("max", 1).is_err());
+        assert_eq!(db.value_type("s"), Some("integer"));
+        assert_eq!(db.value_type("b"), Some("boolean"));
+        assert_eq!(db.value_type("none"), None);
+        assert_eq!(db.count(&Value::Int(8)), 1);
+    }
+
+    #[test]
+    fn test_inferred_type() {
+        let mut db: Database<String, String> = Database::default();
+        db.set("n".to_owned(), "10".to_owned());
+        db.set("f".to_owned(), "0.5".to_owned());
+        db.set("b".to_owned(), "false".to_owned());
+        db.set("s".to_owned(), "ten".to_owned());
+        assert_eq!(db.inferred_type("n").unwrap(), Some("integer"));
+        assert_eq!(db.inferred_type("f").unwrap(), Some("float"));
+        assert_eq!(db.inferred_type("b").unwrap(), Some("boolean"));
+        assert_eq!(db.inferred_type("s").unwrap(), Some("string"));
+        assert_eq!(db.inferred_type("none").unwrap(), None);
+        db.rpush("l".to_owned(), ["x".to_owned()]).unwrap();
+        assert!(matches!(db.inferred_type("l"), Err(Error::WrongType)));
+    }
+}